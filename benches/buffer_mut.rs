@@ -10,6 +10,7 @@ fn buffer_mut(c: &mut Criterion) {
     #[cfg(not(any(target_arch = "wasm32", target_arch = "wasm64")))]
     {
         use criterion::black_box;
+        use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
         use softbuffer::{Context, Surface};
         use std::num::NonZeroU32;
         use winit::platform::run_return::EventLoopExtRunReturn;
@@ -27,8 +28,8 @@ fn buffer_mut(c: &mut Criterion) {
                 control_flow.set_exit();
 
                 let mut surface = unsafe {
-                    let context = Context::new(elwt).unwrap();
-                    Surface::new(&context, &window).unwrap()
+                    let context = Context::from_raw(elwt.raw_display_handle()).unwrap();
+                    Surface::from_raw(&context, window.raw_window_handle()).unwrap()
                 };
 
                 let size = window.inner_size();
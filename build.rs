@@ -1,7 +1,19 @@
 fn main() {
+    println!("cargo:rustc-check-cfg=cfg(free_unix, kms_platform, x11_platform, wayland_platform, web_platform, window_platform, send_platform)");
+    // `objc`'s `msg_send!` expands to a check for this feature.
+    println!("cargo:rustc-check-cfg=cfg(feature, values(\"cargo-clippy\"))");
+
     cfg_aliases::cfg_aliases! {
         free_unix: { all(unix, not(any(target_vendor = "apple", target_os = "android", target_os = "redox"))) },
+        kms_platform: { all(feature = "kms", any(target_os = "linux", target_os = "freebsd")) },
         x11_platform: { all(feature = "x11", free_unix, not(target_arch = "wasm32")) },
         wayland_platform: { all(feature = "wayland", free_unix, not(target_arch = "wasm32")) },
+        // WASI has no browser to draw into, only framebuffers shared with the host.
+        web_platform: { all(target_arch = "wasm32", not(target_os = "wasi")) },
+        // Whether any backend draws to windows, and not only to framebuffers and terminals.
+        window_platform: { any(x11_platform, wayland_platform, kms_platform, web_platform, target_os = "windows", target_os = "macos", target_os = "ios", target_os = "android", target_os = "redox") },
+        // `Context` and `Surface` are `Send`, except where windows can only be drawn to from the
+        // thread that created them.
+        send_platform: { not(any(target_vendor = "apple", web_platform, all(target_os = "windows", feature = "winrt"))) },
     }
 }
@@ -1,4 +1,5 @@
 use instant::Instant;
+use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 #[cfg(not(target_arch = "wasm32"))]
 use rayon::prelude::*;
 use std::f64::consts::PI;
@@ -25,8 +26,9 @@ fn main() {
             .unwrap();
     }
 
-    let context = unsafe { softbuffer::Context::new(&window) }.unwrap();
-    let mut surface = unsafe { softbuffer::Surface::new(&context, &window) }.unwrap();
+    let context = unsafe { softbuffer::Context::from_raw(window.raw_display_handle()) }.unwrap();
+    let mut surface =
+        unsafe { softbuffer::Surface::from_raw(&context, window.raw_window_handle()) }.unwrap();
 
     let mut old_size = (0, 0);
     let mut frames = pre_render_frames(0, 0);
@@ -1,4 +1,5 @@
 use image::GenericImageView;
+use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 use std::num::NonZeroU32;
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
@@ -28,8 +29,9 @@ fn main() {
             .unwrap();
     }
 
-    let context = unsafe { softbuffer::Context::new(&window) }.unwrap();
-    let mut surface = unsafe { softbuffer::Surface::new(&context, &window) }.unwrap();
+    let context = unsafe { softbuffer::Context::from_raw(window.raw_display_handle()) }.unwrap();
+    let mut surface =
+        unsafe { softbuffer::Surface::from_raw(&context, window.raw_window_handle()) }.unwrap();
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Wait;
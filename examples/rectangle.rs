@@ -1,3 +1,4 @@
+use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 use std::num::NonZeroU32;
 use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
@@ -41,8 +42,9 @@ fn main() {
             .unwrap();
     }
 
-    let context = unsafe { softbuffer::Context::new(&window) }.unwrap();
-    let mut surface = unsafe { softbuffer::Surface::new(&context, &window) }.unwrap();
+    let context = unsafe { softbuffer::Context::from_raw(window.raw_display_handle()) }.unwrap();
+    let mut surface =
+        unsafe { softbuffer::Surface::from_raw(&context, window.raw_window_handle()) }.unwrap();
 
     let mut flag = false;
 
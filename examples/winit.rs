@@ -1,11 +1,12 @@
-use std::num::NonZeroU32;
+use softbuffer::Surface;
+use std::rc::Rc;
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 
 fn main() {
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().build(&event_loop).unwrap();
+    let window = Rc::new(WindowBuilder::new().build(&event_loop).unwrap());
 
     #[cfg(target_arch = "wasm32")]
     {
@@ -21,12 +22,16 @@ fn main() {
             .unwrap();
     }
 
-    let context = unsafe { softbuffer::Context::new(&window) }.unwrap();
-    let mut surface = unsafe { softbuffer::Surface::new(&context, &window) }.unwrap();
+    let mut surface = Surface::from_winit(window.clone()).unwrap();
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Wait;
 
+        // Keep the surface the size of the window.
+        if surface.handle_winit_event(&event).unwrap() {
+            window.request_redraw();
+        }
+
         match event {
             Event::RedrawRequested(window_id) if window_id == window.id() => {
                 let (width, height) = {
@@ -34,12 +39,10 @@ fn main() {
                     (size.width, size.height)
                 };
 
-                surface
-                    .resize(
-                        NonZeroU32::new(width).unwrap(),
-                        NonZeroU32::new(height).unwrap(),
-                    )
-                    .unwrap();
+                // The window is minimized, or on Web, not laid out yet.
+                if width == 0 || height == 0 {
+                    return;
+                }
 
                 let mut buffer = surface.buffer_mut().unwrap();
                 for y in 0..height {
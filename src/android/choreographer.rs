@@ -0,0 +1,90 @@
+//! Vertical blank notifications through `AChoreographer`.
+
+use crate::SoftBufferError;
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::os::raw::c_long;
+use std::ptr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a frame before giving up, e.g. because the display is off.
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Block until the choreographer of this thread starts the next frame.
+pub(super) fn wait_for_frame() -> Result<(), SoftBufferError> {
+    next_frame_time().map(drop)
+}
+
+/// Measure the refresh interval of the display, the time between the vertical blanks that start
+/// two consecutive frames.
+pub(super) fn refresh_period() -> Result<Duration, SoftBufferError> {
+    let timed_out = || {
+        SoftBufferError::PlatformError(Some("Timed out waiting for AChoreographer".into()), None)
+    };
+    let first = next_frame_time()?.ok_or_else(timed_out)?;
+    let second = next_frame_time()?.ok_or_else(timed_out)?;
+    // `c_long` is 32 bits on 32-bit targets, so the times wrap around every few seconds.
+    let period = second.wrapping_sub(first);
+    if period <= 0 {
+        return Err(timed_out());
+    }
+    Ok(Duration::from_nanos(period as u64))
+}
+
+/// Block until the choreographer of this thread starts the next frame, and return the time of
+/// its vertical blank in nanoseconds, or `None` if it timed out.
+///
+/// The frame callback is dispatched by the `ALooper` of this thread, so it is polled while
+/// waiting. Events for other sources stay pending for the event loop of the app.
+fn next_frame_time() -> Result<Option<c_long>, SoftBufferError> {
+    let choreographer = unsafe { ndk_sys::AChoreographer_getInstance() };
+    if choreographer.is_null() {
+        return Err(SoftBufferError::PlatformError(
+            Some("AChoreographer is only available on threads with an ALooper".into()),
+            None,
+        ));
+    }
+
+    // Shared with the callback, which may still run after we gave up waiting.
+    let frame_time = Rc::new(Cell::new(None));
+    unsafe {
+        ndk_sys::AChoreographer_postFrameCallback(
+            choreographer,
+            Some(callback),
+            Rc::into_raw(frame_time.clone()) as *mut c_void,
+        )
+    };
+
+    let deadline = Instant::now() + TIMEOUT;
+    while frame_time.get().is_none() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+
+        let result = unsafe {
+            ndk_sys::ALooper_pollOnce(
+                remaining.as_millis() as i32,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if result == ndk_sys::ALOOPER_POLL_ERROR {
+            return Err(SoftBufferError::PlatformError(
+                Some("Failed to poll ALooper".into()),
+                None,
+            ));
+        }
+    }
+
+    Ok(frame_time.get())
+}
+
+unsafe extern "C" fn callback(frame_time_nanos: c_long, data: *mut c_void) {
+    // SAFETY: The data is the `Rc` leaked in `next_frame_time`, on this same thread.
+    let frame_time = unsafe { Rc::from_raw(data as *const Cell<Option<c_long>>) };
+    frame_time.set(Some(frame_time_nanos));
+}
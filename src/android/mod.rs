@@ -0,0 +1,646 @@
+//! Implementation of software buffering for Android.
+
+use crate::{
+    convert, stats, util, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus,
+    Rect, Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
+use ndk::hardware_buffer_format::HardwareBufferFormat;
+use ndk::native_window::{NativeWindow, Rect as ARect};
+use raw_window_handle::AndroidNdkWindowHandle;
+
+mod choreographer;
+mod rotation;
+#[cfg(feature = "hardware-buffer")]
+mod surface_control;
+#[cfg(feature = "hardware-buffer")]
+use surface_control::SurfaceControl;
+
+use rotation::Rotation;
+
+use std::ffi::c_void;
+use std::num::NonZeroU32;
+use std::ops::Range;
+use std::os::raw::{c_char, c_int};
+use std::ptr::NonNull;
+use std::slice;
+use std::time::Duration;
+
+/// The format we ask for, which only needs the red and blue channels of our `0RGB` pixels swapped.
+const PREFERRED_FORMAT: HardwareBufferFormat = HardwareBufferFormat::R8G8B8X8_UNORM;
+
+/// The largest width and height of a buffer, as the geometry of a window is set in `i32`s.
+const MAX_SIZE: (u32, u32) = (i32::MAX as u32, i32::MAX as u32);
+
+pub struct AndroidImpl {
+    /// `None` while the app is suspended and has no window.
+    native_window: Option<NativeWindow>,
+    /// Presents hardware buffers instead of locking the window, if it could be created.
+    #[cfg(feature = "hardware-buffer")]
+    surface_control: Option<SurfaceControl>,
+    /// Hardware buffers are pooled, so single buffered surfaces don't use them for new windows.
+    #[cfg(feature = "hardware-buffer")]
+    single_buffered: bool,
+    width: u32,
+    height: u32,
+    /// The rotation of the display that the buffers of the window are pre-rotated to.
+    rotation: Rotation,
+    buffer: Vec<u32>,
+    /// Whether `buffer` was presented at its current size.
+    presented: bool,
+}
+
+impl AndroidImpl {
+    /// Create a new surface for the given `ANativeWindow`.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be valid.
+    pub unsafe fn new(handle: AndroidNdkWindowHandle) -> Result<Self, SoftBufferError> {
+        let native_window = NonNull::new(handle.a_native_window.cast())
+            .ok_or(SoftBufferError::IncompleteWindowHandle)?;
+        // Acquire our own reference, so the window stays valid for the lifetime of the surface.
+        let native_window = unsafe { NativeWindow::clone_from_ptr(native_window) };
+
+        Ok(Self {
+            #[cfg(feature = "hardware-buffer")]
+            surface_control: create_surface_control(&native_window),
+            #[cfg(feature = "hardware-buffer")]
+            single_buffered: false,
+            native_window: Some(native_window),
+            width: 0,
+            height: 0,
+            rotation: Rotation::Deg0,
+            buffer: Vec::new(),
+            presented: false,
+        })
+    }
+
+    /// Set the size of the buffers of the window, in the format we prefer.
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
+        if (width.get(), height.get()) != (self.width, self.height) {
+            self.presented = false;
+        }
+        self.width = width.get();
+        self.height = height.get();
+
+        // Without a window, the geometry is set once a new one is attached.
+        match &self.native_window {
+            Some(native_window) => {
+                // The display is usually rotated along with a resize of the window.
+                self.rotation = Rotation::query();
+                set_geometry(native_window, self.width, self.height, self.rotation)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Attach the surface to a new window, e.g. after the app was resumed.
+    fn set_native_window(&mut self, native_window: NativeWindow) -> Result<(), SoftBufferError> {
+        if self.width != 0 && self.height != 0 {
+            self.rotation = Rotation::query();
+            set_geometry(&native_window, self.width, self.height, self.rotation)?;
+        }
+        #[cfg(feature = "hardware-buffer")]
+        {
+            self.surface_control = if self.single_buffered {
+                None
+            } else {
+                create_surface_control(&native_window)
+            };
+        }
+        self.native_window = Some(native_window);
+        Ok(())
+    }
+
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(SoftBufferError::SurfaceNotConfigured);
+        }
+
+        util::resize_pixels(&mut self.buffer, self.width as usize * self.height as usize);
+        Ok(BufferImpl { imp: self })
+    }
+
+    /// Copy the buffer, which is kept between presents, since the window can't be read back.
+    pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        let len = self.width as usize * self.height as usize;
+        if self.presented && self.buffer.len() == len {
+            Ok(self.buffer.clone())
+        } else {
+            Ok(vec![0; len])
+        }
+    }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// The buffers belong to the `ANativeWindow`.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Wait for the choreographer to start the next frame.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        choreographer::wait_for_frame()
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Measure the refresh interval with the choreographer.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        choreographer::refresh_period()
+    }
+
+    /// Get the size of the window in physical pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        // Once the buffer geometry is set, the window reports that instead of its own size.
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Not implemented yet, see `Surface::visibility()`.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set how the buffer is scaled to the window.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        match scaling {
+            Scaling::TopLeft => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The buffer is always stretched to the window.
+    pub fn set_background_color(&mut self, _color: u32) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Locking the window waits for a buffer without a timeout.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        if timeout.is_some() {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The window is locked with a single buffer of ours, but hardware buffers are pooled.
+    pub fn set_single_buffered(&mut self, single: bool) -> Result<(), SoftBufferError> {
+        #[cfg(feature = "hardware-buffer")]
+        {
+            if single && self.surface_control.is_some() {
+                return Err(SoftBufferError::Unimplemented);
+            }
+            self.single_buffered = single;
+        }
+        #[cfg(not(feature = "hardware-buffer"))]
+        let _ = single;
+        Ok(())
+    }
+
+    /// Rows are always shown top-down.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        if flip {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Android keeps showing the last buffer posted until the next present.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// The buffers of the window are in physical pixels.
+    pub fn set_scale_factor(&mut self, _scale_factor: f64) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Windows are always rectangular.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        // Hardware buffers aren't preserved, so they are always copied completely.
+        #[cfg(feature = "hardware-buffer")]
+        if self.surface_control.is_some() {
+            return Capabilities {
+                partial_present: false,
+                zero_copy: false,
+                max_size: MAX_SIZE,
+                buffer_count: 1,
+                readback: false,
+            };
+        }
+
+        // Rotated buffers are copied completely too. Windows have no format with the channels of
+        // `0RGB` that ignores the alpha channel, so the pixels are always converted.
+        Capabilities {
+            partial_present: self.rotation == Rotation::Deg0,
+            zero_copy: false,
+            max_size: MAX_SIZE,
+            buffer_count: 1,
+            readback: false,
+        }
+    }
+
+    /// Windows only show their own buffers, or hardware buffers allocated by softbuffer.
+    pub unsafe fn present_external(
+        &mut self,
+        _memory: ExternalMemory,
+        _width: NonZeroU32,
+        _height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+pub struct BufferImpl<'a> {
+    imp: &'a mut AndroidImpl,
+}
+
+impl<'a> BufferImpl<'a> {
+    #[inline]
+    pub fn pixels(&self) -> &[u32] {
+        &self.imp.buffer
+    }
+
+    #[inline]
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.imp.buffer
+    }
+
+    /// The pixels are kept in normal memory, so they stay the same after the copy.
+    pub fn age(&self) -> u8 {
+        if self.imp.presented {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// The pixels are copied into the window, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    /// The pixels are copied into the window.
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        None
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        self.present_with_damage(&[])
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let imp = self.imp;
+        let native_window = match imp.native_window.as_ref() {
+            Some(native_window) => native_window,
+            None => return Ok(PresentStatus::OutOfDate),
+        };
+
+        #[cfg(feature = "hardware-buffer")]
+        if let Some(surface_control) = imp.surface_control.as_mut() {
+            surface_control.present(&imp.buffer, imp.width, imp.height)?;
+            imp.presented = true;
+            return Ok(PresentStatus::Presented);
+        }
+
+        // The window keeps the contents of its buffers outside of the dirty bounds, which are
+        // commonly enlarged by the lock. Rotated buffers are always copied completely.
+        let mut dirty_bounds = match imp.rotation {
+            Rotation::Deg0 => damage_bounds(damage, imp.width, imp.height),
+            _ => None,
+        };
+        let mut guard = {
+            trace_span!("lock_window");
+            native_window.lock(dirty_bounds.as_mut())
+        }
+        .map_err(|err| {
+            SoftBufferError::PlatformError(
+                Some("Failed to lock the ANativeWindow".into()),
+                Some(Box::new(err)),
+            )
+        })?;
+
+        // The window may not have applied the new geometry yet, so only copy what fits.
+        let (rotated_width, rotated_height) = imp.rotation.rotate_size(imp.width, imp.height);
+        let width = guard.width().min(rotated_width as usize);
+        let height = guard.height().min(rotated_height as usize);
+        let (columns, rows) = match dirty_bounds {
+            Some(bounds) => (
+                (bounds.left.max(0) as usize).min(width)..(bounds.right.max(0) as usize).min(width),
+                (bounds.top.max(0) as usize).min(height)
+                    ..(bounds.bottom.max(0) as usize).min(height),
+            ),
+            None => (0..width, 0..height),
+        };
+        let (stride, format) = (guard.stride(), guard.format());
+
+        // SAFETY: The window buffer holds `stride * height` pixels of the locked format, and is
+        // only accessed through `bits` until the guard is dropped.
+        unsafe {
+            copy_converted(
+                &imp.buffer,
+                imp.width as usize,
+                guard.bits(),
+                stride,
+                (columns, rows),
+                format,
+                imp.rotation,
+            )?
+        };
+
+        // Dropping the guard unlocks the buffer and posts it to the window.
+        trace_span!("unlock_and_post");
+        drop(guard);
+        imp.presented = true;
+        Ok(PresentStatus::Presented)
+    }
+
+    /// The buffers of the window are the size of the buffer.
+    pub fn present_viewport(
+        self,
+        _damage: &[Rect],
+        _viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+/// The bounding box of the damage, clipped to the buffer, or `None` if all of it is damaged.
+fn damage_bounds(damage: &[Rect], width: u32, height: u32) -> Option<ARect> {
+    let (width, height) = (NonZeroU32::new(width)?, NonZeroU32::new(height)?);
+    let bounds = damage
+        .iter()
+        .filter_map(|rect| rect.clamp(width, height))
+        .reduce(|a, b| a.union(&b))?;
+    Some(ARect {
+        left: bounds.x as i32,
+        top: bounds.y as i32,
+        right: (bounds.x + bounds.width.get()) as i32,
+        bottom: (bounds.y + bounds.height.get()) as i32,
+    })
+}
+
+/// Extension methods for Android on [`Surface`](crate::Surface).
+///
+/// Android destroys the window of an app when it is suspended, and creates a new one when it is
+/// resumed. The surface survives this: release the window when the app is suspended, and attach
+/// the new one when it is resumed. In between, [`Buffer::present`](crate::Buffer::present) returns
+/// [`PresentStatus::OutOfDate`](crate::PresentStatus::OutOfDate).
+pub trait SurfaceExtAndroid {
+    /// Attach the surface to a new `ANativeWindow`, keeping the size set with
+    /// [`Surface::resize`](crate::Surface::resize).
+    ///
+    /// # Safety
+    ///
+    ///  - Ensure that the provided handle is valid for the lifetime of the Surface
+    unsafe fn set_native_window(
+        &mut self,
+        handle: AndroidNdkWindowHandle,
+    ) -> Result<(), SoftBufferError>;
+
+    /// Release the `ANativeWindow`, so it can be destroyed.
+    ///
+    /// This should be called when the app is suspended, before returning from the callback.
+    fn release_native_window(&mut self);
+}
+
+impl<D, W> SurfaceExtAndroid for crate::Surface<D, W> {
+    unsafe fn set_native_window(
+        &mut self,
+        handle: AndroidNdkWindowHandle,
+    ) -> Result<(), SoftBufferError> {
+        let native_window = NonNull::new(handle.a_native_window.cast())
+            .ok_or(SoftBufferError::IncompleteWindowHandle)?;
+        let native_window = unsafe { NativeWindow::clone_from_ptr(native_window) };
+        // The new window doesn't show the last buffer yet.
+        self.last_hash = None;
+        match &mut *self.surface_impl {
+            crate::SurfaceDispatch::Android(imp) => imp.set_native_window(native_window)?,
+            #[allow(unreachable_patterns)]
+            _ => return Err(SoftBufferError::Unimplemented),
+        }
+        Ok(())
+    }
+
+    // Other backends only exist with some features.
+    #[allow(clippy::single_match)]
+    fn release_native_window(&mut self) {
+        match &mut *self.surface_impl {
+            crate::SurfaceDispatch::Android(imp) => {
+                #[cfg(feature = "hardware-buffer")]
+                {
+                    imp.surface_control = None;
+                }
+                imp.native_window = None;
+            }
+            #[allow(unreachable_patterns)]
+            _ => {}
+        }
+    }
+}
+
+const RTLD_NOW: c_int = 2;
+
+extern "C" {
+    fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+}
+
+/// Look up a function of `libandroid.so` by its nul-terminated name, for functions of newer API
+/// levels, so the library still loads on older devices without them.
+fn libandroid_symbol(name: &[u8]) -> Option<NonNull<c_void>> {
+    debug_assert_eq!(name.last(), Some(&0));
+    unsafe {
+        let library = dlopen(b"libandroid.so\0".as_ptr().cast(), RTLD_NOW);
+        if library.is_null() {
+            return None;
+        }
+        NonNull::new(dlsym(library, name.as_ptr().cast()))
+    }
+}
+
+/// Create a child surface of the window for presenting hardware buffers, or fall back to locking
+/// the window.
+#[cfg(feature = "hardware-buffer")]
+fn create_surface_control(native_window: &NativeWindow) -> Option<SurfaceControl> {
+    match SurfaceControl::new(native_window) {
+        Ok(surface_control) => Some(surface_control),
+        Err(err) => {
+            crate::error::report(
+                crate::Backend::Android,
+                SoftBufferError::PlatformError(
+                    Some(format!(
+                        "Falling back to locking the ANativeWindow: {}",
+                        err
+                    )),
+                    None,
+                ),
+            );
+            None
+        }
+    }
+}
+
+/// Set the size of the buffers of the window, in the format we prefer, and pre-rotated to the
+/// display.
+fn set_geometry(
+    native_window: &NativeWindow,
+    width: u32,
+    height: u32,
+    rotation: Rotation,
+) -> Result<(), SoftBufferError> {
+    // The size was checked by `resize()`.
+    let (width, height) = rotation.rotate_size(width, height);
+    let (width, height) = (width as i32, height as i32);
+
+    // Some devices only accept the format the window was created with, e.g. `RGB_565`. The
+    // size must still be honored, and `present()` converts into whatever format we get.
+    native_window
+        .set_buffers_geometry(width, height, Some(PREFERRED_FORMAT))
+        .or_else(|err| {
+            log::debug!("Falling back to the native window format: {}", err);
+            native_window.set_buffers_geometry(width, height, None)
+        })
+        .map_err(|err| {
+            SoftBufferError::PlatformError(
+                Some("Failed to set the buffer geometry of the ANativeWindow".into()),
+                Some(Box::new(err)),
+            )
+        })?;
+
+    let status =
+        unsafe { rotation::set_buffers_transform(native_window.ptr().as_ptr().cast(), rotation) };
+    if status != 0 {
+        return Err(SoftBufferError::PlatformError(
+            Some(format!(
+                "Failed to set the buffer transform of the ANativeWindow: {}",
+                status
+            )),
+            None,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Convert the pixels of `src` into a locked buffer of the given format, with `stride` pixels per
+/// row, rotating them clockwise by `rotation`.
+///
+/// Only the pixels within `columns` and `rows` of the window buffer, which are in the rotated
+/// orientation, are written.
+///
+/// # Safety
+///
+/// `dst` must point to `stride * rows.end` writable pixels of the format, and `columns.end` may
+/// not exceed `stride`.
+unsafe fn copy_converted(
+    src: &[u32],
+    src_width: usize,
+    dst: *mut c_void,
+    stride: usize,
+    region: (Range<usize>, Range<usize>),
+    format: HardwareBufferFormat,
+    rotation: Rotation,
+) -> Result<(), SoftBufferError> {
+    trace_span!("copy");
+    match format {
+        HardwareBufferFormat::R8G8B8A8_UNORM | HardwareBufferFormat::R8G8B8X8_UNORM => unsafe {
+            copy_pixels(
+                src,
+                src_width,
+                dst.cast(),
+                stride,
+                region.clone(),
+                rotation,
+                convert::xrgb_to_rgbx,
+            )
+        },
+        HardwareBufferFormat::R5G6B5_UNORM => unsafe {
+            copy_pixels(
+                src,
+                src_width,
+                dst.cast(),
+                stride,
+                region.clone(),
+                rotation,
+                convert::xrgb_to_rgb565,
+            )
+        },
+        format => {
+            return Err(SoftBufferError::PlatformError(
+                Some(format!("Unsupported buffer format: {:?}", format)),
+                None,
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/// # Safety
+///
+/// See `copy_converted`.
+#[inline]
+unsafe fn copy_pixels<T: Copy + Default + Send>(
+    src: &[u32],
+    src_width: usize,
+    dst: *mut T,
+    stride: usize,
+    (columns, rows): (Range<usize>, Range<usize>),
+    rotation: Rotation,
+    convert: impl Fn(&[u32], &mut [T]) + Send + Sync,
+) {
+    if rotation == Rotation::Deg0 {
+        let dst =
+            unsafe { slice::from_raw_parts_mut(dst.add(rows.start * stride), stride * rows.len()) };
+        convert::for_each_row(dst, stride, |y, dst| {
+            let y = rows.start + y;
+            if let Some(row) = src.get(y * src_width..(y + 1) * src_width) {
+                convert(&row[columns.clone()], &mut dst[columns.clone()]);
+            }
+        });
+        return;
+    }
+
+    // Rotated rows are scattered over the columns of the window buffer, so convert them first.
+    let src_size = (src_width, src.len() / src_width);
+    let mut converted = vec![T::default(); src_width];
+    for (y, row) in src.chunks_exact(src_width).enumerate() {
+        convert(row, &mut converted);
+        for (x, &pixel) in converted.iter().enumerate() {
+            let (x, y) = rotation.rotate_point((x, y), src_size);
+            if columns.contains(&x) && rows.contains(&y) {
+                unsafe { dst.add(y * stride + x).write(pixel) };
+            }
+        }
+    }
+}
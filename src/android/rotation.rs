@@ -0,0 +1,152 @@
+//! Pre-rotation of the buffer to the orientation of the display.
+//!
+//! When the display is rotated, the compositor has to rotate the buffers of the window to the
+//! natural orientation of the panel, which usually costs a GPU pass. Instead, we rotate the pixels
+//! while copying them anyway, and tell the compositor that the buffer is already rotated.
+
+use jni::objects::JObject;
+use jni::JavaVM;
+
+use std::ffi::c_void;
+
+/// `ANATIVEWINDOW_TRANSFORM_*` values.
+const TRANSFORM_IDENTITY: i32 = 0;
+const TRANSFORM_ROTATE_90: i32 = 4;
+const TRANSFORM_ROTATE_180: i32 = 3;
+const TRANSFORM_ROTATE_270: i32 = 7;
+
+type SetBuffersTransform = unsafe extern "C" fn(window: *mut c_void, transform: i32) -> i32;
+
+/// How far the content of the window is rotated clockwise on the panel, as returned by
+/// `Display.getRotation()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum Rotation {
+    #[default]
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+impl Rotation {
+    /// Query the rotation of the default display of the activity.
+    ///
+    /// This falls back to no rotation if the activity isn't known, or the window can't be told
+    /// about pre-rotated buffers.
+    pub fn query() -> Self {
+        if set_buffers_transform_fn().is_none() {
+            return Self::Deg0;
+        }
+
+        match display_rotation() {
+            Ok(1) => Self::Deg90,
+            Ok(2) => Self::Deg180,
+            Ok(3) => Self::Deg270,
+            Ok(_) => Self::Deg0,
+            Err(err) => {
+                log::debug!("Failed to query the display rotation: {}", err);
+                Self::Deg0
+            }
+        }
+    }
+
+    /// Whether the width and height of the buffer are swapped.
+    pub fn swaps_axes(self) -> bool {
+        matches!(self, Self::Deg90 | Self::Deg270)
+    }
+
+    /// The size of the window buffer for a buffer of the given size.
+    pub fn rotate_size(self, width: u32, height: u32) -> (u32, u32) {
+        if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// The `ANativeWindowTransform` that undoes the rotation, so the compositor can show the
+    /// buffer on the panel as it is.
+    pub fn inverse_transform(self) -> i32 {
+        match self {
+            Self::Deg0 => TRANSFORM_IDENTITY,
+            Self::Deg90 => TRANSFORM_ROTATE_270,
+            Self::Deg180 => TRANSFORM_ROTATE_180,
+            Self::Deg270 => TRANSFORM_ROTATE_90,
+        }
+    }
+
+    /// Map a pixel of a `width` by `height` buffer to its position in the rotated buffer.
+    #[inline]
+    pub fn rotate_point(
+        self,
+        (x, y): (usize, usize),
+        (width, height): (usize, usize),
+    ) -> (usize, usize) {
+        match self {
+            Self::Deg0 => (x, y),
+            Self::Deg90 => (height - 1 - y, x),
+            Self::Deg180 => (width - 1 - x, height - 1 - y),
+            Self::Deg270 => (y, width - 1 - x),
+        }
+    }
+}
+
+/// Set the transform of the buffers of a window.
+///
+/// # Safety
+///
+/// `window` must be a valid `ANativeWindow`.
+pub(super) unsafe fn set_buffers_transform(window: *mut c_void, rotation: Rotation) -> i32 {
+    match set_buffers_transform_fn() {
+        Some(set_buffers_transform) => unsafe {
+            set_buffers_transform(window, rotation.inverse_transform())
+        },
+        // Without the function, `query()` never returns a rotation.
+        None => 0,
+    }
+}
+
+/// `ANativeWindow_setBuffersTransform` is only available since API level 26, so look it up at
+/// runtime instead of failing to load on older devices.
+fn set_buffers_transform_fn() -> Option<SetBuffersTransform> {
+    let symbol = super::libandroid_symbol(b"ANativeWindow_setBuffersTransform\0")?;
+    // SAFETY: The function has this signature.
+    Some(unsafe { std::mem::transmute::<*mut c_void, SetBuffersTransform>(symbol.as_ptr()) })
+}
+
+/// Call `getWindowManager().getDefaultDisplay().getRotation()` on the activity.
+fn display_rotation() -> Result<i32, Box<dyn std::error::Error>> {
+    // `ndk-context` panics if the app never initialized it.
+    let context = std::panic::catch_unwind(ndk_context::android_context)
+        .map_err(|_| "The Android context is not initialized")?;
+    let vm = unsafe { JavaVM::from_raw(context.vm().cast()) }?;
+    let mut env = vm.attach_current_thread()?;
+    let activity = unsafe { JObject::from_raw(context.context().cast()) };
+
+    // Free the local references right away, the thread may stay attached for a long time.
+    let result = env.with_local_frame(4, |env| -> jni::errors::Result<i32> {
+        let window_manager = env
+            .call_method(
+                &activity,
+                "getWindowManager",
+                "()Landroid/view/WindowManager;",
+                &[],
+            )?
+            .l()?;
+        let display = env
+            .call_method(
+                &window_manager,
+                "getDefaultDisplay",
+                "()Landroid/view/Display;",
+                &[],
+            )?
+            .l()?;
+        env.call_method(&display, "getRotation", "()I", &[])?.i()
+    });
+    if result.is_err() {
+        // Don't leave a pending exception behind, e.g. if the context isn't an activity.
+        let _ = env.exception_clear();
+    }
+
+    Ok(result?)
+}
@@ -0,0 +1,400 @@
+//! Presenting `AHardwareBuffer`s through `ASurfaceControl`, enabled by the `hardware-buffer`
+//! feature.
+//!
+//! Instead of locking the window, the buffer is converted into a hardware buffer that the
+//! compositor can scan out or sample directly, and set on a child surface of the window in a
+//! transaction. This requires Android 10 (API level 29), and falls back to locking the window on
+//! older devices.
+
+use super::copy_converted;
+use super::rotation::Rotation;
+use crate::error::SwResultExt;
+use crate::SoftBufferError;
+use ndk::hardware_buffer::{
+    HardwareBuffer, HardwareBufferDesc, HardwareBufferRef, HardwareBufferUsage,
+};
+use ndk::hardware_buffer_format::HardwareBufferFormat;
+use ndk::native_window::NativeWindow;
+
+use std::ffi::c_void;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::io::{FromRawFd, IntoRawFd, OwnedFd};
+use std::ptr::NonNull;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// The number of buffers to cycle through, so one can be drawn while the compositor holds the
+/// others.
+const BUFFER_COUNT: usize = 3;
+
+/// How long to wait for the compositor to release a buffer before giving up.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+const FORMAT: HardwareBufferFormat = HardwareBufferFormat::R8G8B8X8_UNORM;
+
+/// Written by the CPU, and read by the compositor either directly or through the GPU.
+const USAGE: HardwareBufferUsage = HardwareBufferUsage(ndk_sys::AHardwareBuffer_UsageFlags(
+    HardwareBufferUsage::CPU_WRITE_OFTEN.0 .0
+        | HardwareBufferUsage::COMPOSER_OVERLAY.0 .0
+        | HardwareBufferUsage::GPU_SAMPLED_IMAGE.0 .0,
+));
+
+#[repr(C)]
+struct ASurfaceControl {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct ASurfaceTransaction {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct ASurfaceTransactionStats {
+    _private: [u8; 0],
+}
+
+type OnComplete = unsafe extern "C" fn(context: *mut c_void, stats: *mut ASurfaceTransactionStats);
+
+type CreateFromWindow =
+    unsafe extern "C" fn(parent: *mut c_void, debug_name: *const c_char) -> *mut ASurfaceControl;
+type Release = unsafe extern "C" fn(surface_control: *mut ASurfaceControl);
+type TransactionCreate = unsafe extern "C" fn() -> *mut ASurfaceTransaction;
+type TransactionDelete = unsafe extern "C" fn(transaction: *mut ASurfaceTransaction);
+type TransactionApply = unsafe extern "C" fn(transaction: *mut ASurfaceTransaction);
+type TransactionSetBuffer = unsafe extern "C" fn(
+    transaction: *mut ASurfaceTransaction,
+    surface_control: *mut ASurfaceControl,
+    buffer: *mut c_void,
+    acquire_fence_fd: c_int,
+);
+type TransactionSetOnComplete = unsafe extern "C" fn(
+    transaction: *mut ASurfaceTransaction,
+    context: *mut c_void,
+    func: OnComplete,
+);
+type StatsGetPreviousReleaseFenceFd = unsafe extern "C" fn(
+    stats: *mut ASurfaceTransactionStats,
+    surface_control: *mut ASurfaceControl,
+) -> c_int;
+
+/// The functions of `ASurfaceControl`, which are looked up at runtime, as they are only available
+/// since API level 29.
+#[derive(Clone, Copy)]
+struct Functions {
+    create_from_window: CreateFromWindow,
+    release: Release,
+    transaction_create: TransactionCreate,
+    transaction_delete: TransactionDelete,
+    transaction_apply: TransactionApply,
+    transaction_set_buffer: TransactionSetBuffer,
+    transaction_set_on_complete: TransactionSetOnComplete,
+    stats_get_previous_release_fence_fd: StatsGetPreviousReleaseFenceFd,
+}
+
+impl Functions {
+    fn load() -> Result<Self, SoftBufferError> {
+        macro_rules! load {
+            ($name:literal as $ty:ty) => {{
+                let symbol = super::libandroid_symbol(concat!($name, "\0").as_bytes())
+                    .swbuf_err(concat!($name, " requires Android 10 (API level 29)"))?;
+                // SAFETY: The function has this signature.
+                unsafe { std::mem::transmute::<*mut c_void, $ty>(symbol.as_ptr()) }
+            }};
+        }
+        Ok(Self {
+            create_from_window: load!("ASurfaceControl_createFromWindow" as CreateFromWindow),
+            release: load!("ASurfaceControl_release" as Release),
+            transaction_create: load!("ASurfaceTransaction_create" as TransactionCreate),
+            transaction_delete: load!("ASurfaceTransaction_delete" as TransactionDelete),
+            transaction_apply: load!("ASurfaceTransaction_apply" as TransactionApply),
+            transaction_set_buffer: load!("ASurfaceTransaction_setBuffer" as TransactionSetBuffer),
+            transaction_set_on_complete: load!(
+                "ASurfaceTransaction_setOnComplete" as TransactionSetOnComplete
+            ),
+            stats_get_previous_release_fence_fd: load!(
+                "ASurfaceTransactionStats_getPreviousReleaseFenceFd"
+                    as StatsGetPreviousReleaseFenceFd
+            ),
+        })
+    }
+}
+
+/// Whether a buffer may be drawn into, shared with the transaction callbacks.
+struct Slot {
+    busy: bool,
+    /// Signaled once the compositor stopped reading the buffer.
+    release_fence: Option<OwnedFd>,
+}
+
+struct Shared {
+    /// Bumped when the buffers are reallocated, so callbacks for old buffers are ignored.
+    generation: u64,
+    slots: Vec<Slot>,
+    /// The number of transactions whose callback didn't run yet.
+    pending: usize,
+    /// Set when the surface was dropped while transactions were pending, so the last callback
+    /// releases the `ASurfaceControl` instead, which it still needs.
+    dropped: bool,
+}
+
+/// Context of a transaction callback.
+struct Completion {
+    functions: Functions,
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+    surface_control: NonNull<ASurfaceControl>,
+    generation: u64,
+    /// The buffer that the transaction replaced.
+    previous: Option<usize>,
+}
+
+pub(super) struct SurfaceControl {
+    functions: Functions,
+    surface_control: NonNull<ASurfaceControl>,
+    buffers: Vec<HardwareBufferRef>,
+    size: (u32, u32),
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+    /// The buffer set by the last transaction.
+    displayed: Option<usize>,
+}
+
+// SAFETY: `ASurfaceControl`s and `AHardwareBuffer`s are reference counted and may be used from
+// any thread, and the state shared with the transaction callbacks is behind a mutex already.
+unsafe impl Send for SurfaceControl {}
+
+impl SurfaceControl {
+    /// Create a child surface of the window.
+    pub fn new(native_window: &NativeWindow) -> Result<Self, SoftBufferError> {
+        let functions = Functions::load()?;
+        let surface_control = unsafe {
+            (functions.create_from_window)(
+                native_window.ptr().as_ptr().cast(),
+                b"softbuffer\0".as_ptr().cast(),
+            )
+        };
+        let surface_control = NonNull::new(surface_control).ok_or_else(|| {
+            SoftBufferError::PlatformError(Some("Failed to create ASurfaceControl".into()), None)
+        })?;
+
+        Ok(Self {
+            functions,
+            surface_control,
+            buffers: Vec::new(),
+            size: (0, 0),
+            shared: Arc::new((
+                Mutex::new(Shared {
+                    generation: 0,
+                    slots: Vec::new(),
+                    pending: 0,
+                    dropped: false,
+                }),
+                Condvar::new(),
+            )),
+            displayed: None,
+        })
+    }
+
+    /// Convert the pixels into a free hardware buffer, and set it on the surface.
+    pub fn present(
+        &mut self,
+        pixels: &[u32],
+        width: u32,
+        height: u32,
+    ) -> Result<(), SoftBufferError> {
+        if self.size != (width, height) {
+            // The compositor holds its own references to the buffers it still uses.
+            self.buffers.clear();
+            self.displayed = None;
+            self.size = (width, height);
+            let mut shared = self.shared.0.lock().unwrap();
+            shared.generation += 1;
+            shared.slots.clear();
+        }
+
+        let (index, fence) = self.next_buffer()?;
+        let acquire_fence = match self.fill(index, fence, pixels, width, height) {
+            Ok(acquire_fence) => acquire_fence,
+            Err(err) => {
+                // The buffer isn't handed to the compositor, so it's free again.
+                self.shared.0.lock().unwrap().slots[index].busy = false;
+                return Err(err);
+            }
+        };
+        let buffer = &self.buffers[index];
+
+        let generation = {
+            let mut shared = self.shared.0.lock().unwrap();
+            shared.pending += 1;
+            shared.generation
+        };
+        let functions = self.functions;
+        let completion = Box::new(Completion {
+            functions,
+            shared: self.shared.clone(),
+            surface_control: self.surface_control,
+            generation,
+            previous: self.displayed.replace(index),
+        });
+        unsafe {
+            trace_span!("apply_transaction");
+            let transaction = (functions.transaction_create)();
+            // The transaction takes ownership of the fence.
+            (functions.transaction_set_buffer)(
+                transaction,
+                self.surface_control.as_ptr(),
+                buffer.as_ptr().cast(),
+                acquire_fence.map_or(-1, IntoRawFd::into_raw_fd),
+            );
+            (functions.transaction_set_on_complete)(
+                transaction,
+                Box::into_raw(completion).cast(),
+                on_complete,
+            );
+            (functions.transaction_apply)(transaction);
+            (functions.transaction_delete)(transaction);
+        }
+
+        Ok(())
+    }
+
+    /// Lock the buffer at `index` after `fence`, copy the pixels into it, and unlock it, returning
+    /// the fence for when the copy is done.
+    fn fill(
+        &self,
+        index: usize,
+        fence: Option<OwnedFd>,
+        pixels: &[u32],
+        width: u32,
+        height: u32,
+    ) -> Result<Option<OwnedFd>, SoftBufferError> {
+        let buffer = &self.buffers[index];
+        let bits = buffer
+            .lock(HardwareBufferUsage::CPU_WRITE_OFTEN, fence, None)
+            .map_err(|err| {
+                SoftBufferError::PlatformError(
+                    Some("Failed to lock AHardwareBuffer".into()),
+                    Some(Box::new(err)),
+                )
+            })?;
+        let stride = buffer.describe().stride as usize;
+        // SAFETY: The buffer is locked, and holds `stride * height` pixels of `FORMAT`.
+        let copied = unsafe {
+            copy_converted(
+                pixels,
+                width as usize,
+                bits,
+                stride,
+                (0..width as usize, 0..height as usize),
+                FORMAT,
+                // Child surfaces are left to the compositor to rotate.
+                Rotation::Deg0,
+            )
+        };
+        let acquire_fence = buffer.unlock_async().map_err(|err| {
+            SoftBufferError::PlatformError(
+                Some("Failed to unlock AHardwareBuffer".into()),
+                Some(Box::new(err)),
+            )
+        })?;
+        copied?;
+        Ok(acquire_fence)
+    }
+
+    /// Find a buffer that the compositor released, or allocate one, and mark it as busy.
+    fn next_buffer(&mut self) -> Result<(usize, Option<OwnedFd>), SoftBufferError> {
+        trace_span!("next_buffer");
+        let (lock, condvar) = &*self.shared;
+        let mut shared = lock.lock().unwrap();
+        loop {
+            if let Some(index) = shared.slots.iter().position(|slot| !slot.busy) {
+                let slot = &mut shared.slots[index];
+                slot.busy = true;
+                return Ok((index, slot.release_fence.take()));
+            }
+
+            if self.buffers.len() < BUFFER_COUNT {
+                let buffer = HardwareBuffer::allocate(HardwareBufferDesc {
+                    width: self.size.0,
+                    height: self.size.1,
+                    layers: 1,
+                    format: FORMAT,
+                    usage: USAGE,
+                    stride: 0,
+                })
+                .map_err(|err| {
+                    SoftBufferError::PlatformError(
+                        Some("Failed to allocate AHardwareBuffer".into()),
+                        Some(Box::new(err)),
+                    )
+                })?;
+                self.buffers.push(buffer);
+                shared.slots.push(Slot {
+                    busy: true,
+                    release_fence: None,
+                });
+                return Ok((self.buffers.len() - 1, None));
+            }
+
+            let (guard, timeout) = condvar.wait_timeout(shared, TIMEOUT).unwrap();
+            if timeout.timed_out() {
+                return Err(SoftBufferError::PlatformError(
+                    Some("Timed out waiting for the compositor to release a buffer".into()),
+                    None,
+                ));
+            }
+            shared = guard;
+        }
+    }
+}
+
+impl Drop for SurfaceControl {
+    fn drop(&mut self) {
+        let mut shared = self.shared.0.lock().unwrap();
+        if shared.pending > 0 {
+            shared.dropped = true;
+        } else {
+            unsafe { (self.functions.release)(self.surface_control.as_ptr()) };
+        }
+    }
+}
+
+unsafe extern "C" fn on_complete(context: *mut c_void, stats: *mut ASurfaceTransactionStats) {
+    // SAFETY: The context is the `Completion` leaked in `SurfaceControl::present`.
+    let completion = unsafe { Box::from_raw(context.cast::<Completion>()) };
+    let fence = unsafe {
+        (completion.functions.stats_get_previous_release_fence_fd)(
+            stats,
+            completion.surface_control.as_ptr(),
+        )
+    };
+    // SAFETY: We own the returned fence.
+    let fence = (fence >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fence) });
+
+    let (lock, condvar) = &*completion.shared;
+    let mut shared = match lock.lock() {
+        Ok(shared) => shared,
+        Err(_) => return,
+    };
+
+    shared.pending -= 1;
+    if shared.dropped {
+        if shared.pending == 0 {
+            unsafe { (completion.functions.release)(completion.surface_control.as_ptr()) };
+        }
+        return;
+    }
+
+    if shared.generation != completion.generation {
+        return;
+    }
+    if let Some(slot) = completion
+        .previous
+        .and_then(|index| shared.slots.get_mut(index))
+    {
+        *slot = Slot {
+            busy: false,
+            release_fence: fence,
+        };
+        condvar.notify_all();
+    }
+}
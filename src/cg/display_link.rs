@@ -0,0 +1,189 @@
+//! Vertical blank notifications through `CVDisplayLink`.
+
+use crate::SoftBufferError;
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+type CVReturn = i32;
+type CVDisplayLinkRef = *mut c_void;
+type CVDisplayLinkOutputCallback = extern "C" fn(
+    display_link: CVDisplayLinkRef,
+    in_now: *const c_void,
+    in_output_time: *const c_void,
+    flags_in: u64,
+    flags_out: *mut u64,
+    display_link_context: *mut c_void,
+) -> CVReturn;
+
+/// `kCVTimeIsIndefinite`.
+const TIME_IS_INDEFINITE: i32 = 1 << 0;
+
+#[repr(C)]
+struct CVTime {
+    time_value: i64,
+    time_scale: i32,
+    flags: i32,
+}
+
+#[link(name = "CoreVideo", kind = "framework")]
+extern "C" {
+    fn CVDisplayLinkCreateWithActiveCGDisplays(display_link_out: *mut CVDisplayLinkRef)
+        -> CVReturn;
+    fn CVDisplayLinkCreateWithCGDisplay(
+        display_id: u32,
+        display_link_out: *mut CVDisplayLinkRef,
+    ) -> CVReturn;
+    fn CVDisplayLinkGetNominalOutputVideoRefreshPeriod(display_link: CVDisplayLinkRef) -> CVTime;
+    fn CVDisplayLinkSetOutputCallback(
+        display_link: CVDisplayLinkRef,
+        callback: CVDisplayLinkOutputCallback,
+        user_info: *mut c_void,
+    ) -> CVReturn;
+    fn CVDisplayLinkSetCurrentCGDisplay(
+        display_link: CVDisplayLinkRef,
+        display_id: u32,
+    ) -> CVReturn;
+    fn CVDisplayLinkStart(display_link: CVDisplayLinkRef) -> CVReturn;
+    fn CVDisplayLinkStop(display_link: CVDisplayLinkRef) -> CVReturn;
+    fn CVDisplayLinkRelease(display_link: CVDisplayLinkRef);
+}
+
+/// How long to wait for a callback before giving up, e.g. because the display went to sleep.
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The number of vertical blanks seen so far, shared with the display link thread.
+struct Shared {
+    count: Mutex<u64>,
+    condvar: Condvar,
+}
+
+pub(super) struct DisplayLink {
+    link: CVDisplayLinkRef,
+    shared: Arc<Shared>,
+    display_id: Option<u32>,
+}
+
+impl DisplayLink {
+    pub fn new() -> Result<Self, SoftBufferError> {
+        let shared = Arc::new(Shared {
+            count: Mutex::new(0),
+            condvar: Condvar::new(),
+        });
+
+        let mut link = ptr::null_mut();
+        check(
+            unsafe { CVDisplayLinkCreateWithActiveCGDisplays(&mut link) },
+            "Failed to create CVDisplayLink",
+        )?;
+
+        // The display link holds a strong reference, released in `drop`.
+        let user_info = Arc::into_raw(shared.clone()) as *mut c_void;
+        let started = check(
+            unsafe { CVDisplayLinkSetOutputCallback(link, callback, user_info) },
+            "Failed to set CVDisplayLink callback",
+        )
+        .and_then(|()| {
+            check(
+                unsafe { CVDisplayLinkStart(link) },
+                "Failed to start CVDisplayLink",
+            )
+        });
+        if let Err(err) = started {
+            unsafe {
+                CVDisplayLinkRelease(link);
+                drop(Arc::from_raw(user_info as *const Shared));
+            }
+            return Err(err);
+        }
+
+        Ok(Self {
+            link,
+            shared,
+            display_id: None,
+        })
+    }
+
+    /// Follow the display with the given ID, e.g. after the window moved to another screen.
+    pub fn set_display(&mut self, display_id: u32) -> Result<(), SoftBufferError> {
+        if self.display_id != Some(display_id) {
+            check(
+                unsafe { CVDisplayLinkSetCurrentCGDisplay(self.link, display_id) },
+                "Failed to set CVDisplayLink display",
+            )?;
+            self.display_id = Some(display_id);
+        }
+
+        Ok(())
+    }
+
+    /// Block until the next vertical blank.
+    pub fn wait(&self) {
+        let count = self.shared.count.lock().unwrap();
+        let start = *count;
+        let _ = self
+            .shared
+            .condvar
+            .wait_timeout_while(count, TIMEOUT, |count| *count == start)
+            .unwrap();
+    }
+}
+
+/// Get the nominal refresh interval of the display with the given ID.
+pub(super) fn refresh_period(display_id: u32) -> Result<Duration, SoftBufferError> {
+    let mut link = ptr::null_mut();
+    check(
+        unsafe { CVDisplayLinkCreateWithCGDisplay(display_id, &mut link) },
+        "Failed to create CVDisplayLink",
+    )?;
+    let period = unsafe { CVDisplayLinkGetNominalOutputVideoRefreshPeriod(link) };
+    unsafe { CVDisplayLinkRelease(link) };
+
+    if period.flags & TIME_IS_INDEFINITE != 0 || period.time_value <= 0 || period.time_scale <= 0 {
+        return Err(SoftBufferError::Unimplemented);
+    }
+    Ok(Duration::from_nanos(
+        (period.time_value as u128 * 1_000_000_000 / period.time_scale as u128) as u64,
+    ))
+}
+
+impl Drop for DisplayLink {
+    fn drop(&mut self) {
+        unsafe {
+            // Stopping waits for the display link thread, so the callback can't run afterwards.
+            CVDisplayLinkStop(self.link);
+            CVDisplayLinkRelease(self.link);
+            drop(Arc::from_raw(Arc::as_ptr(&self.shared)));
+        }
+    }
+}
+
+extern "C" fn callback(
+    _display_link: CVDisplayLinkRef,
+    _in_now: *const c_void,
+    _in_output_time: *const c_void,
+    _flags_in: u64,
+    _flags_out: *mut u64,
+    display_link_context: *mut c_void,
+) -> CVReturn {
+    // SAFETY: The context is the `Arc<Shared>` leaked in `DisplayLink::new`.
+    let shared = unsafe { &*(display_link_context as *const Shared) };
+    if let Ok(mut count) = shared.count.lock() {
+        *count = count.wrapping_add(1);
+        shared.condvar.notify_all();
+    }
+    0
+}
+
+fn check(result: CVReturn, msg: &str) -> Result<(), SoftBufferError> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(SoftBufferError::PlatformError(
+            Some(format!("{msg}: {result}")),
+            None,
+        ))
+    }
+}
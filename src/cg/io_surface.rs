@@ -0,0 +1,267 @@
+//! Minimal bindings to the `IOSurface` framework.
+//!
+//! An `IOSurface` is memory shared with the window server, so setting one as the contents of a
+//! `CALayer` presents it without copying the pixels into a separate image.
+
+use crate::{convert, ColorSpace, SoftBufferError};
+use core_foundation::base::{Boolean, CFRelease, CFTypeRef, TCFType};
+use core_foundation::dictionary::{CFDictionary, CFDictionaryRef};
+use core_foundation::number::CFNumber;
+use core_foundation::string::{CFString, CFStringRef};
+
+use std::ffi::c_void;
+use std::ptr::{self, NonNull};
+use std::slice;
+
+/// The `'BGRA'` four character code, matching the in-memory layout of our `0RGB` pixels.
+const PIXEL_FORMAT_BGRA: i32 = 0x42475241;
+/// `kIOSurfaceLockReadOnly`, which doesn't change the seed of the surface.
+const LOCK_READ_ONLY: u32 = 1;
+
+#[link(name = "IOSurface", kind = "framework")]
+extern "C" {
+    static kIOSurfaceWidth: CFStringRef;
+    static kIOSurfaceHeight: CFStringRef;
+    static kIOSurfaceBytesPerElement: CFStringRef;
+    static kIOSurfaceBytesPerRow: CFStringRef;
+    static kIOSurfacePixelFormat: CFStringRef;
+
+    fn IOSurfaceCreate(properties: CFDictionaryRef) -> *mut c_void;
+    fn IOSurfaceLock(buffer: *mut c_void, options: u32, seed: *mut u32) -> i32;
+    fn IOSurfaceUnlock(buffer: *mut c_void, options: u32, seed: *mut u32) -> i32;
+    fn IOSurfaceGetBaseAddress(buffer: *mut c_void) -> *mut c_void;
+    fn IOSurfaceGetBytesPerRow(buffer: *mut c_void) -> usize;
+    fn IOSurfaceIsInUse(buffer: *mut c_void) -> Boolean;
+    fn IOSurfaceAlignProperty(property: CFStringRef, value: usize) -> usize;
+    fn IOSurfaceGetPropertyMaximum(property: CFStringRef) -> usize;
+    fn IOSurfaceSetValue(buffer: *mut c_void, key: CFStringRef, value: CFTypeRef);
+}
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    static kCGColorSpaceSRGB: CFStringRef;
+    static kCGColorSpaceDisplayP3: CFStringRef;
+    static kCGColorSpaceLinearSRGB: CFStringRef;
+}
+
+/// Get the name of the `CGColorSpace` corresponding to a color space.
+pub(super) fn color_space_name(color_space: ColorSpace) -> CFStringRef {
+    // SAFETY: The names are immutable statics provided by the framework.
+    unsafe {
+        match color_space {
+            ColorSpace::Srgb => kCGColorSpaceSRGB,
+            ColorSpace::DisplayP3 => kCGColorSpaceDisplayP3,
+            ColorSpace::LinearSrgb => kCGColorSpaceLinearSRGB,
+        }
+    }
+}
+
+/// Get the largest width and height of a surface.
+pub(super) fn max_size() -> (u32, u32) {
+    // SAFETY: The keys are immutable statics provided by the framework.
+    let (width, height) = unsafe {
+        (
+            IOSurfaceGetPropertyMaximum(kIOSurfaceWidth),
+            IOSurfaceGetPropertyMaximum(kIOSurfaceHeight),
+        )
+    };
+    let clamp = |max: usize| u32::try_from(max).unwrap_or(u32::MAX);
+    (clamp(width), clamp(height))
+}
+
+pub(super) struct IOSurface {
+    surface: NonNull<c_void>,
+    width: u32,
+    height: u32,
+    bytes_per_row: usize,
+    locked: bool,
+    color_space: Option<ColorSpace>,
+    /// How many presents ago the surface was presented, or `0` if it never was.
+    pub age: u8,
+    /// Which of the two surfaces this is.
+    pub index: usize,
+}
+
+impl IOSurface {
+    pub fn new(width: u32, height: u32, index: usize) -> Result<Self, SoftBufferError> {
+        trace_span!("create_io_surface", width, height);
+        // SAFETY: The keys are immutable statics provided by the framework.
+        let properties = unsafe {
+            let bytes_per_row =
+                IOSurfaceAlignProperty(kIOSurfaceBytesPerRow, width as usize * 4) as i64;
+            let key = |key| CFString::wrap_under_get_rule(key);
+            CFDictionary::from_CFType_pairs(&[
+                (key(kIOSurfaceWidth), CFNumber::from(width as i64)),
+                (key(kIOSurfaceHeight), CFNumber::from(height as i64)),
+                (key(kIOSurfaceBytesPerElement), CFNumber::from(4)),
+                (key(kIOSurfaceBytesPerRow), CFNumber::from(bytes_per_row)),
+                (
+                    key(kIOSurfacePixelFormat),
+                    CFNumber::from(PIXEL_FORMAT_BGRA),
+                ),
+            ])
+        };
+
+        let surface = unsafe { IOSurfaceCreate(properties.as_concrete_TypeRef()) };
+        let surface = NonNull::new(surface).ok_or_else(|| {
+            SoftBufferError::PlatformError(Some("Failed to create IOSurface".into()), None)
+        })?;
+        let bytes_per_row = unsafe { IOSurfaceGetBytesPerRow(surface.as_ptr()) };
+
+        Ok(Self {
+            surface,
+            width,
+            height,
+            bytes_per_row,
+            locked: false,
+            color_space: None,
+            age: 0,
+            index,
+        })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Whether the rows are tightly packed, so the memory can be handed out as the buffer.
+    pub fn is_packed(&self) -> bool {
+        self.bytes_per_row == self.width as usize * 4
+    }
+
+    /// Tag the surface with the color space its pixels are in.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) {
+        if self.color_space == Some(color_space) {
+            return;
+        }
+
+        let name = color_space_name(color_space);
+        let key = CFString::from_static_string("IOSurfaceColorSpace");
+        unsafe {
+            IOSurfaceSetValue(
+                self.surface.as_ptr(),
+                key.as_concrete_TypeRef(),
+                name as CFTypeRef,
+            )
+        };
+        self.color_space = Some(color_space);
+    }
+
+    /// Whether the window server is still using this surface.
+    pub fn is_in_use(&self) -> bool {
+        unsafe { IOSurfaceIsInUse(self.surface.as_ptr()) != 0 }
+    }
+
+    /// Lock the surface for access by the CPU.
+    pub fn lock(&mut self) -> Result<(), SoftBufferError> {
+        if !self.locked {
+            trace_span!("lock_io_surface");
+            let result = unsafe { IOSurfaceLock(self.surface.as_ptr(), 0, ptr::null_mut()) };
+            check_lock(result)?;
+            self.locked = true;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the pixels out of an unlocked surface, without padding.
+    pub fn read(&self) -> Result<Vec<u32>, SoftBufferError> {
+        debug_assert!(!self.locked);
+        trace_span!("read_io_surface");
+        let surface = self.surface.as_ptr();
+        check_lock(unsafe { IOSurfaceLock(surface, LOCK_READ_ONLY, ptr::null_mut()) })?;
+        let width = self.width as usize;
+        let mut pixels = Vec::with_capacity(self.len());
+        for y in 0..self.height as usize {
+            // SAFETY: The surface is locked, and its rows are `bytes_per_row` apart.
+            let row = unsafe {
+                let start = self.base_address().cast::<u8>().add(y * self.bytes_per_row);
+                slice::from_raw_parts(start.cast::<u32>(), width)
+            };
+            pixels.extend_from_slice(row);
+        }
+        unsafe { IOSurfaceUnlock(surface, LOCK_READ_ONLY, ptr::null_mut()) };
+        Ok(pixels)
+    }
+
+    /// Unlock the surface, so the window server can use it.
+    pub fn unlock(&mut self) {
+        if self.locked {
+            unsafe { IOSurfaceUnlock(self.surface.as_ptr(), 0, ptr::null_mut()) };
+            self.locked = false;
+        }
+    }
+
+    /// Get the pixels of a packed surface.
+    ///
+    /// # Safety
+    ///
+    /// The surface must be locked and packed.
+    pub unsafe fn pixels(&self) -> &[u32] {
+        debug_assert!(self.locked && self.is_packed());
+        unsafe { slice::from_raw_parts(self.base_address(), self.len()) }
+    }
+
+    /// Get the pixels of a packed surface.
+    ///
+    /// # Safety
+    ///
+    /// The surface must be locked and packed.
+    pub unsafe fn pixels_mut(&mut self) -> &mut [u32] {
+        debug_assert!(self.locked && self.is_packed());
+        unsafe { slice::from_raw_parts_mut(self.base_address(), self.len()) }
+    }
+
+    /// Copy a tightly packed buffer into the surface row by row.
+    ///
+    /// # Safety
+    ///
+    /// The surface must be locked.
+    pub unsafe fn copy_from(&mut self, pixels: &[u32]) {
+        debug_assert!(self.locked);
+        trace_span!("copy");
+        let width = self.width as usize;
+        let height = pixels.len() / width;
+        let dst = unsafe {
+            slice::from_raw_parts_mut(self.base_address() as *mut u8, height * self.bytes_per_row)
+        };
+        // SAFETY: The pixels are plain bytes.
+        let src: &[u8] =
+            unsafe { slice::from_raw_parts(pixels.as_ptr().cast(), width * height * 4) };
+        let stream = convert::should_stream(src.len());
+        convert::for_each_row(dst, self.bytes_per_row, |y, dst| {
+            convert::copy(&src[y * width * 4..][..width * 4], dst, stream);
+        });
+    }
+
+    /// The surface as an object that can be set as the contents of a `CALayer`.
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.surface.as_ptr()
+    }
+
+    fn base_address(&self) -> *mut u32 {
+        unsafe { IOSurfaceGetBaseAddress(self.surface.as_ptr()) as *mut u32 }
+    }
+
+    fn len(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+fn check_lock(result: i32) -> Result<(), SoftBufferError> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(SoftBufferError::PlatformError(
+            Some(format!("Failed to lock IOSurface: {result:#x}")),
+            None,
+        ))
+    }
+}
+
+impl Drop for IOSurface {
+    fn drop(&mut self) {
+        self.unlock();
+        unsafe { CFRelease(self.surface.as_ptr()) };
+    }
+}
@@ -0,0 +1,310 @@
+//! Presenting through a `CAMetalLayer`, enabled by the `metal` feature.
+//!
+//! Instead of setting an image as the contents of the layer, the buffer is uploaded into the
+//! texture of the next drawable. The drawable is presented as part of the enclosing
+//! `CATransaction`, which keeps the contents in sync with the window during live resize.
+//!
+//! With extended dynamic range, see `SurfaceExtMacOS::set_edr_brightness`, the layer has
+//! half-float pixels in an extended linear color space instead, and the buffer is converted into
+//! them, so that its white can be brighter than the SDR white of the screen.
+
+use super::io_surface::color_space_name;
+use crate::{ColorSpace, SoftBufferError};
+
+use cocoa::base::{id, nil, NO, YES};
+use cocoa::foundation::{NSAutoreleasePool, NSSize};
+use cocoa::quartzcore::CALayer;
+use core_foundation::string::CFStringRef;
+
+use std::ffi::c_void;
+
+/// `MTLPixelFormatBGRA8Unorm`, matching the in-memory layout of our `0RGB` pixels.
+const PIXEL_FORMAT_BGRA8_UNORM: usize = 80;
+
+/// `MTLPixelFormatRGBA16Float`, which can hold the values above 1.0 of extended dynamic range.
+const PIXEL_FORMAT_RGBA16_FLOAT: usize = 115;
+
+/// 1.0 as a half float, for the unused alpha channel.
+const HALF_ONE: u16 = 0x3c00;
+
+/// The largest width and height of a texture on every GPU of a Mac.
+pub(super) const MAX_TEXTURE_SIZE: u32 = 16384;
+
+#[link(name = "Metal", kind = "framework")]
+extern "C" {
+    fn MTLCreateSystemDefaultDevice() -> id;
+}
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    static kCGColorSpaceExtendedLinearSRGB: CFStringRef;
+    static kCGColorSpaceExtendedLinearDisplayP3: CFStringRef;
+
+    fn CGColorSpaceCreateWithName(name: CFStringRef) -> *mut c_void;
+    fn CGColorSpaceRelease(space: *mut c_void);
+}
+
+#[repr(C)]
+struct MTLOrigin {
+    x: usize,
+    y: usize,
+    z: usize,
+}
+
+#[repr(C)]
+struct MTLSize {
+    width: usize,
+    height: usize,
+    depth: usize,
+}
+
+#[repr(C)]
+struct MTLRegion {
+    origin: MTLOrigin,
+    size: MTLSize,
+}
+
+pub(super) struct MetalLayer {
+    layer: id,
+    device: id,
+    /// The color space the layer is tagged with, `None` if it has to be set again.
+    color_space: Option<ColorSpace>,
+    /// Set with `set_edr_brightness()`, for uploading half floats instead of the buffer as it is.
+    edr: Option<Edr>,
+}
+
+/// The conversion of the buffer for a layer with extended dynamic range.
+struct Edr {
+    brightness: f32,
+    /// The linear half float of each 8-bit channel value, scaled by `brightness`.
+    table: [u16; 256],
+    /// The converted buffer, in RGBA order.
+    pixels: Vec<u16>,
+}
+
+impl MetalLayer {
+    /// Create a layer drawing with the default Metal device, and add it as a sublayer of `parent`.
+    pub fn new(parent: &CALayer) -> Result<Self, SoftBufferError> {
+        let device = unsafe { MTLCreateSystemDefaultDevice() };
+        if device == nil {
+            return Err(SoftBufferError::PlatformError(
+                Some("No Metal device available".into()),
+                None,
+            ));
+        }
+
+        unsafe {
+            let layer: id = msg_send![class!(CAMetalLayer), new];
+            let _: () = msg_send![layer, setDevice: device];
+            let _: () = msg_send![layer, setPixelFormat: PIXEL_FORMAT_BGRA8_UNORM];
+            // The texture is written by the CPU rather than rendered into.
+            let _: () = msg_send![layer, setFramebufferOnly: NO];
+            let _: () = msg_send![layer, setPresentsWithTransaction: YES];
+            let _: () = msg_send![layer, setOpaque: YES];
+            let _: () = msg_send![parent.id(), addSublayer: layer];
+
+            Ok(Self {
+                layer,
+                device,
+                color_space: None,
+                edr: None,
+            })
+        }
+    }
+
+    /// Upload the buffer into the next drawable and present it.
+    ///
+    /// This must be called inside a `CATransaction`. The layer takes its frame, scale, gravity and
+    /// filters from `parent`, so that it looks the same as contents set on the parent would.
+    pub fn present(
+        &mut self,
+        parent: &CALayer,
+        pixels: &[u32],
+        width: u32,
+        height: u32,
+        color_space: ColorSpace,
+    ) -> Result<(), SoftBufferError> {
+        self.set_color_space(color_space);
+
+        unsafe {
+            let layer = self.layer;
+            let bounds = parent.bounds();
+            let parent = parent.id();
+            let _: () = msg_send![layer, setFrame: bounds];
+            let scale: f64 = msg_send![parent, contentsScale];
+            let _: () = msg_send![layer, setContentsScale: scale];
+            let gravity: id = msg_send![parent, contentsGravity];
+            let _: () = msg_send![layer, setContentsGravity: gravity];
+            let filter: id = msg_send![parent, magnificationFilter];
+            let _: () = msg_send![layer, setMagnificationFilter: filter];
+            let filter: id = msg_send![parent, minificationFilter];
+            let _: () = msg_send![layer, setMinificationFilter: filter];
+            let _: () = msg_send![
+                layer,
+                setDrawableSize: NSSize::new(width as f64, height as f64)
+            ];
+
+            // The drawable and its texture are autoreleased.
+            let pool = NSAutoreleasePool::new(nil);
+            let result = self.upload(pixels, width, height);
+            pool.drain();
+            result
+        }
+    }
+
+    /// Show the buffer with extended dynamic range, with its white `brightness` times as bright
+    /// as SDR white, or without it for `None`.
+    pub fn set_edr_brightness(&mut self, brightness: Option<f32>) {
+        let pixel_format = match brightness {
+            Some(_) => PIXEL_FORMAT_RGBA16_FLOAT,
+            None => PIXEL_FORMAT_BGRA8_UNORM,
+        };
+        let wants_edr = if brightness.is_some() { YES } else { NO };
+        unsafe {
+            let _: () = msg_send![self.layer, setPixelFormat: pixel_format];
+            let _: () = msg_send![self.layer, setWantsExtendedDynamicRangeContent: wants_edr];
+        }
+
+        let pixels = self.edr.take().map(|edr| edr.pixels).unwrap_or_default();
+        self.edr = brightness.map(|brightness| Edr {
+            brightness,
+            table: [0; 256],
+            pixels,
+        });
+        // The color space and the table depend on both.
+        self.color_space = None;
+    }
+
+    unsafe fn upload(
+        &mut self,
+        pixels: &[u32],
+        width: u32,
+        height: u32,
+    ) -> Result<(), SoftBufferError> {
+        unsafe {
+            let drawable: id = {
+                trace_span!("next_drawable");
+                msg_send![self.layer, nextDrawable]
+            };
+            if drawable == nil {
+                return Err(SoftBufferError::PlatformError(
+                    Some("Timed out waiting for a CAMetalLayer drawable".into()),
+                    None,
+                ));
+            }
+
+            trace_span!("upload");
+            let texture: id = msg_send![drawable, texture];
+            let region = MTLRegion {
+                origin: MTLOrigin { x: 0, y: 0, z: 0 },
+                size: MTLSize {
+                    width: width as usize,
+                    height: height as usize,
+                    depth: 1,
+                },
+            };
+            let (bytes, bytes_per_row) = match &mut self.edr {
+                Some(edr) => {
+                    edr.convert(pixels);
+                    (edr.pixels.as_ptr() as *const c_void, width as usize * 8)
+                }
+                None => (pixels.as_ptr() as *const c_void, width as usize * 4),
+            };
+            let _: () = msg_send![
+                texture,
+                replaceRegion: region
+                mipmapLevel: 0usize
+                withBytes: bytes
+                bytesPerRow: bytes_per_row
+            ];
+            let _: () = msg_send![drawable, present];
+        }
+
+        Ok(())
+    }
+
+    fn set_color_space(&mut self, color_space: ColorSpace) {
+        if self.color_space == Some(color_space) {
+            return;
+        }
+
+        // SAFETY: The names are immutable statics provided by the framework.
+        let name = match (&mut self.edr, color_space) {
+            (Some(edr), ColorSpace::DisplayP3) => {
+                edr.fill_table(true);
+                unsafe { kCGColorSpaceExtendedLinearDisplayP3 }
+            }
+            (Some(edr), color_space) => {
+                edr.fill_table(color_space != ColorSpace::LinearSrgb);
+                unsafe { kCGColorSpaceExtendedLinearSRGB }
+            }
+            (None, color_space) => color_space_name(color_space),
+        };
+        unsafe {
+            let space = CGColorSpaceCreateWithName(name);
+            let _: () = msg_send![self.layer, setColorspace: space];
+            CGColorSpaceRelease(space);
+        }
+        self.color_space = Some(color_space);
+    }
+}
+
+impl Edr {
+    /// Fill the table for channel values with the sRGB transfer function, or linear ones.
+    fn fill_table(&mut self, srgb: bool) {
+        for (value, half) in self.table.iter_mut().enumerate() {
+            let value = value as f32 / 255.0;
+            let linear = if !srgb {
+                value
+            } else if value <= 0.04045 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            };
+            *half = half_bits(linear * self.brightness);
+        }
+    }
+
+    /// Convert the `0RGB` pixels into `pixels`.
+    fn convert(&mut self, pixels: &[u32]) {
+        let table = &self.table;
+        self.pixels.clear();
+        self.pixels.extend(pixels.iter().flat_map(|&pixel| {
+            let channel = |shift: u32| table[(pixel >> shift) as u8 as usize];
+            [channel(16), channel(8), channel(0), HALF_ONE]
+        }));
+    }
+}
+
+/// Convert a finite, non-negative `value` to the bits of the nearest half float, or the largest
+/// one if it's too large.
+fn half_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let exponent = (bits >> 23) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent >= 31 {
+        0x7bff
+    } else if exponent <= 0 {
+        // Subnormal, with the implicit leading bit of the mantissa shifted in, and rounded down,
+        // which is too dark to tell apart.
+        if exponent < -10 {
+            0
+        } else {
+            ((mantissa | 0x80_0000) >> (14 - exponent)) as u16
+        }
+    } else {
+        // A carry of the rounding into the exponent is still the nearest half float.
+        let half = ((exponent as u32) << 10) | (mantissa >> 13);
+        (half + ((mantissa >> 12) & 1)).min(0x7bff) as u16
+    }
+}
+
+impl Drop for MetalLayer {
+    fn drop(&mut self) {
+        unsafe {
+            let _: () = msg_send![self.layer, removeFromSuperlayer];
+            let _: () = msg_send![self.layer, release];
+            let _: () = msg_send![self.device, release];
+        }
+    }
+}
@@ -0,0 +1,724 @@
+use crate::{
+    stats, util, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus, Rect,
+    Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
+use raw_window_handle::AppKitWindowHandle;
+
+use cocoa::appkit::{NSView, NSViewHeightSizable, NSViewWidthSizable, NSWindow};
+use cocoa::base::{id, nil, BOOL, NO};
+use cocoa::quartzcore::{transaction, AutoresizingMask, CALayer, ContentsGravity, Filter};
+use core_foundation::base::TCFType;
+use core_foundation::string::CFString;
+use core_graphics::color::CGColor;
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::num::NonZeroU32;
+use std::ptr::NonNull;
+use std::time::Duration;
+
+mod display_link;
+mod io_surface;
+#[cfg(feature = "metal")]
+mod metal;
+use display_link::DisplayLink;
+use io_surface::IOSurface;
+#[cfg(feature = "metal")]
+use metal::MetalLayer;
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGMainDisplayID() -> u32;
+}
+
+pub struct CGImpl {
+    layer: CALayer,
+    window: id,
+    width: u32,
+    height: u32,
+    /// The surface currently set as the contents of the layer.
+    front: Option<IOSurface>,
+    /// The surface that the next frame is drawn into.
+    back: Option<IOSurface>,
+    /// Staging buffer, used when the rows of the back surface are padded.
+    staging: Vec<u32>,
+    /// Whether a buffer was presented at the current size, so `staging` holds the last frame.
+    presented: bool,
+    /// Created on the first call to `wait_for_vblank()`.
+    display_link: Option<DisplayLink>,
+    /// The color space the surfaces are tagged with.
+    color_space: ColorSpace,
+    /// Whether the layer is a sublayer of the view's own layer, rather than of a subview.
+    hosted: bool,
+    /// The scale factor set with `set_scale_factor()`, instead of that of the screen.
+    scale_factor: Option<f64>,
+    /// The scale factor of the window, as of the last time it was read on the main thread.
+    backing_scale: Cell<f64>,
+    /// Presents `staging` instead of the surfaces, unless there is no Metal device.
+    #[cfg(feature = "metal")]
+    metal: Option<MetalLayer>,
+}
+
+impl CGImpl {
+    pub unsafe fn new(handle: AppKitWindowHandle) -> Result<Self, SoftBufferError> {
+        let window = handle.ns_window as id;
+        let window: id = msg_send![window, retain];
+        let view = handle.ns_view as id;
+        let host_layer: id = unsafe { msg_send![view, layer] };
+        let hosted = host_layer != nil;
+        // Adding a subview is up to AppKit, which only works on the main thread, but the layer
+        // tree can be changed from any thread.
+        if !hosted && !is_main_thread() {
+            unsafe {
+                let _: () = msg_send![window, release];
+            }
+            return Err(SoftBufferError::PlatformError(
+                Some("Surfaces of views without a layer must be created on the main thread".into()),
+                None,
+            ));
+        }
+
+        let layer = CALayer::new();
+        let _commit = begin_transaction();
+        layer.set_contents_gravity(ContentsGravity::TopLeft);
+        layer.set_needs_display_on_bounds_change(false);
+        // The alpha channel of our buffer is unused.
+        layer.set_opaque(true);
+
+        if hosted {
+            // The view already has a layer, e.g. because it belongs to another toolkit. Replacing
+            // it or covering the view with a subview would break the host, so add a sublayer.
+            layer.set_frame(&unsafe { msg_send![host_layer, bounds] });
+            layer.set_autoresizing_mask(
+                AutoresizingMask::WIDTH_SIZABLE | AutoresizingMask::HEIGHT_SIZABLE,
+            );
+            unsafe {
+                let _: () = msg_send![host_layer, addSublayer: layer.id()];
+            }
+        } else {
+            unsafe {
+                let subview: id = NSView::alloc(nil).initWithFrame_(NSView::frame(view));
+                subview.setLayer(layer.id());
+                subview.setAutoresizingMask_(NSViewWidthSizable | NSViewHeightSizable);
+
+                view.addSubview_(subview); // retains subview (+1) = 2
+                let _: () = msg_send![subview, release]; // releases subview (-1) = 1
+            }
+        }
+        #[cfg(feature = "metal")]
+        let metal = match MetalLayer::new(&layer) {
+            Ok(metal) => Some(metal),
+            Err(err) => {
+                crate::error::report(
+                    crate::Backend::CG,
+                    SoftBufferError::PlatformError(
+                        Some(format!("Falling back to presenting IOSurfaces: {}", err)),
+                        None,
+                    ),
+                );
+                None
+            }
+        };
+        Ok(Self {
+            layer,
+            window,
+            width: 0,
+            height: 0,
+            front: None,
+            back: None,
+            staging: Vec::new(),
+            presented: false,
+            display_link: None,
+            color_space: ColorSpace::default(),
+            hosted,
+            scale_factor: None,
+            backing_scale: Cell::new(unsafe { window.backingScaleFactor() }),
+            #[cfg(feature = "metal")]
+            metal,
+        })
+    }
+
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
+        if (width.get(), height.get()) != (self.width, self.height) {
+            self.presented = false;
+        }
+        self.width = width.get();
+        self.height = height.get();
+
+        // Keep the buffer at native resolution, in case the window moved to another screen.
+        let _commit = begin_transaction();
+        unsafe {
+            self.layer.set_contents_scale(self.contents_scale());
+        }
+
+        Ok(())
+    }
+
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(SoftBufferError::SurfaceNotConfigured);
+        }
+
+        #[cfg(feature = "metal")]
+        if self.metal.is_some() {
+            util::resize_pixels(
+                &mut self.staging,
+                self.width as usize * self.height as usize,
+            );
+            return Ok(BufferImpl { imp: self });
+        }
+
+        // Reuse the back surface unless it has the wrong size, or the window server hasn't let go
+        // of it yet.
+        let reusable = match &self.back {
+            Some(back) => back.size() == (self.width, self.height) && !back.is_in_use(),
+            None => false,
+        };
+        if !reusable {
+            let index = self.front.as_ref().map_or(0, |front| 1 - front.index);
+            self.back = Some(IOSurface::new(self.width, self.height, index)?);
+        }
+
+        let back = self.back.as_mut().unwrap();
+        back.set_color_space(self.color_space);
+        back.lock()?;
+        if !back.is_packed() {
+            util::resize_pixels(
+                &mut self.staging,
+                self.width as usize * self.height as usize,
+            );
+        }
+
+        Ok(BufferImpl { imp: self })
+    }
+
+    /// Copy the surface that is the contents of the layer, since the window can't be read back.
+    pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        let len = self.width as usize * self.height as usize;
+
+        // The staging buffer is kept between presents.
+        #[cfg(feature = "metal")]
+        if self.metal.is_some() {
+            return Ok(if self.staging.len() == len {
+                self.staging.clone()
+            } else {
+                vec![0; len]
+            });
+        }
+
+        match &self.front {
+            Some(front) if front.size() == (self.width, self.height) => front.read(),
+            _ => Ok(vec![0; len]),
+        }
+    }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// Not implemented yet, see `Surface::release_buffers()`.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Wait for the next vertical blank of the screen the window is on.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        if self.display_link.is_none() {
+            self.display_link = Some(DisplayLink::new()?);
+        }
+        let display_link = self.display_link.as_mut().unwrap();
+
+        // The window may have moved to another screen since the last call.
+        if let Some(display_id) = unsafe { screen_display_id(self.window) } {
+            display_link.set_display(display_id)?;
+        }
+
+        display_link.wait();
+        Ok(())
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Get the refresh interval of the screen the window is on, or of the main display.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        let display_id = unsafe { screen_display_id(self.window) }
+            .unwrap_or_else(|| unsafe { CGMainDisplayID() });
+        display_link::refresh_period(display_id)
+    }
+
+    /// Get the size of the layer in physical pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        let bounds = self.layer.bounds();
+        let scale = unsafe { self.backing_scale() };
+        Ok((
+            (bounds.size.width * scale).round() as u32,
+            (bounds.size.height * scale).round() as u32,
+        ))
+    }
+
+    /// The scale factor set with `set_scale_factor()`, or that of the screen the window is on.
+    unsafe fn contents_scale(&self) -> f64 {
+        self.scale_factor
+            .unwrap_or_else(|| unsafe { self.backing_scale() })
+    }
+
+    /// The scale factor of the window. AppKit may only be asked on the main thread, so other
+    /// threads get the one it was asked for last.
+    unsafe fn backing_scale(&self) -> f64 {
+        if is_main_thread() {
+            self.backing_scale
+                .set(unsafe { self.window.backingScaleFactor() });
+        }
+        self.backing_scale.get()
+    }
+
+    /// Not implemented yet, see `Surface::visibility()`.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set the contents gravity and filters of the layer.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        let gravity = match scaling {
+            Scaling::TopLeft => ContentsGravity::TopLeft,
+            Scaling::Center => ContentsGravity::Center,
+            Scaling::AspectFit => ContentsGravity::ResizeAspect,
+            Scaling::Fill => ContentsGravity::Resize,
+        };
+        let filter = || match filter {
+            ScalingFilter::Linear => Filter::Linear,
+            ScalingFilter::Nearest => Filter::Nearest,
+        };
+
+        let _commit = begin_transaction();
+        self.layer.set_contents_gravity(gravity);
+        self.layer.set_magnification_filter(filter());
+        self.layer.set_minification_filter(filter());
+
+        Ok(())
+    }
+
+    /// Set the background color of the layer, which is shown around the contents.
+    pub fn set_background_color(&mut self, color: u32) -> Result<(), SoftBufferError> {
+        let channel = |shift: u32| f64::from((color >> shift) as u8) / 255.0;
+        let color = CGColor::rgb(channel(16), channel(8), channel(0), 1.0);
+        let _commit = begin_transaction();
+        self.layer.set_background_color(Some(color));
+        Ok(())
+    }
+
+    /// Core Animation doesn't make presents wait, and Metal waits at most a second for a drawable.
+    pub fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the color space that the surfaces are tagged with.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        self.color_space = color_space;
+        Ok(())
+    }
+
+    /// The window server keeps reading the last buffer after a present.
+    pub fn set_single_buffered(&mut self, single: bool) -> Result<(), SoftBufferError> {
+        if single {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rows are always shown top-down.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        if flip {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The layer keeps its contents in place, without animating them, until the next present.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the scale of the contents of the layer, instead of taking that of the screen.
+    pub fn set_scale_factor(&mut self, scale_factor: f64) -> Result<(), SoftBufferError> {
+        self.scale_factor = Some(scale_factor);
+        Ok(())
+    }
+
+    /// Switch the Metal layer to extended dynamic range, see `SurfaceExtMacOS`.
+    fn set_edr_brightness(&mut self, brightness: Option<f32>) -> Result<(), SoftBufferError> {
+        #[cfg(feature = "metal")]
+        if let Some(metal) = self.metal.as_mut() {
+            let _commit = begin_transaction();
+            metal.set_edr_brightness(brightness);
+            return Ok(());
+        }
+
+        match brightness {
+            Some(_) => Err(SoftBufferError::Unimplemented),
+            None => Ok(()),
+        }
+    }
+
+    /// Use the mask of the layer instead.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        #[cfg(feature = "metal")]
+        let staged = self.metal.is_some();
+        #[cfg(not(feature = "metal"))]
+        let staged = false;
+
+        // The contents of the layer are replaced as a whole. The pixels are drawn into the
+        // `IOSurface` directly if its rows don't need padding.
+        let surface = self.back.as_ref().or(self.front.as_ref());
+        // Drawables are limited to the texture size of the GPU.
+        let max_size = io_surface::max_size();
+        #[cfg(feature = "metal")]
+        let max_size = match self.metal {
+            Some(_) => (
+                max_size.0.min(metal::MAX_TEXTURE_SIZE),
+                max_size.1.min(metal::MAX_TEXTURE_SIZE),
+            ),
+            None => max_size,
+        };
+
+        let zero_copy = !staged && surface.map_or(false, |surface| surface.is_packed());
+        Capabilities {
+            partial_present: false,
+            zero_copy,
+            max_size,
+            // Surfaces drawn into directly are swapped, `staging` is copied from.
+            buffer_count: if zero_copy { 2 } else { 1 },
+            readback: false,
+        }
+    }
+
+    /// Set the `IOSurface` as the contents of the layer, which retains it.
+    pub unsafe fn present_external(
+        &mut self,
+        memory: ExternalMemory,
+        _width: NonZeroU32,
+        _height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        #[allow(unreachable_patterns)]
+        let io_surface = match memory {
+            ExternalMemory::IOSurface { io_surface } => io_surface,
+            _ => return Err(SoftBufferError::Unimplemented),
+        };
+        // The Metal layer covers the contents.
+        #[cfg(feature = "metal")]
+        if self.metal.is_some() {
+            return Err(SoftBufferError::Unimplemented);
+        }
+
+        let _commit = begin_transaction();
+        unsafe {
+            self.layer.set_contents_scale(self.contents_scale());
+            self.layer.set_contents(io_surface.as_ptr() as id);
+        }
+
+        Ok(PresentStatus::Presented)
+    }
+}
+
+/// Extension methods for macOS on [`Surface`](crate::Surface).
+pub trait SurfaceExtMacOS {
+    /// The `CALayer` that softbuffer adds to the view, whose contents are the buffer, e.g. for
+    /// rounding its corners. With the `metal` feature, the buffer is shown in a `CAMetalLayer`
+    /// inside of it instead. Returns `None` for surfaces of other backends.
+    fn ca_layer(&self) -> Option<NonNull<c_void>>;
+
+    /// Shows the buffer with extended dynamic range, so that its white is `brightness` times as
+    /// bright as the SDR white of the screen, or in SDR again for `None`.
+    ///
+    /// The pixels of the buffer stay 8 bits per channel in the color space of
+    /// [`Surface::set_color_space`](crate::Surface::set_color_space), and are converted to half
+    /// floats in its extended linear variant when presenting. The brightness the screen can show
+    /// is that of `maximumExtendedDynamicRangeColorComponentValue` of its `NSScreen`; values above
+    /// it are clipped.
+    ///
+    /// # Panics
+    ///
+    /// If `brightness` isn't finite and positive.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::Unimplemented`] without the `metal` feature, if there is no
+    /// Metal device, or for surfaces of other backends.
+    fn set_edr_brightness(&mut self, brightness: Option<f32>) -> Result<(), SoftBufferError>;
+}
+
+impl<D, W> SurfaceExtMacOS for crate::Surface<D, W> {
+    fn ca_layer(&self) -> Option<NonNull<c_void>> {
+        match &*self.surface_impl {
+            crate::SurfaceDispatch::CG(imp) => NonNull::new(imp.layer.id().cast()),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    fn set_edr_brightness(&mut self, brightness: Option<f32>) -> Result<(), SoftBufferError> {
+        if let Some(brightness) = brightness {
+            assert!(
+                brightness.is_finite() && brightness > 0.0,
+                "`brightness` must be finite and positive"
+            );
+        }
+        match &mut *self.surface_impl {
+            crate::SurfaceDispatch::CG(imp) => imp.set_edr_brightness(brightness),
+            #[allow(unreachable_patterns)]
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+}
+
+pub struct BufferImpl<'a> {
+    imp: &'a mut CGImpl,
+}
+
+impl<'a> BufferImpl<'a> {
+    #[inline]
+    pub fn pixels(&self) -> &[u32] {
+        #[cfg(feature = "metal")]
+        if self.imp.metal.is_some() {
+            return &self.imp.staging;
+        }
+
+        let back = self.imp.back.as_ref().unwrap();
+        if back.is_packed() {
+            // SAFETY: The back surface was locked in `buffer_mut()`.
+            unsafe { back.pixels() }
+        } else {
+            &self.imp.staging
+        }
+    }
+
+    #[inline]
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        #[cfg(feature = "metal")]
+        if self.imp.metal.is_some() {
+            return &mut self.imp.staging;
+        }
+
+        let back = self.imp.back.as_mut().unwrap();
+        if back.is_packed() {
+            // SAFETY: The back surface was locked in `buffer_mut()`.
+            unsafe { back.pixels_mut() }
+        } else {
+            &mut self.imp.staging
+        }
+    }
+
+    pub fn age(&self) -> u8 {
+        match self.surface() {
+            Some(back) => back.age,
+            // The staging buffer is kept between presents.
+            None => self.imp.presented.into(),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.surface().map_or(0, |back| back.index)
+    }
+
+    /// The back surface, if it is drawn into directly instead of `staging`.
+    fn surface(&self) -> Option<&IOSurface> {
+        #[cfg(feature = "metal")]
+        if self.imp.metal.is_some() {
+            return None;
+        }
+
+        self.imp.back.as_ref().filter(|back| back.is_packed())
+    }
+
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        #[cfg(feature = "metal")]
+        if self.imp.metal.is_some() {
+            return None;
+        }
+
+        // Padded surfaces are drawn into `staging`, which is copied into them when presenting.
+        let back = self.imp.back.as_ref().unwrap();
+        if !back.is_packed() {
+            return None;
+        }
+        Some(NativeBuffer::IOSurface {
+            io_surface: NonNull::new(back.as_ptr())?,
+        })
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let imp = &mut *self.imp;
+
+        #[cfg(feature = "metal")]
+        let scale = unsafe { imp.contents_scale() };
+        #[cfg(feature = "metal")]
+        if let Some(metal) = imp.metal.as_mut() {
+            let _commit = begin_transaction();
+            imp.layer.set_contents_scale(scale);
+            let result = metal.present(
+                &imp.layer,
+                &imp.staging,
+                imp.width,
+                imp.height,
+                imp.color_space,
+            );
+            if result.is_ok() {
+                imp.presented = true;
+            }
+            return result.map(|()| PresentStatus::Presented);
+        }
+
+        let mut back = imp.back.take().unwrap();
+        if !back.is_packed() {
+            // SAFETY: The back surface was locked in `buffer_mut()`.
+            unsafe { back.copy_from(&imp.staging) };
+        }
+        back.unlock();
+
+        // The CALayer has a default action associated with a change in the layer contents, causing
+        // a quarter second fade transition to happen every time a new buffer is applied. This can
+        // be mitigated by wrapping the operation in a transaction and disabling all actions.
+        trace_span!("set_contents");
+        let commit = begin_transaction();
+        unsafe {
+            imp.layer.set_contents_scale(imp.contents_scale());
+            imp.layer.set_contents(back.as_ptr() as id);
+        };
+        drop(commit);
+
+        // The previous front surface becomes the next back surface.
+        back.age = 1;
+        imp.back = imp.front.replace(back);
+        if let Some(back) = imp.back.as_mut().filter(|back| back.age != 0) {
+            back.age += 1;
+        }
+        imp.presented = true;
+
+        Ok(PresentStatus::Presented)
+    }
+
+    pub fn present_with_damage(self, _damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        self.present()
+    }
+
+    /// The layer shows all of its contents.
+    pub fn present_viewport(
+        self,
+        _damage: &[Rect],
+        _viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+impl<'a> Drop for BufferImpl<'a> {
+    fn drop(&mut self) {
+        // Don't keep the surface locked if the buffer wasn't presented.
+        if let Some(back) = self.imp.back.as_mut() {
+            back.unlock();
+        }
+    }
+}
+
+/// Extension methods for macOS on [`Context`](crate::Context).
+pub trait ContextExtMacOS {
+    /// Runs `f` inside a single `CATransaction`, with implicit animations disabled.
+    ///
+    /// All buffers presented inside `f`, on any surface, reach the screen together when the
+    /// transaction is committed after `f` returns. This lets applications with several windows
+    /// update all of them atomically.
+    fn batch_presents<T>(&self, f: impl FnOnce() -> T) -> T;
+}
+
+impl<D> ContextExtMacOS for crate::Context<D> {
+    fn batch_presents<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _commit = begin_transaction();
+        f()
+    }
+}
+
+/// Commits the `CATransaction` that was begun by [`begin_transaction`] when dropped, even when
+/// unwinding from a panic.
+struct Commit;
+
+impl Drop for Commit {
+    fn drop(&mut self) {
+        transaction::commit();
+    }
+}
+
+/// Begins a `CATransaction` with implicit animations disabled.
+///
+/// Every change of the layer is made in one of these. Off the main thread, there is no run loop
+/// to commit the implicit transaction Core Animation would otherwise begin, so the change would
+/// only reach the screen whenever something else happens to commit it, if ever.
+fn begin_transaction() -> Commit {
+    transaction::begin();
+    transaction::set_disable_actions(true);
+    Commit
+}
+
+fn is_main_thread() -> bool {
+    let main: BOOL = unsafe { msg_send![class!(NSThread), isMainThread] };
+    main != NO
+}
+
+/// Get the `CGDirectDisplayID` of the screen the window is on, or `None` off the main thread,
+/// where AppKit can't be asked.
+///
+/// # Safety
+///
+/// `window` must be a valid `NSWindow`.
+unsafe fn screen_display_id(window: id) -> Option<u32> {
+    if !is_main_thread() {
+        return None;
+    }
+    unsafe {
+        let screen: id = msg_send![window, screen];
+        if screen == nil {
+            return None;
+        }
+        let description: id = msg_send![screen, deviceDescription];
+        let key = CFString::from_static_string("NSScreenNumber");
+        let number: id = msg_send![description, objectForKey: key.as_concrete_TypeRef() as id];
+        if number == nil {
+            return None;
+        }
+        let display_id: u32 = msg_send![number, unsignedIntValue];
+        Some(display_id)
+    }
+}
+
+impl Drop for CGImpl {
+    fn drop(&mut self) {
+        // Leave the host view the way we found it.
+        if self.hosted {
+            let _commit = begin_transaction();
+            self.layer.remove_from_superlayer();
+        }
+
+        unsafe {
+            let _: () = msg_send![self.window, release];
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! A compositor that flattens layers of pixels into one surface, for toolkits that draw
+//! windows, popups or cursors into separate layers.
+//!
+//! Each layer has a position, a z-order and an opacity, and its pixels are premultiplied ARGB,
+//! with the alpha in the highest 8 bits. The [`Compositor`] keeps the flattened frame, and
+//! [`Compositor::present`] composites again only the parts of it that changed, the damage of
+//! the layers and of moving, restacking and fading them, before presenting those parts:
+//!
+//! ```no_run
+//! # fn run(surface: &mut softbuffer::Surface) -> Result<(), softbuffer::SoftBufferError> {
+//! use softbuffer::compositor::Compositor;
+//! use std::num::NonZeroU32;
+//!
+//! let size = |n| NonZeroU32::new(n).unwrap();
+//! let mut compositor = Compositor::new(size(640), size(480));
+//! compositor.set_background(0xff20_2020);
+//! let popup = compositor.add_layer(size(100), size(50));
+//! let mut layer = compositor.layer_mut(popup).unwrap();
+//! layer.pixels_mut().fill(0xffff_ffff);
+//! layer.set_position(270, 215);
+//! layer.set_opacity(0xc0);
+//! compositor.present(surface)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{PresentStatus, Rect, Region, SoftBufferError, Surface};
+use std::num::NonZeroU32;
+
+/// The most rects of damage that are composited one by one, instead of as their bounds.
+const MAX_DAMAGE: usize = 16;
+
+/// Identifies a layer of a [`Compositor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LayerId(u64);
+
+struct Layer {
+    id: LayerId,
+    width: NonZeroU32,
+    height: NonZeroU32,
+    x: i32,
+    y: i32,
+    z: i32,
+    opacity: u8,
+    pixels: Vec<u32>,
+}
+
+impl Layer {
+    /// The part of `rect` of the layer that is inside of a frame of the given size, in the
+    /// coordinates of the frame.
+    fn clip(&self, rect: &Rect, size: (NonZeroU32, NonZeroU32)) -> Option<Rect> {
+        let range = |position: i32, start: u32, len: NonZeroU32, frame_len: NonZeroU32| {
+            let start = i64::from(position) + i64::from(start);
+            let end = (start + i64::from(len.get())).min(i64::from(frame_len.get()));
+            let start = start.max(0);
+            NonZeroU32::new(u32::try_from(end - start).ok()?).map(|len| (start as u32, len))
+        };
+        let (x, width) = range(self.x, rect.x, rect.width, size.0)?;
+        let (y, height) = range(self.y, rect.y, rect.height, size.1)?;
+        Some(Rect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    /// All of the layer, in the coordinates of a frame of the given size.
+    fn bounds(&self, size: (NonZeroU32, NonZeroU32)) -> Option<Rect> {
+        let all = Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        self.clip(&all, size)
+    }
+}
+
+/// Flattens layers into the buffer of a [`Surface`], see the [module docs](self).
+pub struct Compositor {
+    width: NonZeroU32,
+    height: NonZeroU32,
+    background: u32,
+    layers: Vec<Layer>,
+    next_id: u64,
+    /// The flattened frame.
+    frame: Vec<u32>,
+    /// The parts of the frame that have to be composited again.
+    damage: Region,
+}
+
+impl Compositor {
+    /// Creates a compositor without layers, for frames of the given size.
+    pub fn new(width: NonZeroU32, height: NonZeroU32) -> Self {
+        let mut compositor = Self {
+            width,
+            height,
+            background: 0,
+            layers: Vec::new(),
+            next_id: 0,
+            frame: Vec::new(),
+            damage: Region::new(),
+        };
+        compositor.resize(width, height);
+        compositor
+    }
+
+    /// Changes the size of the frames, which composites all of the next one again.
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) {
+        self.width = width;
+        self.height = height;
+        self.frame = vec![0; width.get() as usize * height.get() as usize];
+        self.damage_all();
+    }
+
+    /// Sets the pixel below all layers, in premultiplied ARGB. It is black by default.
+    pub fn set_background(&mut self, color: u32) {
+        if color != self.background {
+            self.background = color;
+            self.damage_all();
+        }
+    }
+
+    /// Adds a transparent layer of the given size, in the upper-left corner and above the
+    /// layers of the same z-order that were added before it.
+    pub fn add_layer(&mut self, width: NonZeroU32, height: NonZeroU32) -> LayerId {
+        let id = LayerId(self.next_id);
+        self.next_id += 1;
+        self.layers.push(Layer {
+            id,
+            width,
+            height,
+            x: 0,
+            y: 0,
+            z: 0,
+            opacity: u8::MAX,
+            pixels: vec![0; width.get() as usize * height.get() as usize],
+        });
+        id
+    }
+
+    /// Removes a layer, returning whether it existed.
+    pub fn remove_layer(&mut self, id: LayerId) -> bool {
+        match self.layers.iter().position(|layer| layer.id == id) {
+            Some(index) => {
+                let layer = self.layers.remove(index);
+                if let Some(bounds) = layer.bounds((self.width, self.height)) {
+                    self.damage.add(bounds);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Changes a layer, or returns `None` if it was removed.
+    pub fn layer_mut(&mut self, id: LayerId) -> Option<LayerMut<'_>> {
+        let size = (self.width, self.height);
+        let damage = &mut self.damage;
+        self.layers
+            .iter_mut()
+            .find(|layer| layer.id == id)
+            .map(|layer| LayerMut {
+                layer,
+                damage,
+                size,
+            })
+    }
+
+    /// The flattened frame, as of the last [`Compositor::present`].
+    pub fn pixels(&self) -> &[u32] {
+        &self.frame
+    }
+
+    /// Composites the parts of the frame that changed since the last present, and presents them
+    /// to `surface` with [`Surface::present_pixels`], which resizes it to the size of the
+    /// frames.
+    ///
+    /// If nothing changed, this returns [`PresentStatus::Skipped`] without presenting.
+    pub fn present<D, W>(
+        &mut self,
+        surface: &mut Surface<D, W>,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        trace_span!("composite");
+        if self.damage.is_empty() {
+            return Ok(PresentStatus::Skipped);
+        }
+        if self.damage.rects().len() > MAX_DAMAGE {
+            let bounds = self.damage.bounds().unwrap();
+            self.damage.clear();
+            self.damage.add(bounds);
+        }
+
+        let mut order: Vec<&Layer> = self
+            .layers
+            .iter()
+            .filter(|layer| layer.opacity != 0)
+            .collect();
+        // Stable, so layers of the same z-order stay in the order they were added in.
+        order.sort_by_key(|layer| layer.z);
+        let size = (self.width, self.height);
+        let stride = self.width.get() as usize;
+        for rect in self.damage.rects() {
+            for y in rect.y..rect.y + rect.height.get() {
+                let row = y as usize * stride + rect.x as usize;
+                self.frame[row..row + rect.width.get() as usize].fill(self.background);
+            }
+            for layer in &order {
+                let part = match layer
+                    .bounds(size)
+                    .and_then(|bounds| bounds.intersection(rect))
+                {
+                    Some(part) => part,
+                    None => continue,
+                };
+                let layer_stride = layer.width.get() as usize;
+                let left = (i64::from(part.x) - i64::from(layer.x)) as usize;
+                for y in part.y..part.y + part.height.get() {
+                    let src = (i64::from(y) - i64::from(layer.y)) as usize * layer_stride + left;
+                    let dst = y as usize * stride + part.x as usize;
+                    let width = part.width.get() as usize;
+                    blend(
+                        &layer.pixels[src..src + width],
+                        &mut self.frame[dst..dst + width],
+                        layer.opacity,
+                    );
+                }
+            }
+        }
+
+        let status =
+            surface.present_pixels(&self.frame, self.width, self.height, self.damage.rects())?;
+        self.damage.clear();
+        Ok(status)
+    }
+
+    fn damage_all(&mut self) {
+        self.damage.add(Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        });
+    }
+}
+
+/// A layer of a [`Compositor`], which records the damage of the changes made to it.
+pub struct LayerMut<'a> {
+    layer: &'a mut Layer,
+    damage: &'a mut Region,
+    size: (NonZeroU32, NonZeroU32),
+}
+
+impl LayerMut<'_> {
+    /// The width of the layer.
+    pub fn width(&self) -> NonZeroU32 {
+        self.layer.width
+    }
+
+    /// The height of the layer.
+    pub fn height(&self) -> NonZeroU32 {
+        self.layer.height
+    }
+
+    /// The pixels of the layer, row by row, in premultiplied ARGB. This damages all of the
+    /// layer, use [`LayerMut::update`] to change only parts of it.
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        self.damage_all();
+        &mut self.layer.pixels
+    }
+
+    /// The pixels of the layer, like [`LayerMut::pixels_mut`], but only `damage` of them,
+    /// in the coordinates of the layer, may be changed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::DamageOutOfRange`] if a rect doesn't fit inside the layer.
+    pub fn update(&mut self, damage: &[Rect]) -> Result<&mut [u32], SoftBufferError> {
+        if let Some(rect) = damage
+            .iter()
+            .find(|rect| !rect.fits(self.layer.width, self.layer.height))
+        {
+            return Err(SoftBufferError::DamageOutOfRange { rect: *rect });
+        }
+        let size = self.size;
+        self.damage
+            .extend(damage.iter().filter_map(|rect| self.layer.clip(rect, size)));
+        Ok(&mut self.layer.pixels)
+    }
+
+    /// Changes the size of the layer, which makes it transparent.
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) {
+        self.damage_all();
+        self.layer.width = width;
+        self.layer.height = height;
+        self.layer.pixels = vec![0; width.get() as usize * height.get() as usize];
+    }
+
+    /// Moves the upper-left corner of the layer to the given position in the frame. Layers may
+    /// be partly or entirely outside of the frame.
+    pub fn set_position(&mut self, x: i32, y: i32) {
+        if (x, y) != (self.layer.x, self.layer.y) {
+            self.damage_all();
+            self.layer.x = x;
+            self.layer.y = y;
+            self.damage_all();
+        }
+    }
+
+    /// Sets the z-order of the layer. Layers of a higher z-order are above those of a lower
+    /// one. It is `0` by default.
+    pub fn set_z(&mut self, z: i32) {
+        if z != self.layer.z {
+            self.layer.z = z;
+            self.damage_all();
+        }
+    }
+
+    /// Sets the opacity that the layer is multiplied with, from `0` for invisible to `255` for
+    /// the alpha of its pixels, which is the default.
+    pub fn set_opacity(&mut self, opacity: u8) {
+        if opacity != self.layer.opacity {
+            self.layer.opacity = opacity;
+            self.damage_all();
+        }
+    }
+
+    fn damage_all(&mut self) {
+        if let Some(bounds) = self.layer.bounds(self.size) {
+            self.damage.add(bounds);
+        }
+    }
+}
+
+/// Multiplies each channel of `pixel` with `factor / 255`, rounded.
+fn scale(pixel: u32, factor: u32) -> u32 {
+    // Two channels at a time, 16 bits apart, so their products don't overlap.
+    let scale = |channels: u32| {
+        let product = channels * factor + 0x0080_0080;
+        ((product + ((product >> 8) & 0x00ff_00ff)) >> 8) & 0x00ff_00ff
+    };
+    scale(pixel & 0x00ff_00ff) | (scale((pixel >> 8) & 0x00ff_00ff) << 8)
+}
+
+/// Draws the premultiplied pixels of `src` over those of `dst`, multiplied with `opacity`.
+fn blend(src: &[u32], dst: &mut [u32], opacity: u8) {
+    for (&src, dst) in src.iter().zip(dst) {
+        let src = match opacity {
+            u8::MAX => src,
+            opacity => scale(src, u32::from(opacity)),
+        };
+        *dst = match src >> 24 {
+            0xff => src,
+            // Pixels that aren't premultiplied can overflow, so this is meaningless but safe.
+            alpha => src.wrapping_add(scale(*dst, 0xff - alpha)),
+        };
+    }
+}
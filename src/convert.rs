@@ -0,0 +1,472 @@
+//! Conversions of rows of pixels between the `0RGB` format of the buffer and other formats.
+//!
+//! Every conversion has a SIMD implementation for the bulk of the row, which is chosen at
+//! runtime on x86, and a scalar one for the pixels left over.
+
+// Not needed on all platforms
+#![allow(dead_code)]
+
+use crate::stats;
+
+/// How many bytes a copy needs to have before its rows are split over the thread pool.
+#[cfg(feature = "rayon")]
+const PARALLEL_THRESHOLD: usize = 1 << 20;
+
+/// How many bytes a copy needs to have before it bypasses the cache, which is about the size of
+/// the last level cache of small CPUs.
+const STREAMING_THRESHOLD: usize = 1 << 21;
+
+/// Call `f` with the index and the slice of each `stride` long row of `dst`.
+///
+/// With the `rayon` feature, large copies are split over the global thread pool of `rayon`.
+/// Smaller ones stay on this thread, where they are faster than waking up the pool.
+pub fn for_each_row<T: Send>(
+    dst: &mut [T],
+    stride: usize,
+    f: impl Fn(usize, &mut [T]) + Send + Sync,
+) {
+    stats::time_copy(|| {
+        #[cfg(feature = "rayon")]
+        if std::mem::size_of_val(dst) >= PARALLEL_THRESHOLD {
+            use rayon::prelude::*;
+
+            dst.par_chunks_mut(stride)
+                .enumerate()
+                .for_each(|(y, row)| f(y, row));
+            return;
+        }
+
+        for (y, row) in dst.chunks_mut(stride).enumerate() {
+            f(y, row);
+        }
+    })
+}
+
+/// Whether a copy of `len` bytes should `stream` in [`copy`].
+pub fn should_stream(len: usize) -> bool {
+    len >= STREAMING_THRESHOLD
+}
+
+/// Types without padding, which can be copied as bytes.
+pub trait Plain: Copy {}
+
+impl Plain for u8 {}
+impl Plain for u32 {}
+
+/// Copy `src` into the start of `dst`.
+///
+/// With `stream`, the copy uses non-temporal stores if the CPU has them, which write to memory
+/// without pulling it into the cache. The display server reads the pixels from memory anyway, so
+/// copying a large buffer that way doesn't evict the working set of the application.
+pub fn copy<T: Plain>(src: &[T], dst: &mut [T], stream: bool) {
+    let dst = &mut dst[..src.len()];
+    if !stream {
+        dst.copy_from_slice(src);
+        return;
+    }
+
+    // SAFETY: `Plain` types have no padding, and any bytes are valid for them.
+    let (src, dst) = unsafe {
+        (
+            std::slice::from_raw_parts(src.as_ptr().cast::<u8>(), std::mem::size_of_val(src)),
+            std::slice::from_raw_parts_mut(
+                dst.as_mut_ptr().cast::<u8>(),
+                std::mem::size_of_val(dst),
+            ),
+        )
+    };
+    let done = simd::copy_streaming(src, dst);
+    dst[done..].copy_from_slice(&src[done..]);
+}
+
+/// Convert `0RGB` pixels to the in-memory layout of `R8G8B8X8`, with opaque alpha.
+pub fn xrgb_to_rgbx(src: &[u32], dst: &mut [u32]) {
+    let len = src.len().min(dst.len());
+    let (src, dst) = (&src[..len], &mut dst[..len]);
+    let done = simd::xrgb_to_rgbx(src, dst);
+    for (dst, &pixel) in dst[done..].iter_mut().zip(&src[done..]) {
+        *dst = xrgb_to_rgbx_pixel(pixel);
+    }
+}
+
+/// Convert `0RGB` pixels to `R5G6B5`.
+pub fn xrgb_to_rgb565(src: &[u32], dst: &mut [u16]) {
+    let len = src.len().min(dst.len());
+    let (src, dst) = (&src[..len], &mut dst[..len]);
+    let done = simd::xrgb_to_rgb565(src, dst);
+    for (dst, &pixel) in dst[done..].iter_mut().zip(&src[done..]) {
+        *dst = xrgb_to_rgb565_pixel(pixel);
+    }
+}
+
+/// Convert `R5G6B5` pixels to `0RGB`.
+pub fn rgb565_to_xrgb(src: &[u16], dst: &mut [u32]) {
+    let len = src.len().min(dst.len());
+    let (src, dst) = (&src[..len], &mut dst[..len]);
+    let done = simd::rgb565_to_xrgb(src, dst);
+    for (dst, &pixel) in dst[done..].iter_mut().zip(&src[done..]) {
+        *dst = rgb565_to_xrgb_pixel(pixel);
+    }
+}
+
+#[inline]
+pub fn xrgb_to_rgbx_pixel(pixel: u32) -> u32 {
+    let [b, g, r, _] = pixel.to_le_bytes();
+    u32::from_ne_bytes([r, g, b, 0xff])
+}
+
+#[inline]
+pub fn xrgb_to_rgb565_pixel(pixel: u32) -> u16 {
+    (((pixel >> 8) & 0xf800) | ((pixel >> 5) & 0x07e0) | ((pixel >> 3) & 0x1f)) as u16
+}
+
+#[inline]
+pub fn rgb565_to_xrgb_pixel(pixel: u16) -> u32 {
+    let pixel = u32::from(pixel);
+    // Repeat the high bits in the low bits, so white stays white.
+    let red = (pixel >> 11) << 3 | (pixel >> 13);
+    let green = ((pixel >> 5) & 0x3f) << 2 | ((pixel >> 9) & 0x3);
+    let blue = (pixel & 0x1f) << 3 | ((pixel >> 2) & 0x7);
+    red << 16 | green << 8 | blue
+}
+
+// Each implementation converts as many pixels as it can at once, and returns how many it did.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod simd {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    pub fn xrgb_to_rgbx(src: &[u32], dst: &mut [u32]) -> usize {
+        if is_x86_feature_detected!("avx2") {
+            unsafe { xrgb_to_rgbx_avx2(src, dst) }
+        } else if is_x86_feature_detected!("ssse3") {
+            unsafe { xrgb_to_rgbx_ssse3(src, dst) }
+        } else {
+            0
+        }
+    }
+
+    pub fn xrgb_to_rgb565(src: &[u32], dst: &mut [u16]) -> usize {
+        if is_x86_feature_detected!("sse2") {
+            unsafe { xrgb_to_rgb565_sse2(src, dst) }
+        } else {
+            0
+        }
+    }
+
+    pub fn rgb565_to_xrgb(src: &[u16], dst: &mut [u32]) -> usize {
+        if is_x86_feature_detected!("sse2") {
+            unsafe { rgb565_to_xrgb_sse2(src, dst) }
+        } else {
+            0
+        }
+    }
+
+    pub fn copy_streaming(src: &[u8], dst: &mut [u8]) -> usize {
+        if is_x86_feature_detected!("sse2") {
+            unsafe { copy_streaming_sse2(src, dst) }
+        } else {
+            0
+        }
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn copy_streaming_sse2(src: &[u8], dst: &mut [u8]) -> usize {
+        // Non-temporal stores need an aligned destination, so copy up to it first.
+        let head = dst.as_ptr().align_offset(16).min(dst.len());
+        dst[..head].copy_from_slice(&src[..head]);
+        let chunks = (dst.len() - head) / 16;
+        unsafe {
+            for i in 0..chunks {
+                let offset = head + i * 16;
+                let data = _mm_loadu_si128(src.as_ptr().add(offset).cast());
+                _mm_stream_si128(dst.as_mut_ptr().add(offset).cast(), data);
+            }
+            // Non-temporal stores are weakly ordered, so make sure they are done before the
+            // buffer is handed to the display server.
+            _mm_sfence();
+        }
+        head + chunks * 16
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn xrgb_to_rgbx_avx2(src: &[u32], dst: &mut [u32]) -> usize {
+        let chunks = src.len() / 8;
+        unsafe {
+            // The shuffle works on each 128 bit lane on its own.
+            #[rustfmt::skip]
+            let shuffle = _mm256_setr_epi8(
+                2, 1, 0, -128, 6, 5, 4, -128, 10, 9, 8, -128, 14, 13, 12, -128,
+                2, 1, 0, -128, 6, 5, 4, -128, 10, 9, 8, -128, 14, 13, 12, -128,
+            );
+            let alpha = _mm256_set1_epi32(0xff00_0000_u32 as i32);
+            for i in 0..chunks {
+                let pixels = _mm256_loadu_si256(src.as_ptr().add(i * 8).cast());
+                let pixels = _mm256_or_si256(_mm256_shuffle_epi8(pixels, shuffle), alpha);
+                _mm256_storeu_si256(dst.as_mut_ptr().add(i * 8).cast(), pixels);
+            }
+        }
+        chunks * 8
+    }
+
+    #[target_feature(enable = "ssse3")]
+    unsafe fn xrgb_to_rgbx_ssse3(src: &[u32], dst: &mut [u32]) -> usize {
+        let chunks = src.len() / 4;
+        unsafe {
+            #[rustfmt::skip]
+            let shuffle = _mm_setr_epi8(
+                2, 1, 0, -128, 6, 5, 4, -128, 10, 9, 8, -128, 14, 13, 12, -128,
+            );
+            let alpha = _mm_set1_epi32(0xff00_0000_u32 as i32);
+            for i in 0..chunks {
+                let pixels = _mm_loadu_si128(src.as_ptr().add(i * 4).cast());
+                let pixels = _mm_or_si128(_mm_shuffle_epi8(pixels, shuffle), alpha);
+                _mm_storeu_si128(dst.as_mut_ptr().add(i * 4).cast(), pixels);
+            }
+        }
+        chunks * 4
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn xrgb_to_rgb565_sse2(src: &[u32], dst: &mut [u16]) -> usize {
+        let chunks = src.len() / 8;
+        unsafe {
+            let pack = |pixels: __m128i| {
+                let red = _mm_and_si128(_mm_srli_epi32(pixels, 8), _mm_set1_epi32(0xf800));
+                let green = _mm_and_si128(_mm_srli_epi32(pixels, 5), _mm_set1_epi32(0x07e0));
+                let blue = _mm_and_si128(_mm_srli_epi32(pixels, 3), _mm_set1_epi32(0x1f));
+                let pixels = _mm_or_si128(_mm_or_si128(red, green), blue);
+                // Sign extend the low half, so the saturating pack below keeps it as it is.
+                _mm_srai_epi32(_mm_slli_epi32(pixels, 16), 16)
+            };
+            for i in 0..chunks {
+                let low = pack(_mm_loadu_si128(src.as_ptr().add(i * 8).cast()));
+                let high = pack(_mm_loadu_si128(src.as_ptr().add(i * 8 + 4).cast()));
+                _mm_storeu_si128(
+                    dst.as_mut_ptr().add(i * 8).cast(),
+                    _mm_packs_epi32(low, high),
+                );
+            }
+        }
+        chunks * 8
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn rgb565_to_xrgb_sse2(src: &[u16], dst: &mut [u32]) -> usize {
+        let chunks = src.len() / 8;
+        unsafe {
+            let expand = |pixels: __m128i| {
+                let red = _mm_or_si128(
+                    _mm_slli_epi32(_mm_srli_epi32(pixels, 11), 3),
+                    _mm_srli_epi32(pixels, 13),
+                );
+                let green = _mm_or_si128(
+                    _mm_slli_epi32(
+                        _mm_and_si128(_mm_srli_epi32(pixels, 5), _mm_set1_epi32(0x3f)),
+                        2,
+                    ),
+                    _mm_and_si128(_mm_srli_epi32(pixels, 9), _mm_set1_epi32(0x3)),
+                );
+                let blue = _mm_or_si128(
+                    _mm_slli_epi32(_mm_and_si128(pixels, _mm_set1_epi32(0x1f)), 3),
+                    _mm_and_si128(_mm_srli_epi32(pixels, 2), _mm_set1_epi32(0x7)),
+                );
+                _mm_or_si128(
+                    _mm_or_si128(_mm_slli_epi32(red, 16), _mm_slli_epi32(green, 8)),
+                    blue,
+                )
+            };
+            let zero = _mm_setzero_si128();
+            for i in 0..chunks {
+                let pixels = _mm_loadu_si128(src.as_ptr().add(i * 8).cast());
+                let low = expand(_mm_unpacklo_epi16(pixels, zero));
+                let high = expand(_mm_unpackhi_epi16(pixels, zero));
+                _mm_storeu_si128(dst.as_mut_ptr().add(i * 8).cast(), low);
+                _mm_storeu_si128(dst.as_mut_ptr().add(i * 8 + 4).cast(), high);
+            }
+        }
+        chunks * 8
+    }
+}
+
+// NEON is always available on AArch64.
+#[cfg(all(target_arch = "aarch64", target_endian = "little"))]
+mod simd {
+    use std::arch::aarch64::*;
+
+    pub fn xrgb_to_rgbx(src: &[u32], dst: &mut [u32]) -> usize {
+        let chunks = src.len() / 4;
+        unsafe {
+            // Out of range indices select zero.
+            let table: [u8; 16] = [2, 1, 0, 255, 6, 5, 4, 255, 10, 9, 8, 255, 14, 13, 12, 255];
+            let table = vld1q_u8(table.as_ptr());
+            let alpha = vdupq_n_u32(0xff00_0000);
+            for i in 0..chunks {
+                let pixels = vreinterpretq_u8_u32(vld1q_u32(src.as_ptr().add(i * 4)));
+                let pixels = vreinterpretq_u32_u8(vqtbl1q_u8(pixels, table));
+                vst1q_u32(dst.as_mut_ptr().add(i * 4), vorrq_u32(pixels, alpha));
+            }
+        }
+        chunks * 4
+    }
+
+    pub fn xrgb_to_rgb565(src: &[u32], dst: &mut [u16]) -> usize {
+        let chunks = src.len() / 8;
+        unsafe {
+            let pack = |pixels: uint32x4_t| {
+                let red = vandq_u32(vshrq_n_u32::<8>(pixels), vdupq_n_u32(0xf800));
+                let green = vandq_u32(vshrq_n_u32::<5>(pixels), vdupq_n_u32(0x07e0));
+                let blue = vandq_u32(vshrq_n_u32::<3>(pixels), vdupq_n_u32(0x1f));
+                vmovn_u32(vorrq_u32(vorrq_u32(red, green), blue))
+            };
+            for i in 0..chunks {
+                let low = pack(vld1q_u32(src.as_ptr().add(i * 8)));
+                let high = pack(vld1q_u32(src.as_ptr().add(i * 8 + 4)));
+                vst1q_u16(dst.as_mut_ptr().add(i * 8), vcombine_u16(low, high));
+            }
+        }
+        chunks * 8
+    }
+
+    pub fn rgb565_to_xrgb(src: &[u16], dst: &mut [u32]) -> usize {
+        let chunks = src.len() / 8;
+        unsafe {
+            let expand = |pixels: uint32x4_t| {
+                let red = vorrq_u32(
+                    vshlq_n_u32::<3>(vshrq_n_u32::<11>(pixels)),
+                    vshrq_n_u32::<13>(pixels),
+                );
+                let green = vorrq_u32(
+                    vshlq_n_u32::<2>(vandq_u32(vshrq_n_u32::<5>(pixels), vdupq_n_u32(0x3f))),
+                    vandq_u32(vshrq_n_u32::<9>(pixels), vdupq_n_u32(0x3)),
+                );
+                let blue = vorrq_u32(
+                    vshlq_n_u32::<3>(vandq_u32(pixels, vdupq_n_u32(0x1f))),
+                    vandq_u32(vshrq_n_u32::<2>(pixels), vdupq_n_u32(0x7)),
+                );
+                vorrq_u32(
+                    vorrq_u32(vshlq_n_u32::<16>(red), vshlq_n_u32::<8>(green)),
+                    blue,
+                )
+            };
+            for i in 0..chunks {
+                let pixels = vld1q_u16(src.as_ptr().add(i * 8));
+                let low = expand(vmovl_u16(vget_low_u16(pixels)));
+                let high = expand(vmovl_high_u16(pixels));
+                vst1q_u32(dst.as_mut_ptr().add(i * 8), low);
+                vst1q_u32(dst.as_mut_ptr().add(i * 8 + 4), high);
+            }
+        }
+        chunks * 8
+    }
+
+    pub fn copy_streaming(_src: &[u8], _dst: &mut [u8]) -> usize {
+        // `stnp` is only a hint to the core, so this uses plain stores instead.
+        0
+    }
+}
+
+// WebAssembly can't detect features at runtime, so this needs `-Ctarget-feature=+simd128`.
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+mod simd {
+    use std::arch::wasm32::*;
+
+    pub fn xrgb_to_rgbx(src: &[u32], dst: &mut [u32]) -> usize {
+        let chunks = src.len() / 4;
+        // Out of range indices select zero.
+        let table = u8x16(2, 1, 0, 255, 6, 5, 4, 255, 10, 9, 8, 255, 14, 13, 12, 255);
+        let alpha = u32x4_splat(0xff00_0000);
+        for i in 0..chunks {
+            unsafe {
+                let pixels = v128_load(src.as_ptr().add(i * 4).cast());
+                let pixels = v128_or(u8x16_swizzle(pixels, table), alpha);
+                v128_store(dst.as_mut_ptr().add(i * 4).cast(), pixels);
+            }
+        }
+        chunks * 4
+    }
+
+    pub fn xrgb_to_rgb565(_src: &[u32], _dst: &mut [u16]) -> usize {
+        0
+    }
+
+    pub fn rgb565_to_xrgb(_src: &[u16], _dst: &mut [u32]) -> usize {
+        0
+    }
+
+    pub fn copy_streaming(_src: &[u8], _dst: &mut [u8]) -> usize {
+        0
+    }
+}
+
+#[cfg(not(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    all(target_arch = "aarch64", target_endian = "little"),
+    all(target_arch = "wasm32", target_feature = "simd128")
+)))]
+mod simd {
+    pub fn xrgb_to_rgbx(_src: &[u32], _dst: &mut [u32]) -> usize {
+        0
+    }
+
+    pub fn xrgb_to_rgb565(_src: &[u32], _dst: &mut [u16]) -> usize {
+        0
+    }
+
+    pub fn rgb565_to_xrgb(_src: &[u16], _dst: &mut [u32]) -> usize {
+        0
+    }
+
+    pub fn copy_streaming(_src: &[u8], _dst: &mut [u8]) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simd_matches_scalar() {
+        // Odd lengths, so the scalar path converts the rest.
+        for len in [0, 1, 7, 8, 9, 31, 67] {
+            let src: Vec<u32> = (0..len as u32)
+                .map(|i| i.wrapping_mul(0x9e37_79b9) & 0x00ff_ffff)
+                .collect();
+
+            let mut rgbx = vec![0; len];
+            xrgb_to_rgbx(&src, &mut rgbx);
+            let expected: Vec<_> = src.iter().map(|&p| xrgb_to_rgbx_pixel(p)).collect();
+            assert_eq!(rgbx, expected);
+
+            let mut rgb565 = vec![0; len];
+            xrgb_to_rgb565(&src, &mut rgb565);
+            let expected: Vec<_> = src.iter().map(|&p| xrgb_to_rgb565_pixel(p)).collect();
+            assert_eq!(rgb565, expected);
+
+            let mut xrgb = vec![0; len];
+            rgb565_to_xrgb(&rgb565, &mut xrgb);
+            let expected: Vec<_> = rgb565.iter().map(|&p| rgb565_to_xrgb_pixel(p)).collect();
+            assert_eq!(xrgb, expected);
+        }
+    }
+
+    #[test]
+    fn test_copy_streaming() {
+        // Unaligned starts and odd lengths, so the aligned part doesn't cover everything.
+        let src: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        for (offset, len) in [(0, 0), (1, 15), (3, 64), (5, 291)] {
+            let mut dst = vec![0; 300];
+            copy(&src[..len], &mut dst[offset..], true);
+            assert_eq!(&dst[offset..offset + len], &src[..len]);
+            assert!(dst[..offset]
+                .iter()
+                .chain(&dst[offset + len..])
+                .all(|&b| b == 0));
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Dumps of presented frames with their damage outlined, behind the `frame-dump` feature.
+//!
+//! With `SOFTBUFFER_DUMP_FRAMES` set to a directory, every frame presented is written there as
+//! a PPM image, with the rects of damage passed to the present outlined in red. With
+//! `SOFTBUFFER_DUMP_EVERY` set to `N`, only every `N`th frame is written.
+
+use crate::{util, Rect};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs};
+
+const DIR: &str = "SOFTBUFFER_DUMP_FRAMES";
+const EVERY: &str = "SOFTBUFFER_DUMP_EVERY";
+/// The color damage is outlined in.
+const DAMAGE: u32 = 0x00ff_0000;
+
+/// Numbers the surfaces, so that their frames are written to different files.
+static SURFACES: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes the frames presented to a surface.
+pub struct Dumper {
+    dir: PathBuf,
+    surface: usize,
+    every: u64,
+    frames: u64,
+}
+
+impl Dumper {
+    /// The dumper for a new surface, if enabled by the environment.
+    pub fn from_env() -> Option<Self> {
+        let dir = env::var_os(DIR)?;
+        let every = match env::var(EVERY) {
+            Ok(every) => match every.parse() {
+                Ok(every) if every > 0 => every,
+                _ => {
+                    log::warn!("Ignoring invalid {}={:?}", EVERY, every);
+                    1
+                }
+            },
+            Err(_) => 1,
+        };
+        Some(Self {
+            dir: dir.into(),
+            surface: SURFACES.fetch_add(1, Ordering::Relaxed),
+            every,
+            frames: 0,
+        })
+    }
+
+    /// Counts a frame that is about to be presented, and writes it if it's one of every `N`th.
+    /// `damage` is the part of it that changed, if not all of it.
+    pub fn present(&mut self, pixels: &[u32], width: u32, height: u32, damage: Option<&[Rect]>) {
+        let frame = self.frames;
+        self.frames += 1;
+        if frame % self.every != 0 || pixels.len() != width as usize * height as usize {
+            return;
+        }
+
+        let mut pixels = pixels.to_vec();
+        for rect in damage.unwrap_or_default() {
+            util::outline(&mut pixels, width, height, rect, DAMAGE);
+        }
+        let path = self
+            .dir
+            .join(format!("surface{}-frame{:06}.ppm", self.surface, frame));
+        let written = fs::create_dir_all(&self.dir)
+            .and_then(|()| fs::write(&path, util::ppm(&pixels, width, height)));
+        if let Err(err) = written {
+            log::warn!("Failed to dump frame to {}: {}", path.display(), err);
+        }
+    }
+}
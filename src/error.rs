@@ -1,7 +1,9 @@
-use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
+use crate::Backend;
+use raw_window_handle::{HandleError, RawDisplayHandle, RawWindowHandle};
 use std::error::Error;
 use std::fmt;
 use std::num::NonZeroU32;
+use std::sync::{PoisonError, RwLock};
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -72,6 +74,13 @@ pub enum SoftBufferError {
     /// [`Context::new`]: crate::Context::new
     IncompleteDisplayHandle,
 
+    /// The display or window passed into [`Context::new`] or [`Surface::new`] couldn't provide
+    /// a handle, e.g. because an Android window isn't active.
+    ///
+    /// [`Context::new`]: crate::Context::new
+    /// [`Surface::new`]: crate::Surface::new
+    RawWindowHandle(HandleError),
+
     /// The provided size is outside of the range supported by the backend.
     SizeOutOfRange {
         /// The width that was out of range.
@@ -81,7 +90,7 @@ pub enum SoftBufferError {
         height: NonZeroU32,
     },
 
-    /// The provided damage rect is outside of the range supported by the backend.
+    /// The provided damage rect is outside of the buffer, or of the range supported by the backend.
     DamageOutOfRange {
         /// The damage rect that was out of range.
         rect: crate::Rect,
@@ -95,6 +104,42 @@ pub enum SoftBufferError {
     /// actual error type.
     PlatformError(Option<String>, Option<Box<dyn Error>>),
 
+    /// The size of the surface wasn't set with [`Surface::resize`](crate::Surface::resize) yet,
+    /// or its buffers were released with
+    /// [`Surface::release_buffers`](crate::Surface::release_buffers).
+    SurfaceNotConfigured,
+
+    /// The window of the surface was destroyed while the surface still existed.
+    ///
+    /// The surface can't be used anymore, and should be dropped. This is detected on Win32, and
+    /// on X11, where presents don't wait for the X server: there, the next
+    /// [`Surface::buffer_mut`](crate::Surface::buffer_mut) returns it. On other platforms, the
+    /// window must outlive the surface.
+    WindowDestroyed,
+
+    /// The buffer doesn't have the size of the window, which was resized since the last
+    /// [`Surface::resize`](crate::Surface::resize).
+    ///
+    /// This is only returned with [`Surface::set_strict`](crate::Surface::set_strict).
+    SizeMismatch {
+        /// The size of the buffer.
+        buffer: (NonZeroU32, NonZeroU32),
+
+        /// The size of the window.
+        window: (NonZeroU32, NonZeroU32),
+    },
+
+    /// A buffer with unspecified contents, whose [`Buffer::age`](crate::Buffer::age) is `0`, was
+    /// presented with damage that doesn't cover all of it.
+    ///
+    /// On some platforms, the parts that aren't damaged show whatever the buffer contained.
+    /// This is only returned with [`Surface::set_strict`](crate::Surface::set_strict).
+    IncompleteDamage,
+
+    /// The display server didn't respond within the timeout set with
+    /// [`Surface::set_timeout`](crate::Surface::set_timeout).
+    Timeout,
+
     /// This function is unimplemented on this platform.
     Unimplemented,
 }
@@ -121,6 +166,7 @@ impl fmt::Display for SoftBufferError {
             ),
             Self::IncompleteWindowHandle => write!(f, "The provided window handle is null."),
             Self::IncompleteDisplayHandle => write!(f, "The provided display handle is null."),
+            Self::RawWindowHandle(err) => write!(f, "Failed to get a handle: {err}"),
             Self::SizeOutOfRange { width, height } => write!(
                 f,
                 "Surface size {width}x{height} out of range for backend.",
@@ -132,12 +178,60 @@ impl fmt::Display for SoftBufferError {
                 "Damage rect {}x{} at ({}, {}) out of range for backend.",
                 rect.width, rect.height, rect.x, rect.y
             ),
+            Self::SurfaceNotConfigured => write!(f, "The size of the surface was not set."),
+            Self::WindowDestroyed => write!(f, "The window of the surface was destroyed."),
+            Self::SizeMismatch { buffer, window } => write!(
+                f,
+                "The buffer is {}x{}, but the window was resized to {}x{}.",
+                buffer.0, buffer.1, window.0, window.1
+            ),
+            Self::IncompleteDamage => write!(
+                f,
+                "The damage doesn't cover all of a buffer with unspecified contents."
+            ),
+            Self::Timeout => write!(f, "Timed out waiting for the display server."),
             Self::Unimplemented => write!(f, "This function is unimplemented on this platform."),
         }
     }
 }
 
+impl From<HandleError> for SoftBufferError {
+    fn from(err: HandleError) -> Self {
+        Self::RawWindowHandle(err)
+    }
+}
+
+type ErrorHook = Box<dyn Fn(Backend, &SoftBufferError) + Send + Sync>;
+
+static ERROR_HOOK: RwLock<Option<ErrorHook>> = RwLock::new(None);
+
+/// Sets a function that is called with the errors that softbuffer recovers from, and the backend
+/// they happened in.
+///
+/// These errors don't fail the call they happen in, and are logged with `log::warn!` otherwise,
+/// for example a `BitBlt` that failed to copy some of the damage on Win32, a `wl_buffer` that was
+/// released twice on Wayland, a lost WebGL context on Web, or a backend that fell back to a
+/// slower way of presenting. Applications can count them, or recreate the surface.
+///
+/// This replaces the previous hook. The hook is called on the thread the error happened on, and
+/// must not call this function.
+pub fn set_error_hook(hook: impl Fn(Backend, &SoftBufferError) + Send + Sync + 'static) {
+    *ERROR_HOOK.write().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+}
+
+/// Log an error that `backend` recovered from, and pass it to the hook of [`set_error_hook`].
+// Not every backend recovers from errors.
+#[allow(dead_code)]
+pub(crate) fn report(backend: Backend, error: SoftBufferError) {
+    log::warn!("{:?}: {}", backend, error);
+    if let Some(hook) = &*ERROR_HOOK.read().unwrap_or_else(PoisonError::into_inner) {
+        hook(backend, &error);
+    }
+}
+
 /// Convenient wrapper to cast errors into SoftBufferError.
+// Not needed on all platforms
+#[allow(dead_code)]
 pub(crate) trait SwResultExt<T> {
     fn swbuf_err(self, msg: impl Into<String>) -> Result<T, SoftBufferError>;
 }
@@ -160,6 +254,7 @@ impl<T> SwResultExt<T> for Option<T> {
 ///
 /// This prevents `x11-dl` and `x11rb` from becoming public dependencies, since users cannot downcast
 /// to this type.
+#[allow(dead_code)]
 struct LibraryError<E>(E);
 
 impl<E: fmt::Debug> fmt::Debug for LibraryError<E> {
@@ -0,0 +1,585 @@
+//! Implementation of software buffering for raw memory-mapped framebuffers.
+//!
+//! This is for framebuffers that aren't owned by a window system, like the one of the UEFI
+//! Graphics Output Protocol or one shared with a hypervisor. The buffer is converted into the
+//! format of the framebuffer while copying the damaged regions into it.
+
+use crate::{
+    convert, stats, util, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus,
+    Rect, Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
+
+use std::mem;
+use std::num::NonZeroU32;
+use std::ptr::NonNull;
+use std::slice;
+use std::time::Duration;
+
+/// A framebuffer in memory, for `Surface::from_framebuffer()`.
+#[derive(Clone, Copy, Debug)]
+pub struct RawFramebuffer {
+    /// The first byte of the top left pixel.
+    pub ptr: NonNull<u8>,
+    /// The width of the framebuffer in pixels.
+    pub width: NonZeroU32,
+    /// The height of the framebuffer in pixels.
+    pub height: NonZeroU32,
+    /// The distance between the starts of two rows in bytes.
+    pub stride: usize,
+    /// The layout of the pixels.
+    pub format: FramebufferFormat,
+}
+
+/// The layout of the pixels of a [`RawFramebuffer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FramebufferFormat {
+    /// 32 bits per pixel, with the bytes blue, green, red and unused in memory.
+    ///
+    /// This is `PixelBlueGreenRedReserved8BitPerColor` in UEFI.
+    Bgrx8888,
+    /// 32 bits per pixel, with the bytes red, green, blue and unused in memory.
+    ///
+    /// This is `PixelRedGreenBlueReserved8BitPerColor` in UEFI.
+    Rgbx8888,
+    /// 16 bits per pixel in little endian, with 5 bits of red, 6 of green and 5 of blue from the
+    /// most significant bit.
+    Rgb565,
+}
+
+impl FramebufferFormat {
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Bgrx8888 | Self::Rgbx8888 => 4,
+            Self::Rgb565 => 2,
+        }
+    }
+
+    /// Convert a row of pixels from the buffer into the framebuffer, see [`convert::copy`] for
+    /// `stream`.
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid for writes of `src.len()` pixels.
+    unsafe fn write_row(self, src: &[u32], dst: *mut u8, stream: bool) {
+        match self {
+            // The pixels are already in this format.
+            Self::Bgrx8888 if cfg!(target_endian = "little") => unsafe {
+                let src = slice::from_raw_parts(src.as_ptr().cast::<u8>(), src.len() * 4);
+                let dst = slice::from_raw_parts_mut(dst, src.len());
+                convert::copy(src, dst, stream);
+            },
+            Self::Bgrx8888 => {
+                for (i, &pixel) in src.iter().enumerate() {
+                    unsafe { dst.add(i * 4).cast::<u32>().write_unaligned(pixel.to_le()) };
+                }
+            }
+            Self::Rgbx8888 => match unsafe { aligned_slice_mut(dst, src.len()) } {
+                Some(dst) => convert::xrgb_to_rgbx(src, dst),
+                None => {
+                    for (i, &pixel) in src.iter().enumerate() {
+                        let pixel = convert::xrgb_to_rgbx_pixel(pixel);
+                        unsafe { dst.add(i * 4).cast::<u32>().write_unaligned(pixel) };
+                    }
+                }
+            },
+            Self::Rgb565 => match unsafe { aligned_slice_mut(dst, src.len()) } {
+                Some(dst) if cfg!(target_endian = "little") => convert::xrgb_to_rgb565(src, dst),
+                _ => {
+                    for (i, &pixel) in src.iter().enumerate() {
+                        let pixel = convert::xrgb_to_rgb565_pixel(pixel).to_le();
+                        unsafe { dst.add(i * 2).cast::<u16>().write_unaligned(pixel) };
+                    }
+                }
+            },
+        }
+    }
+
+    /// Convert a row of pixels from the framebuffer into the format of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads of `dst.len()` pixels.
+    unsafe fn read_row(self, src: *const u8, dst: &mut [u32]) {
+        match self {
+            Self::Bgrx8888 => {
+                for (i, pixel) in dst.iter_mut().enumerate() {
+                    let value = unsafe { src.add(i * 4).cast::<u32>().read_unaligned() };
+                    *pixel = u32::from_le(value) & 0x00ff_ffff;
+                }
+            }
+            Self::Rgbx8888 => {
+                for (i, pixel) in dst.iter_mut().enumerate() {
+                    let value = unsafe { src.add(i * 4).cast::<u32>().read_unaligned() };
+                    *pixel = swap_red_blue(u32::from_le(value)) & 0x00ff_ffff;
+                }
+            }
+            Self::Rgb565 => match unsafe { aligned_slice(src, dst.len()) } {
+                Some(src) if cfg!(target_endian = "little") => convert::rgb565_to_xrgb(src, dst),
+                _ => {
+                    for (i, pixel) in dst.iter_mut().enumerate() {
+                        let value = unsafe { src.add(i * 2).cast::<u16>().read_unaligned() };
+                        *pixel = convert::rgb565_to_xrgb_pixel(u16::from_le(value));
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// View `len` pixels at `ptr` as a slice, if it is aligned for them.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` pixels.
+unsafe fn aligned_slice<'a, T>(ptr: *const u8, len: usize) -> Option<&'a [T]> {
+    if ptr.align_offset(mem::align_of::<T>()) == 0 {
+        Some(unsafe { slice::from_raw_parts(ptr.cast(), len) })
+    } else {
+        None
+    }
+}
+
+/// View `len` pixels at `ptr` as a mutable slice, if it is aligned for them.
+///
+/// # Safety
+///
+/// `ptr` must be valid for writes of `len` pixels.
+unsafe fn aligned_slice_mut<'a, T>(ptr: *mut u8, len: usize) -> Option<&'a mut [T]> {
+    if ptr.align_offset(mem::align_of::<T>()) == 0 {
+        Some(unsafe { slice::from_raw_parts_mut(ptr.cast(), len) })
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn swap_red_blue(pixel: u32) -> u32 {
+    (pixel & 0xff00_ff00) | ((pixel >> 16) & 0xff) | ((pixel & 0xff) << 16)
+}
+
+struct Buffer {
+    pixels: Vec<u32>,
+    width: NonZeroU32,
+    height: NonZeroU32,
+    presented: bool,
+}
+
+pub struct FramebufferImpl {
+    framebuffer: RawFramebuffer,
+    buffer: Option<Buffer>,
+    flip_vertical: bool,
+    /// Whether the buffer is copied into the center of the framebuffer.
+    centered: bool,
+    /// The color that the rest of the framebuffer is filled with.
+    background: Option<u32>,
+    /// The position and size of the image that the background was last filled around.
+    background_around: Option<((i64, i64), (u32, u32))>,
+}
+
+// SAFETY: `Surface::from_framebuffer` requires the memory to be valid for the lifetime of the
+// surface, which doesn't depend on the thread it is accessed from.
+unsafe impl Send for FramebufferImpl {}
+
+impl FramebufferImpl {
+    /// Create a new `FramebufferImpl` from a `RawFramebuffer`.
+    ///
+    /// # Safety
+    ///
+    /// The framebuffer must be valid for reads and writes for as long as the surface exists.
+    pub unsafe fn new(framebuffer: RawFramebuffer) -> Result<Self, SoftBufferError> {
+        let row_size = framebuffer.width.get() as usize * framebuffer.format.bytes_per_pixel();
+        if framebuffer.stride < row_size {
+            return Err(SoftBufferError::PlatformError(
+                Some("The stride of the framebuffer is smaller than a row of pixels".into()),
+                None,
+            ));
+        }
+
+        Ok(Self {
+            framebuffer,
+            buffer: None,
+            flip_vertical: false,
+            centered: false,
+            background: None,
+            background_around: None,
+        })
+    }
+
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
+        if let Some(buffer) = self.buffer.as_ref() {
+            if buffer.width == width && buffer.height == height {
+                return Ok(());
+            }
+        }
+
+        self.buffer = Some(Buffer {
+            pixels: vec![0; width.get() as usize * height.get() as usize],
+            width,
+            height,
+            presented: false,
+        });
+
+        Ok(())
+    }
+
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        if self.buffer.is_none() {
+            return Err(SoftBufferError::SurfaceNotConfigured);
+        }
+
+        Ok(BufferImpl(self))
+    }
+
+    /// Copy the damaged parts of `viewport` into the framebuffer, so that its upper-left corner is
+    /// in the upper-left corner or the center of the framebuffer.
+    fn present_with_damage(
+        &mut self,
+        damage: &[Rect],
+        viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        let framebuffer = self.framebuffer;
+        let buffer = self.buffer.as_mut().unwrap();
+        let bytes_per_pixel = framebuffer.format.bytes_per_pixel();
+
+        let format = framebuffer.format;
+        let buffer_width = buffer.width.get() as usize;
+        // SAFETY: The framebuffer is valid.
+        let memory = unsafe {
+            slice::from_raw_parts_mut(
+                framebuffer.ptr.as_ptr(),
+                framebuffer.stride * framebuffer.height.get() as usize,
+            )
+        };
+
+        let image = (viewport.width.get(), viewport.height.get());
+        let size = (framebuffer.width.get(), framebuffer.height.get());
+        let position = util::image_position(self.centered, size, image);
+        // The part of the viewport inside of the framebuffer, in the coordinates of the viewport.
+        let clip = |position: i64, len: u32, max: u32| {
+            let clamp = |x: i64| x.clamp(0, len.into()) as usize;
+            (clamp(-position), clamp(i64::from(max) - position))
+        };
+        let (left, right_edge) = clip(position.0, image.0, size.0);
+        let (top, bottom_edge) = clip(position.1, image.1, size.1);
+        // From the coordinates of the part of the viewport inside of the framebuffer to those of
+        // the framebuffer.
+        let to_framebuffer = |x: usize, position: i64| (x as i64 + position) as usize;
+
+        let (viewport_x, viewport_y) = (viewport.x as usize, viewport.y as usize);
+        let viewport_height = viewport.height.get() as usize;
+        let flip_vertical = self.flip_vertical;
+        // The row of the buffer shown in a row of the framebuffer.
+        let source_row = |row: usize| {
+            if flip_vertical {
+                viewport_y + viewport_height - 1 - row
+            } else {
+                viewport_y + row
+            }
+        };
+        for rect in damage {
+            // In the coordinates of the viewport.
+            let x = (rect.x as usize)
+                .saturating_sub(viewport_x)
+                .clamp(left, right_edge);
+            let right = (rect.x as usize + rect.width.get() as usize)
+                .saturating_sub(viewport_x)
+                .clamp(left, right_edge);
+            let mut y = (rect.y as usize)
+                .saturating_sub(viewport_y)
+                .min(viewport_height);
+            let mut bottom = (rect.y as usize + rect.height.get() as usize)
+                .saturating_sub(viewport_y)
+                .min(viewport_height);
+            if flip_vertical {
+                (y, bottom) = (viewport_height - bottom, viewport_height - y);
+            }
+            let (y, bottom) = (y.clamp(top, bottom_edge), bottom.clamp(top, bottom_edge));
+            if x == right {
+                continue;
+            }
+            trace_span!("copy_rect");
+
+            let pixels = &buffer.pixels;
+            let (dst_top, dst_bottom) = (
+                to_framebuffer(y, position.1),
+                to_framebuffer(bottom, position.1),
+            );
+            let rows = &mut memory[dst_top * framebuffer.stride..dst_bottom * framebuffer.stride];
+            let stream = convert::should_stream((right - x) * (bottom - y) * bytes_per_pixel);
+            convert::for_each_row(rows, framebuffer.stride, |row, dst| {
+                let start = source_row(y + row) * buffer_width + viewport_x;
+                let src = &pixels[start + x..start + right];
+                // SAFETY: The rect is inside the framebuffer.
+                unsafe {
+                    let dst = dst
+                        .as_mut_ptr()
+                        .add(to_framebuffer(x, position.0) * bytes_per_pixel);
+                    format.write_row(src, dst, stream);
+                }
+            });
+        }
+        buffer.presented = true;
+
+        if let Some(color) = self.background {
+            if self.background_around != Some((position, image)) {
+                trace_span!("fill_background");
+                for rect in util::uncovered(size, position, image) {
+                    let row = vec![color; rect.width.get() as usize];
+                    for y in rect.y..rect.y + rect.height.get() {
+                        // SAFETY: The rect is inside the framebuffer.
+                        unsafe {
+                            let dst = memory.as_mut_ptr().add(
+                                y as usize * framebuffer.stride + rect.x as usize * bytes_per_pixel,
+                            );
+                            format.write_row(&row, dst, false);
+                        }
+                    }
+                }
+                self.background_around = Some((position, image));
+            }
+        }
+
+        Ok(PresentStatus::Presented)
+    }
+
+    /// Copy `src` of the framebuffer of `other` to `dst` of this framebuffer, converting between
+    /// their formats, and clipping to both.
+    pub fn copy_from(
+        &mut self,
+        other: &Self,
+        src: Rect,
+        dst: (u32, u32),
+    ) -> Result<(), SoftBufferError> {
+        let (from, to) = (other.framebuffer, self.framebuffer);
+        let clip = |start: u32, len: NonZeroU32, max: NonZeroU32| {
+            len.get().min(max.get().saturating_sub(start)) as usize
+        };
+        let width = clip(src.x, src.width, from.width).min(clip(dst.0, src.width, to.width));
+        let height = clip(src.y, src.height, from.height).min(clip(dst.1, src.height, to.height));
+        trace_span!("copy_framebuffer", width, height);
+
+        // A row at a time, in case both framebuffers are the same memory.
+        let mut row = vec![0; width];
+        for y in 0..height {
+            // SAFETY: The rows are inside of both framebuffers, which are valid.
+            unsafe {
+                let src_row = from.ptr.as_ptr().add((src.y as usize + y) * from.stride);
+                from.format.read_row(
+                    src_row.add(src.x as usize * from.format.bytes_per_pixel()),
+                    &mut row,
+                );
+                let dst_row = to.ptr.as_ptr().add((dst.1 as usize + y) * to.stride);
+                to.format.write_row(
+                    &row,
+                    dst_row.add(dst.0 as usize * to.format.bytes_per_pixel()),
+                    false,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the buffer from the framebuffer.
+    pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        let framebuffer = self.framebuffer;
+        let buffer = self.buffer.as_ref().unwrap();
+        let (width, height) = (buffer.width.get() as usize, buffer.height.get() as usize);
+
+        // Parts outside of the framebuffer stay black.
+        let mut pixels = vec![0; width * height];
+        let copy_width = width.min(framebuffer.width.get() as usize);
+        let copy_height = height.min(framebuffer.height.get() as usize);
+        for (row, dst) in pixels.chunks_exact_mut(width).take(copy_height).enumerate() {
+            // SAFETY: The row is inside the framebuffer, and the framebuffer is valid.
+            unsafe {
+                let src = framebuffer.ptr.as_ptr().add(row * framebuffer.stride);
+                framebuffer.format.read_row(src, &mut dst[..copy_width]);
+            }
+        }
+
+        Ok(pixels)
+    }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// Free the copy of the pixels, the framebuffer itself belongs to its owner.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        self.buffer = None;
+        Ok(())
+    }
+
+    /// Wait for the next vertical blank.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Raw framebuffers don't know the mode of the display they are shown on.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Get the size of the framebuffer in pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        Ok((self.framebuffer.width.get(), self.framebuffer.height.get()))
+    }
+
+    /// A framebuffer doesn't know whether it is scanned out.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set how the buffer is scaled to the framebuffer.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        self.centered = match scaling {
+            Scaling::TopLeft => false,
+            Scaling::Center => true,
+            _ => return Err(SoftBufferError::Unimplemented),
+        };
+        Ok(())
+    }
+
+    /// Fill the rest of the framebuffer when the buffer is presented at another position or size.
+    pub fn set_background_color(&mut self, color: u32) -> Result<(), SoftBufferError> {
+        self.background = Some(color);
+        self.background_around = None;
+        Ok(())
+    }
+
+    /// Presents copy into memory, and don't wait for anything.
+    pub fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// There is only one buffer already.
+    pub fn set_single_buffered(&mut self, _single: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Flip the rows while they are copied.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        self.flip_vertical = flip;
+        Ok(())
+    }
+
+    /// Framebuffers aren't resized by a window system.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// The framebuffer is in physical pixels.
+    pub fn set_scale_factor(&mut self, _scale_factor: f64) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// A framebuffer is always rectangular.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            partial_present: true,
+            zero_copy: false,
+            max_size: (u32::MAX, u32::MAX),
+            buffer_count: 1,
+            readback: true,
+        }
+    }
+
+    /// The framebuffer is the only memory that can be shown.
+    pub unsafe fn present_external(
+        &mut self,
+        _memory: ExternalMemory,
+        _width: NonZeroU32,
+        _height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+pub struct BufferImpl<'a>(&'a mut FramebufferImpl);
+
+impl<'a> BufferImpl<'a> {
+    #[inline]
+    pub fn pixels(&self) -> &[u32] {
+        &self.0.buffer.as_ref().unwrap().pixels
+    }
+
+    #[inline]
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.0.buffer.as_mut().unwrap().pixels
+    }
+
+    pub fn age(&self) -> u8 {
+        match self.0.buffer.as_ref() {
+            Some(buffer) if buffer.presented => 1,
+            _ => 0,
+        }
+    }
+
+    /// The pixels are copied into the framebuffer, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    /// The pixels are copied into the framebuffer.
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        None
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let full = self.full_rect();
+        self.0.present_with_damage(&[full], full)
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let full = self.full_rect();
+        self.0.present_with_damage(damage, full)
+    }
+
+    pub fn present_viewport(
+        self,
+        damage: &[Rect],
+        viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        self.0.present_with_damage(damage, viewport)
+    }
+
+    fn full_rect(&self) -> Rect {
+        let buffer = self.0.buffer.as_ref().unwrap();
+        Rect {
+            x: 0,
+            y: 0,
+            width: buffer.width,
+            height: buffer.height,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! The damage of the last presents, see [`Surface::damage_since`].
+//!
+//! [`Surface::damage_since`]: crate::Surface::damage_since
+
+use crate::{Rect, Region};
+use std::collections::VecDeque;
+use std::num::NonZeroU32;
+
+/// How many presents are remembered, more than the buffers any backend cycles through.
+const LEN: usize = 4;
+
+#[derive(Debug, Default)]
+pub struct History {
+    /// The damage of the last presents, the latest first.
+    presents: VecDeque<Region>,
+}
+
+impl History {
+    /// Remember the damage of a present to a buffer of the given size, or that all of it changed
+    /// if `damage` is `None`.
+    pub fn presented(
+        &mut self,
+        damage: Option<&[Rect]>,
+        (width, height): (NonZeroU32, NonZeroU32),
+    ) {
+        // Reuse the memory of the oldest present.
+        let mut region = match self.presents.len() {
+            LEN => self.presents.pop_back().unwrap(),
+            _ => Region::new(),
+        };
+        region.clear();
+        match damage {
+            Some(damage) => region.extend(damage.iter().copied()),
+            None => region.add(Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            }),
+        }
+        self.presents.push_front(region);
+    }
+
+    /// Forget all presents, e.g. because the buffers were resized.
+    pub fn clear(&mut self) {
+        self.presents.clear();
+    }
+
+    /// The damage of the presents after a buffer of the given age was presented, or `None` if
+    /// they aren't all remembered.
+    pub fn since(&self, age: u8) -> Option<Region> {
+        let missed = usize::from(age.checked_sub(1)?);
+        if missed > self.presents.len() {
+            return None;
+        }
+        Some(
+            self.presents
+                .iter()
+                .take(missed)
+                .flat_map(|region| region.rects().iter().copied())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::rect;
+
+    #[test]
+    fn test_since() {
+        let size = |n| NonZeroU32::new(n).unwrap();
+        let mut history = History::default();
+        assert_eq!(history.since(0), None);
+        assert_eq!(history.since(1), Some(Region::new()));
+        assert_eq!(history.since(2), None);
+
+        history.presented(None, (size(4), size(4)));
+        for x in 0..LEN as u32 {
+            history.presented(Some(&[rect(x, 0, 1, 1)]), (size(4), size(4)));
+        }
+        assert_eq!(
+            history.since(2),
+            Some([rect(3, 0, 1, 1)].into_iter().collect())
+        );
+        assert_eq!(
+            history.since(3),
+            Some([rect(3, 0, 1, 1), rect(2, 0, 1, 1)].into_iter().collect())
+        );
+        // The full present was forgotten.
+        assert_eq!(history.since(LEN as u8 + 1).unwrap().rects().len(), LEN);
+        assert_eq!(history.since(LEN as u8 + 2), None);
+
+        history.clear();
+        assert_eq!(history.since(2), None);
+    }
+}
@@ -0,0 +1,1157 @@
+//! Backend for DRM/KMS, which presents directly to a display without a display server.
+//!
+//! Two dumb buffers are allocated for the CRTC that the plane is on. The pixels are drawn in
+//! normal memory, as dumb buffers are usually slow to read, and copied into the buffer that isn't
+//! on screen before flipping to it. Single buffered surfaces only have one dumb buffer, which is
+//! copied into while it is on screen. Dma-bufs presented with `present_external()` are imported
+//! as framebuffers, which are flipped to like the dumb buffers.
+//!
+//! Overlay planes of the CRTC get two dumb buffers each, which are copied into from the pixels
+//! given to `SurfaceExtKms::present_overlay()` and set on the plane in turn.
+
+use crate::{
+    convert,
+    error::{self, SwResultExt},
+    stats, Backend, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus, Rect,
+    Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
+use drm::buffer::{self, Buffer as _, DrmFourcc, PlanarBuffer};
+use drm::control::dumbbuffer::DumbBuffer;
+use drm::control::{
+    connector, crtc, framebuffer, plane, Device as ControlDevice, Event, PageFlipFlags, PlaneType,
+    ResourceHandles,
+};
+use drm::{Device, VblankWaitFlags, VblankWaitTarget};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::time::{clock_gettime, ClockId};
+use raw_window_handle::{DrmDisplayHandle, DrmWindowHandle};
+use std::{
+    mem,
+    num::NonZeroU32,
+    ops::{Bound, RangeBounds},
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How long `present()` waits for the previous page flip before giving up, unless a timeout was
+/// set.
+const FLIP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long `wait_for_vblank()` waits for a page flip, like the other backends.
+const VBLANK_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct KmsDisplayImpl {
+    fd: BorrowedFd<'static>,
+}
+
+impl AsFd for KmsDisplayImpl {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd
+    }
+}
+
+impl Device for KmsDisplayImpl {}
+impl ControlDevice for KmsDisplayImpl {}
+
+impl KmsDisplayImpl {
+    pub unsafe fn new(display_handle: DrmDisplayHandle) -> Result<Self, SoftBufferError> {
+        if display_handle.fd == -1 {
+            return Err(SoftBufferError::IncompleteDisplayHandle);
+        }
+
+        // SAFETY: Ensured by user
+        let fd = unsafe { BorrowedFd::borrow_raw(display_handle.fd) };
+        Ok(Self { fd })
+    }
+}
+
+pub struct KmsImpl {
+    display: Arc<KmsDisplayImpl>,
+    crtc: crtc::Info,
+    /// The plane of the surface, which isn't one of its overlay planes.
+    plane: plane::Handle,
+    /// The index of the CRTC, for waiting on its vertical blank.
+    pipe: u32,
+    connectors: Vec<connector::Handle>,
+    /// The largest width and height of framebuffers the device supports.
+    max_size: (u32, u32),
+    buffers: Option<Buffers>,
+    /// Whether a page flip was queued, but not completed yet.
+    flip_pending: bool,
+    /// How long presents wait for the pending page flip, if not `FLIP_TIMEOUT`.
+    timeout: Option<Duration>,
+    /// When the pending page flip was queued, on the clock of the page flip events.
+    flip_queued_at: Option<Duration>,
+    feedback: stats::Feedback,
+    /// Whether the CRTC shows one of our framebuffers, instead of the one it had before.
+    mode_set: bool,
+    /// Whether only one dumb buffer is created, which is drawn into while it is on screen.
+    single_buffered: bool,
+    /// Whether the rows of the buffer are copied into the dumb buffer bottom-up.
+    flip_vertical: bool,
+    /// The imported framebuffer on screen, or flipped to.
+    external: Option<ImportedFramebuffer>,
+    /// The imported framebuffer that was on screen before the last flip.
+    retired: Option<ImportedFramebuffer>,
+    /// The overlay planes that are shown with `present_overlay()`.
+    overlays: Vec<Overlay>,
+}
+
+struct Buffers {
+    width: NonZeroU32,
+    height: NonZeroU32,
+    pixels: Vec<u32>,
+    /// One dumb buffer if the surface is single buffered, otherwise two.
+    dumb: Vec<DumbFramebuffer>,
+    /// The index of the dumb buffer on screen.
+    front: usize,
+    /// The damage of the last present, which the other dumb buffer is still missing.
+    previous_damage: Vec<Rect>,
+    presented: bool,
+}
+
+struct DumbFramebuffer {
+    fb: framebuffer::Handle,
+    db: DumbBuffer,
+    /// Whether any pixels were copied into the buffer yet.
+    initialized: bool,
+}
+
+/// The dumb buffers of an overlay plane.
+struct Overlay {
+    plane: plane::Handle,
+    width: NonZeroU32,
+    height: NonZeroU32,
+    /// Two dumb buffers of the size of the overlay.
+    dumb: Vec<DumbFramebuffer>,
+    /// The index of the dumb buffer on the plane.
+    front: usize,
+    /// The damage of the last present, which the other dumb buffer is still missing.
+    previous_damage: Vec<Rect>,
+}
+
+/// A framebuffer of a dma-buf imported with `present_external()`.
+struct ImportedFramebuffer {
+    fb: framebuffer::Handle,
+    handle: buffer::Handle,
+}
+
+/// A linear dma-buf that is imported as a GEM buffer.
+struct ImportedBuffer {
+    size: (u32, u32),
+    handle: buffer::Handle,
+    pitch: u32,
+    offset: u32,
+}
+
+impl PlanarBuffer for ImportedBuffer {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn format(&self) -> DrmFourcc {
+        DrmFourcc::Xrgb8888
+    }
+
+    fn pitches(&self) -> [u32; 4] {
+        [self.pitch, 0, 0, 0]
+    }
+
+    fn handles(&self) -> [Option<buffer::Handle>; 4] {
+        [Some(self.handle), None, None, None]
+    }
+
+    fn offsets(&self) -> [u32; 4] {
+        [self.offset, 0, 0, 0]
+    }
+}
+
+impl KmsImpl {
+    pub unsafe fn new(
+        window_handle: DrmWindowHandle,
+        display: Arc<KmsDisplayImpl>,
+    ) -> Result<Self, SoftBufferError> {
+        let plane: plane::Handle = NonZeroU32::new(window_handle.plane)
+            .ok_or(SoftBufferError::IncompleteWindowHandle)?
+            .into();
+        let plane_info = display
+            .get_plane(plane)
+            .swbuf_err("Failed to get the DRM plane")?;
+        let crtc = plane_info
+            .crtc()
+            .swbuf_err("The DRM plane is not attached to a CRTC")?;
+        let crtc = display
+            .get_crtc(crtc)
+            .swbuf_err("Failed to get the DRM CRTC")?;
+        if crtc.mode().is_none() {
+            return Err(SoftBufferError::PlatformError(
+                Some("The DRM CRTC is not enabled".into()),
+                None,
+            ));
+        }
+
+        let resources = display
+            .resource_handles()
+            .swbuf_err("Failed to get the DRM resources")?;
+        let pipe = resources
+            .crtcs()
+            .iter()
+            .position(|&handle| handle == crtc.handle())
+            .swbuf_err("The DRM CRTC is not one of the device")? as u32;
+
+        let max_size = (
+            range_max(resources.supported_fb_width()),
+            range_max(resources.supported_fb_height()),
+        );
+
+        // Keep showing the framebuffer on the connectors that currently show the CRTC.
+        let connectors = resources
+            .connectors()
+            .iter()
+            .copied()
+            .filter(|&connector| {
+                display
+                    .get_connector(connector, false)
+                    .ok()
+                    .and_then(|info| info.current_encoder())
+                    .and_then(|encoder| display.get_encoder(encoder).ok())
+                    .map_or(false, |encoder| encoder.crtc() == Some(crtc.handle()))
+            })
+            .collect();
+
+        Ok(Self {
+            display,
+            crtc,
+            plane,
+            pipe,
+            connectors,
+            max_size,
+            buffers: None,
+            flip_pending: false,
+            timeout: None,
+            flip_queued_at: None,
+            feedback: stats::Feedback::default(),
+            mode_set: false,
+            single_buffered: false,
+            flip_vertical: false,
+            external: None,
+            retired: None,
+            overlays: Vec::new(),
+        })
+    }
+
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
+        let count = if self.single_buffered { 1 } else { 2 };
+        if let Some(buffers) = &self.buffers {
+            if (buffers.width, buffers.height) == (width, height) && buffers.dumb.len() == count {
+                return Ok(());
+            }
+        }
+
+        // The framebuffer must cover the mode of the CRTC, smaller buffers are drawn in the
+        // upper-left corner.
+        let (mode_width, mode_height) = self.mode_size();
+        let size = (width.get().max(mode_width), height.get().max(mode_height));
+        let dumb = (0..count)
+            .map(|_| self.create_buffer(size))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.destroy_buffers()?;
+        self.buffers = Some(Buffers {
+            width,
+            height,
+            pixels: vec![0; width.get() as usize * height.get() as usize],
+            dumb,
+            front: 0,
+            previous_damage: Vec::new(),
+            presented: false,
+        });
+
+        Ok(())
+    }
+
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        if self.buffers.is_none() {
+            return Err(SoftBufferError::SurfaceNotConfigured);
+        }
+
+        Ok(BufferImpl { imp: self })
+    }
+
+    /// Fetch the buffer from the dumb buffer on screen.
+    pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        let buffers = self
+            .buffers
+            .as_mut()
+            .expect("Must set size of surface before calling `fetch()`");
+        let (width, height) = (buffers.width.get() as usize, buffers.height.get() as usize);
+
+        let db = &mut buffers.dumb[buffers.front].db;
+        let pitch = db.pitch() as usize;
+        let mut mapping = self
+            .display
+            .map_dumb_buffer(db)
+            .swbuf_err("Failed to map the dumb buffer")?;
+
+        Ok(mapping
+            .as_mut()
+            .chunks_exact(pitch)
+            .take(height)
+            .flat_map(|row| bytemuck::cast_slice::<u8, u32>(&row[..width * 4]))
+            .copied()
+            .collect())
+    }
+
+    /// Check whether the dumb buffer that is drawn into next is off screen.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        self.wait_for_flip(Duration::ZERO)
+    }
+
+    /// The CRTC scans out of one of the dumb buffers while it is on.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Wait for the pending page flip, or the next vertical blank of the CRTC.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        if self.flip_pending {
+            self.wait_for_flip(VBLANK_TIMEOUT)?;
+            return Ok(());
+        }
+
+        self.display
+            .wait_vblank(
+                VblankWaitTarget::Relative(1),
+                VblankWaitFlags::empty(),
+                self.pipe,
+                0,
+            )
+            .swbuf_err("Failed to wait for the vertical blank")?;
+        Ok(())
+    }
+
+    /// Take the latencies of the page flips since the last call.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        mem::take(&mut self.feedback)
+    }
+
+    /// Get the refresh interval of the mode of the CRTC, from its pixel clock and timings.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        // The mode was checked to be set in `new()`.
+        let mode = self.crtc.mode().unwrap();
+        let (_, _, htotal) = mode.hsync();
+        let (_, _, vtotal) = mode.vsync();
+        let pixels = u64::from(htotal) * u64::from(vtotal);
+        if mode.clock() == 0 || pixels == 0 {
+            return Err(SoftBufferError::PlatformError(
+                Some("The DRM mode has no timings".into()),
+                None,
+            ));
+        }
+
+        // The clock is in kHz.
+        Ok(Duration::from_nanos(
+            pixels * 1_000_000 / u64::from(mode.clock()),
+        ))
+    }
+
+    /// Get the size of the mode of the CRTC.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        Ok(self.mode_size())
+    }
+
+    /// KMS doesn't report whether the display is on.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set how the buffer is scaled to the display.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        match scaling {
+            Scaling::TopLeft => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The parts of the CRTC outside of the buffer aren't drawn to.
+    pub fn set_background_color(&mut self, _color: u32) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set how long presents wait for the previous page flip.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// Only create one dumb buffer, which takes effect immediately if there are buffers already.
+    pub fn set_single_buffered(&mut self, single: bool) -> Result<(), SoftBufferError> {
+        self.single_buffered = single;
+        match &self.buffers {
+            Some(buffers) => self.resize(buffers.width, buffers.height),
+            None => Ok(()),
+        }
+    }
+
+    /// Flip the rows while they are copied into the dumb buffer.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        self.flip_vertical = flip;
+        Ok(())
+    }
+
+    /// Planes aren't resized by a window system.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// The plane is in physical pixels.
+    pub fn set_scale_factor(&mut self, _scale_factor: f64) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// The plane is always rectangular.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            partial_present: true,
+            zero_copy: false,
+            max_size: self.max_size,
+            buffer_count: 1,
+            readback: true,
+        }
+    }
+
+    /// The overlay planes that can be on the CRTC of the surface, and show XRGB8888 buffers.
+    pub fn overlay_planes(&self) -> Result<Vec<plane::Handle>, SoftBufferError> {
+        let resources = self
+            .display
+            .resource_handles()
+            .swbuf_err("Failed to get the DRM resources")?;
+        let planes = self
+            .display
+            .plane_handles()
+            .swbuf_err("Failed to get the DRM planes")?;
+        Ok(planes
+            .into_iter()
+            .filter(|&plane| plane != self.plane && self.is_overlay(plane, &resources))
+            .collect())
+    }
+
+    /// Copy the damaged parts of `pixels` into the back dumb buffer of the overlay plane, and set
+    /// it on the plane at `position` on the CRTC.
+    pub fn present_overlay(
+        &mut self,
+        plane: plane::Handle,
+        pixels: &[u32],
+        (width, height): (NonZeroU32, NonZeroU32),
+        position: (i32, i32),
+        damage: &[Rect],
+    ) -> Result<(), SoftBufferError> {
+        assert_eq!(
+            pixels.len(),
+            width.get() as usize * height.get() as usize,
+            "`pixels` must have `width * height` pixels"
+        );
+        let full = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        if let Some(rect) = damage.iter().find(|rect| !rect.fits(width, height)) {
+            return Err(SoftBufferError::DamageOutOfRange { rect: *rect });
+        }
+
+        let index = match self
+            .overlays
+            .iter()
+            .position(|overlay| overlay.plane == plane)
+        {
+            Some(index) => index,
+            None => {
+                if !self.overlay_planes()?.contains(&plane) {
+                    return Err(SoftBufferError::PlatformError(
+                        Some("The DRM plane is not an overlay plane of the CRTC".into()),
+                        None,
+                    ));
+                }
+                self.overlays
+                    .push(self.create_overlay(plane, (width, height))?);
+                self.overlays.len() - 1
+            }
+        };
+        if (self.overlays[index].width, self.overlays[index].height) != (width, height) {
+            let resized = self.create_overlay(plane, (width, height))?;
+            let old = mem::replace(&mut self.overlays[index], resized);
+            // The plane shows the old buffers until the new ones are set.
+            self.overlays[index].front = old.front;
+            let result = self.show_overlay(index, pixels, &[full], position);
+            let unused = if result.is_ok() {
+                old
+            } else {
+                mem::replace(&mut self.overlays[index], old)
+            };
+            self.destroy_dumb(unused.dumb);
+            return result;
+        }
+
+        self.show_overlay(index, pixels, damage, position)
+    }
+
+    /// Disable the overlay plane, and destroy its dumb buffers.
+    pub fn hide_overlay(&mut self, plane: plane::Handle) -> Result<(), SoftBufferError> {
+        let index = match self
+            .overlays
+            .iter()
+            .position(|overlay| overlay.plane == plane)
+        {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let overlay = self.overlays.remove(index);
+        let result = self
+            .display
+            .set_plane(
+                plane,
+                self.crtc.handle(),
+                None,
+                0,
+                (0, 0, 0, 0),
+                (0, 0, 0, 0),
+            )
+            .swbuf_err("Failed to disable the DRM plane");
+        self.destroy_dumb(overlay.dumb);
+        result
+    }
+
+    /// Whether the plane is an overlay plane that can be on the CRTC, and show XRGB8888 buffers.
+    fn is_overlay(&self, plane: plane::Handle, resources: &ResourceHandles) -> bool {
+        let info = match self.display.get_plane(plane) {
+            Ok(info) => info,
+            Err(_) => return false,
+        };
+        if !resources
+            .filter_crtcs(info.possible_crtcs())
+            .contains(&self.crtc.handle())
+            || !info.formats().contains(&(DrmFourcc::Xrgb8888 as u32))
+        {
+            return false;
+        }
+
+        // Only overlay planes are listed if the client didn't ask for universal planes.
+        let props = match self.display.get_properties(plane) {
+            Ok(props) => props,
+            Err(_) => return true,
+        };
+        let plane_type = props.iter().find(|&(&prop, _)| {
+            self.display
+                .get_property(prop)
+                .map_or(false, |info| info.name().to_bytes() == b"type")
+        });
+        plane_type.map_or(true, |(_, &value)| value == PlaneType::Overlay as u64)
+    }
+
+    fn create_overlay(
+        &self,
+        plane: plane::Handle,
+        (width, height): (NonZeroU32, NonZeroU32),
+    ) -> Result<Overlay, SoftBufferError> {
+        let size = (width.get(), height.get());
+        let front = self.create_buffer(size)?;
+        let back = match self.create_buffer(size) {
+            Ok(back) => back,
+            Err(err) => {
+                self.destroy_dumb(vec![front]);
+                return Err(err);
+            }
+        };
+
+        Ok(Overlay {
+            plane,
+            width,
+            height,
+            dumb: vec![front, back],
+            front: 0,
+            previous_damage: Vec::new(),
+        })
+    }
+
+    /// Copy the damage into the back dumb buffer of the overlay, and set it on the plane.
+    fn show_overlay(
+        &mut self,
+        index: usize,
+        pixels: &[u32],
+        damage: &[Rect],
+        (x, y): (i32, i32),
+    ) -> Result<(), SoftBufferError> {
+        let overlay = &mut self.overlays[index];
+        let (width, height) = (overlay.width, overlay.height);
+        let back = (overlay.front + 1) % overlay.dumb.len();
+        let dumb = &mut overlay.dumb[back];
+        let full = [Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }];
+        let rects: &mut dyn Iterator<Item = &Rect> = if dumb.initialized {
+            &mut damage.iter().chain(&overlay.previous_damage)
+        } else {
+            &mut full.iter()
+        };
+
+        trace_span!("copy_overlay");
+        let pitch = dumb.db.pitch() as usize;
+        let mut mapping = self
+            .display
+            .map_dumb_buffer(&mut dumb.db)
+            .swbuf_err("Failed to map the dumb buffer")?;
+        let dst = mapping.as_mut();
+        let src: &[u8] = bytemuck::cast_slice(pixels);
+        for rect in rects {
+            let (rect_x, rect_width) = (rect.x as usize, rect.width.get() as usize);
+            let (rect_y, rect_height) = (rect.y as usize, rect.height.get() as usize);
+            let rows = &mut dst[rect_y * pitch..(rect_y + rect_height) * pitch];
+            let stream = convert::should_stream(rect_width * rect_height * 4);
+            convert::for_each_row(rows, pitch, |row, dst| {
+                let start = ((rect_y + row) * width.get() as usize + rect_x) * 4;
+                convert::copy(
+                    &src[start..][..rect_width * 4],
+                    &mut dst[rect_x * 4..],
+                    stream,
+                );
+            });
+        }
+        drop(mapping);
+        dumb.initialized = true;
+
+        // Setting a plane waits for the vertical blank, after which the front buffer is unused.
+        trace_span!("set_plane");
+        self.display
+            .set_plane(
+                overlay.plane,
+                self.crtc.handle(),
+                Some(dumb.fb),
+                0,
+                (x, y, width.get(), height.get()),
+                // In 16.16 fixed point.
+                (0, 0, width.get() << 16, height.get() << 16),
+            )
+            .swbuf_err("Failed to set the DRM plane")?;
+
+        overlay.front = back;
+        overlay.previous_damage.clear();
+        overlay.previous_damage.extend_from_slice(damage);
+        Ok(())
+    }
+
+    fn destroy_dumb(&self, dumb: Vec<DumbFramebuffer>) {
+        for dumb in dumb {
+            let _ = self.display.destroy_framebuffer(dumb.fb);
+            let _ = self.display.destroy_dumb_buffer(dumb.db);
+        }
+    }
+
+    fn mode_size(&self) -> (u32, u32) {
+        // Checked in `new()`.
+        let (width, height) = self.crtc.mode().unwrap().size();
+        (width.into(), height.into())
+    }
+
+    fn create_buffer(&self, size: (u32, u32)) -> Result<DumbFramebuffer, SoftBufferError> {
+        let db = self
+            .display
+            .create_dumb_buffer(size, DrmFourcc::Xrgb8888, 32)
+            .swbuf_err("Failed to create dumb buffer")?;
+        let fb = match self.display.add_framebuffer(&db, 24, 32) {
+            Ok(fb) => fb,
+            Err(err) => {
+                let _ = self.display.destroy_dumb_buffer(db);
+                return Err(err).swbuf_err("Failed to add framebuffer");
+            }
+        };
+
+        Ok(DumbFramebuffer {
+            fb,
+            db,
+            initialized: false,
+        })
+    }
+
+    fn destroy_buffers(&mut self) -> Result<(), SoftBufferError> {
+        if let Some(buffers) = self.buffers.take() {
+            // The flip may still read from the buffers.
+            if !self.wait_for_flip(FLIP_TIMEOUT)? {
+                error::report(
+                    Backend::Kms,
+                    SoftBufferError::PlatformError(
+                        Some("Destroying DRM framebuffers that may still be flipped to".into()),
+                        None,
+                    ),
+                );
+            }
+
+            // Removing the framebuffer on screen disables the CRTC, so it has to be set again.
+            self.mode_set = false;
+            self.destroy_dumb(buffers.dumb);
+        }
+
+        Ok(())
+    }
+
+    /// Read events until the pending page flip completed, or the timeout elapsed.
+    ///
+    /// Returns whether no flip is pending anymore.
+    fn wait_for_flip(&mut self, timeout: Duration) -> Result<bool, SoftBufferError> {
+        if !self.flip_pending {
+            return Ok(true);
+        }
+
+        trace_span!("wait_for_flip");
+        // A timeout too long for an `Instant` never elapses.
+        let deadline = Instant::now().checked_add(timeout);
+        while self.flip_pending {
+            let remaining = deadline.map_or(Duration::MAX, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            // `poll` takes at most `i32::MAX` milliseconds, so longer timeouts take several.
+            let chunk = i32::try_from(remaining.as_millis()).unwrap_or(i32::MAX);
+            let mut fds = [PollFd::new(self.display.fd.as_raw_fd(), PollFlags::POLLIN)];
+            let ready = poll(&mut fds, chunk).swbuf_err("Failed to poll the DRM device")?;
+            if ready == 0 {
+                if chunk < i32::MAX {
+                    return Ok(false);
+                }
+                continue;
+            }
+
+            let events = self
+                .display
+                .receive_events()
+                .swbuf_err("Failed to receive DRM events")?;
+            for event in events {
+                if let Event::PageFlip(event) = event {
+                    if event.crtc == self.crtc.handle() {
+                        self.flip_pending = false;
+                        if let Some(queued_at) = self.flip_queued_at.take() {
+                            self.feedback
+                                .shown(event.duration.saturating_sub(queued_at));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Copy the damaged parts of `viewport` into the back dumb buffer, whose upper-left corner
+    /// shows the upper-left corner of `viewport`, and flip to it.
+    fn present_with_damage(
+        &mut self,
+        damage: &[Rect],
+        viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        // The buffer of the pending flip is drawn into next.
+        if !self.wait_for_flip(self.timeout.unwrap_or(FLIP_TIMEOUT))? {
+            return Err(SoftBufferError::Timeout);
+        }
+
+        // The imported framebuffer on screen is flipped away from.
+        let was_external = self.retire_external();
+
+        let buffers = self.buffers.as_mut().unwrap();
+        let width = buffers.width.get();
+        let back = (buffers.front + 1) % buffers.dumb.len();
+        let single_buffered = buffers.dumb.len() == 1;
+        let dumb = &mut buffers.dumb[back];
+
+        let full = [Rect {
+            x: 0,
+            y: 0,
+            width: buffers.width,
+            height: buffers.height,
+        }];
+        // The only dumb buffer already has the last frame.
+        let rects: &mut dyn Iterator<Item = &Rect> = if single_buffered && dumb.initialized {
+            &mut damage.iter()
+        } else if dumb.initialized {
+            &mut damage.iter().chain(&buffers.previous_damage)
+        } else {
+            &mut full.iter()
+        };
+
+        let pitch = dumb.db.pitch() as usize;
+        let flip_vertical = self.flip_vertical;
+        let mut mapping = self
+            .display
+            .map_dumb_buffer(&mut dumb.db)
+            .swbuf_err("Failed to map the dumb buffer")?;
+        let dst = mapping.as_mut();
+        let src: &[u8] = bytemuck::cast_slice(&buffers.pixels);
+        let (origin_x, origin_y) = (viewport.x as usize, viewport.y as usize);
+        let (view_width, view_height) = (viewport.width.get(), viewport.height.get());
+        for rect in rects {
+            trace_span!("copy_rect");
+            // In the coordinates of the viewport.
+            let clip = |start: u32, len: NonZeroU32, origin: u32, max: u32| {
+                let end = start
+                    .saturating_add(len.get())
+                    .saturating_sub(origin)
+                    .min(max);
+                let start = start.saturating_sub(origin).min(end);
+                (start as usize, (end - start) as usize)
+            };
+            let (x, rect_width) = clip(rect.x, rect.width, viewport.x, view_width);
+            let (y, rect_height) = clip(rect.y, rect.height, viewport.y, view_height);
+
+            // Flipped rows are drawn from the bottom of the viewport up.
+            let dst_y = if flip_vertical {
+                view_height as usize - y - rect_height
+            } else {
+                y
+            };
+            let rows = &mut dst[dst_y * pitch..(dst_y + rect_height) * pitch];
+            let stream = convert::should_stream(rect_width * rect_height * 4);
+            convert::for_each_row(rows, pitch, |row, dst| {
+                let src_y = origin_y
+                    + if flip_vertical {
+                        y + rect_height - 1 - row
+                    } else {
+                        y + row
+                    };
+                let src = &src[(src_y * width as usize + origin_x + x) * 4..][..rect_width * 4];
+                convert::copy(src, &mut dst[x * 4..], stream);
+            });
+        }
+        drop(mapping);
+        dumb.initialized = true;
+
+        // A single dumb buffer that is on screen shows the pixels already.
+        let fb = dumb.fb;
+        if !self.mode_set || !single_buffered || was_external {
+            self.show(fb)?;
+        }
+
+        let buffers = self.buffers.as_mut().unwrap();
+        buffers.front = back;
+        buffers.previous_damage.clear();
+        if (viewport.width, viewport.height) == (buffers.width, buffers.height) {
+            buffers.previous_damage.extend_from_slice(damage);
+        } else {
+            // The other dumb buffer shows the buffer in the upper-left corner still.
+            buffers.previous_damage.extend_from_slice(&full);
+        }
+        buffers.presented = true;
+
+        Ok(PresentStatus::Presented)
+    }
+
+    /// Import the dma-buf as a framebuffer and flip to it.
+    pub unsafe fn present_external(
+        &mut self,
+        memory: ExternalMemory,
+        width: NonZeroU32,
+        height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        let (fd, offset, stride) = match memory {
+            ExternalMemory::DmaBuf { fd, offset, stride } => (fd, offset, stride),
+            _ => return Err(SoftBufferError::Unimplemented),
+        };
+        if !self.wait_for_flip(self.timeout.unwrap_or(FLIP_TIMEOUT))? {
+            return Err(SoftBufferError::Timeout);
+        }
+
+        trace_span!("import_dma_buf");
+        let handle = self
+            .display
+            .prime_fd_to_buffer(fd)
+            .swbuf_err("Failed to import the dma-buf")?;
+        let buffer = ImportedBuffer {
+            size: (width.get(), height.get()),
+            handle,
+            pitch: stride,
+            offset,
+        };
+        let fb = match self.display.add_planar_framebuffer(&buffer, &[None; 4], 0) {
+            Ok(fb) => fb,
+            Err(err) => {
+                let _ = self.display.close_buffer(handle);
+                return Err(err).swbuf_err("Failed to add framebuffer");
+            }
+        };
+        let imported = ImportedFramebuffer { fb, handle };
+
+        self.retire_external();
+        if let Err(err) = self.show(fb) {
+            self.destroy_imported(imported);
+            return Err(err);
+        }
+        self.external = Some(imported);
+
+        Ok(PresentStatus::Presented)
+    }
+
+    /// Show `fb` on the CRTC, by setting it if it doesn't show one of our framebuffers yet,
+    /// and otherwise by flipping to it.
+    fn show(&mut self, fb: framebuffer::Handle) -> Result<(), SoftBufferError> {
+        if !self.mode_set {
+            trace_span!("set_crtc");
+            self.display
+                .set_crtc(
+                    self.crtc.handle(),
+                    Some(fb),
+                    (0, 0),
+                    &self.connectors,
+                    self.crtc.mode(),
+                )
+                .swbuf_err("Failed to set the DRM CRTC")?;
+            self.mode_set = true;
+        } else {
+            trace_span!("page_flip");
+            self.display
+                .page_flip(self.crtc.handle(), fb, PageFlipFlags::EVENT, None)
+                .swbuf_err("Failed to queue the page flip")?;
+            self.flip_pending = true;
+            // Page flip events are timestamped with the monotonic clock.
+            self.flip_queued_at = clock_gettime(ClockId::CLOCK_MONOTONIC)
+                .ok()
+                .map(Duration::from);
+        }
+
+        Ok(())
+    }
+
+    /// Destroy the imported framebuffer that is off screen, now that the last flip completed,
+    /// and retire the one on screen, which the next flip replaces.
+    ///
+    /// Returns whether an imported framebuffer was on screen.
+    fn retire_external(&mut self) -> bool {
+        if let Some(retired) = self.retired.take() {
+            self.destroy_imported(retired);
+        }
+        self.retired = self.external.take();
+        self.retired.is_some()
+    }
+
+    fn destroy_imported(&self, imported: ImportedFramebuffer) {
+        let _ = self.display.destroy_framebuffer(imported.fb);
+        let _ = self.display.close_buffer(imported.handle);
+    }
+}
+
+impl Drop for KmsImpl {
+    fn drop(&mut self) {
+        let mode_set = self.mode_set;
+        for plane in self
+            .overlays
+            .iter()
+            .map(|overlay| overlay.plane)
+            .collect::<Vec<_>>()
+        {
+            let _ = self.hide_overlay(plane);
+        }
+        if self.destroy_buffers().is_err() {
+            return;
+        }
+        if self.wait_for_flip(FLIP_TIMEOUT).is_err() {
+            return;
+        }
+        for imported in [self.external.take(), self.retired.take()]
+            .into_iter()
+            .flatten()
+        {
+            self.destroy_imported(imported);
+        }
+
+        // Show whatever the CRTC showed before, e.g. the console.
+        if mode_set {
+            let _ = self.display.set_crtc(
+                self.crtc.handle(),
+                self.crtc.framebuffer(),
+                self.crtc.position(),
+                &self.connectors,
+                self.crtc.mode(),
+            );
+        }
+    }
+}
+
+/// Hardware overlay planes of surfaces on DRM/KMS.
+///
+/// The display controller composites overlay planes over the plane of the surface when scanning
+/// out, e.g. a video over the user interface, without copying either into the other. Each overlay
+/// has two dumb buffers, of which only the damaged parts are copied into.
+pub trait SurfaceExtKms {
+    /// The IDs of the overlay planes that can be shown over the surface, on the CRTC of the
+    /// surface.
+    ///
+    /// Returns an empty list for surfaces of other backends.
+    fn overlay_planes(&self) -> Result<Vec<NonZeroU32>, SoftBufferError>;
+
+    /// Show `pixels` on the overlay plane, with its upper-left corner at `position` on the CRTC.
+    ///
+    /// The pixels are in the format of the buffer, `width` pixels wide and `height` pixels
+    /// tall. Only the `damage` is copied, apart from the first present to the plane, or after its
+    /// size changed. Overlays are shown until they are hidden with [`hide_overlay`], or until the
+    /// surface is dropped.
+    ///
+    /// [`hide_overlay`]: Self::hide_overlay
+    ///
+    /// # Panics
+    ///
+    /// If `pixels` isn't `width * height` long.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::DamageOutOfRange`] without presenting anything if a rect
+    /// doesn't fit inside the overlay, and [`SoftBufferError::Unimplemented`] for surfaces of
+    /// other backends.
+    fn present_overlay(
+        &mut self,
+        plane: NonZeroU32,
+        pixels: &[u32],
+        width: NonZeroU32,
+        height: NonZeroU32,
+        position: (i32, i32),
+        damage: &[Rect],
+    ) -> Result<(), SoftBufferError>;
+
+    /// Stop showing the overlay plane, and free its buffers.
+    fn hide_overlay(&mut self, plane: NonZeroU32) -> Result<(), SoftBufferError>;
+}
+
+impl<D, W> SurfaceExtKms for crate::Surface<D, W> {
+    fn overlay_planes(&self) -> Result<Vec<NonZeroU32>, SoftBufferError> {
+        match &*self.surface_impl {
+            crate::SurfaceDispatch::Kms(imp) => Ok(imp
+                .overlay_planes()?
+                .into_iter()
+                .filter_map(|plane| NonZeroU32::new(plane.into()))
+                .collect()),
+            #[allow(unreachable_patterns)]
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn present_overlay(
+        &mut self,
+        plane: NonZeroU32,
+        pixels: &[u32],
+        width: NonZeroU32,
+        height: NonZeroU32,
+        position: (i32, i32),
+        damage: &[Rect],
+    ) -> Result<(), SoftBufferError> {
+        match &mut *self.surface_impl {
+            crate::SurfaceDispatch::Kms(imp) => {
+                imp.present_overlay(plane.into(), pixels, (width, height), position, damage)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    fn hide_overlay(&mut self, plane: NonZeroU32) -> Result<(), SoftBufferError> {
+        match &mut *self.surface_impl {
+            crate::SurfaceDispatch::Kms(imp) => imp.hide_overlay(plane.into()),
+            #[allow(unreachable_patterns)]
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+}
+
+pub struct BufferImpl<'a> {
+    imp: &'a mut KmsImpl,
+}
+
+impl<'a> BufferImpl<'a> {
+    #[inline]
+    pub fn pixels(&self) -> &[u32] {
+        &self.imp.buffers.as_ref().unwrap().pixels
+    }
+
+    #[inline]
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.imp.buffers.as_mut().unwrap().pixels
+    }
+
+    pub fn age(&self) -> u8 {
+        // The pixels are kept in normal memory, so they stay the same after the copy.
+        if self.imp.buffers.as_ref().unwrap().presented {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// The pixels are copied into the dumb buffers, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    /// The pixels are copied into dumb buffers.
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        None
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let full = self.full_rect();
+        self.imp.present_with_damage(&[full], full)
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let full = self.full_rect();
+        self.imp.present_with_damage(damage, full)
+    }
+
+    pub fn present_viewport(
+        self,
+        damage: &[Rect],
+        viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        self.imp.present_with_damage(damage, viewport)
+    }
+
+    fn full_rect(&self) -> Rect {
+        let buffers = self.imp.buffers.as_ref().unwrap();
+        Rect {
+            x: 0,
+            y: 0,
+            width: buffers.width,
+            height: buffers.height,
+        }
+    }
+}
+
+/// Get the largest value in a range of supported sizes.
+fn range_max(range: impl RangeBounds<u32>) -> u32 {
+    match range.end_bound() {
+        Bound::Included(&max) => max,
+        Bound::Excluded(&end) => end.saturating_sub(1),
+        Bound::Unbounded => u32::MAX,
+    }
+}
@@ -3,50 +3,147 @@
 #![warn(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "macos", target_os = "ios"))]
 #[macro_use]
 extern crate objc;
 extern crate core;
 
+// First, so the backends can use its macros.
+#[macro_use]
+mod trace;
+
+#[cfg(target_os = "android")]
+mod android;
 #[cfg(target_os = "macos")]
 mod cg;
+#[cfg(feature = "framebuffer")]
+mod framebuffer;
+#[cfg(kms_platform)]
+mod kms;
 #[cfg(target_os = "redox")]
 mod orbital;
+#[cfg(feature = "terminal")]
+mod terminal;
+#[cfg(target_os = "ios")]
+mod uikit;
 #[cfg(wayland_platform)]
 mod wayland;
-#[cfg(target_arch = "wasm32")]
+#[cfg(web_platform)]
 mod web;
 #[cfg(target_os = "windows")]
 mod win32;
+#[cfg(feature = "winit")]
+mod winit;
+#[cfg(all(target_os = "windows", feature = "winrt"))]
+mod winrt;
 #[cfg(x11_platform)]
 mod x11;
 
+#[cfg(all(target_os = "wasi", not(feature = "framebuffer")))]
+compile_error!("On WASI, softbuffer needs the `framebuffer` feature to present into memory shared with the host.");
+#[cfg(not(any(
+    window_platform,
+    target_os = "wasi",
+    feature = "framebuffer",
+    feature = "terminal"
+)))]
+compile_error!("softbuffer has no backend for this platform, enable one of the `x11`, `wayland`, `kms`, `framebuffer` or `terminal` features.");
+
+mod convert;
+#[cfg(feature = "frame-dump")]
+mod dump;
 mod error;
+mod history;
+mod resample;
+mod stats;
+#[cfg(feature = "metrics")]
+mod telemetry;
 mod util;
+mod view;
 
+#[cfg(feature = "compositor")]
+pub mod compositor;
+#[cfg(feature = "present-thread")]
+pub mod present_thread;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+use std::borrow::Borrow;
+#[cfg(send_platform)]
+use std::cell::Cell;
+use std::ffi::c_void;
 use std::marker::PhantomData;
+use std::mem;
 use std::num::NonZeroU32;
 use std::ops;
-#[cfg(any(wayland_platform, x11_platform))]
-use std::rc::Rc;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::ptr::NonNull;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub use error::SoftBufferError;
+pub use error::{set_error_hook, SoftBufferError};
+pub use stats::{FrameStats, PresentInfo};
 
 use raw_window_handle::{
-    HasRawDisplayHandle, HasRawWindowHandle, RawDisplayHandle, RawWindowHandle,
+    HasDisplayHandle, HasRawDisplayHandle, HasRawWindowHandle, HasWindowHandle, RawDisplayHandle,
+    RawWindowHandle,
 };
 
-#[cfg(target_arch = "wasm32")]
-pub use self::web::SurfaceExtWeb;
+#[cfg(target_os = "android")]
+pub use self::android::SurfaceExtAndroid;
+#[cfg(target_os = "macos")]
+pub use self::cg::{ContextExtMacOS, SurfaceExtMacOS};
+#[cfg(feature = "framebuffer")]
+pub use self::framebuffer::{FramebufferFormat, RawFramebuffer};
+#[cfg(kms_platform)]
+pub use self::kms::SurfaceExtKms;
+#[cfg(feature = "terminal")]
+pub use self::terminal::TerminalProtocol;
+#[cfg(wayland_platform)]
+pub use self::wayland::SurfaceExtWayland;
+#[cfg(web_platform)]
+pub use self::web::{frame_channel, FrameReader, FrameWriter, SurfaceExtWeb};
+#[cfg(target_os = "windows")]
+pub use self::win32::SurfaceExtWindows;
 
 /// An instance of this struct contains the platform-specific data that must be managed in order to
 /// write to a window on that platform.
-pub struct Context {
+///
+/// The context owns the display it was created for, or a [`NoDisplayHandle`] if it was created
+/// from a raw handle with [`Context::from_raw`]. This is `Send` on the same platforms as
+/// [`Surface`], if the display is `Send` and `Sync`.
+pub struct Context<D = NoDisplayHandle> {
     /// The inner static dispatch object.
     context_impl: ContextDispatch,
-    _marker: PhantomData<*mut ()>,
+    /// The display, which surfaces keep alive as well.
+    display: Arc<D>,
+    _marker: ThreadMarker,
 }
 
+/// The display of a [`Context`] created from a raw display handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NoDisplayHandle(());
+
+/// The window of a [`Surface`] created from a raw window handle, or without a window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NoWindowHandle(());
+
+/// Makes [`Context`] and [`Surface`] `!Sync`, and `!Send` on platforms where windows can only be
+/// drawn to from the thread that created them.
+#[cfg(send_platform)]
+type ThreadMarker = PhantomData<Cell<()>>;
+#[cfg(not(send_platform))]
+type ThreadMarker = PhantomData<*mut ()>;
+
+// Check that the backends of these platforms don't make them `!Send`.
+#[cfg(send_platform)]
+const _: () = {
+    const fn assert_send<T: Send>() {}
+    assert_send::<Context>();
+    assert_send::<Surface>();
+};
+
 /// A macro for creating the enum used to statically dispatch to the platform-specific implementation.
 macro_rules! make_dispatch {
     (
@@ -63,16 +160,41 @@ macro_rules! make_dispatch {
         }
 
         impl ContextDispatch {
+            // Dereferenced, so this compiles without any variants, as on WASI.
             fn variant_name(&self) -> &'static str {
-                match self {
+                match *self {
                     $(
                         $(#[$attr])*
                         Self::$name(_) => stringify!($name),
                     )*
                 }
             }
+
+            fn backend(&self) -> Backend {
+                match *self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(_) => Backend::$name,
+                    )*
+                }
+            }
+        }
+
+        impl Backend {
+            /// Returns the backends compiled into this build of softbuffer, for the target
+            /// platform and the enabled features.
+            pub fn compiled() -> &'static [Backend] {
+                &[
+                    $(
+                        $(#[$attr])*
+                        Backend::$name,
+                    )*
+                ]
+            }
         }
 
+        // `Surface` boxes this, so the size difference between backends doesn't matter.
+        #[allow(clippy::large_enum_variant)]
         enum SurfaceDispatch {
             $(
                 $(#[$attr])*
@@ -81,6 +203,26 @@ macro_rules! make_dispatch {
         }
 
         impl SurfaceDispatch {
+            // Only used for tracing.
+            #[allow(dead_code)]
+            fn variant_name(&self) -> &'static str {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(_) => stringify!($name),
+                    )*
+                }
+            }
+
+            fn backend(&self) -> Backend {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(_) => Backend::$name,
+                    )*
+                }
+            }
+
             pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
                 match self {
                     $(
@@ -90,7 +232,7 @@ macro_rules! make_dispatch {
                 }
             }
 
-            pub fn buffer_mut(&mut self) -> Result<BufferDispatch, SoftBufferError> {
+            pub fn buffer_mut(&mut self) -> Result<BufferDispatch<'_>, SoftBufferError> {
                 match self {
                     $(
                         $(#[$attr])*
@@ -107,6 +249,173 @@ macro_rules! make_dispatch {
                     )*
                 }
             }
+
+            pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.buffer_released(),
+                    )*
+                }
+            }
+
+            pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.release_buffers(),
+                    )*
+                }
+            }
+
+            pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.wait_for_vblank(),
+                    )*
+                }
+            }
+
+            pub fn take_feedback(&mut self) -> stats::Feedback {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.take_feedback(),
+                    )*
+                }
+            }
+
+            pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.refresh_rate(),
+                    )*
+                }
+            }
+
+            pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.window_size(),
+                    )*
+                }
+            }
+
+            pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.visibility(),
+                    )*
+                }
+            }
+
+            pub fn set_scaling(&mut self, scaling: Scaling, filter: ScalingFilter) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_scaling(scaling, filter),
+                    )*
+                }
+            }
+
+            pub fn set_background_color(&mut self, color: u32) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_background_color(color),
+                    )*
+                }
+            }
+
+            pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_timeout(timeout),
+                    )*
+                }
+            }
+
+            pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_color_space(color_space),
+                    )*
+                }
+            }
+
+            pub fn set_single_buffered(&mut self, single: bool) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_single_buffered(single),
+                    )*
+                }
+            }
+
+            pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_flip_vertical(flip),
+                    )*
+                }
+            }
+
+            pub fn set_smooth_resize(&mut self, smooth: bool) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_smooth_resize(smooth),
+                    )*
+                }
+            }
+
+            pub fn set_scale_factor(&mut self, scale_factor: f64) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_scale_factor(scale_factor),
+                    )*
+                }
+            }
+
+            pub fn set_shape(&mut self, shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_shape(shape),
+                    )*
+                }
+            }
+
+            pub unsafe fn present_external(
+                &mut self,
+                memory: ExternalMemory,
+                width: NonZeroU32,
+                height: NonZeroU32,
+            ) -> Result<PresentStatus, SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => unsafe { inner.present_external(memory, width, height) },
+                    )*
+                }
+            }
+
+            pub fn capabilities(&self) -> Capabilities {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.capabilities(),
+                    )*
+                }
+            }
         }
 
         enum BufferDispatch<'a> {
@@ -117,6 +426,17 @@ macro_rules! make_dispatch {
         }
 
         impl<'a> BufferDispatch<'a> {
+            // Only used for tracing.
+            #[allow(dead_code)]
+            fn variant_name(&self) -> &'static str {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(_) => stringify!($name),
+                    )*
+                }
+            }
+
             #[inline]
             pub fn pixels(&self) -> &[u32] {
                 match self {
@@ -146,7 +466,34 @@ macro_rules! make_dispatch {
                 }
             }
 
-            pub fn present(self) -> Result<(), SoftBufferError> {
+            pub fn index(&self) -> usize {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.index(),
+                    )*
+                }
+            }
+
+            pub fn set_shape(&mut self, shape: &[Rect]) -> Result<(), SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.set_shape(shape),
+                    )*
+                }
+            }
+
+            pub fn native_buffer(&self) -> Option<NativeBuffer> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.native_buffer(),
+                    )*
+                }
+            }
+
+            pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
                 match self {
                     $(
                         $(#[$attr])*
@@ -155,7 +502,7 @@ macro_rules! make_dispatch {
                 }
             }
 
-            pub fn present_with_damage(self, damage: &[Rect]) -> Result<(), SoftBufferError> {
+            pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
                 match self {
                     $(
                         $(#[$attr])*
@@ -163,6 +510,15 @@ macro_rules! make_dispatch {
                     )*
                 }
             }
+
+            pub fn present_viewport(self, damage: &[Rect], viewport: Rect) -> Result<PresentStatus, SoftBufferError> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        Self::$name(inner) => inner.present_viewport(damage, viewport),
+                    )*
+                }
+            }
         }
     };
 }
@@ -171,53 +527,114 @@ macro_rules! make_dispatch {
 
 make_dispatch! {
     #[cfg(x11_platform)]
-    X11(Rc<x11::X11DisplayImpl>, x11::X11Impl, x11::BufferImpl<'a>),
+    X11(Arc<x11::X11DisplayImpl>, x11::X11Impl, x11::BufferImpl<'a>),
     #[cfg(wayland_platform)]
-    Wayland(Rc<wayland::WaylandDisplayImpl>, wayland::WaylandImpl, wayland::BufferImpl<'a>),
+    Wayland(Arc<wayland::WaylandDisplayImpl>, wayland::WaylandImpl, wayland::BufferImpl<'a>),
+    #[cfg(kms_platform)]
+    Kms(Arc<kms::KmsDisplayImpl>, kms::KmsImpl, kms::BufferImpl<'a>),
     #[cfg(target_os = "windows")]
     Win32((), win32::Win32Impl, win32::BufferImpl<'a>),
+    // `CoreWindow`s share the `Win32` context, so the `WinRt` context is never constructed.
+    #[cfg(all(target_os = "windows", feature = "winrt"))]
+    #[allow(dead_code)]
+    WinRt((), winrt::WinRtImpl, winrt::BufferImpl<'a>),
     #[cfg(target_os = "macos")]
     CG((), cg::CGImpl, cg::BufferImpl<'a>),
-    #[cfg(target_arch = "wasm32")]
+    #[cfg(target_os = "android")]
+    Android((), android::AndroidImpl, android::BufferImpl<'a>),
+    #[cfg(target_os = "ios")]
+    UIKit((), uikit::UIKitImpl, uikit::BufferImpl<'a>),
+    #[cfg(web_platform)]
     Web(web::WebDisplayImpl, web::WebImpl, web::BufferImpl<'a>),
     #[cfg(target_os = "redox")]
     Orbital((), orbital::OrbitalImpl, orbital::BufferImpl<'a>),
+    // Framebuffers don't need a `Context`, so this context is never constructed.
+    #[cfg(feature = "framebuffer")]
+    #[allow(dead_code)]
+    Framebuffer((), framebuffer::FramebufferImpl, framebuffer::BufferImpl<'a>),
+    // Terminals don't need a `Context` either.
+    #[cfg(feature = "terminal")]
+    #[allow(dead_code)]
+    Terminal((), terminal::TerminalImpl, terminal::BufferImpl<'a>),
 }
 
-impl Context {
+impl<D: HasDisplayHandle> Context<D> {
     /// Creates a new instance of this struct, using the provided display.
     ///
-    /// # Safety
-    ///
-    ///  - Ensure that the provided object is valid for the lifetime of the Context
-    pub unsafe fn new<D: HasRawDisplayHandle>(display: &D) -> Result<Self, SoftBufferError> {
-        unsafe { Self::from_raw(display.raw_display_handle()) }
+    /// The context owns the display, which can be a reference, e.g. to the event loop of the
+    /// windowing library, or a shared pointer like an `Arc`.
+    pub fn new(display: D) -> Result<Self, SoftBufferError> {
+        let raw_display_handle = display.display_handle()?.raw_display_handle();
+        // SAFETY: The display outlives the context and its surfaces, which keep it alive.
+        let context_impl = unsafe { ContextDispatch::new(raw_display_handle)? };
+
+        Ok(Self {
+            context_impl,
+            display: Arc::new(display),
+            _marker: PhantomData,
+        })
     }
+}
 
+impl Context {
     /// Creates a new instance of this struct, using the provided display handles
     ///
     /// # Safety
     ///
     ///  - Ensure that the provided handle is valid for the lifetime of the Context
     pub unsafe fn from_raw(raw_display_handle: RawDisplayHandle) -> Result<Self, SoftBufferError> {
+        Ok(Self {
+            context_impl: unsafe { ContextDispatch::new(raw_display_handle)? },
+            display: Arc::new(NoDisplayHandle(())),
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<D> Context<D> {
+    /// Returns the backend that the context uses, which depends on the display it was created
+    /// for. Its surfaces may still use another backend, see [`Surface::backend`].
+    pub fn backend(&self) -> Backend {
+        self.context_impl.backend()
+    }
+}
+
+impl ContextDispatch {
+    // Without a backend for windows, no display handle is supported.
+    #[cfg_attr(not(window_platform), allow(unreachable_code, unused_variables))]
+    unsafe fn new(raw_display_handle: RawDisplayHandle) -> Result<Self, SoftBufferError> {
+        trace_span!(
+            "create_context",
+            display = display_handle_type_name(&raw_display_handle)
+        );
         let imple: ContextDispatch = match raw_display_handle {
             #[cfg(x11_platform)]
             RawDisplayHandle::Xlib(xlib_handle) => unsafe {
-                ContextDispatch::X11(Rc::new(x11::X11DisplayImpl::from_xlib(xlib_handle)?))
+                ContextDispatch::X11(Arc::new(x11::X11DisplayImpl::from_xlib(xlib_handle)?))
             },
             #[cfg(x11_platform)]
             RawDisplayHandle::Xcb(xcb_handle) => unsafe {
-                ContextDispatch::X11(Rc::new(x11::X11DisplayImpl::from_xcb(xcb_handle)?))
+                ContextDispatch::X11(Arc::new(x11::X11DisplayImpl::from_xcb(xcb_handle)?))
             },
             #[cfg(wayland_platform)]
             RawDisplayHandle::Wayland(wayland_handle) => unsafe {
-                ContextDispatch::Wayland(Rc::new(wayland::WaylandDisplayImpl::new(wayland_handle)?))
+                ContextDispatch::Wayland(Arc::new(wayland::WaylandDisplayImpl::new(
+                    wayland_handle,
+                )?))
+            },
+            #[cfg(kms_platform)]
+            RawDisplayHandle::Drm(drm_handle) => unsafe {
+                ContextDispatch::Kms(Arc::new(kms::KmsDisplayImpl::new(drm_handle)?))
             },
             #[cfg(target_os = "windows")]
             RawDisplayHandle::Windows(_) => ContextDispatch::Win32(()),
             #[cfg(target_os = "macos")]
             RawDisplayHandle::AppKit(_) => ContextDispatch::CG(()),
-            #[cfg(target_arch = "wasm32")]
+            #[cfg(target_os = "ios")]
+            RawDisplayHandle::UiKit(_) => ContextDispatch::UIKit(()),
+            #[cfg(target_os = "android")]
+            RawDisplayHandle::Android(_) => ContextDispatch::Android(()),
+            #[cfg(web_platform)]
             RawDisplayHandle::Web(_) => ContextDispatch::Web(web::WebDisplayImpl::new()?),
             #[cfg(target_os = "redox")]
             RawDisplayHandle::Orbital(_) => ContextDispatch::Orbital(()),
@@ -231,15 +648,12 @@ impl Context {
             }
         };
 
-        Ok(Self {
-            context_impl: imple,
-            _marker: PhantomData,
-        })
+        Ok(imple)
     }
 }
 
 /// A rectangular region of the buffer coordinate space.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Rect {
     /// x coordinate of top left corner
     pub x: u32,
@@ -251,99 +665,1418 @@ pub struct Rect {
     pub height: NonZeroU32,
 }
 
-/// A surface for drawing to a window with software buffers.
-pub struct Surface {
-    /// This is boxed so that `Surface` is the same size on every platform.
-    surface_impl: Box<SurfaceDispatch>,
-    _marker: PhantomData<*mut ()>,
-}
+impl Rect {
+    /// The part of the rect that is inside of `other`, if they overlap.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let range = |start: u32, len: NonZeroU32, other_start: u32, other_len: NonZeroU32| {
+            let end = start.saturating_add(len.get());
+            let other_end = other_start.saturating_add(other_len.get());
+            let start = start.max(other_start);
+            Some((
+                start,
+                NonZeroU32::new(end.min(other_end).checked_sub(start)?)?,
+            ))
+        };
+        let (x, width) = range(self.x, self.width, other.x, other.width)?;
+        let (y, height) = range(self.y, self.height, other.y, other.height)?;
+        Some(Rect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
 
-impl Surface {
-    /// Creates a new surface for the context for the provided window.
-    ///
-    /// # Safety
+    /// The smallest rect that contains both rects, e.g. for merging damage into one rect.
     ///
-    ///  - Ensure that the provided objects are valid to draw a 2D buffer to, and are valid for the
-    ///    lifetime of the Context
-    pub unsafe fn new<W: HasRawWindowHandle>(
-        context: &Context,
-        window: &W,
-    ) -> Result<Self, SoftBufferError> {
-        unsafe { Self::from_raw(context, window.raw_window_handle()) }
+    /// Edges past `u32::MAX` are clamped to it.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let range = |start: u32, len: NonZeroU32, other_start: u32, other_len: NonZeroU32| {
+            let end = start
+                .saturating_add(len.get())
+                .max(other_start.saturating_add(other_len.get()));
+            let start = start.min(other_start);
+            // Both lengths are non-zero, so the start is before the end.
+            (start, NonZeroU32::new(end - start).unwrap())
+        };
+        let (x, width) = range(self.x, self.width, other.x, other.width);
+        let (y, height) = range(self.y, self.height, other.y, other.height);
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
     }
 
-    /// Creates a new surface for the context for the provided raw window handle.
-    ///
-    /// # Safety
+    /// Whether the pixel at `x` and `y` is inside of the rect.
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        let inside =
+            |pos: u32, start: u32, len: NonZeroU32| pos >= start && pos - start < len.get();
+        inside(x, self.x, self.width) && inside(y, self.y, self.height)
+    }
+
+    /// The part of the rect that is inside a buffer of the given size, if any.
+    pub fn clamp(&self, width: NonZeroU32, height: NonZeroU32) -> Option<Rect> {
+        self.intersection(&Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        })
+    }
+
+    /// Iterates over the coordinates of the pixels in the rect, row by row from the top.
     ///
-    ///  - Ensure that the provided handles are valid to draw a 2D buffer to, and are valid for the
-    ///    lifetime of the Context
-    pub unsafe fn from_raw(
-        context: &Context,
-        raw_window_handle: RawWindowHandle,
-    ) -> Result<Self, SoftBufferError> {
-        let imple: SurfaceDispatch = match (&context.context_impl, raw_window_handle) {
+    /// Pixels past `u32::MAX` are skipped.
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> {
+        let (x, width) = (self.x, self.width.get());
+        let rows = self.y..self.y.saturating_add(self.height.get());
+        rows.flat_map(move |y| (x..x.saturating_add(width)).map(move |x| (x, y)))
+    }
+
+    /// Whether the rect is inside a buffer of the given size.
+    fn fits(&self, width: NonZeroU32, height: NonZeroU32) -> bool {
+        let inside = |start: u32, len: NonZeroU32, max: NonZeroU32| {
+            start
+                .checked_add(len.get())
+                .map_or(false, |end| end <= max.get())
+        };
+        inside(self.x, self.width, width) && inside(self.y, self.height, height)
+    }
+}
+
+/// A set of rects, e.g. the damage of a frame that is collected while drawing it.
+///
+/// Keeping a region from frame to frame and [clearing](Region::clear) it after presenting reuses
+/// its memory. Presenting with `&region` damages its rects, see [`Buffer::present_with_damage`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Region {
+    rects: Vec<Rect>,
+}
+
+impl Region {
+    /// Creates an empty region.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rect to the region. Rects that are already inside of the region's rects are
+    /// skipped, and those that are inside of `rect` are removed.
+    pub fn add(&mut self, rect: Rect) {
+        let inside = |rect: &Rect, other: &Rect| rect.intersection(other) == Some(*rect);
+        if self.rects.iter().any(|other| inside(&rect, other)) {
+            return;
+        }
+        self.rects.retain(|other| !inside(other, &rect));
+        self.rects.push(rect);
+    }
+
+    /// Removes all rects from the region, keeping its memory.
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+
+    /// Whether the region has no rects.
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// The rects of the region, which may overlap.
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    /// The smallest rect that contains the region, if it isn't empty.
+    pub fn bounds(&self) -> Option<Rect> {
+        let (first, rest) = self.rects.split_first()?;
+        Some(rest.iter().fold(*first, |bounds, rect| bounds.union(rect)))
+    }
+}
+
+impl Extend<Rect> for Region {
+    fn extend<I: IntoIterator<Item = Rect>>(&mut self, rects: I) {
+        for rect in rects {
+            self.add(rect);
+        }
+    }
+}
+
+impl FromIterator<Rect> for Region {
+    fn from_iter<I: IntoIterator<Item = Rect>>(rects: I) -> Self {
+        let mut region = Self::new();
+        region.extend(rects);
+        region
+    }
+}
+
+impl<'a> IntoIterator for &'a Region {
+    type Item = &'a Rect;
+    type IntoIter = std::slice::Iter<'a, Rect>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.rects.iter()
+    }
+}
+
+/// A rect of the buffer that is copied from elsewhere in it, see [`Buffer::copy_rects`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RectCopy {
+    /// x coordinate of the top left corner of the pixels that are copied
+    pub src_x: u32,
+    /// y coordinate of the top left corner of the pixels that are copied
+    pub src_y: u32,
+    /// Where the pixels are copied to, which is damaged.
+    pub dst: Rect,
+}
+
+impl RectCopy {
+    /// The rect that is copied from.
+    pub fn src(&self) -> Rect {
+        Rect {
+            x: self.src_x,
+            y: self.src_y,
+            ..self.dst
+        }
+    }
+}
+
+/// How the buffer is drawn when its size differs from the size of the window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Scaling {
+    /// Draw the buffer unscaled in the upper-left corner of the window.
+    #[default]
+    TopLeft,
+    /// Draw the buffer unscaled in the center of the window.
+    Center,
+    /// Scale the buffer to fit inside the window, preserving its aspect ratio.
+    AspectFit,
+    /// Stretch the buffer to fill the window.
+    Fill,
+}
+
+/// The filter used when the buffer is scaled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ScalingFilter {
+    /// Interpolate linearly between neighboring pixels.
+    #[default]
+    Linear,
+    /// Use the nearest pixel, keeping hard edges.
+    Nearest,
+}
+
+/// The filter of [`Buffer::downscale_from`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DownscaleFilter {
+    /// Average the pixels that each pixel of the buffer covers, which is exact for whole
+    /// factors, e.g. 2x supersampling.
+    #[default]
+    Box,
+    /// Lanczos with three lobes, which is sharper for other factors, but slower.
+    Lanczos3,
+}
+
+/// The color space that the pixels of the buffer are interpreted in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ColorSpace {
+    /// sRGB, which most content and displays assume.
+    #[default]
+    Srgb,
+    /// Display P3, a wide gamut color space with the sRGB transfer function.
+    DisplayP3,
+    /// sRGB primaries with a linear transfer function.
+    LinearSrgb,
+}
+
+/// What the backend of a [`Surface`] supports, see [`Surface::capabilities`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// Whether [`Buffer::present_with_damage`] only copies the damaged parts of the buffer to the
+    /// window. Otherwise it copies all of it, or nothing if the buffer is shown as it is.
+    pub partial_present: bool,
+    /// Whether the buffer is memory that the window is shown from, so presenting it doesn't copy
+    /// or convert any pixels.
+    pub zero_copy: bool,
+    /// The largest width and height of a buffer that can be presented. [`Surface::resize`]
+    /// returns [`SoftBufferError::SizeOutOfRange`] for larger sizes, so applications can clamp
+    /// the size of their windows to this.
+    ///
+    /// Large buffers can still fail to be allocated, e.g. on Wayland, where the whole buffer has to
+    /// fit in 512 MiB.
+    pub max_size: (u32, u32),
+    /// The number of buffers that [`Surface::buffer_mut`] cycles through, see [`Buffer::index`].
+    pub buffer_count: usize,
+    /// Whether [`Surface::fetch`] reads the contents of the window back from the display server.
+    /// Otherwise it returns a copy of the last buffer presented, without anything else that was
+    /// drawn to the window, or [`SoftBufferError::Unimplemented`] if not even that is kept.
+    pub readback: bool,
+}
+
+/// A platform-specific implementation of softbuffer, see [`Context::backend`] and
+/// [`Surface::backend`].
+///
+/// All of these exist on every platform, but only the ones in [`Backend::compiled`] are used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Backend {
+    /// X11, through XCB. This copies the buffer to the X server unless XShm is available.
+    X11,
+    /// Wayland, with `wl_shm` buffers.
+    Wayland,
+    /// DRM/KMS dumb buffers.
+    Kms,
+    /// Win32, with GDI.
+    Win32,
+    /// DXGI swap chains, for WinRT `CoreWindow`s, and for `HWND`s when GDI isn't used, see
+    /// [`Surface::with_backends`].
+    WinRt,
+    /// macOS, with Core Animation layers.
+    CG,
+    /// Android `ANativeWindow`s.
+    Android,
+    /// iOS, with UIKit views.
+    UIKit,
+    /// Web canvases.
+    Web,
+    /// Redox's Orbital windows.
+    Orbital,
+    /// Memory-mapped framebuffers, see `Surface::from_framebuffer`.
+    Framebuffer,
+    /// Terminals with graphics support, see `Surface::from_terminal`.
+    Terminal,
+}
+
+/// What happened to a buffer passed to [`Buffer::present`] or [`Buffer::present_with_damage`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PresentStatus {
+    /// The buffer was sent to the window.
+    Presented,
+    /// The buffer wasn't presented because the window can't currently be seen, for example
+    /// because it is minimized, or because it is unchanged, see [`Surface::set_skip_unchanged`].
+    ///
+    /// The window keeps showing the last frame presented before.
+    Skipped,
+    /// The buffer wasn't presented because the surface no longer has a window to present to.
+    ///
+    /// Presenting won't succeed until the surface is attached to a new window or recreated.
+    OutOfDate,
+}
+
+/// How much of the window of a surface can be seen, see [`Surface::visibility`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Visibility {
+    /// The window isn't covered by anything, as far as the platform tells.
+    Visible,
+    /// Parts of the window are covered by other windows, or are outside of the screen.
+    PartiallyOccluded,
+    /// None of the window can be seen, for example because it is minimized, on another virtual
+    /// desktop, or in a background tab.
+    Hidden,
+}
+
+/// The platform object that the pixels of a [`Buffer`] are in, see [`Buffer::native_buffer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum NativeBuffer {
+    /// A `wl_buffer` in `wl_shm` memory.
+    Wayland {
+        /// The `wl_buffer`, as a `wl_proxy` of libwayland-client.
+        wl_buffer: NonNull<c_void>,
+    },
+    /// An XShm segment that the pixels are put into the window from.
+    X11Shm {
+        /// The ID of the segment on the X server.
+        shmseg: u32,
+    },
+    /// A DIB section.
+    Win32 {
+        /// The `HBITMAP` of the DIB section.
+        hbitmap: NonNull<c_void>,
+        /// The `HDC` of the memory device context that the DIB section is selected into.
+        hdc: NonNull<c_void>,
+    },
+    /// An `IOSurface`, which is locked for the CPU until the buffer is presented.
+    IOSurface {
+        /// The `IOSurfaceRef`.
+        io_surface: NonNull<c_void>,
+    },
+}
+
+/// Memory allocated outside of softbuffer, with pixels in the format of [`Buffer`], see
+/// [`Surface::present_external`].
+///
+/// Rows are `stride` bytes apart, starting at `offset` bytes into the memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ExternalMemory {
+    /// Shared memory, e.g. from `memfd_create` or `shm_open`.
+    #[cfg(unix)]
+    SharedMemory {
+        /// The file descriptor of the memory, which is only borrowed.
+        fd: RawFd,
+        /// The offset of the first row.
+        offset: u32,
+        /// The distance between rows.
+        stride: u32,
+    },
+    /// A linear dma-buf, e.g. exported by a hardware video decoder.
+    #[cfg(unix)]
+    DmaBuf {
+        /// The file descriptor of the dma-buf, which is only borrowed.
+        fd: RawFd,
+        /// The offset of the first row.
+        offset: u32,
+        /// The distance between rows.
+        stride: u32,
+    },
+    /// A section from `CreateFileMappingW`, which may be shared with other processes. Its rows
+    /// are as long as the buffer.
+    Win32Section {
+        /// The `HANDLE` of the section.
+        section: NonNull<c_void>,
+        /// The offset of the first row, which must be a multiple of 4.
+        offset: u32,
+    },
+    /// An `IOSurface` with 32-bit BGRA pixels, which is the same layout as the format of
+    /// [`Buffer`] with the alpha channel in the unused bits, and its own rows.
+    IOSurface {
+        /// The `IOSurfaceRef`.
+        io_surface: NonNull<c_void>,
+    },
+}
+
+/// A surface for drawing to a window with software buffers.
+///
+/// The surface owns the window it was created for, which can be a reference, or a shared pointer
+/// like an `Arc`, and keeps the display of its [`Context`] alive. Surfaces created from raw
+/// handles, or without a window, have a [`NoWindowHandle`] instead.
+///
+/// # Platform dependent behavior
+///
+/// `Surface` and [`Context`] are `Send`, so rendering can move to another thread, on all
+/// platforms except:
+/// - macOS and iOS, where AppKit and UIKit may only be used from the main thread.
+/// - Web, where JavaScript objects can't be shared between threads. Transfer an
+///   `OffscreenCanvas` to a web worker instead, and create the surface there with
+///   `SurfaceExtWeb::from_offscreen_canvas`.
+/// - Windows with the `winrt` feature, since `CoreWindow`s belong to the thread they were
+///   created on.
+///
+/// On Win32, GDI lets the buffer be drawn to the window from any thread, but the thread of the
+/// window still has to handle `WM_PAINT`, e.g. by calling `ValidateRect` or by presenting
+/// again. Terminal surfaces need a `Send` writer, and framebuffers must be accessible from the
+/// thread the surface is sent to. The window and display must be `Send` as well.
+///
+/// On macOS, surfaces can still be created from raw handles on another thread and presented
+/// there, if the view has a layer. Every change of the layer is committed in its own
+/// `CATransaction`, so presents reach the screen without the run loop of the main thread, but
+/// AppKit can't be asked for the scale factor of the window there. The one from the last call on
+/// the main thread is used instead, so resize with [`Surface::resize_scaled`] when it
+/// changes.
+pub struct Surface<D = NoDisplayHandle, W = NoWindowHandle> {
+    /// This is boxed so that `Surface` is the same size on every platform.
+    ///
+    /// This is declared first, so it is dropped before the window and display it draws to.
+    surface_impl: Box<SurfaceDispatch>,
+    stats: stats::Collector,
+    /// Writes the frames presented, if enabled by the environment.
+    #[cfg(feature = "frame-dump")]
+    dump: Option<dump::Dumper>,
+    /// Whether presents of unchanged buffers are skipped, see [`Surface::set_skip_unchanged`].
+    skip_unchanged: bool,
+    /// The hash of the last buffer presented while skipping unchanged ones.
+    last_hash: Option<u64>,
+    /// See [`Surface::set_present_hook`].
+    present_hook: Option<PresentHook>,
+    /// The view into the buffer, see [`Surface::set_view_size`].
+    view: view::View,
+    /// The shape last set on the window, see [`Surface::set_shape_from_alpha`].
+    shape: Option<Vec<Rect>>,
+    /// Reused for the damage of presents, so it isn't allocated for every frame.
+    damage: Vec<Rect>,
+    /// The damage of the last presents, see [`Surface::damage_since`].
+    history: history::History,
+    /// The last frame and its width, which the next buffer is filled with after a resize, see
+    /// [`Surface::set_smooth_resize`]. It is empty if only [`Surface::set_resize_fill`] is set.
+    resized_from: Option<(Vec<u32>, usize)>,
+    /// Whether the buffers were freed with [`Surface::release_buffers`], and have to be
+    /// allocated again before they are used.
+    buffers_released: bool,
+    /// Whether calls are validated, see [`Surface::set_strict`].
+    strict: bool,
+    config: Config,
+    /// The backends to try, see [`Surface::with_backends`].
+    backends: Option<Vec<Backend>>,
+    /// The window the surface draws to, see [`Surface::recreate`].
+    window: W,
+    /// The display of the context, if the surface was created with one.
+    display: Option<Arc<D>>,
+    _marker: ThreadMarker,
+}
+
+type PresentHook = Box<dyn FnMut(&PresentInfo<'_>) + Send>;
+
+/// The configuration of a surface, which is applied again when its window is replaced.
+#[derive(Clone, Copy, Default)]
+struct Config {
+    size: Option<(NonZeroU32, NonZeroU32)>,
+    scaling: Option<(Scaling, ScalingFilter)>,
+    background_color: Option<u32>,
+    timeout: Option<Duration>,
+    color_space: Option<ColorSpace>,
+    single_buffered: bool,
+    flip_vertical: bool,
+    smooth_resize: bool,
+    /// The color set with [`Surface::set_resize_fill`].
+    resize_fill: Option<u32>,
+    shape_threshold: u8,
+    /// The scale factor set with [`Surface::resize_scaled`].
+    scale_factor: Option<f64>,
+}
+
+impl Config {
+    fn apply(&self, surface_impl: &mut SurfaceDispatch) -> Result<(), SoftBufferError> {
+        if self.single_buffered {
+            surface_impl.set_single_buffered(true)?;
+        }
+        if let Some(color_space) = self.color_space {
+            surface_impl.set_color_space(color_space)?;
+        }
+        if let Some((scaling, filter)) = self.scaling {
+            surface_impl.set_scaling(scaling, filter)?;
+        }
+        if let Some(color) = self.background_color {
+            surface_impl.set_background_color(color)?;
+        }
+        if self.timeout.is_some() {
+            surface_impl.set_timeout(self.timeout)?;
+        }
+        if self.flip_vertical {
+            surface_impl.set_flip_vertical(true)?;
+        }
+        if self.smooth_resize {
+            surface_impl.set_smooth_resize(true)?;
+        }
+        // The shape is set by the next present.
+        if self.shape_threshold != 0 {
+            surface_impl.set_shape(None)?;
+        }
+        if let Some(scale_factor) = self.scale_factor {
+            surface_impl.set_scale_factor(scale_factor)?;
+        }
+        // Last, so the buffers are only allocated once.
+        if let Some((width, height)) = self.size {
+            surface_impl.resize(width, height)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D, W: HasWindowHandle> Surface<D, W> {
+    /// Creates a new surface for the context for the provided window.
+    ///
+    /// The surface owns the window, which can be a reference, or a shared pointer like an `Arc`.
+    pub fn new(context: &Context<D>, window: W) -> Result<Self, SoftBufferError> {
+        let raw_window_handle = window.window_handle()?.raw_window_handle();
+        // SAFETY: The surface owns the window, and drops it after the platform-specific objects.
+        let imple =
+            unsafe { SurfaceDispatch::new(&context.context_impl, raw_window_handle, None)? };
+
+        Ok(Self::from_impl(
+            imple,
+            window,
+            Some(context.display.clone()),
+        ))
+    }
+
+    /// Creates a new surface like [`Surface::new`], with the first of `backends` that draws to the
+    /// window.
+    ///
+    /// Backends that can't draw to this kind of window are skipped, and if a backend fails, the
+    /// next one is tried. [`Surface::backend`] returns the one that was created. The backends are
+    /// tried again in this order by [`Surface::set_window`] and [`Surface::recreate`]. Without a
+    /// list, the backends are tried in the order of [`Backend::compiled`].
+    ///
+    /// An `HWND` is drawn to with [`Backend::Win32`], or [`Backend::WinRt`] with the `winrt`
+    /// feature. The other kinds of windows have one backend each. A window on Wayland can't be
+    /// drawn to through XWayland, since its handle doesn't have an X11 window; create the window
+    /// through X11 instead.
+    ///
+    /// If no backend was created, this returns the error of the last backend tried, or
+    /// [`SoftBufferError::UnsupportedWindowPlatform`] if none can draw to the window.
+    pub fn with_backends(
+        context: &Context<D>,
+        window: W,
+        backends: &[Backend],
+    ) -> Result<Self, SoftBufferError> {
+        let raw_window_handle = window.window_handle()?.raw_window_handle();
+        // SAFETY: The surface owns the window, and drops it after the platform-specific objects.
+        let imple = unsafe {
+            SurfaceDispatch::new(&context.context_impl, raw_window_handle, Some(backends))?
+        };
+
+        let mut surface = Self::from_impl(imple, window, Some(context.display.clone()));
+        surface.backends = Some(backends.to_vec());
+        Ok(surface)
+    }
+
+    /// Attaches the surface to another window, keeping the size and other settings of the
+    /// surface. This recreates the platform-specific objects of the surface, e.g. after a window
+    /// was reparented on X11 or recreated on Android. The next buffer has an age of `0`.
+    ///
+    /// Settings of the platform extension traits, like `SurfaceExtWeb`, are not kept. On
+    /// Android, `SurfaceExtAndroid::set_native_window` attaches a new window without recreating
+    /// the surface.
+    ///
+    /// If this fails, the surface stays attached to the previous window, and `window` is dropped.
+    pub fn set_window(&mut self, context: &Context<D>, window: W) -> Result<(), SoftBufferError> {
+        let raw_window_handle = window.window_handle()?.raw_window_handle();
+        // SAFETY: The previous window is only dropped after the objects drawing to it.
+        unsafe { self.replace_impl(context, raw_window_handle)? };
+        self.window = window;
+        Ok(())
+    }
+
+    /// Recreates the platform-specific objects of the surface for its window, keeping its
+    /// settings like [`Surface::set_window`]. This can recover a surface whose presents return
+    /// [`PresentStatus::OutOfDate`] while the window is still there.
+    ///
+    /// On Android, this uses the window the surface was created with, not one attached with
+    /// `SurfaceExtAndroid::set_native_window`.
+    pub fn recreate(&mut self, context: &Context<D>) -> Result<(), SoftBufferError> {
+        let raw_window_handle = self.window.window_handle()?.raw_window_handle();
+        // SAFETY: The surface owns the window.
+        unsafe { self.replace_impl(context, raw_window_handle) }
+    }
+}
+
+impl<D> Surface<D> {
+    /// Creates a new surface for the context for the provided raw window handle.
+    ///
+    /// # Safety
+    ///
+    ///  - Ensure that the provided handles are valid to draw a 2D buffer to, and are valid for the
+    ///    lifetime of the Surface
+    pub unsafe fn from_raw(
+        context: &Context<D>,
+        raw_window_handle: RawWindowHandle,
+    ) -> Result<Self, SoftBufferError> {
+        let imple =
+            unsafe { SurfaceDispatch::new(&context.context_impl, raw_window_handle, None)? };
+
+        Ok(Self::from_impl(
+            imple,
+            NoWindowHandle(()),
+            Some(context.display.clone()),
+        ))
+    }
+
+    /// Attaches the surface to the provided raw window handle, see [`Surface::set_window`].
+    ///
+    /// Calling this with the handle the surface already draws to recreates it, like
+    /// [`Surface::recreate`].
+    ///
+    /// # Safety
+    ///
+    ///  - Ensure that the provided handles are valid to draw a 2D buffer to, and are valid for the
+    ///    lifetime of the Surface
+    pub unsafe fn set_raw_window(
+        &mut self,
+        context: &Context<D>,
+        raw_window_handle: RawWindowHandle,
+    ) -> Result<(), SoftBufferError> {
+        unsafe { self.replace_impl(context, raw_window_handle) }
+    }
+}
+
+impl SurfaceDispatch {
+    /// Create the first of `backends` that draws to the window, see [`Surface::with_backends`].
+    unsafe fn new(
+        context_impl: &ContextDispatch,
+        raw_window_handle: RawWindowHandle,
+        backends: Option<&[Backend]>,
+    ) -> Result<Self, SoftBufferError> {
+        trace_span!(
+            "create_surface",
+            window = window_handle_type_name(&raw_window_handle)
+        );
+        let mut error = None;
+        for &backend in backends.unwrap_or(Backend::compiled()) {
+            match unsafe { Self::with_backend(context_impl, raw_window_handle, backend) } {
+                Ok(Some(imple)) => return Ok(imple),
+                Ok(None) => {}
+                Err(err) => {
+                    log::debug!("Failed to create a surface with {:?}: {}", backend, err);
+                    error = Some(err);
+                }
+            }
+        }
+
+        Err(
+            error.unwrap_or_else(|| SoftBufferError::UnsupportedWindowPlatform {
+                human_readable_window_platform_name: window_handle_type_name(&raw_window_handle),
+                human_readable_display_platform_name: context_impl.variant_name(),
+                window_handle: raw_window_handle,
+            }),
+        )
+    }
+
+    /// Create the surface of `backend`, or `None` if it doesn't draw to this kind of window.
+    // Without a backend for windows, no window handle is supported.
+    #[cfg_attr(not(window_platform), allow(unreachable_code, unused_variables))]
+    unsafe fn with_backend(
+        context_impl: &ContextDispatch,
+        raw_window_handle: RawWindowHandle,
+        backend: Backend,
+    ) -> Result<Option<Self>, SoftBufferError> {
+        let imple: SurfaceDispatch = match (backend, context_impl, raw_window_handle) {
             #[cfg(x11_platform)]
             (
+                Backend::X11,
                 ContextDispatch::X11(xcb_display_handle),
                 RawWindowHandle::Xlib(xlib_window_handle),
             ) => SurfaceDispatch::X11(unsafe {
                 x11::X11Impl::from_xlib(xlib_window_handle, xcb_display_handle.clone())?
             }),
             #[cfg(x11_platform)]
-            (ContextDispatch::X11(xcb_display_handle), RawWindowHandle::Xcb(xcb_window_handle)) => {
-                SurfaceDispatch::X11(unsafe {
-                    x11::X11Impl::from_xcb(xcb_window_handle, xcb_display_handle.clone())?
-                })
-            }
+            (
+                Backend::X11,
+                ContextDispatch::X11(xcb_display_handle),
+                RawWindowHandle::Xcb(xcb_window_handle),
+            ) => SurfaceDispatch::X11(unsafe {
+                x11::X11Impl::from_xcb(xcb_window_handle, xcb_display_handle.clone())?
+            }),
             #[cfg(wayland_platform)]
             (
+                Backend::Wayland,
                 ContextDispatch::Wayland(wayland_display_impl),
                 RawWindowHandle::Wayland(wayland_window_handle),
             ) => SurfaceDispatch::Wayland(unsafe {
                 wayland::WaylandImpl::new(wayland_window_handle, wayland_display_impl.clone())?
             }),
+            #[cfg(kms_platform)]
+            (
+                Backend::Kms,
+                ContextDispatch::Kms(kms_display_impl),
+                RawWindowHandle::Drm(drm_window_handle),
+            ) => SurfaceDispatch::Kms(unsafe {
+                kms::KmsImpl::new(drm_window_handle, kms_display_impl.clone())?
+            }),
             #[cfg(target_os = "windows")]
-            (ContextDispatch::Win32(()), RawWindowHandle::Win32(win32_handle)) => {
+            (Backend::Win32, ContextDispatch::Win32(()), RawWindowHandle::Win32(win32_handle)) => {
                 SurfaceDispatch::Win32(unsafe { win32::Win32Impl::new(&win32_handle)? })
             }
+            #[cfg(all(target_os = "windows", feature = "winrt"))]
+            (Backend::WinRt, ContextDispatch::Win32(()), RawWindowHandle::WinRt(winrt_handle)) => {
+                SurfaceDispatch::WinRt(unsafe { winrt::WinRtImpl::new(&winrt_handle)? })
+            }
+            #[cfg(all(target_os = "windows", feature = "winrt"))]
+            (Backend::WinRt, ContextDispatch::Win32(()), RawWindowHandle::Win32(win32_handle)) => {
+                SurfaceDispatch::WinRt(unsafe { winrt::WinRtImpl::from_hwnd(&win32_handle)? })
+            }
             #[cfg(target_os = "macos")]
-            (ContextDispatch::CG(()), RawWindowHandle::AppKit(appkit_handle)) => {
+            (Backend::CG, ContextDispatch::CG(()), RawWindowHandle::AppKit(appkit_handle)) => {
                 SurfaceDispatch::CG(unsafe { cg::CGImpl::new(appkit_handle)? })
             }
-            #[cfg(target_arch = "wasm32")]
-            (ContextDispatch::Web(context), RawWindowHandle::Web(web_handle)) => {
+            #[cfg(target_os = "ios")]
+            (Backend::UIKit, ContextDispatch::UIKit(()), RawWindowHandle::UiKit(uikit_handle)) => {
+                SurfaceDispatch::UIKit(unsafe { uikit::UIKitImpl::new(uikit_handle)? })
+            }
+            #[cfg(target_os = "android")]
+            (
+                Backend::Android,
+                ContextDispatch::Android(()),
+                RawWindowHandle::AndroidNdk(android_handle),
+            ) => SurfaceDispatch::Android(unsafe { android::AndroidImpl::new(android_handle)? }),
+            #[cfg(web_platform)]
+            (Backend::Web, ContextDispatch::Web(context), RawWindowHandle::Web(web_handle)) => {
                 SurfaceDispatch::Web(web::WebImpl::new(context, web_handle)?)
             }
             #[cfg(target_os = "redox")]
-            (ContextDispatch::Orbital(()), RawWindowHandle::Orbital(orbital_handle)) => {
-                SurfaceDispatch::Orbital(orbital::OrbitalImpl::new(orbital_handle)?)
+            (
+                Backend::Orbital,
+                ContextDispatch::Orbital(()),
+                RawWindowHandle::Orbital(orbital_handle),
+            ) => SurfaceDispatch::Orbital(orbital::OrbitalImpl::new(orbital_handle)?),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(imple))
+    }
+
+    /// Copy between the windows of two surfaces of the same backend, without the client.
+    fn copy_from(
+        &mut self,
+        other: &SurfaceDispatch,
+        src: Rect,
+        dst: (u32, u32),
+    ) -> Result<(), SoftBufferError> {
+        #[allow(unreachable_patterns)]
+        match (self, other) {
+            #[cfg(x11_platform)]
+            (SurfaceDispatch::X11(imp), SurfaceDispatch::X11(other)) => {
+                imp.copy_from(other, src, dst)
             }
-            (unsupported_display_impl, unimplemented_window_handle) => {
-                return Err(SoftBufferError::UnsupportedWindowPlatform {
-                    human_readable_window_platform_name: window_handle_type_name(
-                        &unimplemented_window_handle,
-                    ),
-                    human_readable_display_platform_name: unsupported_display_impl.variant_name(),
-                    window_handle: unimplemented_window_handle,
-                })
+            #[cfg(target_os = "windows")]
+            (SurfaceDispatch::Win32(imp), SurfaceDispatch::Win32(other)) => {
+                imp.copy_from(other, src, dst)
             }
-        };
+            #[cfg(feature = "framebuffer")]
+            (SurfaceDispatch::Framebuffer(imp), SurfaceDispatch::Framebuffer(other)) => {
+                imp.copy_from(other, src, dst)
+            }
+            _ => {
+                let _ = (src, dst);
+                Err(SoftBufferError::Unimplemented)
+            }
+        }
+    }
+}
 
-        Ok(Self {
-            surface_impl: Box::new(imple),
+impl Surface {
+    /// Creates a new surface that presents into a framebuffer in memory, e.g. the one of the
+    /// UEFI Graphics Output Protocol.
+    ///
+    /// The buffer is converted into the format of the framebuffer while it is presented, and
+    /// only the damaged regions are copied.
+    ///
+    /// # Safety
+    ///
+    ///  - Ensure that `framebuffer.ptr` is valid for reads and writes of `framebuffer.stride`
+    ///    times `framebuffer.height` bytes for the lifetime of the Surface
+    #[cfg(feature = "framebuffer")]
+    pub unsafe fn from_framebuffer(framebuffer: RawFramebuffer) -> Result<Self, SoftBufferError> {
+        trace_span!("create_surface", window = "Framebuffer");
+        let imple = SurfaceDispatch::Framebuffer(unsafe {
+            framebuffer::FramebufferImpl::new(framebuffer)?
+        });
+
+        Ok(Self::from_impl(imple, NoWindowHandle(()), None))
+    }
+
+    /// Creates a new surface that draws into a terminal with graphics support, by writing escape
+    /// sequences to `output`, e.g. [`std::io::stdout`].
+    ///
+    /// The buffer is drawn from the top left cell of the terminal. `cell_width` and
+    /// `cell_height` are the size of a character cell in pixels: damage is rounded out to whole
+    /// cells, and only those are sent again.
+    #[cfg(feature = "terminal")]
+    pub fn from_terminal<W: std::io::Write + Send + 'static>(
+        output: W,
+        protocol: TerminalProtocol,
+        cell_width: NonZeroU32,
+        cell_height: NonZeroU32,
+    ) -> Result<Self, SoftBufferError> {
+        trace_span!("create_surface", window = "Terminal");
+        let imple = SurfaceDispatch::Terminal(terminal::TerminalImpl::new(
+            Box::new(output),
+            protocol,
+            cell_width,
+            cell_height,
+        ));
+
+        Ok(Self::from_impl(imple, NoWindowHandle(()), None))
+    }
+}
+
+impl<D, W> Surface<D, W> {
+    fn from_impl(surface_impl: SurfaceDispatch, window: W, display: Option<Arc<D>>) -> Self {
+        Self {
+            surface_impl: Box::new(surface_impl),
+            stats: stats::Collector::default(),
+            #[cfg(feature = "frame-dump")]
+            dump: dump::Dumper::from_env(),
+            skip_unchanged: false,
+            last_hash: None,
+            present_hook: None,
+            view: view::View::default(),
+            shape: None,
+            damage: Vec::new(),
+            history: history::History::default(),
+            resized_from: None,
+            buffers_released: false,
+            strict: false,
+            config: Config::default(),
+            backends: None,
+            window,
+            display,
             _marker: PhantomData,
-        })
+        }
+    }
+
+    /// Replaces the platform-specific objects of the surface with ones for the provided handle.
+    ///
+    /// # Safety
+    ///
+    ///  - Ensure that the provided handle is valid to draw a 2D buffer to, and is valid for the
+    ///    lifetime of the Surface
+    unsafe fn replace_impl(
+        &mut self,
+        context: &Context<D>,
+        raw_window_handle: RawWindowHandle,
+    ) -> Result<(), SoftBufferError> {
+        let mut imple = unsafe {
+            SurfaceDispatch::new(
+                &context.context_impl,
+                raw_window_handle,
+                self.backends.as_deref(),
+            )?
+        };
+        self.config.apply(&mut imple)?;
+
+        *self.surface_impl = imple;
+        self.display = Some(context.display.clone());
+        self.last_hash = None;
+        self.view.shown = None;
+        self.shape = None;
+        self.buffers_released = false;
+        Ok(())
+    }
+
+    /// Returns the window the surface draws to.
+    pub fn window(&self) -> &W {
+        &self.window
     }
 
     /// Set the size of the buffer that will be returned by [`Surface::buffer_mut`].
     ///
     /// If the size of the buffer does not match the size of the window, the buffer is drawn
-    /// in the upper-left corner of the window. It is recommended in most production use cases
+    /// in the upper-left corner of the window, unless configured otherwise with
+    /// [`Surface::set_scaling`]. It is recommended in most production use cases
     /// to have the buffer fill the entire window. Use your windowing library to find the size
     /// of the window.
+    ///
+    /// The buffers aren't cleared when their size changes, so the next buffer has an age of `0`
+    /// and unspecified contents, e.g. zeros or pixels of earlier frames.
+    ///
+    /// Sizes larger than [`Capabilities::max_size`] are rejected with
+    /// [`SoftBufferError::SizeOutOfRange`].
     pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
-        self.surface_impl.resize(width, height)
+        trace_span!(
+            "resize",
+            backend = self.surface_impl.variant_name(),
+            width = width.get(),
+            height = height.get()
+        );
+        let (max_width, max_height) = self.surface_impl.capabilities().max_size;
+        if width.get() > max_width || height.get() > max_height {
+            return Err(SoftBufferError::SizeOutOfRange { width, height });
+        }
+
+        if self.config.size != Some((width, height)) {
+            if self.config.smooth_resize && self.resized_from.is_none() {
+                self.resized_from = Some(self.last_frame()?.unwrap_or_default());
+            } else if self.config.resize_fill.is_some() && self.resized_from.is_none() {
+                self.resized_from = Some(Default::default());
+            }
+            self.history.clear();
+        }
+        self.last_hash = None;
+        self.view.shown = None;
+        self.surface_impl.resize(width, height)?;
+        self.config.size = Some((width, height));
+        self.buffers_released = false;
+        Ok(())
+    }
+
+    /// Resizes the buffer to the physical size of a window of the given logical size, at the
+    /// given scale factor of the display, e.g. the window's `inner_size` and `scale_factor` in
+    /// `winit`, and returns the physical size.
+    ///
+    /// The physical size is the logical size multiplied by the scale factor, rounded to whole
+    /// pixels. Where the platform draws buffers in logical units, the scale factor is also set
+    /// on the window, so the buffer is shown at the native resolution of the display.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On Wayland, this sets the buffer scale of the `wl_surface`, which has to be a whole
+    ///   number. Fractional scale factors are unimplemented.
+    /// - On macOS and iOS, this sets the `contentsScale` of the layer, instead of taking the
+    ///   scale of the screen the window is on.
+    /// - On Web, this sets the CSS size of the canvas to the logical size.
+    /// - On other platforms, buffers are drawn in physical pixels already, so this only resizes
+    ///   the buffer.
+    ///
+    /// # Panics
+    ///
+    /// If `scale_factor` isn't positive and finite.
+    pub fn resize_scaled(
+        &mut self,
+        logical_size: (f64, f64),
+        scale_factor: f64,
+    ) -> Result<(NonZeroU32, NonZeroU32), SoftBufferError> {
+        assert!(
+            scale_factor.is_finite() && scale_factor > 0.0,
+            "`scale_factor` must be positive and finite"
+        );
+        // Casts saturate, and the window is at least a pixel large.
+        let physical = |logical: f64| {
+            NonZeroU32::new(((logical * scale_factor).round() as u32).max(1)).unwrap()
+        };
+        let (width, height) = (physical(logical_size.0), physical(logical_size.1));
+
+        self.surface_impl.set_scale_factor(scale_factor)?;
+        self.config.scale_factor = Some(scale_factor);
+        self.resize(width, height)?;
+        Ok((width, height))
+    }
+
+    /// Frees the memory of the buffers, e.g. while the window is minimized or otherwise hidden,
+    /// see [`Surface::visibility`]. The next [`Surface::buffer_mut`] allocates them again, with
+    /// an age of `0`.
+    ///
+    /// This reduces the memory used by apps with many windows, at the cost of a full redraw
+    /// when the window is shown again. With the `winit` feature, `Surface::handle_winit_event`
+    /// does this when the window is occluded.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On Win32, X11 and Web, the window keeps showing the last frame.
+    /// - On Wayland, buffers that the compositor hasn't released yet are kept, as it may still
+    ///   read them.
+    /// - With the framebuffer and terminal backends, the copy of the pixels in memory is freed.
+    /// - On other platforms, this is unimplemented.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        trace_span!(
+            "release_buffers",
+            backend = self.surface_impl.variant_name()
+        );
+        self.surface_impl.release_buffers()?;
+        self.buffers_released = true;
+        Ok(())
+    }
+
+    /// Allocate the buffers again, if they were released with [`Surface::release_buffers`].
+    fn restore_buffers(&mut self) -> Result<(), SoftBufferError> {
+        if self.buffers_released {
+            if let Some((width, height)) = self.config.size {
+                self.surface_impl.resize(width, height)?;
+            }
+            self.buffers_released = false;
+        }
+        Ok(())
+    }
+
+    /// Returns the current size of the window in physical pixels.
+    ///
+    /// Passing this to [`Surface::resize`] makes the buffer fill the window at the native
+    /// resolution of the display, e.g. on HiDPI screens where the window's logical size is smaller.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On macOS, this is the size of the layer multiplied by the window's `backingScaleFactor`.
+    /// - On iOS, this is the size of the view multiplied by the `scale` of its screen.
+    /// - On DRM/KMS, this is the size of the mode of the CRTC.
+    /// - On Web, this is the CSS size of the canvas multiplied by `window.devicePixelRatio`. The
+    ///   canvas needs a size in CSS, otherwise it is laid out at the size of the buffer, which
+    ///   grows with every resize. This is unimplemented for an `OffscreenCanvas`.
+    /// - On Android and Wayland, this is unimplemented.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        self.surface_impl.window_size()
+    }
+
+    /// Returns the size of the buffer set with [`Surface::resize`], or `None` if it wasn't set
+    /// yet.
+    pub fn size(&self) -> Option<(NonZeroU32, NonZeroU32)> {
+        self.config.size
+    }
+
+    /// Returns whether the window was resized to another size than the buffer, so the surface
+    /// should be resized before drawing the next frame.
+    ///
+    /// This compares [`Surface::size`] with [`Surface::window_size`], so it returns the errors
+    /// of the latter on platforms that don't know the size of the window, and
+    /// [`SoftBufferError::SurfaceNotConfigured`] if the size wasn't set yet. Windows with a size
+    /// of zero, e.g. minimized ones on Win32, aren't stale.
+    pub fn is_size_stale(&self) -> Result<bool, SoftBufferError> {
+        let size = self
+            .config
+            .size
+            .ok_or(SoftBufferError::SurfaceNotConfigured)?;
+        Ok(self
+            .nonzero_window_size()?
+            .map_or(false, |window| window != size))
+    }
+
+    /// The size of the window, unless it is zero.
+    fn nonzero_window_size(&self) -> Result<Option<(NonZeroU32, NonZeroU32)>, SoftBufferError> {
+        let (width, height) = self.surface_impl.window_size()?;
+        Ok(NonZeroU32::new(width).zip(NonZeroU32::new(height)))
+    }
+
+    /// Returns how much of the window can be seen, so that rendering can be paused while it is
+    /// hidden.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On Win32, minimized windows and windows cloaked by DWM, e.g. on another virtual desktop,
+    ///   are hidden. Without desktop composition, the window is partially occluded if it is
+    ///   clipped by other windows.
+    /// - On X11, unmapped windows are hidden, and the window is occluded by the top-level windows
+    ///   stacked above it. Windows above that are covered themselves, or translucent, still count.
+    /// - On Wayland, the compositor doesn't tell, so the window is hidden when it didn't ask for
+    ///   a new frame for a second after a present. It is never partially occluded.
+    /// - On Web, the canvas is hidden if the page isn't visible, e.g. in a background tab, and
+    ///   hidden or partially occluded if it is scrolled out of the viewport. Other elements on top
+    ///   of it aren't taken into account. This is unimplemented for an `OffscreenCanvas`.
+    /// - On other platforms, this is unimplemented.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        self.surface_impl.visibility()
+    }
+
+    /// Set how the buffer is drawn when its size differs from the size of the window, for
+    /// example during a live resize. The default is [`Scaling::TopLeft`].
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On macOS and iOS, this sets the `contentsGravity` and the magnification and
+    ///   minification filters of the layer.
+    /// - On X11, Windows and framebuffers, [`Scaling::Center`] is implemented too. On X11 and
+    ///   Windows, the size of the window is queried by every present then.
+    /// - On other platforms, only [`Scaling::TopLeft`] is implemented.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        self.surface_impl.set_scaling(scaling, filter)?;
+        self.config.scaling = Some((scaling, filter));
+        Ok(())
+    }
+
+    /// Set the color that the parts of the window outside of the buffer are filled with, when
+    /// it is drawn unscaled with [`Scaling::TopLeft`] or [`Scaling::Center`]. The color is in
+    /// the format of the pixels of the buffer, `0x00RRGGBB`.
+    ///
+    /// By default, these parts of the window aren't drawn to, and show whatever the platform
+    /// leaves there.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On X11 and Windows, the parts are filled by every present, and the size of the window
+    ///   is queried for it.
+    /// - On framebuffers, the parts are filled when the buffer is first presented at another
+    ///   position or size.
+    /// - On macOS and iOS, this sets the `backgroundColor` of the layer.
+    /// - On other platforms, this is unimplemented.
+    pub fn set_background_color(&mut self, color: u32) -> Result<(), SoftBufferError> {
+        self.surface_impl.set_background_color(color)?;
+        self.config.background_color = Some(color);
+        Ok(())
+    }
+
+    /// Set how long calls wait for the display server before returning
+    /// [`SoftBufferError::Timeout`], or `None` to wait as long as the platform does, which is the
+    /// default. This keeps a frozen compositor from hanging the thread forever.
+    ///
+    /// After a timeout, the surface can be used again, e.g. to retry on the next frame.
+    ///
+    /// # Platform dependent behavior
+    ///
+    /// - On Wayland, [`Surface::buffer_mut`] waits for the compositor to release the back
+    ///   buffer, which it may never do.
+    /// - On DRM/KMS, presents wait for the previous page flip, for a second by default.
+    /// - On Win32, macOS, iOS, Web, Orbital and the framebuffer backend, nothing waits for the
+    ///   display server for long, so this has no effect.
+    /// - On X11, Android, WinRT and terminals, this returns [`SoftBufferError::Unimplemented`],
+    ///   unless `timeout` is `None`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        self.surface_impl.set_timeout(timeout)?;
+        self.config.timeout = timeout;
+        Ok(())
+    }
+
+    /// Set the color space that the pixels of the buffer are in. The default is
+    /// [`ColorSpace::Srgb`].
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On macOS, the `IOSurface`s are tagged with the color space, so the window server converts
+    ///   the colors for the display instead of interpreting them as device RGB.
+    /// - On iOS, the images set as the contents of the layer are created in the color space.
+    /// - On other platforms, only [`ColorSpace::Srgb`] is implemented.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        self.surface_impl.set_color_space(color_space)?;
+        self.config.color_space = Some(color_space);
+        Ok(())
+    }
+
+    /// Only keep one buffer for the surface, apart from the memory the window is shown from,
+    /// trading tear-free presentation for memory. This is off by default.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On Wayland, the buffer attached to the surface is drawn into while the compositor may
+    ///   still read from it, so frames can tear. [`Surface::buffer_mut`] doesn't wait for the
+    ///   compositor to release it.
+    /// - On DRM/KMS, the pixels are copied into the dumb buffer on screen, instead of flipping
+    ///   between two, so frames can tear.
+    /// - On Android, this is unimplemented while hardware buffers are presented, which are
+    ///   pooled. Windows attached afterwards are locked instead. The one buffer is converted into
+    ///   the buffers of the window, which it owns.
+    /// - On macOS, iOS and WinRT, this is unimplemented, since the window server keeps reading
+    ///   the contents of the layer or swap chain after a present.
+    /// - On other platforms there is only one buffer already, so this costs nothing.
+    pub fn set_single_buffered(&mut self, single: bool) -> Result<(), SoftBufferError> {
+        self.surface_impl.set_single_buffered(single)?;
+        self.config.single_buffered = single;
+        Ok(())
+    }
+
+    /// Show the rows of the buffer bottom-up, so the first row is at the bottom of the window,
+    /// for pixels from producers like BMP decoders or GPU readbacks. This is off by default.
+    ///
+    /// Damage rects are still in the coordinates of the buffer, so a rect at the top of the
+    /// buffer damages the bottom of the window. The window keeps what was shown before, so the
+    /// next present after changing this should damage the whole buffer. [`Surface::fetch`]
+    /// returns the rows as they are shown, top-down.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On Win32, the bitmap is made bottom-up, which costs nothing.
+    /// - On Wayland, the buffer transform of the surface is set, which costs nothing unless the
+    ///   compositor can't scan out transformed buffers. This needs version 2 of `wl_surface`.
+    /// - On X11 with XShm, each row is drawn with its own request.
+    /// - On X11 without XShm, DRM/KMS, Web, and the framebuffer backend, the rows are flipped
+    ///   while they are copied.
+    /// - On other platforms, this is unimplemented.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        self.surface_impl.set_flip_vertical(flip)?;
+        self.config.flip_vertical = flip;
+        Ok(())
+    }
+
+    /// Make interactive resizes of the window look like those of native applications, without
+    /// flashes of cleared or uninitialized parts of the window before the next present. This
+    /// is off by default.
+    ///
+    /// The window keeps showing what it showed in its upper-left corner until the next present,
+    /// and the first buffer after [`Surface::resize`] is filled with the last frame, in its
+    /// upper-left corner, and around it with black, or the color set with
+    /// [`Surface::set_resize_fill`]. Its age is still `0`, but drawing and damaging the parts
+    /// that changed is enough, so the present can follow each resize right away, e.g. in the
+    /// handler of the resize event of the window. The last frame is taken with
+    /// [`Surface::fetch`], which costs a copy of it for each resize, but doesn't wait for the
+    /// display server to release a buffer. Errors of it, like [`SoftBufferError::WindowDestroyed`],
+    /// are returned by [`Surface::resize`]. Where it is unimplemented, on iOS and for WinRT
+    /// windows, all of the buffer is filled like the parts around the last frame.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On X11, the bit gravity of the window is set to `NorthWest`, so the X server keeps its
+    ///   contents instead of clearing all of it.
+    /// - On Web, the canvas is resized by the next present instead of by [`Surface::resize`],
+    ///   since resizing clears it.
+    /// - On Windows, the window procedure erases the window with the background brush of its
+    ///   class, unless the class has none, or `WM_ERASEBKGND` is handled.
+    /// - On Wayland, the compositor shows the last buffer until the next present, which should
+    ///   follow acknowledging the configure event of the resize.
+    /// - On other platforms, the window keeps its contents already, so only the buffer is
+    ///   filled.
+    pub fn set_smooth_resize(&mut self, smooth: bool) -> Result<(), SoftBufferError> {
+        self.surface_impl.set_smooth_resize(smooth)?;
+        self.config.smooth_resize = smooth;
+        if !smooth {
+            self.resized_from = match self.config.resize_fill {
+                Some(_) => self.resized_from.take().map(|_| Default::default()),
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// The last frame presented and its width, if it is still known, for filling the buffer
+    /// with after a resize.
+    ///
+    /// This only reads back what backends keep of the last present, without mapping a buffer,
+    /// which may wait for the display server.
+    fn last_frame(&mut self) -> Result<Option<(Vec<u32>, usize)>, SoftBufferError> {
+        let (width, height) = match self.config.size {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        if self.buffers_released || !self.stats.has_presented() {
+            return Ok(None);
+        }
+        let len = width.get() as usize * height.get() as usize;
+        match self.surface_impl.fetch() {
+            Ok(pixels) if pixels.len() == len => Ok(Some((pixels, width.get() as usize))),
+            Ok(_) | Err(SoftBufferError::Unimplemented) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Shape the window from the alpha channel of the buffer, the highest 8 bits of its
+    /// pixels, so only the pixels whose alpha is at least `threshold` are part of the window,
+    /// e.g. for splash screens and widgets that aren't rectangular. A `threshold` of `0` makes
+    /// the window rectangular again, which is the default.
+    ///
+    /// The shape is found at each present, which reads the whole buffer, and only sent to the
+    /// window when it changes. It is in the coordinates of the buffer, so it matches the pixels
+    /// while the buffer is drawn unscaled in the upper-left corner, without a view or flipping.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On Win32, the region of the window is set with `SetWindowRgn`.
+    /// - On X11, the bounding shape of the window is set, which needs the Shape extension.
+    /// - On Wayland, the input and opaque regions of the surface are set, so clicks outside of
+    ///   the shape go to what is below the window. The pixels outside of it are still drawn,
+    ///   as the buffers have no alpha channel.
+    /// - On other platforms, this is unimplemented.
+    pub fn set_shape_from_alpha(&mut self, threshold: u8) -> Result<(), SoftBufferError> {
+        if threshold == 0 {
+            self.shape = None;
+        }
+        // The last shape is kept until the next present finds the new one.
+        self.surface_impl.set_shape(self.shape.as_deref())?;
+        self.config.shape_threshold = threshold;
+        Ok(())
+    }
+
+    /// Fill the first buffer after a resize with `color`, or leave its contents unspecified if
+    /// `None`, which is the default. This is what the parts of the window that a larger buffer
+    /// exposes show until the application draws them, e.g. the background color of the
+    /// application instead of black or garbage.
+    ///
+    /// With [`Surface::set_smooth_resize`], only the parts of the buffer around the last frame
+    /// are filled, otherwise all of it is. The color is a pixel of the buffer, so with
+    /// [`Surface::set_shape_from_alpha`] it can also be transparent, e.g. `0`.
+    pub fn set_resize_fill(&mut self, color: Option<u32>) {
+        self.config.resize_fill = color;
+        if color.is_none() && !self.config.smooth_resize {
+            self.resized_from = None;
+        }
+    }
+
+    /// Skip presenting buffers that are the same as the last one presented, which saves power for
+    /// applications that present on a timer while nothing changes. This is off by default.
+    ///
+    /// Each present hashes the whole buffer to find out, and returns [`PresentStatus::Skipped`]
+    /// for skipped buffers. Calling this forgets the last buffer, so the next one is presented
+    /// even if it didn't change. That is needed when the window lost its contents, e.g. when
+    /// handling an `Expose` event on X11 or `WM_PAINT` on Windows.
+    pub fn set_skip_unchanged(&mut self, skip: bool) {
+        self.skip_unchanged = skip;
+        self.last_hash = None;
+        self.view.shown = None;
+    }
+
+    /// Sets a function that is called after each frame presented to this surface, e.g. to
+    /// advance animations by the time between frames, or to record the damage of each frame,
+    /// without changing every call that presents.
+    ///
+    /// This replaces the previous hook. It isn't called for presents that return
+    /// [`PresentStatus::Skipped`], or that fail.
+    pub fn set_present_hook(&mut self, hook: impl FnMut(&PresentInfo<'_>) + Send + 'static) {
+        self.present_hook = Some(Box::new(hook));
+    }
+
+    /// Validate how the surface is used, and return errors for uses that otherwise draw
+    /// differently on each platform, or garbage. This is off by default, and meant for debug
+    /// builds, e.g. with `surface.set_strict(cfg!(debug_assertions))`.
+    ///
+    /// Damage outside of the buffer is always an error. With strict validation:
+    ///
+    /// - [`Surface::buffer_mut`] returns [`SoftBufferError::SurfaceNotConfigured`] instead of
+    ///   resizing the surface to the window, if [`Surface::resize`] wasn't called yet.
+    /// - [`Surface::buffer_mut`] queries the size of the window, so it returns
+    ///   [`SoftBufferError::WindowDestroyed`] if the window was destroyed on every platform
+    ///   that can find out, and [`SoftBufferError::SizeMismatch`] if the window was resized
+    ///   since [`Surface::resize`]. Buffers of another size are fine once how they are drawn
+    ///   was set with [`Surface::set_scaling`], [`Surface::set_background_color`] or
+    ///   [`Surface::set_view_size`].
+    /// - [`Buffer::present_with_damage`] returns [`SoftBufferError::IncompleteDamage`] for a
+    ///   buffer with an [`Buffer::age`] of `0`, unless the damage covers all of it.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Add what the backend found out about its presented frames to the statistics.
+    fn take_feedback(&mut self) {
+        let feedback = self.surface_impl.take_feedback();
+        #[cfg(feature = "metrics")]
+        telemetry::feedback(self.surface_impl.variant_name(), &feedback);
+        self.stats.feedback(feedback);
+    }
+
+    /// The checks of [`Surface::buffer_mut`] with [`Surface::set_strict`].
+    fn validate(&self) -> Result<(), SoftBufferError> {
+        let size = self
+            .config
+            .size
+            .ok_or(SoftBufferError::SurfaceNotConfigured)?;
+        let window = match self.nonzero_window_size() {
+            Ok(window) => window,
+            Err(SoftBufferError::WindowDestroyed) => return Err(SoftBufferError::WindowDestroyed),
+            // The size of the window isn't known on every platform.
+            Err(_) => None,
+        };
+        let drawn_as_set = self.config.scaling.is_some()
+            || self.config.background_color.is_some()
+            || self.view.size.is_some();
+        match window {
+            Some(window) if window != size && !drawn_as_set => Err(SoftBufferError::SizeMismatch {
+                buffer: size,
+                window,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make the surface virtual, with a buffer larger than the window, of which a view of
+    /// `size` is shown in the upper-left corner of the window, e.g. for panning over a map or a
+    /// document without rendering it again. `None` shows the buffer as usual again.
+    ///
+    /// The buffer keeps the size set with [`Surface::resize`], and the view is moved with
+    /// [`Surface::set_pan`]. [`Buffer::present`] then presents the view like
+    /// [`Buffer::present_viewport`], and [`Buffer::present_with_damage`] only copies the damage
+    /// inside of the view, unless it moved since the last present, which changes all of the
+    /// window. With the `winit` feature, `Surface::handle_winit_event` resizes the view
+    /// instead of the buffer.
+    ///
+    /// This needs [`Buffer::present_viewport`], so presents return
+    /// [`SoftBufferError::Unimplemented`] on platforms that don't support it.
+    pub fn set_view_size(&mut self, size: Option<(NonZeroU32, NonZeroU32)>) {
+        self.view.size = size;
+        self.view.shown = None;
+    }
+
+    /// Move the view of a virtual surface, see [`Surface::set_view_size`], so that its
+    /// upper-left corner is at `(x, y)` of the buffer. The view is kept inside the buffer, so
+    /// it stops at the edges.
+    pub fn set_pan(&mut self, x: u32, y: u32) {
+        self.view.pan = (x, y);
+    }
+
+    /// Returns the upper-left corner of the view of a virtual surface in the buffer, as set
+    /// with [`Surface::set_pan`], and kept inside the buffer.
+    pub fn pan(&self) -> (u32, u32) {
+        self.config
+            .size
+            .and_then(|size| self.view.viewport(size))
+            .map_or(self.view.pan, |viewport| (viewport.x, viewport.y))
+    }
+
+    /// Returns the backend that draws to the window of this surface.
+    ///
+    /// Applications can log this, or adjust what they draw on backends that copy the buffer,
+    /// e.g. X11 without XShm.
+    pub fn backend(&self) -> Backend {
+        self.surface_impl.backend()
+    }
+
+    /// Returns what the backend of this surface supports.
+    ///
+    /// This may change when the surface is resized, or attached to another window.
+    pub fn capabilities(&self) -> Capabilities {
+        self.surface_impl.capabilities()
     }
 
     /// Copies the window contents into a buffer.
@@ -351,22 +2084,312 @@ impl Surface {
     /// ## Platform Dependent Behavior
     ///
     /// - On X11, the window must be visible.
-    /// - On macOS, Redox and Wayland, this function is unimplemented.
-    /// - On Web, this will fail if the content was supplied by
-    ///   a different origin depending on the sites CORS rules.
+    /// - On Android, macOS, Wayland and terminals, the window can't be read back, so this returns
+    ///   a copy of the last buffer presented, see [`Capabilities::readback`].
+    /// - On iOS and with the `winrt` feature, this function is unimplemented.
+    /// - On Web, this reads the canvas back with `getImageData`, or `readPixels` with the `webgl`
+    ///   feature. This will fail if the content was supplied by a different origin depending on
+    ///   the sites CORS rules.
+    ///
+    /// This returns [`SoftBufferError::SurfaceNotConfigured`] if the size wasn't set with
+    /// [`Surface::resize`] yet, or if the buffers were released with
+    /// [`Surface::release_buffers`] and not mapped again with [`Surface::buffer_mut`] since,
+    /// as there may be no copy of the last buffer left.
     pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        if self.config.size.is_none() || self.buffers_released {
+            return Err(SoftBufferError::SurfaceNotConfigured);
+        }
+        trace_span!("fetch", backend = self.surface_impl.variant_name());
         self.surface_impl.fetch()
     }
 
-    /// Return a [`Buffer`] that the next frame should be rendered into. The size must
-    /// be set with [`Surface::resize`] first. The initial contents of the buffer may be zeroed, or
-    /// may contain a previous frame. Call [`Buffer::age`] to determine this.
-    pub fn buffer_mut(&mut self) -> Result<Buffer, SoftBufferError> {
+    /// Returns `true` if the display server is done reading the buffer that the next call to
+    /// [`Surface::buffer_mut`] will return.
+    ///
+    /// This never blocks, so it can be polled to avoid [`Surface::buffer_mut`] waiting on the
+    /// display server, or to implement a custom swap strategy.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On Wayland, this is `false` until the compositor sends `wl_buffer.release` for the back
+    ///   buffer.
+    /// - On DRM/KMS, this is `false` until the last page flip completed.
+    /// - On Web, this is `false` while a frame waits for the next animation frame, see
+    ///   `SurfaceExtWeb::set_sync_to_animation_frame()`.
+    /// - On other platforms, this always returns `true`. With the X11 SHM extension,
+    ///   [`Surface::buffer_mut`] may still wait for the X server to finish processing the last
+    ///   image.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        let released = self.surface_impl.buffer_released()?;
+        self.take_feedback();
+        Ok(released)
+    }
+
+    /// Block until the next vertical blank of the display that the window is on.
+    ///
+    /// Calling this once per frame before [`Surface::buffer_mut`] paces rendering to the refresh
+    /// rate of the display, instead of relying on a timer that drifts from it.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On Android, this waits for the next `AChoreographer` frame callback, polling the `ALooper`
+    ///   of the calling thread. It gives up after 100ms.
+    /// - On macOS, this waits for the next `CVDisplayLink` callback. It gives up after 100ms, for
+    ///   example while the display is asleep.
+    /// - On DRM/KMS, this waits for the pending page flip, giving up after 100ms, or for the next
+    ///   vertical blank of the CRTC.
+    /// - On Web, the main thread can't block, so this is unimplemented. Use
+    ///   `SurfaceExtWeb::set_sync_to_animation_frame()` instead.
+    /// - On other platforms, this is unimplemented.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        trace_span!(
+            "wait_for_vblank",
+            backend = self.surface_impl.variant_name()
+        );
+        self.surface_impl.wait_for_vblank()
+    }
+
+    /// Returns the refresh interval of the display that the window is on, the time between two
+    /// of its vertical blanks, e.g. about 16.7ms at 60 Hz.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On X11, this is the mode of the RandR CRTC that shows the center of the window.
+    /// - On Wayland, the output of the surface isn't known, so this fails unless all outputs
+    ///   have the same refresh rate.
+    /// - On Android, this is measured by waiting for two `AChoreographer` frames, like
+    ///   [`Surface::wait_for_vblank`].
+    /// - On iOS, this is the highest rate of the screen, which ProMotion screens lower while the
+    ///   content doesn't change.
+    /// - On Web, WinRT, Orbital, framebuffers and terminals, this is unimplemented.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        self.surface_impl.refresh_rate()
+    }
+
+    /// Return a [`Buffer`] that the next frame should be rendered into. The initial contents of
+    /// the buffer may be zeroed, or may contain a previous frame. Call [`Buffer::age`] to
+    /// determine this.
+    ///
+    /// The size should be set with [`Surface::resize`] first. Otherwise the surface is resized
+    /// to [`Surface::window_size`], or this returns [`SoftBufferError::SurfaceNotConfigured`]
+    /// where the size of the window is unknown or zero.
+    pub fn buffer_mut(&mut self) -> Result<Buffer<'_>, SoftBufferError> {
+        trace_span!("buffer_mut", backend = self.surface_impl.variant_name());
+        if self.strict {
+            self.validate()?;
+        }
+        if self.config.size.is_none() {
+            let window_size = self
+                .surface_impl
+                .window_size()
+                .ok()
+                .and_then(|(width, height)| {
+                    Some((NonZeroU32::new(width)?, NonZeroU32::new(height)?))
+                });
+            let (width, height) = window_size.ok_or(SoftBufferError::SurfaceNotConfigured)?;
+            self.resize(width, height)?;
+        }
+        self.restore_buffers()?;
+
+        self.take_feedback();
+        let started = stats::Timestamp::now();
+        let mut buffer_impl = self.surface_impl.buffer_mut()?;
+        self.stats.mapped(started);
+        if let (Some((old, old_width)), Some((width, _))) =
+            (self.resized_from.take(), self.config.size)
+        {
+            util::fill_resized(
+                buffer_impl.pixels_mut(),
+                width.get() as usize,
+                &old,
+                old_width,
+                self.config.resize_fill.unwrap_or(0),
+            );
+        }
+        // Forget the copies of a buffer that was dropped without presenting it.
+        self.damage.clear();
         Ok(Buffer {
-            buffer_impl: self.surface_impl.buffer_mut()?,
+            buffer_impl,
+            stats: &mut self.stats,
+            #[cfg(feature = "frame-dump")]
+            dump: &mut self.dump,
+            last_hash: if self.skip_unchanged {
+                Some(&mut self.last_hash)
+            } else {
+                None
+            },
+            present_hook: self.present_hook.as_mut(),
+            view: &mut self.view,
+            shape: match self.config.shape_threshold {
+                0 => None,
+                threshold => Some((threshold, &mut self.shape)),
+            },
+            damage: Some(&mut self.damage),
+            history: &mut self.history,
+            size: self.config.size,
+            strict: self.strict,
             _marker: PhantomData,
         })
     }
+
+    /// The damage of the presents since a buffer of the given [`Buffer::age`] was presented,
+    /// which is what such a buffer is missing, like `EGL_KHR_partial_update`.
+    ///
+    /// Together with what changed since the last frame, this is what has to be drawn into a
+    /// buffer of that age. Presents without damage damage all of the buffer. Returns `None` if
+    /// all of it has to be drawn, because the age is `0`, or the buffer is older than the
+    /// presents that are remembered. The age of the buffer of [`Surface::buffer_mut`] is at most
+    /// [`Capabilities::buffer_count`], so its presents are always remembered.
+    ///
+    /// This is also available as [`Buffer::missing_damage`], while the buffer borrows the
+    /// surface.
+    pub fn damage_since(&self, age: u8) -> Option<Region> {
+        self.history.since(age)
+    }
+
+    /// Copies `pixels` of a buffer of the given size into the buffer of the surface, and
+    /// presents it with `damage`, resizing the surface to the size of `pixels` first.
+    ///
+    /// This mirrors a buffer that was rendered once onto several surfaces, e.g. a main window
+    /// and a preview, or windows on two monitors, by calling it for each of them. The pixels
+    /// are in the format of [`Buffer`], and each surface draws them with its own
+    /// [`Surface::set_scaling`]. Only the damage is copied if the buffer of the surface has the
+    /// last frame, see [`Buffer::age`].
+    ///
+    /// # Panics
+    ///
+    /// If `pixels` isn't `width * height` long.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::DamageOutOfRange`] without presenting anything if a rect
+    /// doesn't fit inside the buffer.
+    pub fn present_pixels(
+        &mut self,
+        pixels: &[u32],
+        width: NonZeroU32,
+        height: NonZeroU32,
+        damage: &[Rect],
+    ) -> Result<PresentStatus, SoftBufferError> {
+        assert_eq!(
+            pixels.len(),
+            width.get() as usize * height.get() as usize,
+            "`pixels` must have `width * height` pixels"
+        );
+        if let Some(rect) = damage.iter().find(|rect| !rect.fits(width, height)) {
+            return Err(SoftBufferError::DamageOutOfRange { rect: *rect });
+        }
+        if self.config.size != Some((width, height)) {
+            self.resize(width, height)?;
+        }
+
+        let mut buffer = self.buffer_mut()?;
+        let stride = width.get() as usize;
+        if buffer.age() == 1 {
+            for rect in damage {
+                let (x, right) = (rect.x as usize, (rect.x + rect.width.get()) as usize);
+                for y in rect.y as usize..(rect.y + rect.height.get()) as usize {
+                    let row = y * stride;
+                    buffer[row + x..row + right].copy_from_slice(&pixels[row + x..row + right]);
+                }
+            }
+        } else {
+            buffer.copy_from_slice(pixels);
+        }
+        buffer.present_with_damage(damage)
+    }
+
+    /// Copies `src` of what the window of `other` shows to `dst` of the window of this surface,
+    /// e.g. for picture-in-picture, without the pixels going through the client. The window of
+    /// this surface then shows something else than the last present, but its buffer isn't
+    /// changed.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// - On X11, the X server copies the pixels with `CopyArea`, which needs both windows to
+    ///   have the same depth and to be on the same X server.
+    /// - On Win32, GDI copies the pixels with `BitBlt`.
+    /// - Between framebuffers, the pixels are converted into the format of this one, and
+    ///   clipped to both of them.
+    /// - Other platforms, and surfaces of different backends, return
+    ///   [`SoftBufferError::Unimplemented`]. Use [`Surface::fetch`] and
+    ///   [`Surface::present_pixels`] instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::DamageOutOfRange`] if `src` doesn't fit inside the buffer of
+    /// `other`.
+    pub fn copy_from<D2, W2>(
+        &mut self,
+        other: &Surface<D2, W2>,
+        src: Rect,
+        dst: (u32, u32),
+    ) -> Result<(), SoftBufferError> {
+        trace_span!("copy_from", backend = self.surface_impl.variant_name());
+        if let Some((width, height)) = other.config.size {
+            if !src.fits(width, height) {
+                return Err(SoftBufferError::DamageOutOfRange { rect: src });
+            }
+        }
+        self.surface_impl.copy_from(&other.surface_impl, src, dst)?;
+        // The window doesn't show the last buffer presented anymore.
+        self.last_hash = None;
+        self.view.shown = None;
+        Ok(())
+    }
+
+    /// Presents a frame of the given size from memory allocated outside of softbuffer, e.g. by
+    /// another process or a hardware decoder, without copying it on the CPU.
+    ///
+    /// The frame is shown like a buffer of its size, and the buffer of the surface isn't
+    /// changed, nor its size. The next present of the buffer should be a full one, as the
+    /// window shows something else than the last one.
+    ///
+    /// # Platform dependent behavior
+    ///
+    /// - Wayland attaches `ExternalMemory::SharedMemory` as a `wl_shm` buffer.
+    /// - X puts `ExternalMemory::SharedMemory` into the window through an XShm segment, which
+    ///   needs MIT-SHM 1.2.
+    /// - DRM/KMS flips to `ExternalMemory::DmaBuf` as a framebuffer.
+    /// - Win32 draws [`ExternalMemory::Win32Section`] with GDI.
+    /// - macOS sets [`ExternalMemory::IOSurface`] as the contents of the layer, without the
+    ///   `metal` feature.
+    /// - Other platforms, other kinds of memory, and X without MIT-SHM 1.2 return
+    ///   [`SoftBufferError::Unimplemented`].
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid, hold `height` rows of `width` pixels, and not be written to
+    /// while it is shown. X and Win32 are done reading the memory when this returns. Wayland,
+    /// DRM/KMS and macOS read it until another frame is shown, so it may only be changed or
+    /// freed after the next present on this surface, and [`Surface::wait_for_vblank`] after
+    /// that.
+    pub unsafe fn present_external(
+        &mut self,
+        memory: ExternalMemory,
+        width: NonZeroU32,
+        height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        trace_span!(
+            "present_external",
+            backend = self.surface_impl.variant_name()
+        );
+        let status = unsafe { self.surface_impl.present_external(memory, width, height)? };
+        // The window doesn't show the last buffer presented anymore.
+        self.last_hash = None;
+        self.view.shown = None;
+        Ok(status)
+    }
+
+    /// Returns statistics about the time softbuffer spent on the last frames presented to this
+    /// surface, for finding out how much it costs, and on some backends about how long frames
+    /// took to be shown, and how many never were.
+    ///
+    /// What the display server reports about shown frames is collected by
+    /// [`Surface::buffer_mut`] and [`Surface::buffer_released`].
+    pub fn frame_stats(&self) -> FrameStats {
+        self.stats.stats()
+    }
 }
 
 /// A buffer that can be written to by the CPU and presented to the window.
@@ -397,16 +2420,36 @@ impl Surface {
 /// B: Blue channel
 ///
 /// # Platform dependent behavior
-/// No-copy presentation is currently supported on:
+/// No-copy presentation is currently supported on the following platforms, and
+/// [`Surface::capabilities`] tells whether it is used:
 /// - Wayland
 /// - X, when XShm is available
 /// - Win32
 /// - Orbital, when buffer size matches window size
+/// - macOS, when the rows of the `IOSurface` don't need padding
+///
 /// Currently [`Buffer::present`] must block copying image data on:
+/// - Android, which converts the pixels into the format of the window
 /// - Web
-/// - macOS
+/// - WinRT, which uploads the pixels into the swap chain
 pub struct Buffer<'a> {
     buffer_impl: BufferDispatch<'a>,
+    stats: &'a mut stats::Collector,
+    #[cfg(feature = "frame-dump")]
+    dump: &'a mut Option<dump::Dumper>,
+    /// The hash of the last buffer presented, if unchanged ones are skipped.
+    last_hash: Option<&'a mut Option<u64>>,
+    present_hook: Option<&'a mut PresentHook>,
+    view: &'a mut view::View,
+    /// The alpha threshold of the shape of the window, and the shape last set, if shaped.
+    shape: Option<(u8, &'a mut Option<Vec<Rect>>)>,
+    /// The memory for the damage of the present, which is taken while presenting.
+    damage: Option<&'a mut Vec<Rect>>,
+    history: &'a mut history::History,
+    /// The size set with [`Surface::resize`], if any.
+    size: Option<(NonZeroU32, NonZeroU32)>,
+    /// Whether presents are validated, see [`Surface::set_strict`].
+    strict: bool,
     _marker: PhantomData<*mut ()>,
 }
 
@@ -417,38 +2460,405 @@ impl<'a> Buffer<'a> {
     /// buffer that has unspecified contents.
     ///
     /// This can be used to update only a portion of the buffer.
+    ///
+    /// ## Platform Dependent Behavior
+    ///
+    /// The age is `0` after the surface was resized or its buffers were released, and:
+    /// - On Wayland, and on macOS if the buffer is drawn into an `IOSurface` directly, there are
+    ///   two buffers, so the age is `2` once both were presented.
+    /// - On iOS, the buffer is handed over to an image when presenting, so it is always `0`.
+    /// - On all other platforms, the pixels are kept after being copied to the window, so it is
+    ///   `1` after the first present.
     pub fn age(&self) -> u8 {
         self.buffer_impl.age()
     }
 
+    /// The damage that this buffer is missing, see [`Surface::damage_since`].
+    pub fn missing_damage(&self) -> Option<Region> {
+        self.history.since(self.age())
+    }
+
+    /// Returns which of the [`Capabilities::buffer_count`] buffers of the surface this is, like
+    /// the image index of a swap chain.
+    ///
+    /// Together with [`Buffer::age`], this lets applications keep their own state for each
+    /// buffer, e.g. the damage that it is missing, or fences of GPU work still writing to it.
+    /// [`Surface::buffer_released`] tells whether the next buffer can be acquired without
+    /// blocking. On Wayland, the surface has two buffers unless it is single buffered. Other
+    /// platforms copy the pixels out of the buffer, so they only have one.
+    pub fn index(&self) -> usize {
+        self.buffer_impl.index()
+    }
+
+    /// The platform object that the pixels of the buffer are in, so other platform drawing
+    /// code can draw into the same buffer, e.g. GDI text on Win32.
+    ///
+    /// This returns `None` where the pixels are copied into the window when presenting, and
+    /// thus aren't in an object of their own, which is the case on X without XShm, DRM/KMS,
+    /// Android, iOS, Orbital, Web, WinRT, the framebuffer and terminal backends, and on macOS
+    /// with the `metal` feature or when the rows of the `IOSurface` need padding.
+    ///
+    /// # Safety
+    ///
+    /// The handle may only be used until the buffer is presented or dropped, and its pixels
+    /// may only be accessed while no reference from [`Deref`](ops::Deref) or
+    /// [`DerefMut`](ops::DerefMut) on the buffer is alive. Drawing into it must be finished
+    /// before presenting, e.g. with `GdiFlush` on Win32. The object must not be destroyed,
+    /// resized or attached to a surface, and no other state of it may be changed.
+    pub unsafe fn native_buffer(&self) -> Option<NativeBuffer> {
+        self.buffer_impl.native_buffer()
+    }
+
     /// Presents buffer to the window.
     ///
+    /// The returned [`PresentStatus`] tells whether the buffer was actually shown.
+    ///
     /// # Platform dependent behavior
     ///
     /// ## Wayland
     ///
     /// On Wayland, calling this function may send requests to the underlying `wl_surface`. The
-    /// graphics context may issue `wl_surface.attach`, `wl_surface.damage`, `wl_surface.damage_buffer`
-    /// and `wl_surface.commit` requests when presenting the buffer.
+    /// graphics context may issue `wl_surface.attach`, `wl_surface.damage`, `wl_surface.damage_buffer`,
+    /// `wl_surface.set_opaque_region` and `wl_surface.commit` requests when presenting the buffer.
     ///
     /// If the caller wishes to synchronize other surface/window changes, such requests must be sent to the
     /// Wayland compositor before calling this function.
-    pub fn present(self) -> Result<(), SoftBufferError> {
-        self.buffer_impl.present()
+    ///
+    /// ## Android
+    ///
+    /// While the surface has no window, e.g. after
+    /// `SurfaceExtAndroid::release_native_window` was called for a suspended app, this returns
+    /// [`PresentStatus::OutOfDate`].
+    ///
+    /// ## iOS
+    ///
+    /// While the application is in the background, this does nothing and returns
+    /// [`PresentStatus::Skipped`].
+    ///
+    /// ## Win32
+    ///
+    /// While the window is minimized, this returns [`PresentStatus::Skipped`].
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        if let Some(viewport) = self.viewport() {
+            return self.present_viewport(viewport);
+        }
+        self.present_inner(None, None, |buffer_impl| buffer_impl.present())
     }
 
     /// Presents buffer to the window, with damage regions.
     ///
+    /// The damage is anything that iterates over rects or references to them, e.g. `[rect]`, a
+    /// slice, a [`Region`] by reference, or an iterator generating the rects of dirty tiles.
+    /// They are collected into memory that the surface reuses for every frame.
+    ///
     /// # Platform dependent behavior
     ///
-    /// Supported on:
-    /// - Wayland
-    /// - X, when XShm is available
+    /// Only the damaged parts of the buffer are copied on:
+    /// - Android, unless the display is rotated or hardware buffers are presented
+    /// - DRM/KMS
+    /// - Orbital, when buffer size doesn't match window size
+    /// - Web
     /// - Win32
+    /// - WinRT
+    /// - X
+    /// - The framebuffer and terminal backends
+    ///
+    /// Wayland and X, when XShm is available, present the buffer without copying, and pass the
+    /// damage on to the display server. On macOS and iOS, the window server always redraws the
+    /// whole layer, so this is equivalent to [`Self::present`]. [`Surface::capabilities`] tells
+    /// whether the damage is used.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::DamageOutOfRange`] without presenting anything if a rect
+    /// doesn't fit inside the buffer, on every platform. With [`Surface::set_strict`], returns
+    /// [`SoftBufferError::IncompleteDamage`] if the [`Self::age`] is `0` and the damage
+    /// doesn't cover all of the buffer, or of the view of a virtual surface.
+    pub fn present_with_damage<R: Borrow<Rect>>(
+        mut self,
+        damage: impl IntoIterator<Item = R>,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        let mut store = self.damage.take();
+        let mut rects = match &mut store {
+            Some(store) => mem::take(*store),
+            None => Vec::new(),
+        };
+        // Keep the destinations of `copy_rects()`.
+        rects.extend(damage.into_iter().map(|rect| *rect.borrow()));
+        let status = self.present_damage(&rects);
+        if let Some(store) = store {
+            rects.clear();
+            *store = rects;
+        }
+        status
+    }
+
+    /// Copies rects of the buffer to other positions in it, e.g. to scroll or to move a sprite
+    /// without drawing the moved pixels again.
+    ///
+    /// The copies are made right away and in order, so their rects may overlap, and the pixels
+    /// that scroll in can be drawn afterwards. Only the rows of the copies are touched. Their
+    /// destinations are damaged, so the next [`Self::present_with_damage`] presents them along
+    /// with its own damage.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::DamageOutOfRange`] without copying anything if either rect of
+    /// a copy doesn't fit inside the buffer.
+    pub fn copy_rects(&mut self, copies: &[RectCopy]) -> Result<(), SoftBufferError> {
+        if let Some((width, height)) = self.size {
+            let rects = copies.iter().flat_map(|copy| [copy.src(), copy.dst]);
+            if let Some(rect) = rects.into_iter().find(|rect| !rect.fits(width, height)) {
+                return Err(SoftBufferError::DamageOutOfRange { rect });
+            }
+            let pixels = self.buffer_impl.pixels_mut();
+            for copy in copies {
+                util::copy_rect(
+                    pixels,
+                    width.get() as usize,
+                    (copy.src_x, copy.src_y),
+                    &copy.dst,
+                );
+            }
+        }
+        if let Some(damage) = &mut self.damage {
+            damage.extend(copies.iter().map(|copy| copy.dst));
+        }
+        Ok(())
+    }
+
+    /// Fills `rect` of the buffer with copies of `tile`, which has rows of `tile_width` pixels,
+    /// e.g. for a background pattern, or the checkerboard behind transparent images.
+    ///
+    /// The tiles are lined up with the upper-left corner of the buffer, so a pattern continues
+    /// seamlessly across fills of neighboring rects. Only the first rows of a tile are put
+    /// together pixel by pixel, the rest of the rect is copied from them.
+    ///
+    /// # Panics
+    ///
+    /// If `tile` is empty, or isn't a whole number of rows long.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::DamageOutOfRange`] without filling anything if `rect` doesn't
+    /// fit inside the buffer.
+    pub fn fill_tiled(
+        &mut self,
+        rect: Rect,
+        tile: &[u32],
+        tile_width: NonZeroU32,
+    ) -> Result<(), SoftBufferError> {
+        let tile_width = tile_width.get() as usize;
+        assert!(
+            !tile.is_empty() && tile.len() % tile_width == 0,
+            "`tile` must be a whole number of rows long"
+        );
+        if let Some((width, height)) = self.size {
+            if !rect.fits(width, height) {
+                return Err(SoftBufferError::DamageOutOfRange { rect });
+            }
+            trace_span!("fill_tiled");
+            let pixels = self.buffer_impl.pixels_mut();
+            util::fill_tiled(pixels, width.get() as usize, &rect, tile, tile_width);
+        }
+        Ok(())
+    }
+
+    /// Fills the buffer with `pixels` of an image of the given size, scaled to the size of the
+    /// buffer with `filter`, e.g. to downscale a frame rendered at a higher resolution for
+    /// supersampling.
+    ///
+    /// The pixels are averaged in linear light, contrary to the filters of the platforms. These
+    /// average the sRGB values, which makes thin bright lines, like those of text on a dark
+    /// background, too dark. Images smaller than the buffer are upscaled with the same filter.
+    ///
+    /// # Panics
+    ///
+    /// If `pixels` isn't `width * height` long.
+    pub fn downscale_from(
+        &mut self,
+        pixels: &[u32],
+        width: NonZeroU32,
+        height: NonZeroU32,
+        filter: DownscaleFilter,
+    ) {
+        assert_eq!(
+            pixels.len(),
+            width.get() as usize * height.get() as usize,
+            "`pixels` must have `width * height` pixels"
+        );
+        if let Some((buffer_width, _)) = self.size {
+            trace_span!("downscale");
+            resample::resample(
+                pixels,
+                width.get() as usize,
+                self.buffer_impl.pixels_mut(),
+                buffer_width.get() as usize,
+                filter,
+            );
+        }
+    }
+
+    /// Presents with the collected `damage`, see [`Self::present_with_damage`].
+    fn present_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        if let Some((width, height)) = self.size {
+            if let Some(rect) = damage.iter().find(|rect| !rect.fits(width, height)) {
+                return Err(SoftBufferError::DamageOutOfRange { rect: *rect });
+            }
+            if self.strict && self.age() == 0 {
+                // Only the view of a virtual surface is shown.
+                let shown = self.viewport().unwrap_or(Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                });
+                let damage: Vec<Rect> = damage
+                    .iter()
+                    .filter_map(|rect| rect.intersection(&shown))
+                    .map(|rect| Rect {
+                        x: rect.x - shown.x,
+                        y: rect.y - shown.y,
+                        ..rect
+                    })
+                    .collect();
+                if !util::covers(&damage, shown.width.get(), shown.height.get()) {
+                    return Err(SoftBufferError::IncompleteDamage);
+                }
+            }
+        }
+        if let Some(viewport) = self.viewport() {
+            let damage = self.view.damage(viewport, damage);
+            return self.present_inner(Some(viewport), Some(&damage), |buffer_impl| {
+                buffer_impl.present_viewport(&damage, viewport)
+            });
+        }
+        self.present_inner(None, Some(damage), |buffer_impl| {
+            buffer_impl.present_with_damage(damage)
+        })
+    }
+
+    /// Presents the `viewport` part of the buffer in the upper-left corner of the window, so
+    /// the buffer can be larger than the window, e.g. to pan over a pre-rendered map without
+    /// rendering it again for every frame.
+    ///
+    /// Like [`Self::present`], this shows all of `viewport`, and nothing else of the buffer.
+    /// Presenting with damage afterwards shows the buffer in the upper-left corner again, so
+    /// the next present should be a full one. With [`Surface::set_flip_vertical`], the rows of
+    /// the viewport are shown bottom-up.
+    ///
+    /// # Platform dependent behavior
     ///
-    /// Otherwise this is equivalent to [`Self::present`].
-    pub fn present_with_damage(self, damage: &[Rect]) -> Result<(), SoftBufferError> {
-        self.buffer_impl.present_with_damage(damage)
+    /// This is implemented on DRM/KMS, Win32, X, and the framebuffer backend, which only copy
+    /// the viewport. Other platforms return [`SoftBufferError::Unimplemented`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::DamageOutOfRange`] without presenting anything if `viewport`
+    /// doesn't fit inside the buffer.
+    pub fn present_viewport(self, viewport: Rect) -> Result<PresentStatus, SoftBufferError> {
+        if let Some((width, height)) = self.size {
+            if !viewport.fits(width, height) {
+                return Err(SoftBufferError::DamageOutOfRange { rect: viewport });
+            }
+        }
+        self.present_inner(Some(viewport), None, |buffer_impl| {
+            buffer_impl.present_viewport(&[viewport], viewport)
+        })
+    }
+
+    /// The viewport of a virtual surface, see [`Surface::set_view_size`].
+    fn viewport(&self) -> Option<Rect> {
+        self.view.viewport(self.size?)
+    }
+
+    /// Present with `present`, unless the buffer is unchanged, and `viewport` is the part of
+    /// the buffer that is shown, if not all of it. `damage` is the part of it that changed, if
+    /// not all of it.
+    fn present_inner(
+        mut self,
+        viewport: Option<Rect>,
+        damage: Option<&[Rect]>,
+        present: impl FnOnce(BufferDispatch<'a>) -> Result<PresentStatus, SoftBufferError>,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        trace_span!("present", backend = self.buffer_impl.variant_name());
+        // The same pixels look different through another viewport.
+        let salt = viewport.map_or(0, |viewport| {
+            util::hash_pixels(&[
+                viewport.x,
+                viewport.y,
+                viewport.width.get(),
+                viewport.height.get(),
+            ])
+        });
+        let hash = self
+            .last_hash
+            .as_ref()
+            .map(|_| util::hash_pixels(self.buffer_impl.pixels()) ^ salt);
+        if hash.is_some() && self.last_hash.as_deref() == Some(&hash) {
+            #[cfg(feature = "metrics")]
+            telemetry::presented(
+                self.buffer_impl.variant_name(),
+                PresentStatus::Skipped,
+                None,
+                None,
+                Duration::ZERO,
+            );
+            return Ok(PresentStatus::Skipped);
+        }
+
+        if let (Some((threshold, shape)), Some((width, _))) = (&mut self.shape, self.size) {
+            trace_span!("alpha_shape");
+            let new =
+                util::alpha_shape(self.buffer_impl.pixels(), width.get() as usize, *threshold);
+            if shape.as_ref() != Some(&new) {
+                self.buffer_impl.set_shape(&new)?;
+                **shape = Some(new);
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        let (backend, shown) = (
+            self.buffer_impl.variant_name(),
+            viewport.or_else(|| {
+                self.size.map(|(width, height)| Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                })
+            }),
+        );
+        #[cfg(feature = "frame-dump")]
+        if let (Some(dump), Some((width, height))) = (self.dump.as_mut(), self.size) {
+            dump.present(self.buffer_impl.pixels(), width.get(), height.get(), damage);
+        }
+        let started = stats::Timestamp::now();
+        let status = present(self.buffer_impl)?;
+        #[cfg(feature = "metrics")]
+        telemetry::presented(backend, status, shown, damage, started.elapsed());
+        if status == PresentStatus::Presented {
+            self.stats.presented(started);
+            if let Some(size) = self.size {
+                self.history.presented(damage, size);
+            }
+            if let (Some(hook), Some((width, height))) = (self.present_hook, self.size) {
+                let all = [Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                }];
+                if let Some(info) = self.stats.last_present(damage.unwrap_or(&all)) {
+                    hook(&info);
+                }
+            }
+            if let Some(last_hash) = self.last_hash {
+                *last_hash = hash;
+            }
+            self.view.shown = viewport;
+        }
+        Ok(status)
     }
 }
 
@@ -1,7 +1,10 @@
 use raw_window_handle::OrbitalWindowHandle;
-use std::{cmp, num::NonZeroU32, slice, str};
+use std::{cmp, num::NonZeroU32, slice, str, time::Duration};
 
-use crate::{Rect, SoftBufferError};
+use crate::{
+    convert, stats, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus, Rect,
+    Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
 
 struct OrbitalMap {
     address: usize,
@@ -11,6 +14,7 @@ struct OrbitalMap {
 
 impl OrbitalMap {
     unsafe fn new(fd: usize, size_unaligned: usize) -> syscall::Result<Self> {
+        trace_span!("map_window", size = size_unaligned);
         // Page align size
         let pages = (size_unaligned + syscall::PAGE_SIZE - 1) / syscall::PAGE_SIZE;
         let size = pages * syscall::PAGE_SIZE;
@@ -39,7 +43,7 @@ impl OrbitalMap {
         unsafe { slice::from_raw_parts(self.address as *const u32, self.size_unaligned / 4) }
     }
 
-    unsafe fn data_mut(&self) -> &mut [u32] {
+    unsafe fn data_mut(&mut self) -> &mut [u32] {
         unsafe { slice::from_raw_parts_mut(self.address as *mut u32, self.size_unaligned / 4) }
     }
 }
@@ -60,6 +64,9 @@ pub struct OrbitalImpl {
     presented: bool,
 }
 
+// SAFETY: The pointer in the handle is the file descriptor of the window, see `window_fd()`.
+unsafe impl Send for OrbitalImpl {}
+
 impl OrbitalImpl {
     pub fn new(handle: OrbitalWindowHandle) -> Result<Self, SoftBufferError> {
         Ok(Self {
@@ -73,7 +80,7 @@ impl OrbitalImpl {
     pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
         let width = width.get();
         let height = height.get();
-        if width != self.width && height != self.height {
+        if width != self.width || height != self.height {
             self.presented = false;
             self.width = width;
             self.height = height;
@@ -86,7 +93,7 @@ impl OrbitalImpl {
     }
 
     // Read the current width and size
-    fn window_size(&self) -> (usize, usize) {
+    fn read_window_size(&self) -> (usize, usize) {
         let mut window_width = 0;
         let mut window_height = 0;
 
@@ -105,8 +112,8 @@ impl OrbitalImpl {
         (window_width, window_height)
     }
 
-    pub fn buffer_mut(&mut self) -> Result<BufferImpl, SoftBufferError> {
-        let (window_width, window_height) = self.window_size();
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        let (window_width, window_height) = self.read_window_size();
         let pixels = if self.width as usize == window_width && self.height as usize == window_height
         {
             Pixels::Mapping(
@@ -119,40 +126,189 @@ impl OrbitalImpl {
         Ok(BufferImpl { imp: self, pixels })
     }
 
-    fn set_buffer(&self, buffer: &[u32], width_u32: u32, height_u32: u32) {
+    fn set_buffer(&self, buffer: &[u32], width_u32: u32, height_u32: u32, damage: &[Rect]) {
         // Read the current width and size
-        let (window_width, window_height) = self.window_size();
+        let (window_width, window_height) = self.read_window_size();
 
         {
             // Map window buffer
-            let window_map =
+            let mut window_map =
                 unsafe { OrbitalMap::new(self.window_fd(), window_width * window_height * 4) }
                     .expect("failed to map orbital window");
 
             // Window buffer is u32 color data in 0xAABBGGRR format
             let window_data = unsafe { window_map.data_mut() };
 
-            // Copy each line, cropping to fit
+            // Copy each line of the damage, cropping to fit
+            trace_span!("copy");
             let width = width_u32 as usize;
             let height = height_u32 as usize;
             let min_width = cmp::min(width, window_width);
             let min_height = cmp::min(height, window_height);
-            for y in 0..min_height {
-                let offset_buffer = y * width;
-                let offset_data = y * window_width;
-                window_data[offset_data..offset_data + min_width]
-                    .copy_from_slice(&buffer[offset_buffer..offset_buffer + min_width]);
+            for rect in damage {
+                let x = cmp::min(rect.x as usize, min_width);
+                let y = cmp::min(rect.y as usize, min_height);
+                let x_end = cmp::min(x + rect.width.get() as usize, min_width);
+                let y_end = cmp::min(y + rect.height.get() as usize, min_height);
+                let rows = &mut window_data[y * window_width..y_end * window_width];
+                let stream = convert::should_stream((x_end - x) * (y_end - y) * 4);
+                convert::for_each_row(rows, window_width, |row, data| {
+                    let offset_buffer = (y + row) * width;
+                    convert::copy(
+                        &buffer[offset_buffer + x..offset_buffer + x_end],
+                        &mut data[x..x_end],
+                        stream,
+                    );
+                });
             }
 
             // Window buffer map is dropped here
         }
 
         // Tell orbital to show the latest window data
+        trace_span!("sync");
         syscall::fsync(self.window_fd()).expect("failed to sync orbital window");
     }
 
     /// Fetch the buffer from the window.
     pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        let (window_width, window_height) = self.read_window_size();
+        let window_map =
+            unsafe { OrbitalMap::new(self.window_fd(), window_width * window_height * 4) }
+                .expect("failed to map orbital window");
+        let window_data = unsafe { window_map.data() };
+
+        // Copy each line, cropping to fit, and leave the rest of the buffer black
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let min_width = cmp::min(width, window_width);
+        let min_height = cmp::min(height, window_height);
+        let mut buffer = vec![0; width * height];
+        for y in 0..min_height {
+            let offset_buffer = y * width;
+            let offset_data = y * window_width;
+            buffer[offset_buffer..offset_buffer + min_width]
+                .copy_from_slice(&window_data[offset_data..offset_data + min_width]);
+        }
+
+        Ok(buffer)
+    }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// The pixels are drawn into the window's own memory, which Orbital owns.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Wait for the next vertical blank.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Get the refresh interval of the display.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Get the size of the window in pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        let (width, height) = self.read_window_size();
+        Ok((width as u32, height as u32))
+    }
+
+    /// Orbital doesn't report whether windows are covered.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set how the buffer is scaled to the window.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        match scaling {
+            Scaling::TopLeft => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The parts of the window outside of the buffer aren't drawn to.
+    pub fn set_background_color(&mut self, _color: u32) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Orbital doesn't make presents wait.
+    pub fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// There is only one buffer already.
+    pub fn set_single_buffered(&mut self, _single: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Rows are always shown top-down.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        if flip {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Orbital keeps showing the last data of the window until the next present.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Orbital windows are in physical pixels.
+    pub fn set_scale_factor(&mut self, _scale_factor: f64) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Orbital has no window shapes.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        // The window is drawn into directly when the sizes match, see `buffer_mut()`.
+        let (window_width, window_height) = self.read_window_size();
+        Capabilities {
+            partial_present: true,
+            zero_copy: self.width as usize == window_width && self.height as usize == window_height,
+            max_size: (u32::MAX, u32::MAX),
+            buffer_count: 1,
+            readback: true,
+        }
+    }
+
+    /// Orbital only shows the memory of the window.
+    pub unsafe fn present_external(
+        &mut self,
+        _memory: ExternalMemory,
+        _width: NonZeroU32,
+        _height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
         Err(SoftBufferError::Unimplemented)
     }
 }
@@ -191,23 +347,63 @@ impl<'a> BufferImpl<'a> {
         }
     }
 
-    pub fn present(self) -> Result<(), SoftBufferError> {
+    /// There is one buffer, which is the window or is copied into it.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    /// The pixels are mapped from the window, which has no handle of its own.
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        None
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let damage = match (
+            NonZeroU32::new(self.imp.width),
+            NonZeroU32::new(self.imp.height),
+        ) {
+            (Some(width), Some(height)) => Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            // Nothing to copy, but still sync the window.
+            _ => return self.present_with_damage(&[]),
+        };
+        self.present_with_damage(&[damage])
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
         match self.pixels {
             Pixels::Mapping(mapping) => {
+                // The pixels were drawn into the window directly. Orbital always redraws the
+                // whole window on sync, so the damage isn't needed.
                 drop(mapping);
+                trace_span!("sync");
                 syscall::fsync(self.imp.window_fd()).expect("failed to sync orbital window");
                 self.imp.presented = true;
             }
             Pixels::Buffer(buffer) => {
                 self.imp
-                    .set_buffer(&buffer, self.imp.width, self.imp.height);
+                    .set_buffer(&buffer, self.imp.width, self.imp.height, damage);
             }
         }
 
-        Ok(())
+        Ok(PresentStatus::Presented)
     }
 
-    pub fn present_with_damage(self, _damage: &[Rect]) -> Result<(), SoftBufferError> {
-        self.present()
+    /// The window shows all of the buffer.
+    pub fn present_viewport(
+        self,
+        _damage: &[Rect],
+        _viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
     }
 }
@@ -0,0 +1,274 @@
+//! Presenting from a thread that softbuffer owns, so the blocking parts of presenting, like
+//! waiting for the display server or for vsync, don't stall the thread that renders.
+//!
+//! A [`PresentThread`] takes a [`Surface`], and the application submits finished frames to it,
+//! which are presented in order on its thread while the application renders the next one:
+//!
+//! ```no_run
+//! # fn run(surface: softbuffer::Surface) -> Result<(), softbuffer::SoftBufferError> {
+//! use softbuffer::present_thread::PresentThread;
+//! use softbuffer::Rect;
+//! use std::num::NonZeroU32;
+//!
+//! let (width, height) = (NonZeroU32::new(640).unwrap(), NonZeroU32::new(480).unwrap());
+//! let all = Rect { x: 0, y: 0, width, height };
+//! let mut frame = vec![0; 640 * 480];
+//! let mut present_thread = PresentThread::new(surface)?;
+//! for color in 0..60 {
+//!     frame.fill(color);
+//!     present_thread.submit(&frame, width, height, &[all])?;
+//! }
+//! let (surface, result) = present_thread.finish();
+//! result?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Surfaces can only be moved to another thread on platforms where they are [`Send`], so there
+//! is no present thread on macOS, iOS, Web, and for WinRT windows.
+
+use crate::error::SwResultExt;
+use crate::{Rect, SoftBufferError, Surface};
+use std::num::NonZeroU32;
+use std::panic;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// How many frames may wait for the thread, besides the one it presents.
+const QUEUE_LEN: usize = 1;
+
+struct Frame {
+    pixels: Vec<u32>,
+    width: NonZeroU32,
+    height: NonZeroU32,
+    damage: Vec<Rect>,
+}
+
+/// An error of the thread, which is sent back without the parts of [`SoftBufferError`] that
+/// aren't `Send`.
+enum Failure {
+    SizeOutOfRange {
+        width: NonZeroU32,
+        height: NonZeroU32,
+    },
+    DamageOutOfRange {
+        rect: Rect,
+    },
+    SurfaceNotConfigured,
+    WindowDestroyed,
+    SizeMismatch {
+        buffer: (NonZeroU32, NonZeroU32),
+        window: (NonZeroU32, NonZeroU32),
+    },
+    IncompleteDamage,
+    Timeout,
+    Unimplemented,
+    /// Any other error, which becomes a [`SoftBufferError::PlatformError`] with its message.
+    Other(String),
+}
+
+impl From<SoftBufferError> for Failure {
+    fn from(error: SoftBufferError) -> Self {
+        match error {
+            SoftBufferError::SizeOutOfRange { width, height } => {
+                Self::SizeOutOfRange { width, height }
+            }
+            SoftBufferError::DamageOutOfRange { rect } => Self::DamageOutOfRange { rect },
+            SoftBufferError::SurfaceNotConfigured => Self::SurfaceNotConfigured,
+            SoftBufferError::WindowDestroyed => Self::WindowDestroyed,
+            SoftBufferError::SizeMismatch { buffer, window } => {
+                Self::SizeMismatch { buffer, window }
+            }
+            SoftBufferError::IncompleteDamage => Self::IncompleteDamage,
+            SoftBufferError::Timeout => Self::Timeout,
+            SoftBufferError::Unimplemented => Self::Unimplemented,
+            error => Self::Other(error.to_string()),
+        }
+    }
+}
+
+impl From<Failure> for SoftBufferError {
+    fn from(failure: Failure) -> Self {
+        match failure {
+            Failure::SizeOutOfRange { width, height } => Self::SizeOutOfRange { width, height },
+            Failure::DamageOutOfRange { rect } => Self::DamageOutOfRange { rect },
+            Failure::SurfaceNotConfigured => Self::SurfaceNotConfigured,
+            Failure::WindowDestroyed => Self::WindowDestroyed,
+            Failure::SizeMismatch { buffer, window } => Self::SizeMismatch { buffer, window },
+            Failure::IncompleteDamage => Self::IncompleteDamage,
+            Failure::Timeout => Self::Timeout,
+            Failure::Unimplemented => Self::Unimplemented,
+            Failure::Other(msg) => Self::PlatformError(Some(msg), None),
+        }
+    }
+}
+
+/// Presents the frames submitted to it on its own thread, see the [module docs](self).
+pub struct PresentThread<D, W> {
+    frames: Option<SyncSender<Frame>>,
+    /// The pixels of the presented frames, for copying the next ones into, and whether they were
+    /// presented.
+    presented: Receiver<(Vec<u32>, Result<(), Failure>)>,
+    /// The number of frames submitted but not presented yet.
+    pending: usize,
+    spare: Vec<Vec<u32>>,
+    thread: Option<JoinHandle<Surface<D, W>>>,
+}
+
+impl<D: 'static, W: 'static> PresentThread<D, W>
+where
+    Surface<D, W>: Send,
+{
+    /// Starts a thread that presents to `surface`.
+    ///
+    /// The surface is configured as before, and the hook of [`Surface::set_present_hook`] is
+    /// called on the thread. Use [`PresentThread::finish`] to configure it again.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftBufferError::PlatformError`] if the thread can't be started.
+    pub fn new(mut surface: Surface<D, W>) -> Result<Self, SoftBufferError> {
+        let (frames, queue) = mpsc::sync_channel::<Frame>(QUEUE_LEN);
+        let (done, presented) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("softbuffer-present".into())
+            .spawn(move || {
+                for frame in queue {
+                    let result = surface
+                        .present_pixels(&frame.pixels, frame.width, frame.height, &frame.damage)
+                        .map(drop)
+                        .map_err(Failure::from);
+                    // The receiver is only dropped after the queue.
+                    let _ = done.send((frame.pixels, result));
+                }
+                surface
+            })
+            .swbuf_err("Failed to start the present thread")?;
+        Ok(Self {
+            frames: Some(frames),
+            presented,
+            pending: 0,
+            spare: Vec::new(),
+            thread: Some(thread),
+        })
+    }
+
+    /// Copies `pixels` of the given size, and presents them with `damage` on the thread, like
+    /// [`Surface::present_pixels`]. This only waits if the thread is a frame behind already.
+    ///
+    /// # Panics
+    ///
+    /// If `pixels` isn't `width * height` long, or if the thread panicked.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of the frames presented since the last call, without submitting
+    /// this frame, or [`SoftBufferError::DamageOutOfRange`] if a rect doesn't fit inside the
+    /// frame.
+    pub fn submit(
+        &mut self,
+        pixels: &[u32],
+        width: NonZeroU32,
+        height: NonZeroU32,
+        damage: &[Rect],
+    ) -> Result<(), SoftBufferError> {
+        assert_eq!(
+            pixels.len(),
+            width.get() as usize * height.get() as usize,
+            "`pixels` must have `width * height` pixels"
+        );
+        if let Some(rect) = damage.iter().find(|rect| !rect.fits(width, height)) {
+            return Err(SoftBufferError::DamageOutOfRange { rect: *rect });
+        }
+        let mut result = Ok(());
+        while let Ok(presented) = self.presented.try_recv() {
+            self.received(presented, &mut result);
+        }
+        result?;
+
+        let mut frame = self.spare.pop().unwrap_or_default();
+        frame.clear();
+        frame.extend_from_slice(pixels);
+        let frame = Frame {
+            pixels: frame,
+            width,
+            height,
+            damage: damage.to_vec(),
+        };
+        if self.frames.as_ref().unwrap().send(frame).is_err() {
+            // The thread only stops early if it panicked.
+            self.join();
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Waits until all submitted frames were presented.
+    ///
+    /// # Panics
+    ///
+    /// If the thread panicked.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of the frames presented since the last call.
+    pub fn flush(&mut self) -> Result<(), SoftBufferError> {
+        let mut result = Ok(());
+        while self.pending > 0 {
+            match self.presented.recv() {
+                Ok(presented) => self.received(presented, &mut result),
+                Err(_) => {
+                    self.join();
+                    break;
+                }
+            }
+        }
+        result
+    }
+
+    /// Waits until all submitted frames were presented, and stops the thread, returning the
+    /// surface, and the first error of the frames presented since the last call.
+    ///
+    /// The surface is returned even if a present failed, and can still be used.
+    ///
+    /// # Panics
+    ///
+    /// If the thread panicked.
+    pub fn finish(mut self) -> (Surface<D, W>, Result<(), SoftBufferError>) {
+        let result = self.flush();
+        (self.join(), result)
+    }
+
+    fn received(
+        &mut self,
+        (pixels, presented): (Vec<u32>, Result<(), Failure>),
+        result: &mut Result<(), SoftBufferError>,
+    ) {
+        self.pending -= 1;
+        self.spare.push(pixels);
+        if let (Ok(()), Err(failure)) = (&result, presented) {
+            *result = Err(failure.into());
+        }
+    }
+
+    /// Stops the thread after the submitted frames, and returns the surface, or the panic of the
+    /// thread.
+    fn join(&mut self) -> Surface<D, W> {
+        self.frames = None;
+        match self.thread.take().unwrap().join() {
+            Ok(surface) => surface,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+impl<D, W> Drop for PresentThread<D, W> {
+    /// Presents the submitted frames, and stops the thread.
+    fn drop(&mut self) {
+        self.frames = None;
+        if let Some(thread) = self.thread.take() {
+            // A panic of the thread was already reported to the panic hook.
+            let _ = thread.join();
+        }
+    }
+}
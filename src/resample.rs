@@ -0,0 +1,164 @@
+//! Gamma-correct resampling of images, see [`Buffer::downscale_from`].
+//!
+//! [`Buffer::downscale_from`]: crate::Buffer::downscale_from
+
+use crate::DownscaleFilter;
+use std::f32::consts::PI;
+
+/// The number of entries of the table that encodes linear values as sRGB.
+const ENCODE_LEN: usize = 4096;
+
+/// The source pixels that an output pixel is made of, and their weights.
+struct Taps {
+    start: usize,
+    weights: Vec<f32>,
+}
+
+impl DownscaleFilter {
+    /// How far the filter reaches, in pixels of the output.
+    fn radius(self) -> f32 {
+        match self {
+            Self::Box => 0.5,
+            Self::Lanczos3 => 3.0,
+        }
+    }
+
+    fn weight(self, x: f32) -> f32 {
+        let sinc = |x: f32| {
+            if x == 0.0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            }
+        };
+        match self {
+            Self::Box if x.abs() < 0.5 => 1.0,
+            Self::Lanczos3 if x.abs() < 3.0 => sinc(x) * sinc(x / 3.0),
+            _ => 0.0,
+        }
+    }
+}
+
+/// The taps of each of `dst_len` pixels resampled from `src_len` pixels.
+fn taps(src_len: usize, dst_len: usize, filter: DownscaleFilter) -> Vec<Taps> {
+    let scale = src_len as f32 / dst_len as f32;
+    // When upscaling, the filter still reaches over the neighboring source pixels.
+    let filter_scale = scale.max(1.0);
+    let support = filter.radius() * filter_scale;
+    (0..dst_len)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale;
+            let start = ((center - support).floor().max(0.0) as usize).min(src_len - 1);
+            let end = ((center + support).ceil() as usize).clamp(start + 1, src_len);
+            let mut weights: Vec<f32> = (start..end)
+                .map(|j| filter.weight((j as f32 + 0.5 - center) / filter_scale))
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            if sum.abs() < f32::EPSILON {
+                // The filter fell between the pixels, so take the nearest one.
+                weights.iter_mut().for_each(|weight| *weight = 0.0);
+                let nearest = (center as usize).clamp(start, end - 1);
+                weights[nearest - start] = 1.0;
+            } else {
+                weights.iter_mut().for_each(|weight| *weight /= sum);
+            }
+            Taps { start, weights }
+        })
+        .collect()
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Resamples `src`, an image of `0RGB` pixels `src_width` wide, into `dst`, which is `dst_width`
+/// wide. The channels are filtered in linear light.
+pub fn resample(
+    src: &[u32],
+    src_width: usize,
+    dst: &mut [u32],
+    dst_width: usize,
+    filter: DownscaleFilter,
+) {
+    let (src_height, dst_height) = (src.len() / src_width, dst.len() / dst_width);
+    let decode: Vec<f32> = (0..=255u8)
+        .map(|value| srgb_to_linear(f32::from(value) / 255.0))
+        .collect();
+    let encode: Vec<u8> = (0..ENCODE_LEN)
+        .map(|i| {
+            let value = linear_to_srgb(i as f32 / (ENCODE_LEN - 1) as f32);
+            (value * 255.0).round() as u8
+        })
+        .collect();
+
+    // Filter the rows first, into linear channels of `dst_width` pixels for each source row.
+    let columns = taps(src_width, dst_width, filter);
+    let mut rows = vec![[0.0f32; 3]; dst_width * src_height];
+    for (src_row, row) in src
+        .chunks_exact(src_width)
+        .zip(rows.chunks_exact_mut(dst_width))
+    {
+        for (pixel, taps) in row.iter_mut().zip(&columns) {
+            for (&src, weight) in src_row[taps.start..].iter().zip(&taps.weights) {
+                let [_, r, g, b] = src.to_be_bytes();
+                pixel[0] += decode[usize::from(r)] * weight;
+                pixel[1] += decode[usize::from(g)] * weight;
+                pixel[2] += decode[usize::from(b)] * weight;
+            }
+        }
+    }
+
+    // Then the columns, into the output.
+    let to_srgb = |value: f32| {
+        let index = (value.clamp(0.0, 1.0) * (ENCODE_LEN - 1) as f32).round() as usize;
+        u32::from(encode[index])
+    };
+    for (dst_row, taps) in dst
+        .chunks_exact_mut(dst_width)
+        .zip(taps(src_height, dst_height, filter))
+    {
+        for (x, dst) in dst_row.iter_mut().enumerate() {
+            let mut pixel = [0.0f32; 3];
+            for (i, weight) in taps.weights.iter().enumerate() {
+                let src = rows[(taps.start + i) * dst_width + x];
+                for (channel, src) in pixel.iter_mut().zip(src) {
+                    *channel += src * weight;
+                }
+            }
+            *dst = to_srgb(pixel[0]) << 16 | to_srgb(pixel[1]) << 8 | to_srgb(pixel[2]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample() {
+        // Averaging black and white in linear light is lighter than the average of their sRGB
+        // values, `0x80`.
+        let checkerboard = [0x00ff_ffff, 0, 0, 0x00ff_ffff];
+        let mut dst = [0; 1];
+        resample(&checkerboard, 2, &mut dst, 1, DownscaleFilter::Box);
+        assert_eq!(dst, [0x00bc_bcbc]);
+
+        // Flat colors stay the same through the negative lobes of Lanczos.
+        let flat = [0x0012_3456; 7 * 5];
+        let mut dst = [0; 3 * 2];
+        resample(&flat, 7, &mut dst, 3, DownscaleFilter::Lanczos3);
+        assert_eq!(dst, [0x0012_3456; 3 * 2]);
+    }
+}
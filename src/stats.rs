@@ -0,0 +1,272 @@
+//! Rolling measurements of the time spent in softbuffer, see [`Surface::frame_stats`].
+//!
+//! [`Surface::frame_stats`]: crate::Surface::frame_stats
+
+use crate::Rect;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The number of frames the statistics are averaged over.
+const WINDOW: usize = 60;
+
+/// Statistics about the last frames presented to a surface, averaged over the last 60 frames.
+///
+/// Most of these only measure the work done by softbuffer on the calling thread. On backends
+/// that report when frames are shown, `latency` and `dropped_frames` measure the display
+/// server or compositor as well.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct FrameStats {
+    /// The number of frames the statistics are averaged over.
+    pub frames: usize,
+    /// The time [`Surface::buffer_mut`](crate::Surface::buffer_mut) takes to map a buffer,
+    /// including waiting for the display server to release it.
+    pub map_time: Duration,
+    /// The part of `present_time` spent copying and converting the pixels of the buffer, on
+    /// backends that don't present it without copying.
+    pub copy_time: Duration,
+    /// The time [`Buffer::present`](crate::Buffer::present) and
+    /// [`Buffer::present_with_damage`](crate::Buffer::present_with_damage) take to return.
+    pub present_time: Duration,
+    /// The number of frames presented per second.
+    pub presents_per_second: f64,
+    /// The time from presenting a frame until it is shown, averaged over the last 60 frames that
+    /// were reported shown, or `None` if the backend doesn't report it.
+    ///
+    /// On DRM/KMS, this is the time until the page flip. On Web, with
+    /// `SurfaceExtWeb::set_sync_to_animation_frame()`, it is the time until the animation frame
+    /// that the browser renders the frame in.
+    pub latency: Option<Duration>,
+    /// The number of frames that were presented, but replaced by a later frame before they were
+    /// shown, since the surface was created.
+    ///
+    /// This is only reported on Web, where the presents of one animation frame are coalesced.
+    pub dropped_frames: u64,
+}
+
+/// A frame that was presented, which the hook of
+/// [`Surface::set_present_hook`](crate::Surface::set_present_hook) is called with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PresentInfo<'a> {
+    /// The number of frames presented to the surface before this one.
+    pub frame: u64,
+    /// The time since the previous frame was presented, or `None` for the first frame.
+    pub interval: Option<Duration>,
+    /// The time the present took.
+    pub present_time: Duration,
+    /// The parts of the buffer that were presented. This is all of it if the buffer was
+    /// presented without damage.
+    pub damage: &'a [Rect],
+}
+
+/// What a backend found out about its presented frames since the last time it was taken.
+#[derive(Debug, Default)]
+pub struct Feedback {
+    /// The time from presenting each frame until it was shown.
+    latencies: Vec<Duration>,
+    dropped: u64,
+}
+
+// Only the backends that find out when frames are shown record anything.
+impl Feedback {
+    /// Record that a frame was shown `latency` after it was presented.
+    #[cfg_attr(not(any(kms_platform, web_platform)), allow(dead_code))]
+    pub fn shown(&mut self, latency: Duration) {
+        if self.latencies.len() == WINDOW {
+            self.latencies.remove(0);
+        }
+        self.latencies.push(latency);
+    }
+
+    /// Record that a frame was replaced before it was shown.
+    #[cfg_attr(not(web_platform), allow(dead_code))]
+    pub fn dropped(&mut self) {
+        self.dropped += 1;
+    }
+
+    /// The time from presenting each frame until it was shown.
+    #[cfg(feature = "metrics")]
+    pub fn latencies(&self) -> &[Duration] {
+        &self.latencies
+    }
+
+    /// The number of frames that were replaced before they were shown.
+    #[cfg(feature = "metrics")]
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Frame {
+    map_time: Duration,
+    copy_time: Duration,
+    present_time: Duration,
+    presented_at: Timestamp,
+}
+
+/// Collects the measurements of a surface.
+#[derive(Default)]
+pub struct Collector {
+    frames: VecDeque<Frame>,
+    /// The time the current buffer took to map.
+    map_time: Duration,
+    latencies: VecDeque<Duration>,
+    dropped_frames: u64,
+    /// The number of frames presented since the surface was created.
+    presents: u64,
+}
+
+impl Collector {
+    /// Record the time it took to map a buffer.
+    pub fn mapped(&mut self, started: Timestamp) {
+        self.map_time = started.elapsed();
+    }
+
+    /// Record a presented frame, which started presenting at `started`.
+    pub fn presented(&mut self, started: Timestamp) {
+        let presented_at = Timestamp::now();
+        if self.frames.len() == WINDOW {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(Frame {
+            map_time: self.map_time,
+            copy_time: COPY_TIME.with(|copy_time| copy_time.take()),
+            present_time: presented_at.duration_since(started),
+            presented_at,
+        });
+        self.presents += 1;
+    }
+
+    /// The last frame that was presented, with the given damage.
+    pub fn last_present<'a>(&self, damage: &'a [Rect]) -> Option<PresentInfo<'a>> {
+        let mut frames = self.frames.iter().rev();
+        let last = frames.next()?;
+        Some(PresentInfo {
+            frame: self.presents - 1,
+            interval: frames
+                .next()
+                .map(|previous| last.presented_at.duration_since(previous.presented_at)),
+            present_time: last.present_time,
+            damage,
+        })
+    }
+
+    /// Add what the backend found out about its presented frames.
+    pub fn feedback(&mut self, feedback: Feedback) {
+        for latency in feedback.latencies {
+            if self.latencies.len() == WINDOW {
+                self.latencies.pop_front();
+            }
+            self.latencies.push_back(latency);
+        }
+        self.dropped_frames += feedback.dropped;
+    }
+
+    /// Whether a frame was presented.
+    pub fn has_presented(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// The average time from presenting a frame until it was shown, if the backend reports it.
+    pub fn latency(&self) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            None
+        } else {
+            Some(self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32)
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let latency = self.latency();
+
+        let frames = self.frames.len();
+        if frames == 0 {
+            return FrameStats {
+                latency,
+                dropped_frames: self.dropped_frames,
+                ..FrameStats::default()
+            };
+        }
+
+        let average =
+            |f: fn(&Frame) -> Duration| self.frames.iter().map(f).sum::<Duration>() / frames as u32;
+        // The rate needs two frames to measure the time between them.
+        let first = self.frames.front().unwrap().presented_at;
+        let last = self.frames.back().unwrap().presented_at;
+        let elapsed = last.duration_since(first).as_secs_f64();
+        let presents_per_second = if elapsed > 0.0 {
+            (frames - 1) as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        FrameStats {
+            frames,
+            map_time: average(|frame| frame.map_time),
+            copy_time: average(|frame| frame.copy_time),
+            present_time: average(|frame| frame.present_time),
+            presents_per_second,
+            latency,
+            dropped_frames: self.dropped_frames,
+        }
+    }
+}
+
+thread_local! {
+    /// The time spent copying pixels since the last present on this thread.
+    static COPY_TIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// Count the time `f` takes towards the copy time of the frame being presented.
+pub fn time_copy<R>(f: impl FnOnce() -> R) -> R {
+    trace_span!("copy");
+    let started = Timestamp::now();
+    let result = f();
+    let elapsed = started.elapsed();
+    COPY_TIME.with(|copy_time| copy_time.set(copy_time.get() + elapsed));
+    result
+}
+
+/// A point in time, on a monotonic clock.
+#[derive(Clone, Copy)]
+pub struct Timestamp(
+    #[cfg(not(web_platform))] std::time::Instant,
+    /// `std::time::Instant` panics on the web, so use `performance.now()` in milliseconds.
+    #[cfg(web_platform)]
+    f64,
+);
+
+impl Timestamp {
+    #[cfg(not(web_platform))]
+    pub fn now() -> Self {
+        Self(std::time::Instant::now())
+    }
+
+    #[cfg(web_platform)]
+    pub fn now() -> Self {
+        use wasm_bindgen::JsCast;
+
+        // Both windows and workers have a `performance` object.
+        let performance = js_sys::Reflect::get(&js_sys::global(), &"performance".into())
+            .expect("failed to get `performance`")
+            .unchecked_into::<web_sys::Performance>();
+        Self(performance.now())
+    }
+
+    #[cfg(not(web_platform))]
+    pub fn duration_since(&self, earlier: Timestamp) -> Duration {
+        self.0.saturating_duration_since(earlier.0)
+    }
+
+    #[cfg(web_platform)]
+    pub fn duration_since(&self, earlier: Timestamp) -> Duration {
+        Duration::from_secs_f64((self.0 - earlier.0).max(0.0) / 1000.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Timestamp::now().duration_since(*self)
+    }
+}
@@ -0,0 +1,67 @@
+//! Metrics of the presents of surfaces, behind the `metrics` feature. The README lists them.
+
+use crate::{stats, util, PresentStatus, Rect};
+use std::time::Duration;
+
+/// Record a present, where `shown` is the part of the buffer that is shown, and `damage` the
+/// part of it that changed, if not all of it.
+pub fn presented(
+    backend: &'static str,
+    status: PresentStatus,
+    shown: Option<Rect>,
+    damage: Option<&[Rect]>,
+    time: Duration,
+) {
+    let status = match status {
+        PresentStatus::Presented => "presented",
+        PresentStatus::Skipped => "skipped",
+        PresentStatus::OutOfDate => "out_of_date",
+    };
+    metrics::counter!("softbuffer_presents_total", 1, "backend" => backend, "status" => status);
+    if status != "presented" {
+        return;
+    }
+    metrics::histogram!("softbuffer_present_seconds", time, "backend" => backend);
+
+    // The size isn't known if the surface wasn't resized, which only the backend reports.
+    let shown = match shown {
+        Some(shown) => shown,
+        None => return,
+    };
+    let area = u64::from(shown.width.get()) * u64::from(shown.height.get());
+    let damaged = match damage {
+        Some(damage) => {
+            let damage: Vec<Rect> = damage
+                .iter()
+                .filter_map(|rect| rect.intersection(&shown))
+                .map(|rect| Rect {
+                    x: rect.x - shown.x,
+                    y: rect.y - shown.y,
+                    ..rect
+                })
+                .collect();
+            util::covered_area(&damage, shown.width.get(), shown.height.get())
+        }
+        None => area,
+    };
+    metrics::counter!("softbuffer_uploaded_bytes_total", damaged * 4, "backend" => backend);
+    metrics::histogram!(
+        "softbuffer_damage_ratio",
+        damaged as f64 / area as f64,
+        "backend" => backend
+    );
+}
+
+/// Record what the backend found out about its presented frames.
+pub fn feedback(backend: &'static str, feedback: &stats::Feedback) {
+    for &latency in feedback.latencies() {
+        metrics::histogram!("softbuffer_display_latency_seconds", latency, "backend" => backend);
+    }
+    if feedback.dropped_frames() != 0 {
+        metrics::counter!(
+            "softbuffer_dropped_frames_total",
+            feedback.dropped_frames(),
+            "backend" => backend
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! Implementation of software buffering for terminals with graphics support.
+//!
+//! The buffer is drawn from the top left corner of the terminal, with either the kitty graphics
+//! protocol or sixel. Damage is rounded out to whole character cells, and only those cells are
+//! sent again.
+
+use crate::error::SwResultExt;
+use crate::{
+    stats, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus, Rect, Scaling,
+    ScalingFilter, SoftBufferError, Visibility,
+};
+
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// How much of the base64 payload goes into one kitty graphics escape sequence.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// Levels of red, green and blue in the sixel palette.
+const SIXEL_LEVELS: [u32; 3] = [6, 7, 6];
+
+/// The graphics protocol to draw a terminal surface with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TerminalProtocol {
+    /// The kitty graphics protocol, supported by kitty, WezTerm, Konsole and others.
+    Kitty,
+    /// Sixel graphics, supported by xterm, foot, mlterm and others. Colors are reduced to a
+    /// palette of 252 colors.
+    Sixel,
+}
+
+pub struct TerminalImpl {
+    output: Box<dyn Write + Send>,
+    protocol: TerminalProtocol,
+    cell_width: u32,
+    cell_height: u32,
+    buffer: Option<Buffer>,
+    /// The kitty image id for the next damaged region. Id 1 is the whole buffer.
+    next_image_id: u32,
+}
+
+struct Buffer {
+    pixels: Vec<u32>,
+    width: NonZeroU32,
+    height: NonZeroU32,
+    presented: bool,
+}
+
+impl TerminalImpl {
+    pub fn new(
+        output: Box<dyn Write + Send>,
+        protocol: TerminalProtocol,
+        cell_width: NonZeroU32,
+        cell_height: NonZeroU32,
+    ) -> Self {
+        Self {
+            output,
+            protocol,
+            cell_width: cell_width.get(),
+            cell_height: cell_height.get(),
+            buffer: None,
+            next_image_id: 2,
+        }
+    }
+
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
+        if let Some(buffer) = self.buffer.as_ref() {
+            if buffer.width == width && buffer.height == height {
+                return Ok(());
+            }
+        }
+
+        self.buffer = Some(Buffer {
+            pixels: vec![0; width.get() as usize * height.get() as usize],
+            width,
+            height,
+            presented: false,
+        });
+
+        Ok(())
+    }
+
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        if self.buffer.is_none() {
+            return Err(SoftBufferError::SurfaceNotConfigured);
+        }
+
+        Ok(BufferImpl(self))
+    }
+
+    fn present_with_damage(&mut self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let buffer = self.buffer.as_mut().unwrap();
+        let (width, height) = (buffer.width.get(), buffer.height.get());
+
+        let mut out = Vec::new();
+        // Save the cursor, so drawing doesn't disturb the text around it.
+        out.extend_from_slice(b"\x1b7");
+        for rect in damage {
+            // Round out to whole cells, since the image is placed at a cell.
+            let left = rect.x / self.cell_width * self.cell_width;
+            let top = rect.y / self.cell_height * self.cell_height;
+            let right = rect.x.saturating_add(rect.width.get()).min(width);
+            let bottom = rect.y.saturating_add(rect.height.get()).min(height);
+            if left >= right || top >= bottom {
+                continue;
+            }
+
+            let row = top / self.cell_height + 1;
+            let column = left / self.cell_width + 1;
+            out.extend_from_slice(format!("\x1b[{};{}H", row, column).as_bytes());
+
+            let region = Region {
+                pixels: &buffer.pixels,
+                stride: width as usize,
+                x: left as usize,
+                y: top as usize,
+                width: (right - left) as usize,
+                height: (bottom - top) as usize,
+            };
+            trace_span!("encode");
+            match self.protocol {
+                TerminalProtocol::Kitty => {
+                    let image_id = if region.width == width as usize
+                        && region.height == height as usize
+                    {
+                        // The whole buffer covers all the patches, so drop them.
+                        if self.next_image_id > 2 {
+                            out.extend_from_slice(
+                                format!("\x1b_Ga=d,d=R,x=2,y={},q=2\x1b\\", self.next_image_id - 1)
+                                    .as_bytes(),
+                            );
+                            self.next_image_id = 2;
+                        }
+                        1
+                    } else {
+                        self.next_image_id += 1;
+                        self.next_image_id - 1
+                    };
+                    encode_kitty(&region, image_id, &mut out);
+                }
+                TerminalProtocol::Sixel => encode_sixel(&region, &mut out),
+            }
+        }
+        out.extend_from_slice(b"\x1b8");
+
+        trace_span!("write", bytes = out.len());
+        self.output
+            .write_all(&out)
+            .and_then(|()| self.output.flush())
+            .swbuf_err("Failed to write to the terminal")?;
+        buffer.presented = true;
+
+        Ok(PresentStatus::Presented)
+    }
+
+    /// Copy the buffer written to the terminal last, which can't be read back.
+    pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        let buffer = self
+            .buffer
+            .as_ref()
+            .expect("Must set size of surface before calling `fetch()`");
+        if buffer.presented {
+            Ok(buffer.pixels.clone())
+        } else {
+            Ok(vec![0; buffer.pixels.len()])
+        }
+    }
+
+    /// Check whether the next buffer is released by the terminal.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// Free the pixels, the terminal keeps showing the last image.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        self.buffer = None;
+        Ok(())
+    }
+
+    /// Wait for the next vertical blank.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Terminals don't report the refresh rate of the display.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Get the size of the terminal in pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Terminals don't report whether they are visible.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set how the buffer is scaled to the terminal.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        match scaling {
+            Scaling::TopLeft => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The image is only as large as the buffer.
+    pub fn set_background_color(&mut self, _color: u32) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Writes to the terminal block until it reads them.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        if timeout.is_some() {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// There is only one buffer already.
+    pub fn set_single_buffered(&mut self, _single: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Rows are always shown top-down.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        if flip {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Terminals have no window that is resized.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Images are placed in physical pixels of the terminal.
+    pub fn set_scale_factor(&mut self, _scale_factor: f64) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// The terminal draws images as rectangles.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            partial_present: true,
+            zero_copy: false,
+            max_size: (u32::MAX, u32::MAX),
+            buffer_count: 1,
+            readback: false,
+        }
+    }
+
+    /// The terminal only gets pixels through escape sequences.
+    pub unsafe fn present_external(
+        &mut self,
+        _memory: ExternalMemory,
+        _width: NonZeroU32,
+        _height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+/// A rectangle of the buffer to send to the terminal.
+struct Region<'a> {
+    pixels: &'a [u32],
+    stride: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Region<'_> {
+    fn rows(&self) -> impl Iterator<Item = &[u32]> + '_ {
+        (self.y..self.y + self.height).map(move |row| {
+            let start = row * self.stride + self.x;
+            &self.pixels[start..start + self.width]
+        })
+    }
+}
+
+/// Transmit and display the region as 24 bit RGB with the kitty graphics protocol.
+fn encode_kitty(region: &Region<'_>, image_id: u32, out: &mut Vec<u8>) {
+    let mut rgb = Vec::with_capacity(region.width * region.height * 3);
+    for row in region.rows() {
+        for &pixel in row {
+            rgb.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+        }
+    }
+    let payload = base64(&rgb);
+
+    let mut chunks = payload.chunks(KITTY_CHUNK_SIZE).peekable();
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let more = u8::from(chunks.peek().is_some());
+        if first {
+            // Don't move the cursor, and don't answer, since nobody reads the answer.
+            out.extend_from_slice(
+                format!(
+                    "\x1b_Ga=T,f=24,s={},v={},i={},C=1,q=2,m={};",
+                    region.width, region.height, image_id, more
+                )
+                .as_bytes(),
+            );
+            first = false;
+        } else {
+            out.extend_from_slice(format!("\x1b_Gm={};", more).as_bytes());
+        }
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\x1b\\");
+    }
+}
+
+/// Draw the region as sixels, with a fixed palette of `6 * 7 * 6` colors.
+fn encode_sixel(region: &Region<'_>, out: &mut Vec<u8>) {
+    let [red_levels, green_levels, blue_levels] = SIXEL_LEVELS;
+    let palette_size = (red_levels * green_levels * blue_levels) as usize;
+    let quantize = |value: u32, levels: u32| (value * (levels - 1) + 127) / 255;
+    let indices = region
+        .rows()
+        .flat_map(|row| row.iter())
+        .map(|&pixel| {
+            let red = quantize((pixel >> 16) & 0xff, red_levels);
+            let green = quantize((pixel >> 8) & 0xff, green_levels);
+            let blue = quantize(pixel & 0xff, blue_levels);
+            ((red * green_levels + green) * blue_levels + blue) as u8
+        })
+        .collect::<Vec<_>>();
+
+    // Keep the pixels that aren't drawn, and set the size so the terminal doesn't clear more.
+    out.extend_from_slice(
+        format!("\x1bP0;1;0q\"1;1;{};{}", region.width, region.height).as_bytes(),
+    );
+    for index in 0..palette_size as u32 {
+        let blue = index % blue_levels;
+        let green = index / blue_levels % green_levels;
+        let red = index / blue_levels / green_levels;
+        out.extend_from_slice(
+            format!(
+                "#{};2;{};{};{}",
+                index,
+                red * 100 / (red_levels - 1),
+                green * 100 / (green_levels - 1),
+                blue * 100 / (blue_levels - 1)
+            )
+            .as_bytes(),
+        );
+    }
+
+    let mut used = vec![false; palette_size];
+    let mut sixels = vec![0u8; region.width];
+    for band in indices.chunks(region.width * 6) {
+        used.iter_mut().for_each(|used| *used = false);
+        for &index in band {
+            used[index as usize] = true;
+        }
+
+        let mut first = true;
+        for color in (0..palette_size).filter(|&color| used[color]) {
+            sixels.iter_mut().for_each(|sixel| *sixel = 0);
+            for (bit, row) in band.chunks(region.width).enumerate() {
+                for (sixel, &index) in sixels.iter_mut().zip(row) {
+                    if index as usize == color {
+                        *sixel |= 1 << bit;
+                    }
+                }
+            }
+
+            // Go back to the start of the band for every color after the first.
+            if !first {
+                out.push(b'$');
+            }
+            first = false;
+            out.extend_from_slice(format!("#{}", color).as_bytes());
+            encode_sixel_run_lengths(&sixels, out);
+        }
+        out.push(b'-');
+    }
+    out.extend_from_slice(b"\x1b\\");
+}
+
+fn encode_sixel_run_lengths(sixels: &[u8], out: &mut Vec<u8>) {
+    let mut rest = sixels;
+    while let Some(&sixel) = rest.first() {
+        let count = rest.iter().take_while(|&&other| other == sixel).count();
+        let character = sixel + 0x3f;
+        if count > 3 {
+            out.extend_from_slice(format!("!{}", count).as_bytes());
+            out.push(character);
+        } else {
+            out.extend(std::iter::repeat(character).take(count));
+        }
+        rest = &rest[count..];
+    }
+}
+
+fn base64(data: &[u8]) -> Vec<u8> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = Vec::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let value = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(value >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+    out
+}
+
+pub struct BufferImpl<'a>(&'a mut TerminalImpl);
+
+impl<'a> BufferImpl<'a> {
+    #[inline]
+    pub fn pixels(&self) -> &[u32] {
+        &self.0.buffer.as_ref().unwrap().pixels
+    }
+
+    #[inline]
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.0.buffer.as_mut().unwrap().pixels
+    }
+
+    pub fn age(&self) -> u8 {
+        match self.0.buffer.as_ref() {
+            Some(buffer) if buffer.presented => 1,
+            _ => 0,
+        }
+    }
+
+    /// The pixels are written to the terminal, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    /// The pixels are encoded into escape sequences.
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        None
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let imp = self.0;
+        let buffer = imp.buffer.as_ref().unwrap();
+        imp.present_with_damage(&[Rect {
+            x: 0,
+            y: 0,
+            width: buffer.width,
+            height: buffer.height,
+        }])
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let imp = self.0;
+        imp.present_with_damage(damage)
+    }
+
+    /// The cells are drawn from all of the buffer.
+    pub fn present_viewport(
+        self,
+        _damage: &[Rect],
+        _viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
@@ -0,0 +1,373 @@
+//! Helpers for rendering regression tests, which present into memory instead of a window and
+//! compare the frames with reference images.
+//!
+//! A [`HeadlessSurface`] is a [`Surface`] of a framebuffer that it allocates itself, so it works
+//! without a display server, e.g. in CI. What was presented into it is taken with
+//! [`HeadlessSurface::frame`], and compared with a reference image with
+//! [`assert_frame_matches!`](crate::assert_frame_matches):
+//!
+//! ```no_run
+//! use softbuffer::assert_frame_matches;
+//! use softbuffer::testing::HeadlessSurface;
+//! use std::num::NonZeroU32;
+//!
+//! let size = NonZeroU32::new(64).unwrap();
+//! let mut surface = HeadlessSurface::new(size, size);
+//! surface.resize(size, size).unwrap();
+//! let mut buffer = surface.buffer_mut().unwrap();
+//! buffer.fill(0x00ff_8000);
+//! buffer.present().unwrap();
+//!
+//! assert_frame_matches!(surface.frame(), "tests/frames/orange.ppm");
+//! ```
+//!
+//! Reference images are binary PPM files, which most image viewers and editors open. Running
+//! the tests with the `SOFTBUFFER_BLESS` environment variable set writes the frames as the
+//! reference images, instead of comparing them.
+
+use crate::{util, FramebufferFormat, RawFramebuffer, Surface};
+use std::error::Error;
+use std::mem::ManuallyDrop;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::{env, fmt, fs, io, ops};
+
+/// The environment variable that makes [`assert_frame_matches`] write the reference images.
+const BLESS: &str = "SOFTBUFFER_BLESS";
+
+/// A [`Surface`] that presents into memory, as if into a window of a fixed size.
+///
+/// This dereferences to the [`Surface`], so it's used like any other surface. Its
+/// [`Surface::window_size`] is the size it was created with.
+pub struct HeadlessSurface {
+    /// This is dropped before the memory it presents into is freed.
+    surface: ManuallyDrop<Surface>,
+    /// The memory of a boxed slice, which is only accessed through this pointer, since the
+    /// surface writes through it too.
+    memory: NonNull<[u32]>,
+    width: NonZeroU32,
+    height: NonZeroU32,
+}
+
+// SAFETY: The memory is owned like a `Box`, and the surface of a framebuffer is `Send`.
+unsafe impl Send for HeadlessSurface {}
+
+impl HeadlessSurface {
+    /// Creates a surface that presents into a black framebuffer of the given size.
+    pub fn new(width: NonZeroU32, height: NonZeroU32) -> Self {
+        let memory = vec![0u32; width.get() as usize * height.get() as usize].into_boxed_slice();
+        let memory = NonNull::new(Box::into_raw(memory)).unwrap();
+        let framebuffer = RawFramebuffer {
+            ptr: memory.cast(),
+            width,
+            height,
+            stride: width.get() as usize * 4,
+            format: FramebufferFormat::Bgrx8888,
+        };
+        // SAFETY: The memory is only freed after the surface is dropped.
+        let surface = unsafe { Surface::from_framebuffer(framebuffer) }
+            .expect("A framebuffer with rows of pixels is valid");
+        Self {
+            surface: ManuallyDrop::new(surface),
+            memory,
+            width,
+            height,
+        }
+    }
+
+    /// What was presented into the surface so far.
+    pub fn frame(&self) -> Frame {
+        let start = self.memory.cast::<u32>().as_ptr();
+        let pixels = (0..self.memory.len())
+            // SAFETY: The memory is valid until `drop()`, and not written to during this call.
+            .map(|i| u32::from_le(unsafe { start.add(i).read() }) & 0x00ff_ffff)
+            .collect();
+        Frame::new(self.width, self.height, pixels)
+    }
+}
+
+impl Drop for HeadlessSurface {
+    fn drop(&mut self) {
+        // SAFETY: The surface isn't used after this, and the memory is that of the box of `new()`.
+        unsafe {
+            ManuallyDrop::drop(&mut self.surface);
+            drop(Box::from_raw(self.memory.as_ptr()));
+        }
+    }
+}
+
+impl ops::Deref for HeadlessSurface {
+    type Target = Surface;
+
+    fn deref(&self) -> &Surface {
+        &self.surface
+    }
+}
+
+impl ops::DerefMut for HeadlessSurface {
+    fn deref_mut(&mut self) -> &mut Surface {
+        &mut self.surface
+    }
+}
+
+/// An image in the format of the pixels of a [`Buffer`](crate::Buffer), `0x00RRGGBB`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Frame {
+    width: NonZeroU32,
+    height: NonZeroU32,
+    pixels: Vec<u32>,
+}
+
+impl Frame {
+    /// Creates a frame from its pixels, row by row.
+    ///
+    /// # Panics
+    ///
+    /// If `pixels` isn't `width * height` long.
+    pub fn new(width: NonZeroU32, height: NonZeroU32, pixels: Vec<u32>) -> Self {
+        assert_eq!(
+            pixels.len(),
+            width.get() as usize * height.get() as usize,
+            "`pixels` must have `width * height` pixels"
+        );
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// The width of the frame in pixels.
+    pub fn width(&self) -> NonZeroU32 {
+        self.width
+    }
+
+    /// The height of the frame in pixels.
+    pub fn height(&self) -> NonZeroU32 {
+        self.height
+    }
+
+    /// The pixels of the frame, row by row.
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    /// Reads a frame from a binary PPM image with 8 bits per channel.
+    pub fn from_ppm(ppm: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+        let mut rest = ppm
+            .strip_prefix(b"P6")
+            .ok_or_else(|| invalid("Not a binary PPM image"))?;
+        let mut field = || -> io::Result<u32> {
+            // Skip whitespace and comments before the field.
+            loop {
+                match rest.first() {
+                    Some(byte) if byte.is_ascii_whitespace() => rest = &rest[1..],
+                    Some(b'#') => {
+                        let end = rest.iter().position(|&byte| byte == b'\n');
+                        rest = &rest[end.map_or(rest.len(), |end| end + 1)..];
+                    }
+                    _ => break,
+                }
+            }
+            let len = rest.iter().take_while(|byte| byte.is_ascii_digit()).count();
+            let value = std::str::from_utf8(&rest[..len])
+                .ok()
+                .and_then(|digits| digits.parse().ok())
+                .ok_or_else(|| invalid("Invalid PPM header"))?;
+            rest = &rest[len..];
+            Ok(value)
+        };
+        let (width, height, max) = (field()?, field()?, field()?);
+        if max != 255 {
+            return Err(invalid(
+                "Only PPM images with 8 bits per channel are supported",
+            ));
+        }
+        let (width, height) = NonZeroU32::new(width)
+            .zip(NonZeroU32::new(height))
+            .ok_or_else(|| invalid("The PPM image is empty"))?;
+
+        // A single whitespace character ends the header.
+        let data = rest.get(1..).unwrap_or_default();
+        let len = width.get() as usize * height.get() as usize;
+        if data.len() != len * 3 {
+            return Err(invalid("The pixels of the PPM image don't match its size"));
+        }
+        let pixels = data
+            .chunks_exact(3)
+            .map(|rgb| u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]]))
+            .collect();
+        Ok(Self::new(width, height, pixels))
+    }
+
+    /// Writes the frame as a binary PPM image.
+    pub fn to_ppm(&self) -> Vec<u8> {
+        util::ppm(&self.pixels, self.width.get(), self.height.get())
+    }
+
+    /// Reads a frame from a binary PPM file, see [`Frame::from_ppm`].
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_ppm(&fs::read(path)?)
+    }
+
+    /// Writes the frame to a binary PPM file, creating the directories it is in.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_ppm())
+    }
+
+    /// Compares the frame with a reference, allowing each channel of each pixel to differ by up
+    /// to `tolerance`, e.g. for rounding differences of blending.
+    pub fn compare(&self, reference: &Frame, tolerance: u8) -> Result<(), Mismatch> {
+        if (self.width, self.height) != (reference.width, reference.height) {
+            return Err(Mismatch::Size {
+                frame: (self.width, self.height),
+                reference: (reference.width, reference.height),
+            });
+        }
+
+        let difference = |a: u32, b: u32| {
+            (0..3)
+                .map(|channel| {
+                    let (a, b) = ((a >> (channel * 8)) as u8, (b >> (channel * 8)) as u8);
+                    a.abs_diff(b)
+                })
+                .max()
+                .unwrap()
+        };
+        let (mut count, mut first, mut max_difference) = (0, None, 0);
+        for (i, (&a, &b)) in self.pixels.iter().zip(&reference.pixels).enumerate() {
+            let difference = difference(a, b);
+            if difference > tolerance {
+                count += 1;
+                first.get_or_insert(i);
+                max_difference = max_difference.max(difference);
+            }
+        }
+        match first {
+            None => Ok(()),
+            Some(i) => Err(Mismatch::Pixels {
+                count,
+                first: (i as u32 % self.width, i as u32 / self.width),
+                max_difference,
+            }),
+        }
+    }
+}
+
+/// How a [`Frame`] differs from a reference, see [`Frame::compare`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Mismatch {
+    /// The frames have different sizes.
+    Size {
+        /// The width and height of the frame.
+        frame: (NonZeroU32, NonZeroU32),
+        /// The width and height of the reference.
+        reference: (NonZeroU32, NonZeroU32),
+    },
+
+    /// Pixels differ by more than the tolerance.
+    Pixels {
+        /// How many pixels differ.
+        count: usize,
+        /// The position of the first pixel that differs, row by row.
+        first: (u32, u32),
+        /// The largest difference of a channel.
+        max_difference: u8,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Size { frame, reference } => write!(
+                f,
+                "The frame is {}x{}, but the reference is {}x{}.",
+                frame.0, frame.1, reference.0, reference.1
+            ),
+            Self::Pixels {
+                count,
+                first,
+                max_difference,
+            } => write!(
+                f,
+                "{count} pixels differ, the first at ({}, {}), by up to {max_difference}.",
+                first.0, first.1
+            ),
+        }
+    }
+}
+
+impl Error for Mismatch {}
+
+/// Asserts that `frame` matches the reference image at `reference` with `tolerance`, see
+/// [`Frame::compare`]. This is what [`assert_frame_matches!`](crate::assert_frame_matches)
+/// calls.
+///
+/// With the `SOFTBUFFER_BLESS` environment variable set, this writes `frame` to `reference`
+/// instead.
+///
+/// # Panics
+///
+/// If the frame doesn't match, or the reference can't be read. If it doesn't match, the frame
+/// is written next to the reference, with the extension `actual.ppm`, to look at.
+#[track_caller]
+pub fn assert_frame_matches(frame: &Frame, reference: impl AsRef<Path>, tolerance: u8) {
+    let reference = reference.as_ref();
+    if env::var_os(BLESS).is_some() {
+        if let Err(err) = frame.save(reference) {
+            panic!("Failed to write {}: {err}", reference.display());
+        }
+        return;
+    }
+
+    let expected = match Frame::load(reference) {
+        Ok(expected) => expected,
+        Err(err) => panic!(
+            "Failed to read the reference image {}: {err}\n\
+             Run the test with {BLESS}=1 to write it.",
+            reference.display()
+        ),
+    };
+    if let Err(mismatch) = frame.compare(&expected, tolerance) {
+        let actual = reference.with_extension("actual.ppm");
+        let saved = match frame.save(&actual) {
+            Ok(()) => format!("The frame was written to {}.", actual.display()),
+            Err(err) => format!("Failed to write the frame to {}: {err}", actual.display()),
+        };
+        panic!(
+            "The frame doesn't match {}: {mismatch}\n{saved}",
+            reference.display()
+        );
+    }
+}
+
+/// Asserts that a [`Frame`](crate::testing::Frame) matches a reference image, allowing each
+/// channel to differ by a tolerance, which is `0` if it's left out.
+///
+/// This calls [`testing::assert_frame_matches`](crate::testing::assert_frame_matches).
+///
+/// ```no_run
+/// # use softbuffer::assert_frame_matches;
+/// # let frame = softbuffer::testing::HeadlessSurface::new(
+/// #     std::num::NonZeroU32::new(1).unwrap(),
+/// #     std::num::NonZeroU32::new(1).unwrap(),
+/// # ).frame();
+/// assert_frame_matches!(frame, "tests/frames/button.ppm");
+/// assert_frame_matches!(frame, "tests/frames/button.ppm", 2);
+/// ```
+#[macro_export]
+macro_rules! assert_frame_matches {
+    ($frame:expr, $reference:expr $(,)?) => {
+        $crate::testing::assert_frame_matches(&$frame, $reference, 0)
+    };
+    ($frame:expr, $reference:expr, $tolerance:expr $(,)?) => {
+        $crate::testing::assert_frame_matches(&$frame, $reference, $tolerance)
+    };
+}
@@ -0,0 +1,10 @@
+//! Tracing of the calls that can make frames late, behind the `tracing` feature.
+
+/// Enters a `tracing` span at the trace level until the end of the enclosing scope, if the
+/// `tracing` feature is enabled. This takes the same arguments as `tracing::trace_span!`.
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!($($args)*).entered();
+    };
+}
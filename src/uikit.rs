@@ -0,0 +1,423 @@
+use crate::{
+    stats, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus, Rect, Scaling,
+    ScalingFilter, SoftBufferError, Visibility,
+};
+use core_foundation::base::TCFType;
+use core_foundation::string::{CFString, CFStringRef};
+use core_graphics::base::{
+    kCGBitmapByteOrder32Little, kCGImageAlphaNoneSkipFirst, kCGRenderingIntentDefault, CGFloat,
+};
+use core_graphics::color::CGColor;
+use core_graphics::color_space::CGColorSpace;
+use core_graphics::data_provider::CGDataProvider;
+use core_graphics::geometry::CGRect;
+use core_graphics::image::CGImage;
+use foreign_types::ForeignType;
+use objc::runtime::{Object, NO, YES};
+use raw_window_handle::UiKitWindowHandle;
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[allow(non_camel_case_types)]
+type id = *mut Object;
+const NIL: id = std::ptr::null_mut();
+
+/// `UIApplicationStateBackground`.
+const APPLICATION_STATE_BACKGROUND: isize = 2;
+
+#[link(name = "UIKit", kind = "framework")]
+extern "C" {}
+
+#[link(name = "QuartzCore", kind = "framework")]
+extern "C" {}
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    static kCGColorSpaceSRGB: CFStringRef;
+    static kCGColorSpaceDisplayP3: CFStringRef;
+    static kCGColorSpaceLinearSRGB: CFStringRef;
+}
+
+struct Buffer(Vec<u32>);
+
+impl AsRef<[u8]> for Buffer {
+    fn as_ref(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.0)
+    }
+}
+
+pub struct UIKitImpl {
+    /// The `CALayer` that the buffer is set as the contents of.
+    layer: id,
+    view: id,
+    color_space: CGColorSpace,
+    width: u32,
+    height: u32,
+    /// The scale factor set with `set_scale_factor()`, instead of that of the screen.
+    scale_factor: Option<CGFloat>,
+}
+
+impl UIKitImpl {
+    pub unsafe fn new(handle: UiKitWindowHandle) -> Result<Self, SoftBufferError> {
+        let view = handle.ui_view as id;
+        if view.is_null() {
+            return Err(SoftBufferError::IncompleteWindowHandle);
+        }
+
+        let mut this = unsafe {
+            let view: id = msg_send![view, retain];
+            let layer: id = msg_send![class!(CALayer), new];
+            let gravity = CFString::from_static_string("topLeft");
+            let _: () = msg_send![layer, setContentsGravity: gravity.as_concrete_TypeRef() as id];
+            let _: () = msg_send![layer, setNeedsDisplayOnBoundsChange: NO];
+            // The alpha channel of our buffer is unused.
+            let _: () = msg_send![layer, setOpaque: YES];
+
+            // Add a sublayer instead of replacing the layer of the view, which UIKit owns.
+            let view_layer: id = msg_send![view, layer];
+            let bounds: CGRect = msg_send![view_layer, bounds];
+            let _: () = msg_send![layer, setFrame: bounds];
+            let _: () = msg_send![view_layer, addSublayer: layer];
+
+            Self {
+                layer,
+                view,
+                color_space: CGColorSpace::create_device_rgb(),
+                width: 0,
+                height: 0,
+                scale_factor: None,
+            }
+        };
+        this.set_color_space(ColorSpace::default())?;
+        Ok(this)
+    }
+
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
+        self.width = width.get();
+        self.height = height.get();
+        Ok(())
+    }
+
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        Ok(BufferImpl {
+            buffer: vec![0; self.width as usize * self.height as usize],
+            imp: self,
+        })
+    }
+
+    /// Fetch the buffer from the window.
+    pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// Not implemented yet, see `Surface::release_buffers()`.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Wait for the next vertical blank of the display.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Get the refresh interval of the screen at its highest rate. ProMotion screens lower their
+    /// rate when the content doesn't change, which isn't reported.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        let frames_per_second: isize = unsafe { msg_send![self.screen(), maximumFramesPerSecond] };
+        match u32::try_from(frames_per_second) {
+            Ok(frames_per_second) if frames_per_second > 0 => {
+                Ok(Duration::from_secs(1) / frames_per_second)
+            }
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// Get the size of the view in physical pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        let (bounds, scale) = unsafe { (self.view_bounds(), self.scale()) };
+        Ok((
+            (bounds.size.width * scale).round() as u32,
+            (bounds.size.height * scale).round() as u32,
+        ))
+    }
+
+    /// Not implemented yet, see `Surface::visibility()`.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set the contents gravity and filters of the layer.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        let gravity = CFString::from_static_string(match scaling {
+            Scaling::TopLeft => "topLeft",
+            Scaling::Center => "center",
+            Scaling::AspectFit => "resizeAspect",
+            Scaling::Fill => "resize",
+        });
+        let filter = CFString::from_static_string(match filter {
+            ScalingFilter::Linear => "linear",
+            ScalingFilter::Nearest => "nearest",
+        });
+
+        unsafe {
+            let filter = filter.as_concrete_TypeRef() as id;
+            let _: () =
+                msg_send![self.layer, setContentsGravity: gravity.as_concrete_TypeRef() as id];
+            let _: () = msg_send![self.layer, setMagnificationFilter: filter];
+            let _: () = msg_send![self.layer, setMinificationFilter: filter];
+        }
+
+        Ok(())
+    }
+
+    /// Set the background color of the layer, which is shown around the contents.
+    pub fn set_background_color(&mut self, color: u32) -> Result<(), SoftBufferError> {
+        let channel = |shift: u32| CGFloat::from((color >> shift) as u8) / 255.0;
+        let color = CGColor::rgb(channel(16), channel(8), channel(0), 1.0);
+        unsafe {
+            let _: () = msg_send![self.layer, setBackgroundColor: color.as_concrete_TypeRef()];
+        }
+        Ok(())
+    }
+
+    /// Core Animation doesn't make presents wait.
+    pub fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the color space that the images are created in.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        // SAFETY: The names are immutable statics provided by the framework.
+        let name = unsafe {
+            match color_space {
+                ColorSpace::Srgb => kCGColorSpaceSRGB,
+                ColorSpace::DisplayP3 => kCGColorSpaceDisplayP3,
+                ColorSpace::LinearSrgb => kCGColorSpaceLinearSRGB,
+            }
+        };
+        self.color_space = CGColorSpace::create_with_name(name).ok_or_else(|| {
+            SoftBufferError::PlatformError(Some("Failed to create CGColorSpace".into()), None)
+        })?;
+        Ok(())
+    }
+
+    /// The window server keeps reading the last buffer after a present.
+    pub fn set_single_buffered(&mut self, single: bool) -> Result<(), SoftBufferError> {
+        if single {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rows are always shown top-down.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        if flip {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// UIKit keeps showing the contents of the layer until the next present.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the scale of the contents of the layer, instead of taking that of the screen.
+    pub fn set_scale_factor(&mut self, scale_factor: f64) -> Result<(), SoftBufferError> {
+        self.scale_factor = Some(scale_factor as CGFloat);
+        Ok(())
+    }
+
+    /// Use the mask of the view instead.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        // The contents of the layer are replaced as a whole, and uploaded into a texture, which
+        // can be up to 8192 pixels wide and high on every GPU that runs iOS 12.
+        Capabilities {
+            partial_present: false,
+            zero_copy: false,
+            max_size: (8192, 8192),
+            buffer_count: 1,
+            readback: false,
+        }
+    }
+
+    /// The layer is only given images, which are copied.
+    pub unsafe fn present_external(
+        &mut self,
+        _memory: ExternalMemory,
+        _width: NonZeroU32,
+        _height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    unsafe fn view_bounds(&self) -> CGRect {
+        unsafe { msg_send![self.view, bounds] }
+    }
+
+    /// The screen of the window of the view, or the main screen if it isn't in a window yet.
+    unsafe fn screen(&self) -> id {
+        unsafe {
+            let window: id = msg_send![self.view, window];
+            let screen: id = if window.is_null() {
+                NIL
+            } else {
+                msg_send![window, screen]
+            };
+            if screen.is_null() {
+                msg_send![class!(UIScreen), mainScreen]
+            } else {
+                screen
+            }
+        }
+    }
+
+    /// The scale of the screen the view is on, or of the main screen if it isn't in a window.
+    unsafe fn scale(&self) -> CGFloat {
+        unsafe { msg_send![self.screen(), scale] }
+    }
+
+    /// The scale factor set with `set_scale_factor()`, or that of the screen.
+    unsafe fn contents_scale(&self) -> CGFloat {
+        self.scale_factor.unwrap_or_else(|| unsafe { self.scale() })
+    }
+}
+
+pub struct BufferImpl<'a> {
+    imp: &'a mut UIKitImpl,
+    buffer: Vec<u32>,
+}
+
+impl<'a> BufferImpl<'a> {
+    #[inline]
+    pub fn pixels(&self) -> &[u32] {
+        &self.buffer
+    }
+
+    #[inline]
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.buffer
+    }
+
+    pub fn age(&self) -> u8 {
+        0
+    }
+
+    /// The pixels are copied into an image, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    /// The pixels are copied into an image when presenting.
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        None
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        // Apps may not update their UI in the background. The layer keeps showing the last frame
+        // until the app returns to the foreground and presents again.
+        if unsafe { is_in_background() } {
+            return Ok(PresentStatus::Skipped);
+        }
+
+        trace_span!("create_image");
+        let data_provider = CGDataProvider::from_buffer(Arc::new(Buffer(self.buffer)));
+        let image = CGImage::new(
+            self.imp.width as usize,
+            self.imp.height as usize,
+            8,
+            32,
+            (self.imp.width * 4) as usize,
+            &self.imp.color_space,
+            kCGBitmapByteOrder32Little | kCGImageAlphaNoneSkipFirst,
+            &data_provider,
+            false,
+            kCGRenderingIntentDefault,
+        );
+
+        // Disable the implicit animation of the layer contents, see the macOS backend.
+        unsafe {
+            trace_span!("set_contents");
+            let layer = self.imp.layer;
+            let _: () = msg_send![class!(CATransaction), begin];
+            let _: () = msg_send![class!(CATransaction), setDisableActions: YES];
+
+            // Sublayers aren't resized with the view on iOS, so follow it here. The view may
+            // also have moved to a screen with another scale.
+            let _: () = msg_send![layer, setFrame: self.imp.view_bounds()];
+            let _: () = msg_send![layer, setContentsScale: self.imp.contents_scale()];
+            let _: () = msg_send![layer, setContents: image.as_ptr() as id];
+
+            let _: () = msg_send![class!(CATransaction), commit];
+        }
+
+        Ok(PresentStatus::Presented)
+    }
+
+    pub fn present_with_damage(self, _damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        self.present()
+    }
+
+    /// The layer shows all of its contents.
+    pub fn present_viewport(
+        self,
+        _damage: &[Rect],
+        _viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+/// Whether the application is in the background.
+///
+/// # Safety
+///
+/// Must be called on the main thread.
+unsafe fn is_in_background() -> bool {
+    unsafe {
+        let application: id = msg_send![class!(UIApplication), sharedApplication];
+        if application.is_null() {
+            return false;
+        }
+        let state: isize = msg_send![application, applicationState];
+        state == APPLICATION_STATE_BACKGROUND
+    }
+}
+
+impl Drop for UIKitImpl {
+    fn drop(&mut self) {
+        unsafe {
+            let _: () = msg_send![self.layer, removeFromSuperlayer];
+            let _: () = msg_send![self.layer, release];
+            let _: () = msg_send![self.view, release];
+        }
+    }
+}
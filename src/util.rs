@@ -1,7 +1,8 @@
 // Not needed on all platforms
 #![allow(dead_code)]
 
-use crate::SoftBufferError;
+use crate::{Rect, SoftBufferError};
+use std::num::NonZeroU32;
 
 /// Takes a mutable reference to a container and a function deriving a
 /// reference into it, and stores both, making it possible to get back the
@@ -44,10 +45,307 @@ impl<'a, T: 'static + ?Sized, U: 'static + ?Sized> BorrowStack<'a, T, U> {
     }
 }
 
+/// Resize a buffer of pixels, without writing to its memory where possible.
+///
+/// New buffers have unspecified contents, so this doesn't clear them. Growing past the capacity
+/// allocates zeroed memory, which the OS hands out lazily as zero pages, instead of writing zeros
+/// into it. Shrinking keeps the memory and the old pixels in it.
+pub fn resize_pixels(buffer: &mut Vec<u32>, len: usize) {
+    if len > buffer.capacity() {
+        // Free the old memory first, so both aren't allocated at once.
+        *buffer = Vec::new();
+        *buffer = vec![0; len];
+    } else {
+        buffer.resize(len, 0);
+    }
+}
+
+/// Hash the pixels of a buffer, for noticing whether they changed.
+///
+/// This mixes the pixels into four lanes at once, so it runs at about the speed of memory. It
+/// doesn't resist collisions made on purpose, which don't matter here.
+pub fn hash_pixels(pixels: &[u32]) -> u64 {
+    const K: u64 = 0x517c_c1b7_2722_0a95;
+    let mix = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(K);
+
+    let mut lanes = [0u64; 4];
+    let chunks = pixels.chunks_exact(8);
+    let rest = chunks.remainder();
+    for chunk in chunks {
+        for (lane, pair) in lanes.iter_mut().zip(chunk.chunks_exact(2)) {
+            *lane = mix(*lane, u64::from(pair[0]) | u64::from(pair[1]) << 32);
+        }
+    }
+
+    let hash = rest
+        .iter()
+        .map(|&pixel| u64::from(pixel))
+        .fold(pixels.len() as u64, mix);
+    lanes.iter().copied().fold(hash, mix)
+}
+
+/// The rects covering the pixels of a buffer whose alpha, the highest 8 bits, is at least
+/// `threshold`, for shaping a window.
+///
+/// Each rect is a run of pixels in a row, and rows with the same runs as the row above extend
+/// the rects of that one. So the rects are sorted by `y` and then `x`, and each band of rects
+/// started in the same row has the same `y` and height, as X's `YXBanded` ordering needs.
+pub fn alpha_shape(pixels: &[u32], width: usize, threshold: u8) -> Vec<Rect> {
+    let inside = |pixel: u32| (pixel >> 24) as u8 >= threshold;
+    let mut rects: Vec<Rect> = Vec::new();
+    // The runs of the row above, and where the rects they started begin in `rects`.
+    let (mut band, mut band_start) = (Vec::new(), 0);
+    let mut runs = Vec::new();
+    for (y, row) in pixels.chunks_exact(width).enumerate() {
+        runs.clear();
+        let mut x = 0;
+        while x < width {
+            if !inside(row[x]) {
+                x += 1;
+                continue;
+            }
+            let start = x;
+            while x < width && inside(row[x]) {
+                x += 1;
+            }
+            runs.push((start as u32, x as u32));
+        }
+
+        if runs == band {
+            for rect in &mut rects[band_start..] {
+                rect.height = NonZeroU32::new(rect.height.get() + 1).unwrap();
+            }
+        } else {
+            band_start = rects.len();
+            rects.extend(runs.iter().map(|&(start, end)| Rect {
+                x: start,
+                y: y as u32,
+                width: NonZeroU32::new(end - start).unwrap(),
+                height: NonZeroU32::new(1).unwrap(),
+            }));
+            std::mem::swap(&mut band, &mut runs);
+        }
+    }
+    rects
+}
+
+/// Whether `rects` cover all pixels of a buffer of the given size.
+pub fn covers(rects: &[Rect], width: u32, height: u32) -> bool {
+    let bottom = |rect: &Rect| rect.y.saturating_add(rect.height.get());
+    let right = |rect: &Rect| rect.x.saturating_add(rect.width.get());
+    // Between two of these rows, the same rects cover each row.
+    let mut edges: Vec<u32> = rects
+        .iter()
+        .flat_map(|rect| [rect.y, bottom(rect)])
+        .chain([0, height])
+        .filter(|&y| y <= height)
+        .collect();
+    edges.sort_unstable();
+    edges.dedup();
+
+    let mut spans = Vec::new();
+    edges.windows(2).all(|band| {
+        spans.clear();
+        spans.extend(
+            rects
+                .iter()
+                .filter(|rect| rect.y <= band[0] && bottom(rect) >= band[1])
+                .map(|rect| (rect.x, right(rect))),
+        );
+        spans.sort_unstable();
+        let mut covered = 0;
+        for &(left, right) in &spans {
+            if left > covered {
+                break;
+            }
+            covered = covered.max(right);
+        }
+        covered >= width
+    })
+}
+
+/// The number of pixels of a buffer of the given size that `rects` cover.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub fn covered_area(rects: &[Rect], width: u32, height: u32) -> u64 {
+    let bottom = |rect: &Rect| rect.y.saturating_add(rect.height.get()).min(height);
+    let right = |rect: &Rect| rect.x.saturating_add(rect.width.get()).min(width);
+    // Between two of these rows, the same rects cover each row.
+    let mut edges: Vec<u32> = rects
+        .iter()
+        .flat_map(|rect| [rect.y.min(height), bottom(rect)])
+        .collect();
+    edges.sort_unstable();
+    edges.dedup();
+
+    let mut spans = Vec::new();
+    edges
+        .windows(2)
+        .map(|band| {
+            spans.clear();
+            spans.extend(
+                rects
+                    .iter()
+                    .filter(|rect| rect.y <= band[0] && bottom(rect) >= band[1])
+                    .map(|rect| (rect.x.min(width), right(rect))),
+            );
+            spans.sort_unstable();
+            let (mut covered, mut end) = (0, 0);
+            for &(left, right) in &spans {
+                let left = left.max(end);
+                if right > left {
+                    covered += u64::from(right - left);
+                    end = right;
+                }
+            }
+            covered * u64::from(band[1] - band[0])
+        })
+        .sum()
+}
+
+/// Fills a buffer of the given width with the `old` pixels of a buffer of another width in its
+/// upper-left corner, and `color` around them.
+pub fn fill_resized(pixels: &mut [u32], width: usize, old: &[u32], old_width: usize, color: u32) {
+    for (y, row) in pixels.chunks_exact_mut(width).enumerate() {
+        let kept = old
+            .get(y * old_width..(y + 1) * old_width)
+            .map_or(&[][..], |old| &old[..old_width.min(width)]);
+        row[..kept.len()].copy_from_slice(kept);
+        row[kept.len()..].fill(color);
+    }
+}
+
+/// Copies the pixels of the rect of the size of `dst` at `src` to `dst`, in a buffer of the
+/// given width. The rects may overlap.
+pub fn copy_rect(pixels: &mut [u32], width: usize, (src_x, src_y): (u32, u32), dst: &Rect) {
+    let len = dst.width.get() as usize;
+    let start = |x: u32, y: u32| y as usize * width + x as usize;
+    let copy_row = |pixels: &mut [u32], row: u32| {
+        let src = start(src_x, src_y + row);
+        pixels.copy_within(src..src + len, start(dst.x, dst.y + row));
+    };
+    // Copy the rows that overlap the source before they are overwritten.
+    if dst.y > src_y {
+        (0..dst.height.get())
+            .rev()
+            .for_each(|row| copy_row(pixels, row));
+    } else {
+        (0..dst.height.get()).for_each(|row| copy_row(pixels, row));
+    }
+}
+
+/// Fills `rect` of a buffer of the given width with copies of `tile`, which is `tile_width`
+/// pixels wide, lined up with the upper-left corner of the buffer.
+pub fn fill_tiled(pixels: &mut [u32], width: usize, rect: &Rect, tile: &[u32], tile_width: usize) {
+    let (x, len) = (rect.x as usize, rect.width.get() as usize);
+    let tile_height = tile.len() / tile_width;
+    for y in rect.y as usize..(rect.y + rect.height.get()) as usize {
+        let start = y * width + x;
+        if y - rect.y as usize >= tile_height {
+            // The row a tile above is the same, so copy all of it at once.
+            pixels.copy_within(
+                start - tile_height * width..start - tile_height * width + len,
+                start,
+            );
+            continue;
+        }
+
+        let row = &mut pixels[start..start + len];
+        let tile_row = &tile[y % tile_height * tile_width..][..tile_width];
+        let period = tile_width.min(len);
+        for (i, pixel) in row[..period].iter_mut().enumerate() {
+            *pixel = tile_row[(x + i) % tile_width];
+        }
+        // Double the filled part, which stays a whole number of tiles, until the row is full.
+        let mut filled = period;
+        while filled < len {
+            let copied = filled.min(len - filled);
+            row.copy_within(..copied, filled);
+            filled += copied;
+        }
+    }
+}
+
+/// Encodes 0RGB pixels as a binary PPM image.
+pub fn ppm(pixels: &[u32], width: u32, height: u32) -> Vec<u8> {
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    ppm.reserve(pixels.len() * 3);
+    for pixel in pixels {
+        ppm.extend_from_slice(&pixel.to_be_bytes()[1..]);
+    }
+    ppm
+}
+
+/// Draws the one pixel wide outline of `rect` in `color`, cut off at the edges of the buffer.
+pub fn outline(pixels: &mut [u32], width: u32, height: u32, rect: &Rect, color: u32) {
+    let (left, top) = (rect.x, rect.y);
+    if left >= width || top >= height {
+        return;
+    }
+    let right = left.saturating_add(rect.width.get() - 1).min(width - 1);
+    let bottom = top.saturating_add(rect.height.get() - 1).min(height - 1);
+    let row = |y: u32| (y * width) as usize;
+    for y in [top, bottom] {
+        pixels[row(y) + left as usize..=row(y) + right as usize].fill(color);
+    }
+    for y in top..=bottom {
+        pixels[row(y) + left as usize] = color;
+        pixels[row(y) + right as usize] = color;
+    }
+}
+
+/// Where the upper-left corner of an image of the given size is drawn in a window, either in the
+/// upper-left corner or centered. A centered image larger than the window is cut off equally on
+/// both sides.
+pub fn image_position(centered: bool, window: (u32, u32), image: (u32, u32)) -> (i64, i64) {
+    if !centered {
+        return (0, 0);
+    }
+    let center = |window: u32, image: u32| (i64::from(window) - i64::from(image)) / 2;
+    (center(window.0, image.0), center(window.1, image.1))
+}
+
+/// The parts of a window that an image drawn at `position` doesn't cover, as the rects above,
+/// below, left and right of it.
+pub fn uncovered(window: (u32, u32), position: (i64, i64), image: (u32, u32)) -> Vec<Rect> {
+    let clip = |start: i64, len: u32, max: u32| {
+        let clamp = |x: i64| x.clamp(0, max.into()) as u32;
+        (clamp(start), clamp(start + i64::from(len)))
+    };
+    let (left, right) = clip(position.0, image.0, window.0);
+    let (top, bottom) = clip(position.1, image.1, window.1);
+    let rect = |x, y, width, height| {
+        Some(Rect {
+            x,
+            y,
+            width: NonZeroU32::new(width)?,
+            height: NonZeroU32::new(height)?,
+        })
+    };
+    [
+        rect(0, 0, window.0, top),
+        rect(0, bottom, window.0, window.1 - bottom),
+        rect(0, top, left, bottom - top),
+        rect(right, top, window.0 - right, bottom - top),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
 
+    /// A rect of the given position and size, which must not be `0`.
+    pub fn rect(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width: NonZeroU32::new(width).unwrap(),
+            height: NonZeroU32::new(height).unwrap(),
+        }
+    }
+
     #[test]
     fn test_borrowstack_slice_int() {
         fn f(mut stack: BorrowStack<[u32], u32>) {
@@ -60,4 +358,152 @@ mod tests {
         f(BorrowStack::new(v.as_mut(), |v: &mut [u32]| Ok(&mut v[2])).unwrap());
         assert_eq!(&v, &[1, 2, 42, 4, 5]);
     }
+
+    #[test]
+    fn test_hash_pixels_changes() {
+        let mut pixels = vec![0x00ff_ffff; 37];
+        let hash = hash_pixels(&pixels);
+        assert_eq!(hash_pixels(&pixels.clone()), hash);
+        // Every pixel counts, including the ones past the last full chunk.
+        for i in 0..pixels.len() {
+            pixels[i] ^= 1;
+            assert_ne!(hash_pixels(&pixels), hash);
+            pixels[i] ^= 1;
+        }
+        assert_ne!(hash_pixels(&pixels[..36]), hash);
+    }
+
+    #[test]
+    fn test_alpha_shape_merges_rows() {
+        const IN: u32 = 0xff00_0000;
+        #[rustfmt::skip]
+        let pixels = [
+            IN, IN, 0, IN,
+            IN, IN, 0, IN,
+            0, 0x7f00_0000, 0, 0,
+        ];
+        assert_eq!(
+            alpha_shape(&pixels, 4, 0x80),
+            [rect(0, 0, 2, 2), rect(3, 0, 1, 2)]
+        );
+        assert_eq!(
+            alpha_shape(&pixels, 4, 0x7f),
+            [rect(0, 0, 2, 2), rect(3, 0, 1, 2), rect(1, 2, 1, 1)]
+        );
+        assert_eq!(alpha_shape(&[0; 8], 4, 1), []);
+    }
+
+    #[test]
+    fn test_rect_geometry() {
+        let a = rect(0, 0, 4, 2);
+        let b = rect(2, 1, 4, 4);
+        assert_eq!(a.intersection(&b), Some(rect(2, 1, 2, 1)));
+        assert_eq!(a.intersection(&rect(4, 0, 1, 1)), None);
+        assert_eq!(a.union(&b), rect(0, 0, 6, 5));
+        assert!(b.contains(5, 4) && !b.contains(6, 4) && !b.contains(1, 1));
+
+        let size = NonZeroU32::new(3).unwrap();
+        assert_eq!(b.clamp(size, size), Some(rect(2, 1, 1, 2)));
+        assert_eq!(rect(3, 0, 1, 1).clamp(size, size), None);
+        assert_eq!(
+            rect(1, 2, 2, 2).pixels().collect::<Vec<_>>(),
+            [(1, 2), (2, 2), (1, 3), (2, 3)]
+        );
+    }
+
+    #[test]
+    fn test_uncovered_around_centered_image() {
+        let position = image_position(true, (6, 4), (2, 3));
+        assert_eq!(position, (2, 0));
+        assert_eq!(
+            uncovered((6, 4), position, (2, 3)),
+            [rect(0, 3, 6, 1), rect(0, 0, 2, 3), rect(4, 0, 2, 3)]
+        );
+
+        // Larger images are cut off, and cover the window.
+        let position = image_position(true, (2, 2), (5, 4));
+        assert_eq!(position, (-1, -1));
+        assert_eq!(uncovered((2, 2), position, (5, 4)), []);
+        assert_eq!(image_position(false, (2, 2), (5, 4)), (0, 0));
+    }
+
+    #[test]
+    fn test_covers() {
+        assert!(covers(&[rect(0, 0, 4, 3)], 4, 3));
+        assert!(covers(
+            &[rect(0, 0, 2, 3), rect(1, 0, 3, 2), rect(2, 2, 2, 1)],
+            4,
+            3
+        ));
+        assert!(!covers(&[rect(0, 0, 2, 3), rect(3, 0, 1, 3)], 4, 3));
+        assert!(!covers(&[rect(0, 0, 4, 2)], 4, 3));
+        assert!(!covers(&[], 4, 3));
+    }
+
+    #[test]
+    fn test_covered_area() {
+        assert_eq!(covered_area(&[rect(0, 0, 4, 3)], 4, 3), 12);
+        assert_eq!(
+            covered_area(
+                &[rect(0, 0, 2, 3), rect(1, 0, 3, 2), rect(2, 2, 2, 1)],
+                4,
+                3
+            ),
+            12
+        );
+        assert_eq!(covered_area(&[rect(0, 0, 2, 2), rect(1, 1, 2, 2)], 4, 3), 7);
+        assert_eq!(covered_area(&[rect(3, 2, 5, 5)], 4, 3), 1);
+        assert_eq!(covered_area(&[rect(4, 0, 1, 1)], 4, 3), 0);
+        assert_eq!(covered_area(&[], 4, 3), 0);
+    }
+
+    #[test]
+    fn test_fill_resized() {
+        let mut pixels = vec![0; 3 * 3];
+        fill_resized(&mut pixels, 3, &[1, 2, 3, 4, 5, 6, 7, 8], 4, 9);
+        assert_eq!(pixels, [1, 2, 3, 5, 6, 7, 9, 9, 9]);
+        fill_resized(&mut pixels, 3, &[], 0, 9);
+        assert_eq!(pixels, [9; 9]);
+    }
+
+    #[test]
+    fn test_copy_rect() {
+        let mut pixels: Vec<u32> = (0..9).collect();
+        // Scroll down by a row, and then the right column to the left.
+        copy_rect(&mut pixels, 3, (0, 0), &rect(0, 1, 3, 2));
+        assert_eq!(pixels, [0, 1, 2, 0, 1, 2, 3, 4, 5]);
+        copy_rect(&mut pixels, 3, (1, 0), &rect(0, 0, 2, 3));
+        assert_eq!(pixels, [1, 2, 2, 1, 2, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn test_fill_tiled() {
+        let mut pixels = vec![0; 5 * 4];
+        // A checkerboard of 1 and 2, and a column of 3.
+        fill_tiled(&mut pixels, 5, &rect(1, 1, 4, 3), &[1, 2, 3, 2, 1, 3], 3);
+        #[rustfmt::skip]
+        let expected = [
+            0, 0, 0, 0, 0,
+            0, 1, 3, 2, 1,
+            0, 2, 3, 1, 2,
+            0, 1, 3, 2, 1,
+        ];
+        assert_eq!(pixels, expected);
+    }
+
+    #[test]
+    fn test_outline() {
+        let mut pixels = vec![0; 4 * 4];
+        outline(&mut pixels, 4, 4, &rect(1, 0, 3, 3), 1);
+        outline(&mut pixels, 4, 4, &rect(3, 3, 5, 5), 2);
+        outline(&mut pixels, 4, 4, &rect(4, 0, 1, 1), 3);
+        #[rustfmt::skip]
+        let expected = [
+            0, 1, 1, 1,
+            0, 1, 0, 1,
+            0, 1, 1, 1,
+            0, 0, 0, 2,
+        ];
+        assert_eq!(pixels, expected);
+    }
 }
@@ -0,0 +1,47 @@
+//! The view into a virtual surface, see [`Surface::set_view_size`].
+//!
+//! [`Surface::set_view_size`]: crate::Surface::set_view_size
+
+use crate::Rect;
+use std::num::NonZeroU32;
+
+/// The part of a buffer larger than the window that is shown, and what was shown last.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct View {
+    /// The size of the view, if the surface is virtual.
+    pub size: Option<(NonZeroU32, NonZeroU32)>,
+    /// The upper-left corner of the view in the buffer.
+    pub pan: (u32, u32),
+    /// The viewport shown by the last present, if it is still on screen.
+    pub shown: Option<Rect>,
+}
+
+impl View {
+    /// The part of a buffer of the given size that is shown, if the surface is virtual.
+    ///
+    /// The view is kept inside the buffer, and is cut down to it if the buffer is smaller.
+    pub fn viewport(&self, (width, height): (NonZeroU32, NonZeroU32)) -> Option<Rect> {
+        let (view_width, view_height) = self.size?;
+        let (view_width, view_height) = (view_width.min(width), view_height.min(height));
+        Some(Rect {
+            x: self.pan.0.min(width.get() - view_width.get()),
+            y: self.pan.1.min(height.get() - view_height.get()),
+            width: view_width,
+            height: view_height,
+        })
+    }
+
+    /// The damage to present so that `viewport` is shown, given the damage of the buffer.
+    ///
+    /// After panning, every pixel of the window changes, so all of the viewport is damaged.
+    /// Otherwise only the damage inside of the viewport is shown.
+    pub fn damage(&self, viewport: Rect, damage: &[Rect]) -> Vec<Rect> {
+        if self.shown != Some(viewport) {
+            return vec![viewport];
+        }
+        damage
+            .iter()
+            .filter_map(|rect| rect.intersection(&viewport))
+            .collect()
+    }
+}
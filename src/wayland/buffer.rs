@@ -1,6 +1,10 @@
+use crate::error::SwResultExt;
+use crate::{error, Backend, SoftBufferError};
 use memmap2::MmapMut;
+#[cfg(target_os = "linux")]
+use memmap2::RemapOptions;
 use std::{
-    ffi::CStr,
+    ffi::{c_void, CStr},
     fs::File,
     os::unix::prelude::{AsRawFd, FromRawFd},
     slice,
@@ -11,10 +15,10 @@ use std::{
 };
 use wayland_client::{
     protocol::{wl_buffer, wl_shm, wl_shm_pool, wl_surface},
-    Connection, Dispatch, QueueHandle,
+    Connection, Dispatch, Proxy, QueueHandle,
 };
 
-use super::State;
+use super::{State, MAX_POOL_SIZE};
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
 fn create_memfile() -> File {
@@ -74,93 +78,143 @@ fn create_memfile() -> File {
     panic!("Failed to generate non-existant shm name")
 }
 
-// Round size to use for pool for given dimentions, rounding up to power of 2
-fn get_pool_size(width: i32, height: i32) -> i32 {
-    ((width * height * 4) as u32).next_power_of_two() as i32
+// Round size to use for pool for the given number of bytes, rounding up to power of 2
+fn get_pool_size(bytes: i32) -> Result<i32, SoftBufferError> {
+    u32::try_from(bytes)
+        .ok()
+        .and_then(u32::checked_next_power_of_two)
+        .and_then(|size| i32::try_from(size).ok())
+        .swbuf_err("Wayland shm pool is too large")
 }
 
 unsafe fn map_file(file: &File) -> MmapMut {
     unsafe { MmapMut::map_mut(file.as_raw_fd()).expect("Failed to map shared memory") }
 }
 
-pub(super) struct WaylandBuffer {
-    qh: QueueHandle<State>,
+/// The shared memory that the buffers of a surface are sub-allocated from. It grows with them,
+/// instead of being created again for every resize.
+pub(super) struct ShmPool {
     tempfile: File,
     map: MmapMut,
     pool: wl_shm_pool::WlShmPool,
-    pool_size: i32,
+    size: i32,
+}
+
+impl ShmPool {
+    pub fn new(
+        shm: &wl_shm::WlShm,
+        bytes: i32,
+        qh: &QueueHandle<State>,
+    ) -> Result<Self, SoftBufferError> {
+        trace_span!("create_pool", bytes);
+        let size = get_pool_size(bytes)?;
+
+        // Create an `mmap` shared memory
+        let tempfile = create_memfile();
+        let _ = tempfile.set_len(size as u64);
+        let map = unsafe { map_file(&tempfile) };
+
+        let pool = shm.create_pool(tempfile.as_raw_fd(), size, qh, ());
+        Ok(Self {
+            tempfile,
+            map,
+            pool,
+            size,
+        })
+    }
+
+    /// Grow the pool to hold at least `bytes`, keeping the buffers that were allocated in it.
+    fn grow(&mut self, bytes: i32) -> Result<(), SoftBufferError> {
+        let size = get_pool_size(bytes)?;
+        if size <= self.size {
+            return Ok(());
+        }
+        trace_span!("resize_pool", size);
+        let _ = self.tempfile.set_len(size as u64);
+        self.pool.resize(size);
+        self.size = size;
+        // Extend the mapping in place if possible, else move it, without unmapping it first.
+        #[cfg(target_os = "linux")]
+        let remapped = unsafe {
+            self.map
+                .remap(size as usize, RemapOptions::new().may_move(true))
+                .is_ok()
+        };
+        #[cfg(not(target_os = "linux"))]
+        let remapped = false;
+        if !remapped {
+            self.map = unsafe { map_file(&self.tempfile) };
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ShmPool {
+    fn drop(&mut self) {
+        // The compositor keeps the memory until the buffers created from it are destroyed too.
+        self.pool.destroy();
+    }
+}
+
+pub(super) struct WaylandBuffer {
+    qh: QueueHandle<State>,
     buffer: wl_buffer::WlBuffer,
+    /// Where the pixels of the buffer start in the pool, in bytes.
+    offset: i32,
     width: i32,
     height: i32,
     released: Arc<AtomicBool>,
     pub age: u8,
+    /// Which of the two buffers of the surface this is, and so which slot of the pool it's in.
+    pub index: usize,
 }
 
 impl WaylandBuffer {
-    pub fn new(shm: &wl_shm::WlShm, width: i32, height: i32, qh: &QueueHandle<State>) -> Self {
-        // Calculate size to use for shm pool
-        let pool_size = get_pool_size(width, height);
-
-        // Create an `mmap` shared memory
-        let tempfile = create_memfile();
-        let _ = tempfile.set_len(pool_size as u64);
-        let map = unsafe { map_file(&tempfile) };
-
-        // Create wayland shm pool and buffer
-        let pool = shm.create_pool(tempfile.as_raw_fd(), pool_size, qh, ());
+    /// Allocate a buffer in the slot of `index` in `pool`.
+    pub fn new(
+        pool: &mut ShmPool,
+        width: i32,
+        height: i32,
+        index: usize,
+        qh: &QueueHandle<State>,
+    ) -> Result<Self, SoftBufferError> {
+        trace_span!("create_buffer", width, height);
         let released = Arc::new(AtomicBool::new(true));
-        let buffer = pool.create_buffer(
-            0,
-            width,
-            height,
-            width * 4,
-            wl_shm::Format::Xrgb8888,
-            qh,
-            released.clone(),
-        );
+        let offset = place(pool, index, width, height)?;
+        let buffer = create_buffer(pool, offset, width, height, qh, &released);
 
-        Self {
+        Ok(Self {
             qh: qh.clone(),
-            map,
-            tempfile,
-            pool,
-            pool_size,
             buffer,
+            offset,
             width,
             height,
             released,
             age: 0,
-        }
+            index,
+        })
     }
 
-    pub fn resize(&mut self, width: i32, height: i32) {
+    /// Resize the buffer in its slot of `pool`, which the other buffer of the surface never
+    /// overlaps.
+    pub fn resize(
+        &mut self,
+        pool: &mut ShmPool,
+        width: i32,
+        height: i32,
+    ) -> Result<(), SoftBufferError> {
         // If size is the same, there's nothing to do
         if self.width != width || self.height != height {
+            self.offset = place(pool, self.index, width, height)?;
             // Destroy old buffer
             self.buffer.destroy();
-
-            // Grow pool, if needed
-            let size = ((width * height * 4) as u32).next_power_of_two() as i32;
-            if size > self.pool_size {
-                let _ = self.tempfile.set_len(size as u64);
-                self.pool.resize(size);
-                self.pool_size = size;
-                self.map = unsafe { map_file(&self.tempfile) };
-            }
-
-            // Create buffer with correct size
-            self.buffer = self.pool.create_buffer(
-                0,
-                width,
-                height,
-                width * 4,
-                wl_shm::Format::Xrgb8888,
-                &self.qh,
-                self.released.clone(),
-            );
+            self.buffer = create_buffer(pool, self.offset, width, height, &self.qh, &self.released);
             self.width = width;
             self.height = height;
+            // The pixels of the old size don't line up with the new rows.
+            self.age = 0;
         }
+        Ok(())
     }
 
     pub fn attach(&self, surface: &wl_surface::WlSurface) {
@@ -168,6 +222,11 @@ impl WaylandBuffer {
         surface.attach(Some(&self.buffer), 0, 0);
     }
 
+    /// The `wl_buffer` as a `wl_proxy` of libwayland-client.
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.buffer.id().as_ptr().cast()
+    }
+
     pub fn released(&self) -> bool {
         self.released.load(Ordering::SeqCst)
     }
@@ -176,18 +235,77 @@ impl WaylandBuffer {
         self.width as usize * self.height as usize
     }
 
-    pub unsafe fn mapped_mut(&mut self) -> &mut [u32] {
-        unsafe { slice::from_raw_parts_mut(self.map.as_mut_ptr() as *mut u32, self.len()) }
+    pub fn size(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    /// The pixels of the buffer, which is in `pool`.
+    pub unsafe fn mapped<'a>(&self, pool: &'a ShmPool) -> &'a [u32] {
+        unsafe {
+            let start = pool.map.as_ptr().add(self.offset as usize);
+            slice::from_raw_parts(start as *const u32, self.len())
+        }
+    }
+
+    /// The pixels of the buffer, which is in `pool`.
+    pub unsafe fn mapped_mut<'a>(&self, pool: &'a mut ShmPool) -> &'a mut [u32] {
+        unsafe {
+            let start = pool.map.as_mut_ptr().add(self.offset as usize);
+            slice::from_raw_parts_mut(start as *mut u32, self.len())
+        }
     }
 }
 
 impl Drop for WaylandBuffer {
     fn drop(&mut self) {
         self.buffer.destroy();
-        self.pool.destroy();
     }
 }
 
+/// Where a buffer of the given size goes in `pool`, which is grown to hold it: at the start of
+/// the slot of `index`. The slots are `MAX_POOL_SIZE` bytes apart, so both buffers fit in a pool
+/// of at most twice that.
+fn place(
+    pool: &mut ShmPool,
+    index: usize,
+    width: i32,
+    height: i32,
+) -> Result<i32, SoftBufferError> {
+    let offset = i32::try_from(index)
+        .ok()
+        .and_then(|index| index.checked_mul(MAX_POOL_SIZE as i32));
+    let end = offset.and_then(|offset| {
+        width
+            .checked_mul(height)?
+            .checked_mul(4)?
+            .checked_add(offset)
+    });
+    let (offset, end) = offset
+        .zip(end)
+        .swbuf_err("Wayland buffer doesn't fit in the shm pool")?;
+    pool.grow(end)?;
+    Ok(offset)
+}
+
+fn create_buffer(
+    pool: &ShmPool,
+    offset: i32,
+    width: i32,
+    height: i32,
+    qh: &QueueHandle<State>,
+    released: &Arc<AtomicBool>,
+) -> wl_buffer::WlBuffer {
+    pool.pool.create_buffer(
+        offset,
+        width,
+        height,
+        width * 4,
+        wl_shm::Format::Xrgb8888,
+        qh,
+        released.clone(),
+    )
+}
+
 impl Dispatch<wl_shm_pool::WlShmPool, ()> for State {
     fn event(
         _: &mut State,
@@ -209,8 +327,17 @@ impl Dispatch<wl_buffer::WlBuffer, Arc<AtomicBool>> for State {
         _: &Connection,
         _: &QueueHandle<State>,
     ) {
+        // Buffers are only released once after each attach, so the compositor misbehaves.
         if let wl_buffer::Event::Release = event {
-            released.store(true, Ordering::SeqCst);
+            if released.swap(true, Ordering::SeqCst) {
+                error::report(
+                    Backend::Wayland,
+                    SoftBufferError::PlatformError(
+                        Some("A `wl_buffer` was released without being attached".into()),
+                        None,
+                    ),
+                );
+            }
         }
     }
 }
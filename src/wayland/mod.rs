@@ -1,27 +1,51 @@
-use crate::{error::SwResultExt, util, Rect, SoftBufferError};
+use crate::{
+    error::SwResultExt, stats, util, Capabilities, ColorSpace, ExternalMemory, NativeBuffer,
+    PresentStatus, Rect, Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
+use nix::poll::{poll, PollFd, PollFlags};
 use raw_window_handle::{WaylandDisplayHandle, WaylandWindowHandle};
 use std::{
-    cell::RefCell,
+    ffi::c_void,
     num::{NonZeroI32, NonZeroU32},
-    rc::Rc,
+    os::unix::io::AsRawFd,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use wayland_client::{
     backend::{Backend, ObjectId},
     globals::{registry_queue_init, GlobalListContents},
-    protocol::{wl_registry, wl_shm, wl_surface},
-    Connection, Dispatch, EventQueue, Proxy, QueueHandle,
+    protocol::{
+        wl_buffer, wl_callback, wl_compositor, wl_output, wl_region, wl_registry, wl_shm,
+        wl_surface,
+    },
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
 };
 
 mod buffer;
-use buffer::WaylandBuffer;
+use buffer::{ShmPool, WaylandBuffer};
+
+/// The largest size of a buffer in bytes, and the distance between the slots of the two buffers
+/// in the pool, so that the pool fits in an `i32` when rounded up to a power of two.
+const MAX_POOL_SIZE: u32 = 1 << 29;
+
+/// The largest width or height of a buffer, that of a buffer with a single row or column.
+const MAX_SIZE: u32 = MAX_POOL_SIZE / 4;
+
+/// How long a frame callback can be pending before the surface is considered hidden.
+const FRAME_STARVATION: Duration = Duration::from_secs(1);
 
 struct State;
 
 pub struct WaylandDisplayImpl {
     conn: Connection,
-    event_queue: RefCell<EventQueue<State>>,
+    event_queue: Mutex<EventQueue<State>>,
     qh: QueueHandle<State>,
     shm: wl_shm::WlShm,
+    compositor: wl_compositor::WlCompositor,
+    /// The outputs, whose user data is the refresh rate of their current mode in mHz.
+    outputs: Vec<wl_output::WlOutput>,
 }
 
 impl WaylandDisplayImpl {
@@ -29,32 +53,68 @@ impl WaylandDisplayImpl {
         // SAFETY: Ensured by user
         let backend = unsafe { Backend::from_foreign_display(display_handle.display as *mut _) };
         let conn = Connection::from_backend(backend);
-        let (globals, event_queue) =
+        let (globals, mut event_queue) =
             registry_queue_init(&conn).swbuf_err("Failed to make round trip to server")?;
         let qh = event_queue.handle();
         let shm: wl_shm::WlShm = globals
             .bind(&qh, 1..=1, ())
             .swbuf_err("Failed to instantiate Wayland Shm")?;
+        let compositor: wl_compositor::WlCompositor = globals
+            .bind(&qh, 1..=1, ())
+            .swbuf_err("Failed to instantiate Wayland Compositor")?;
+
+        let registry = globals.registry();
+        let outputs: Vec<wl_output::WlOutput> = globals.contents().with_list(|list| {
+            list.iter()
+                .filter(|global| global.interface == wl_output::WlOutput::interface().name)
+                .map(|global| registry.bind(global.name, 1, &qh, AtomicI32::new(0)))
+                .collect()
+        });
+        if !outputs.is_empty() {
+            // Receive the current modes of the outputs.
+            event_queue
+                .roundtrip(&mut State)
+                .swbuf_err("Failed to make round trip to server")?;
+        }
+
         Ok(Self {
             conn,
-            event_queue: RefCell::new(event_queue),
+            event_queue: Mutex::new(event_queue),
             qh,
             shm,
+            compositor,
+            outputs,
         })
     }
 }
 
 pub struct WaylandImpl {
-    display: Rc<WaylandDisplayImpl>,
+    display: Arc<WaylandDisplayImpl>,
     surface: wl_surface::WlSurface,
-    buffers: Option<(WaylandBuffer, WaylandBuffer)>,
+    /// The memory that the buffers are allocated in, while there are any.
+    pool: Option<ShmPool>,
+    /// The buffer drawn into next.
+    back: Option<WaylandBuffer>,
+    /// The buffer attached to the surface, unless it is single buffered and that is `back`.
+    front: Option<WaylandBuffer>,
+    single_buffered: bool,
+    /// How long `buffer_mut()` waits for the back buffer to be released, if not forever.
+    timeout: Option<Duration>,
     size: Option<(NonZeroI32, NonZeroI32)>,
+    /// The size of the opaque region last set on the surface.
+    opaque_size: Option<(NonZeroI32, NonZeroI32)>,
+    /// The buffer of external memory attached to the surface, if any.
+    external: Option<wl_buffer::WlBuffer>,
+    /// Whether the input and opaque regions are set to a shape.
+    shaped: bool,
+    /// Whether the last frame callback was done, and when it was requested.
+    frame: Option<(Arc<AtomicBool>, Instant)>,
 }
 
 impl WaylandImpl {
     pub unsafe fn new(
         window_handle: WaylandWindowHandle,
-        display: Rc<WaylandDisplayImpl>,
+        display: Arc<WaylandDisplayImpl>,
     ) -> Result<Self, SoftBufferError> {
         // SAFETY: Ensured by user
         let surface_id = unsafe {
@@ -69,14 +129,27 @@ impl WaylandImpl {
         Ok(Self {
             display,
             surface,
-            buffers: Default::default(),
+            pool: None,
+            back: None,
+            front: None,
+            single_buffered: false,
+            timeout: None,
             size: None,
+            opaque_size: None,
+            external: None,
+            shaped: false,
+            frame: None,
         })
     }
 
     pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
         self.size = Some(
             (|| {
+                // The size of the pool has to fit in an `i32` when rounded up to a power of two.
+                let bytes = width.get().checked_mul(height.get())?.checked_mul(4)?;
+                if bytes > MAX_POOL_SIZE {
+                    return None;
+                }
                 let width = NonZeroI32::try_from(width).ok()?;
                 let height = NonZeroI32::try_from(height).ok()?;
                 Some((width, height))
@@ -86,110 +159,487 @@ impl WaylandImpl {
         Ok(())
     }
 
-    pub fn buffer_mut(&mut self) -> Result<BufferImpl, SoftBufferError> {
-        let (width, height) = self
-            .size
-            .expect("Must set size of surface before calling `buffer_mut()`");
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        let (width, height) = self.size.ok_or(SoftBufferError::SurfaceNotConfigured)?;
 
-        if let Some((_front, back)) = &mut self.buffers {
-            // Block if back buffer not released yet
-            if !back.released() {
-                let mut event_queue = self.display.event_queue.borrow_mut();
+        let (width, height) = (width.get(), height.get());
+        let qh = &self.display.qh;
+        if self.pool.is_none() {
+            self.pool = Some(ShmPool::new(&self.display.shm, width * height * 4, qh)?);
+        }
+        let pool = self.pool.as_mut().unwrap();
+        if let Some(back) = &mut self.back {
+            // Block if back buffer not released yet. A single buffer is drawn into while the
+            // compositor may still read it.
+            if !self.single_buffered && !back.released() {
+                trace_span!("wait_for_release");
+                let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+                let mut event_queue = self.display.event_queue.lock().unwrap();
                 while !back.released() {
-                    event_queue.blocking_dispatch(&mut State).map_err(|err| {
-                        SoftBufferError::PlatformError(
-                            Some("Wayland dispatch failure".to_string()),
-                            Some(Box::new(err)),
-                        )
-                    })?;
+                    match deadline {
+                        Some(deadline) => dispatch_until(&mut event_queue, deadline)?,
+                        None => {
+                            event_queue.blocking_dispatch(&mut State).map_err(|err| {
+                                SoftBufferError::PlatformError(
+                                    Some("Wayland dispatch failure".to_string()),
+                                    Some(Box::new(err)),
+                                )
+                            })?;
+                        }
+                    }
                 }
             }
 
             // Resize, if buffer isn't large enough
-            back.resize(width.get(), height.get());
+            back.resize(pool, width, height)?;
         } else {
-            // Allocate front and back buffer
-            self.buffers = Some((
-                WaylandBuffer::new(
-                    &self.display.shm,
-                    width.get(),
-                    height.get(),
-                    &self.display.qh,
-                ),
-                WaylandBuffer::new(
-                    &self.display.shm,
-                    width.get(),
-                    height.get(),
-                    &self.display.qh,
-                ),
-            ));
-        };
+            // The front buffer may have been kept by `release_buffers()`.
+            let index = self.front.as_ref().map_or(0, |front| 1 - front.index);
+            self.back = Some(WaylandBuffer::new(pool, width, height, index, qh)?);
+        }
+        if !self.single_buffered && self.front.is_none() {
+            let index = 1 - self.back.as_ref().unwrap().index;
+            self.front = Some(WaylandBuffer::new(pool, width, height, index, qh)?);
+        }
 
-        let age = self.buffers.as_mut().unwrap().1.age;
+        let back = self.back.as_ref().unwrap();
+        let (age, wl_buffer) = (back.age, NonNull::new(back.as_ptr()));
+        let index = if self.single_buffered { 0 } else { back.index };
         Ok(BufferImpl {
             stack: util::BorrowStack::new(self, |buffer| {
-                Ok(unsafe { buffer.buffers.as_mut().unwrap().1.mapped_mut() })
+                let pool = buffer.pool.as_mut().unwrap();
+                Ok(unsafe { buffer.back.as_ref().unwrap().mapped_mut(pool) })
             })?,
             age,
+            index,
+            wl_buffer,
+            shape: None,
         })
     }
 
-    /// Fetch the buffer from the window.
+    /// Copy the buffer that was attached to the surface last.
     pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        let (width, height) = self
+            .size
+            .expect("Must set size of surface before calling `fetch()`");
+        let len = width.get() as usize * height.get() as usize;
+        if self.external.is_some() {
+            return Err(SoftBufferError::Unimplemented);
+        }
+
+        // The compositor's copy can't be read back, but the last buffer attached is our own.
+        let presented = [&self.front, &self.back]
+            .into_iter()
+            .flatten()
+            .find(|buffer| buffer.age == 1 && buffer.size() == (width.get(), height.get()));
+        Ok(match (presented, &self.pool) {
+            (Some(buffer), Some(pool)) => unsafe { buffer.mapped(pool) }.to_vec(),
+            _ => vec![0; len],
+        })
+    }
+
+    /// Check whether the compositor has released the back buffer, without blocking.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        self.read_events()?;
+        Ok(match &self.back {
+            Some(back) => self.single_buffered || back.released(),
+            None => true,
+        })
+    }
+
+    /// Destroy the buffers that the compositor released, the others may still be read.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        self.read_events()?;
+        for buffer in [&mut self.front, &mut self.back] {
+            if buffer.as_ref().map_or(false, |buffer| buffer.released()) {
+                *buffer = None;
+            }
+        }
+        if self.front.is_none() && self.back.is_none() {
+            self.pool = None;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next vertical blank.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
         Err(SoftBufferError::Unimplemented)
     }
 
-    fn present_with_damage(&mut self, damage: &[Rect]) -> Result<(), SoftBufferError> {
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Get the refresh interval of the current mode of the outputs.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
         let _ = self
             .display
             .event_queue
-            .borrow_mut()
+            .lock()
+            .unwrap()
             .dispatch_pending(&mut State);
 
-        if let Some((front, back)) = &mut self.buffers {
-            front.age = 1;
-            if back.age != 0 {
-                back.age += 1;
+        // The `enter` events of the surface go to the queue of the toolkit that created it, so
+        // the output it is on isn't known. Unless all outputs agree, there is no single answer.
+        let mut refresh = None;
+        for output in &self.display.outputs {
+            let rate = output
+                .data::<AtomicI32>()
+                .map_or(0, |rate| rate.load(Ordering::Relaxed));
+            if rate <= 0 {
+                continue;
             }
+            match refresh {
+                Some(refresh) if refresh != rate => {
+                    return Err(SoftBufferError::PlatformError(
+                        Some("The outputs have different refresh rates".into()),
+                        None,
+                    ))
+                }
+                _ => refresh = Some(rate),
+            }
+        }
 
-            // Swap front and back buffer
-            std::mem::swap(front, back);
+        let refresh = refresh.ok_or(SoftBufferError::Unimplemented)?;
+        Ok(Duration::from_nanos(1_000_000_000_000 / refresh as u64))
+    }
 
-            front.attach(&self.surface);
+    /// Get the size of the window in pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
 
-            // Like Mesa's EGL/WSI implementation, we damage the whole buffer with `i32::MAX` if
-            // the compositor doesn't support `damage_buffer`.
-            // https://bugs.freedesktop.org/show_bug.cgi?id=78190
-            if self.surface.version() < 4 {
-                self.surface.damage(0, 0, i32::MAX, i32::MAX);
+    /// Guess whether the surface is hidden, from how long the last frame callback is pending.
+    ///
+    /// Compositors don't send frame callbacks for surfaces that aren't shown.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        self.read_events()?;
+        Ok(match &self.frame {
+            Some((done, requested))
+                if !done.load(Ordering::Relaxed) && requested.elapsed() >= FRAME_STARVATION =>
+            {
+                Visibility::Hidden
+            }
+            _ => Visibility::Visible,
+        })
+    }
+
+    /// Dispatch the events that have arrived on the socket, without blocking.
+    fn read_events(&self) -> Result<(), SoftBufferError> {
+        let mut event_queue = self.display.event_queue.lock().unwrap();
+        let _ = event_queue.flush();
+
+        // Read any events that have arrived on the socket. `WouldBlock` just means there are none.
+        if let Ok(guard) = event_queue.prepare_read() {
+            let _ = guard.read();
+        }
+        event_queue
+            .dispatch_pending(&mut State)
+            .swbuf_err("Wayland dispatch failure")?;
+        Ok(())
+    }
+
+    /// Set how the buffer is scaled to the window.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        match scaling {
+            Scaling::TopLeft => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The surface is only as large as the buffer.
+    pub fn set_background_color(&mut self, _color: u32) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set how long `buffer_mut()` waits for the compositor to release the back buffer.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// Draw into the buffer attached to the surface, instead of swapping between two.
+    pub fn set_single_buffered(&mut self, single: bool) -> Result<(), SoftBufferError> {
+        if single && !self.single_buffered {
+            // Keep the attached buffer, which holds the last frame.
+            if let Some(front) = self.front.take() {
+                self.back = Some(front);
+            }
+        }
+        self.single_buffered = single;
+        Ok(())
+    }
+
+    /// Have the compositor flip the buffer, which is applied at the next commit.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        // Introduced in version 2.
+        if self.surface.version() < 2 {
+            return if flip {
+                Err(SoftBufferError::Unimplemented)
             } else {
-                for rect in damage {
-                    // Introduced in version 4, it is an error to use this request in version 3 or lower.
-                    let (x, y, width, height) = (|| {
-                        Some((
-                            i32::try_from(rect.x).ok()?,
-                            i32::try_from(rect.y).ok()?,
-                            i32::try_from(rect.width.get()).ok()?,
-                            i32::try_from(rect.height.get()).ok()?,
-                        ))
-                    })()
-                    .ok_or(SoftBufferError::DamageOutOfRange { rect: *rect })?;
-                    self.surface.damage_buffer(x, y, width, height);
+                Ok(())
+            };
+        }
+        // Mirroring left to right and then turning it around is the same as flipping it.
+        self.surface.set_buffer_transform(match flip {
+            false => wl_output::Transform::Normal,
+            true => wl_output::Transform::Flipped180,
+        });
+        Ok(())
+    }
+
+    /// The compositor keeps showing the last buffer committed until the next present.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the buffer scale of the surface, for the next commit.
+    pub fn set_scale_factor(&mut self, scale_factor: f64) -> Result<(), SoftBufferError> {
+        // Fractional scales need `wp_fractional_scale_v1` and `wp_viewporter`.
+        if scale_factor.fract() != 0.0 || scale_factor > i32::MAX as f64 {
+            return Err(SoftBufferError::Unimplemented);
+        }
+        // Introduced in version 3, which is needed for any scale other than 1.
+        if self.surface.version() >= 3 {
+            self.surface.set_buffer_scale(scale_factor as i32);
+        } else if scale_factor != 1.0 {
+            return Err(SoftBufferError::Unimplemented);
+        }
+        Ok(())
+    }
+
+    /// Set the input and opaque regions of the surface to the shape, for the next commit.
+    pub fn set_shape(&mut self, shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        match shape {
+            Some(shape) => {
+                let region = self.display.compositor.create_region(&self.display.qh, ());
+                // The rects fit inside the buffer, whose size fits in an `i32`.
+                for rect in shape {
+                    region.add(
+                        rect.x as i32,
+                        rect.y as i32,
+                        rect.width.get() as i32,
+                        rect.height.get() as i32,
+                    );
+                }
+                self.surface.set_input_region(Some(&region));
+                self.surface.set_opaque_region(Some(&region));
+                region.destroy();
+            }
+            None => self.surface.set_input_region(None),
+        }
+        self.shaped = shape.is_some();
+        // Otherwise the next commit makes the whole buffer opaque again.
+        self.opaque_size = None;
+        Ok(())
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            partial_present: true,
+            zero_copy: true,
+            max_size: (MAX_SIZE, MAX_SIZE),
+            buffer_count: if self.single_buffered { 1 } else { 2 },
+            readback: false,
+        }
+    }
+
+    /// Attach shared memory to the surface as a `wl_shm` buffer.
+    pub unsafe fn present_external(
+        &mut self,
+        memory: ExternalMemory,
+        width: NonZeroU32,
+        height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        #[allow(unreachable_patterns)]
+        let (fd, offset, stride) = match memory {
+            ExternalMemory::SharedMemory { fd, offset, stride } => (fd, offset, stride),
+            _ => return Err(SoftBufferError::Unimplemented),
+        };
+        let (size, offset, stride, pool_size) = (|| {
+            let size = (
+                NonZeroI32::try_from(width).ok()?,
+                NonZeroI32::try_from(height).ok()?,
+            );
+            let offset = i32::try_from(offset).ok()?;
+            let stride = i32::try_from(stride).ok()?;
+            let pool_size = stride.checked_mul(size.1.get())?.checked_add(offset)?;
+            Some((size, offset, stride, pool_size))
+        })()
+        .ok_or(SoftBufferError::SizeOutOfRange { width, height })?;
+
+        let qh = &self.display.qh;
+        let pool = self.display.shm.create_pool(fd, pool_size, qh, ());
+        let buffer = pool.create_buffer(
+            offset,
+            size.0.get(),
+            size.1.get(),
+            stride,
+            wl_shm::Format::Xrgb8888,
+            qh,
+            Arc::new(AtomicBool::new(false)),
+        );
+        // The buffer keeps the memory mapped.
+        pool.destroy();
+
+        self.surface.attach(Some(&buffer), 0, 0);
+        let full = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        self.commit(&[full], Some(size))?;
+        // The memory of the last one is kept until this present, so it can be destroyed.
+        if let Some(external) = self.external.replace(buffer) {
+            external.destroy();
+        }
+
+        let _ = self.display.event_queue.lock().unwrap().flush();
+
+        Ok(PresentStatus::Presented)
+    }
+
+    fn present_with_damage(&mut self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let _ = self
+            .display
+            .event_queue
+            .lock()
+            .unwrap()
+            .dispatch_pending(&mut State);
+
+        let attached = match (&mut self.front, &mut self.back) {
+            (Some(front), Some(back)) => {
+                front.age = 1;
+                if back.age != 0 {
+                    back.age += 1;
                 }
+
+                // Swap front and back buffer
+                std::mem::swap(front, back);
+
+                front.attach(&self.surface);
+                true
+            }
+            // The single buffer stays attached, and holds this frame.
+            (None, Some(back)) => {
+                back.age = 1;
+                back.attach(&self.surface);
+                true
             }
+            _ => false,
+        };
 
-            self.surface.commit();
+        if attached {
+            self.commit(damage, self.size)?;
+            // The compositor let go of the external buffer, or never reads it again.
+            if let Some(external) = self.external.take() {
+                external.destroy();
+            }
         }
 
-        let _ = self.display.event_queue.borrow_mut().flush();
+        let _ = self.display.event_queue.lock().unwrap().flush();
+
+        Ok(PresentStatus::Presented)
+    }
+
+    /// Damage and commit the buffer attached to the surface, which has the given size.
+    fn commit(
+        &mut self,
+        damage: &[Rect],
+        size: Option<(NonZeroI32, NonZeroI32)>,
+    ) -> Result<(), SoftBufferError> {
+        // Like Mesa's EGL/WSI implementation, we damage the whole buffer with `i32::MAX` if
+        // the compositor doesn't support `damage_buffer`.
+        // https://bugs.freedesktop.org/show_bug.cgi?id=78190
+        if self.surface.version() < 4 {
+            self.surface.damage(0, 0, i32::MAX, i32::MAX);
+        } else {
+            for rect in damage {
+                // Introduced in version 4, it is an error to use this request in version 3 or lower.
+                let (x, y, width, height) = (|| {
+                    Some((
+                        i32::try_from(rect.x).ok()?,
+                        i32::try_from(rect.y).ok()?,
+                        i32::try_from(rect.width.get()).ok()?,
+                        i32::try_from(rect.height.get()).ok()?,
+                    ))
+                })()
+                .ok_or(SoftBufferError::DamageOutOfRange { rect: *rect })?;
+                self.surface.damage_buffer(x, y, width, height);
+            }
+        }
+
+        // `Xrgb8888` has no alpha channel, so the whole buffer is opaque. Telling the
+        // compositor lets it skip drawing anything behind the surface.
+        if !self.shaped && self.opaque_size != size {
+            if let Some((width, height)) = size {
+                let region = self.display.compositor.create_region(&self.display.qh, ());
+                region.add(0, 0, width.get(), height.get());
+                self.surface.set_opaque_region(Some(&region));
+                region.destroy();
+            }
+            self.opaque_size = size;
+        }
+
+        // Only the oldest pending frame callback tells how long the compositor held them back.
+        if self
+            .frame
+            .as_ref()
+            .map_or(true, |(done, _)| done.load(Ordering::Relaxed))
+        {
+            let done = Arc::new(AtomicBool::new(false));
+            self.surface.frame(&self.display.qh, done.clone());
+            self.frame = Some((done, Instant::now()));
+        }
+
+        trace_span!("commit");
+        self.surface.commit();
 
         Ok(())
     }
 }
 
+/// Extension methods for Wayland on [`Surface`](crate::Surface).
+pub trait SurfaceExtWayland {
+    /// The `wl_surface` that the buffers are attached to, as a `wl_proxy` of libwayland-client,
+    /// e.g. for setting its buffer scale. Its pending state is committed with the next present.
+    /// Returns `None` for surfaces of other backends.
+    fn wl_surface(&self) -> Option<NonNull<c_void>>;
+}
+
+impl<D, W> SurfaceExtWayland for crate::Surface<D, W> {
+    fn wl_surface(&self) -> Option<NonNull<c_void>> {
+        match &*self.surface_impl {
+            crate::SurfaceDispatch::Wayland(imp) => NonNull::new(imp.surface.id().as_ptr().cast()),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
 pub struct BufferImpl<'a> {
     stack: util::BorrowStack<'a, WaylandImpl, [u32]>,
     age: u8,
+    index: usize,
+    /// The `wl_proxy` of the back buffer.
+    wl_buffer: Option<NonNull<c_void>>,
+    /// The shape to set on the surface when presenting.
+    shape: Option<Vec<Rect>>,
 }
 
 impl<'a> BufferImpl<'a> {
@@ -207,12 +657,36 @@ impl<'a> BufferImpl<'a> {
         self.age
     }
 
-    pub fn present_with_damage(self, damage: &[Rect]) -> Result<(), SoftBufferError> {
-        self.stack.into_container().present_with_damage(damage)
+    pub fn index(&self) -> usize {
+        self.index
     }
 
-    pub fn present(self) -> Result<(), SoftBufferError> {
-        let imp = self.stack.into_container();
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        Some(NativeBuffer::Wayland {
+            wl_buffer: self.wl_buffer?,
+        })
+    }
+
+    pub fn set_shape(&mut self, shape: &[Rect]) -> Result<(), SoftBufferError> {
+        self.shape = Some(shape.to_vec());
+        Ok(())
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        self.into_surface()?.present_with_damage(damage)
+    }
+
+    /// Buffers are attached to the surface whole, and `wp_viewporter` isn't used.
+    pub fn present_viewport(
+        self,
+        _damage: &[Rect],
+        _viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let imp = self.into_surface()?;
         let (width, height) = imp
             .size
             .expect("Must set size of surface before calling `present()`");
@@ -224,6 +698,51 @@ impl<'a> BufferImpl<'a> {
             height: height.try_into().unwrap(),
         }])
     }
+
+    /// The surface, with the shape of this buffer set on it for the next commit.
+    fn into_surface(self) -> Result<&'a mut WaylandImpl, SoftBufferError> {
+        let imp = self.stack.into_container();
+        if let Some(shape) = &self.shape {
+            imp.set_shape(Some(shape))?;
+        }
+        Ok(imp)
+    }
+}
+
+/// Dispatch the events that arrive before `deadline`, like `EventQueue::blocking_dispatch`.
+fn dispatch_until(
+    event_queue: &mut EventQueue<State>,
+    deadline: Instant,
+) -> Result<(), SoftBufferError> {
+    if event_queue
+        .dispatch_pending(&mut State)
+        .swbuf_err("Wayland dispatch failure")?
+        > 0
+    {
+        return Ok(());
+    }
+    event_queue.flush().swbuf_err("Wayland flush failure")?;
+
+    // Events were queued since dispatching them, so there is nothing to wait for.
+    if let Ok(guard) = event_queue.prepare_read() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(
+            guard.connection_fd().as_raw_fd(),
+            PollFlags::POLLIN,
+        )];
+        // Round up, so this doesn't spin for the last millisecond.
+        let timeout = (remaining.as_micros() + 999) / 1000;
+        let ready = poll(&mut fds, timeout.min(i32::MAX as u128) as i32)
+            .swbuf_err("Failed to poll the Wayland connection")?;
+        if ready == 0 {
+            return Err(SoftBufferError::Timeout);
+        }
+        guard.read().swbuf_err("Wayland read failure")?;
+    }
+    event_queue
+        .dispatch_pending(&mut State)
+        .swbuf_err("Wayland dispatch failure")?;
+    Ok(())
 }
 
 impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
@@ -250,3 +769,64 @@ impl Dispatch<wl_shm::WlShm, ()> for State {
     ) {
     }
 }
+
+impl Dispatch<wl_compositor::WlCompositor, ()> for State {
+    fn event(
+        _: &mut State,
+        _: &wl_compositor::WlCompositor,
+        _: wl_compositor::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<State>,
+    ) {
+    }
+}
+
+impl Dispatch<wl_output::WlOutput, AtomicI32> for State {
+    fn event(
+        _: &mut State,
+        _: &wl_output::WlOutput,
+        event: wl_output::Event,
+        refresh: &AtomicI32,
+        _: &Connection,
+        _: &QueueHandle<State>,
+    ) {
+        if let wl_output::Event::Mode {
+            flags: WEnum::Value(flags),
+            refresh: rate,
+            ..
+        } = event
+        {
+            if flags.contains(wl_output::Mode::Current) {
+                refresh.store(rate, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_callback::WlCallback, Arc<AtomicBool>> for State {
+    fn event(
+        _: &mut State,
+        _: &wl_callback::WlCallback,
+        event: wl_callback::Event,
+        done: &Arc<AtomicBool>,
+        _: &Connection,
+        _: &QueueHandle<State>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            done.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Dispatch<wl_region::WlRegion, ()> for State {
+    fn event(
+        _: &mut State,
+        _: &wl_region::WlRegion,
+        _: wl_region::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<State>,
+    ) {
+    }
+}
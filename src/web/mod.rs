@@ -0,0 +1,964 @@
+//! Implementation of software buffering for web targets.
+
+#![allow(clippy::uninlined_format_args)]
+
+use js_sys::{Object, Reflect};
+use raw_window_handle::WebWindowHandle;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement};
+use web_sys::{ImageData, VisibilityState};
+use web_sys::{OffscreenCanvas, OffscreenCanvasRenderingContext2d};
+
+use crate::error::{self, SwResultExt};
+use crate::{
+    convert, stats, util, Backend, Capabilities, ColorSpace, ExternalMemory, NativeBuffer,
+    PresentStatus, Rect, Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::num::NonZeroU32;
+use std::rc::Rc;
+use std::slice;
+use std::time::Duration;
+
+mod shared;
+#[cfg(feature = "webgl")]
+mod webgl;
+
+pub use self::shared::{frame_channel, FrameReader, FrameWriter};
+
+/// The largest width and height of a canvas in the major browsers.
+const MAX_CANVAS_SIZE: u32 = 32767;
+
+/// Display implementation for the web platform.
+///
+/// This just caches the document to prevent having to query it every time.
+pub struct WebDisplayImpl {
+    /// `None` in a worker, where surfaces can only be created from an [`OffscreenCanvas`].
+    document: Option<web_sys::Document>,
+}
+
+impl WebDisplayImpl {
+    pub(super) fn new() -> Result<Self, SoftBufferError> {
+        let document = web_sys::window().and_then(|window| window.document());
+
+        Ok(Self { document })
+    }
+}
+
+pub struct WebImpl {
+    /// The handle and context to the canvas that we're drawing to, shared with the
+    /// animation frame callback.
+    canvas: Rc<RefCell<Canvas>>,
+
+    /// The buffer that we're drawing to.
+    buffer: Vec<u32>,
+
+    /// Buffer has been presented.
+    buffer_presented: bool,
+
+    /// The current canvas width/height.
+    size: Option<(NonZeroU32, NonZeroU32)>,
+
+    /// Whether presents are deferred to the next animation frame.
+    sync_to_animation_frame: bool,
+
+    /// The frame waiting for the next animation frame.
+    pending: Rc<RefCell<Option<PendingFrame>>>,
+
+    /// The latencies of the frames shown in animation frames, and the frames coalesced away.
+    feedback: Rc<RefCell<stats::Feedback>>,
+
+    /// Whether the rows of the buffer are drawn onto the canvas bottom-up.
+    flip_vertical: bool,
+
+    /// The scale factor the CSS size of the canvas is set from, if any.
+    scale_factor: Option<f64>,
+
+    /// Whether the canvas is resized by the next present instead of by `resize()`, since
+    /// resizing clears it.
+    smooth_resize: bool,
+}
+
+/// The presents since the last animation frame, which are coalesced into one.
+struct PendingFrame {
+    buffer: Vec<u32>,
+    width: NonZeroU32,
+    damage: Vec<Rect>,
+    flip_vertical: bool,
+    /// When the last of the presents happened.
+    presented_at: stats::Timestamp,
+}
+
+/// Holding canvas and context for [`HtmlCanvasElement`] or [`OffscreenCanvas`],
+/// since they have different types.
+#[allow(clippy::enum_variant_names)]
+enum Canvas {
+    Canvas {
+        canvas: HtmlCanvasElement,
+        ctx: CanvasRenderingContext2d,
+    },
+    OffscreenCanvas {
+        canvas: OffscreenCanvas,
+        ctx: OffscreenCanvasRenderingContext2d,
+    },
+    #[cfg(feature = "webgl")]
+    WebGl {
+        canvas: HtmlCanvasElement,
+        gl: webgl::WebGl,
+    },
+    #[cfg(feature = "webgl")]
+    OffscreenWebGl {
+        canvas: OffscreenCanvas,
+        gl: webgl::WebGl,
+    },
+}
+
+impl WebImpl {
+    pub fn new(display: &WebDisplayImpl, handle: WebWindowHandle) -> Result<Self, SoftBufferError> {
+        let canvas: HtmlCanvasElement = display
+            .document
+            .as_ref()
+            .swbuf_err(
+                "`Document` is not present in this runtime, \
+                 use `SurfaceExtWeb::from_offscreen_canvas()` in workers",
+            )?
+            .query_selector(&format!("canvas[data-raw-handle=\"{}\"]", handle.id))
+            // `querySelector` only throws an error if the selector is invalid.
+            .unwrap()
+            .swbuf_err("No canvas found with the given id")?
+            // We already made sure this was a canvas in `querySelector`.
+            .unchecked_into();
+
+        Self::from_canvas(canvas)
+    }
+
+    fn from_canvas(canvas: HtmlCanvasElement) -> Result<Self, SoftBufferError> {
+        #[cfg(feature = "webgl")]
+        match webgl::WebGl::new(
+            canvas
+                .get_context_with_context_options("webgl2", &webgl::context_options())
+                .ok()
+                .flatten(),
+        ) {
+            Ok(gl) => return Ok(Self::with_canvas(Canvas::WebGl { canvas, gl })),
+            Err(err) => error::report(
+                Backend::Web,
+                SoftBufferError::PlatformError(
+                    Some(format!("Falling back to a 2D canvas context: {}", err)),
+                    None,
+                ),
+            ),
+        }
+
+        let ctx = Self::resolve_ctx(
+            canvas
+                .get_context_with_context_options("2d", &context_options())
+                .ok(),
+            "CanvasRenderingContext2d",
+        )?;
+
+        Ok(Self::with_canvas(Canvas::Canvas { canvas, ctx }))
+    }
+
+    fn from_offscreen_canvas(canvas: OffscreenCanvas) -> Result<Self, SoftBufferError> {
+        #[cfg(feature = "webgl")]
+        match webgl::WebGl::new(
+            canvas
+                .get_context_with_context_options("webgl2", &webgl::context_options())
+                .ok()
+                .flatten(),
+        ) {
+            Ok(gl) => return Ok(Self::with_canvas(Canvas::OffscreenWebGl { canvas, gl })),
+            Err(err) => error::report(
+                Backend::Web,
+                SoftBufferError::PlatformError(
+                    Some(format!("Falling back to a 2D canvas context: {}", err)),
+                    None,
+                ),
+            ),
+        }
+
+        let ctx = Self::resolve_ctx(
+            canvas
+                .get_context_with_context_options("2d", &context_options())
+                .ok(),
+            "OffscreenCanvasRenderingContext2d",
+        )?;
+
+        Ok(Self::with_canvas(Canvas::OffscreenCanvas { canvas, ctx }))
+    }
+
+    fn with_canvas(canvas: Canvas) -> Self {
+        Self {
+            canvas: Rc::new(RefCell::new(canvas)),
+            buffer: Vec::new(),
+            buffer_presented: false,
+            size: None,
+            sync_to_animation_frame: false,
+            pending: Rc::new(RefCell::new(None)),
+            feedback: Rc::new(RefCell::new(stats::Feedback::default())),
+            flip_vertical: false,
+            scale_factor: None,
+            smooth_resize: false,
+        }
+    }
+
+    /// De-duplicates the error handling between `HtmlCanvasElement` and `OffscreenCanvas`.
+    fn resolve_ctx<T: JsCast>(
+        result: Option<Option<Object>>,
+        name: &str,
+    ) -> Result<T, SoftBufferError> {
+        let ctx = result
+            .swbuf_err("Canvas already controlled using `OffscreenCanvas`")?
+            .swbuf_err(format!(
+                "A canvas context other than `{name}` was already created"
+            ))?
+            .dyn_into()
+            .unwrap_or_else(|_| panic!("`getContext(\"2d\") didn't return a `{name}`"));
+
+        Ok(ctx)
+    }
+
+    /// Resize the canvas to the given dimensions.
+    pub(crate) fn resize(
+        &mut self,
+        width: NonZeroU32,
+        height: NonZeroU32,
+    ) -> Result<(), SoftBufferError> {
+        if self.size != Some((width, height)) {
+            self.buffer_presented = false;
+            util::resize_pixels(&mut self.buffer, total_len(width.get(), height.get()));
+            if !self.smooth_resize {
+                let canvas = self.canvas.borrow();
+                canvas.set_width(width.get());
+                canvas.set_height(height.get());
+            }
+            self.size = Some((width, height));
+            self.set_css_size()?;
+        } else if self.buffer.is_empty() {
+            // The pixels were freed by `release_buffers()`. Setting the size of the canvas would
+            // clear it.
+            self.buffer_presented = false;
+            util::resize_pixels(&mut self.buffer, total_len(width.get(), height.get()));
+        }
+
+        Ok(())
+    }
+
+    /// Get a pointer to the mutable buffer.
+    pub(crate) fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        Ok(BufferImpl { imp: self })
+    }
+
+    fn present_with_damage(&mut self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let (width, _height) = self
+            .size
+            .expect("Must set size of surface before calling `present_with_damage()`");
+
+        if self.sync_to_animation_frame {
+            let mut pending = self.pending.borrow_mut();
+            if let Some(frame) = &mut *pending {
+                // Only the last buffer of a frame is shown, but all of the damage is.
+                frame.buffer.clone_from(&self.buffer);
+                frame.width = width;
+                frame.damage.extend_from_slice(damage);
+                frame.flip_vertical = self.flip_vertical;
+                frame.presented_at = stats::Timestamp::now();
+                self.feedback.borrow_mut().dropped();
+            } else {
+                *pending = Some(PendingFrame {
+                    buffer: self.buffer.clone(),
+                    width,
+                    damage: damage.to_vec(),
+                    flip_vertical: self.flip_vertical,
+                    presented_at: stats::Timestamp::now(),
+                });
+                drop(pending);
+                if let Err(err) = self.request_animation_frame() {
+                    error::report(
+                        Backend::Web,
+                        SoftBufferError::PlatformError(
+                            Some(format!(
+                                "Presenting without `requestAnimationFrame()`: {:?}",
+                                err
+                            )),
+                            None,
+                        ),
+                    );
+                    self.sync_to_animation_frame = false;
+                    self.flush();
+                }
+            }
+        } else {
+            self.canvas
+                .borrow_mut()
+                .present(&self.buffer, width, damage, self.flip_vertical);
+        }
+
+        self.buffer_presented = true;
+
+        Ok(PresentStatus::Presented)
+    }
+
+    fn present_frame(&mut self, reader: &mut FrameReader) -> Result<bool, SoftBufferError> {
+        let (pixels, width) = match reader.take() {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        // `FrameWriter` only publishes frames of a non-zero size.
+        let height = NonZeroU32::new(pixels.len() as u32 / width).unwrap();
+
+        // Don't let a pending frame overwrite this one later.
+        if self.pending.borrow_mut().take().is_some() {
+            self.feedback.borrow_mut().dropped();
+        }
+        self.resize(width, height)?;
+        self.canvas.borrow_mut().present(
+            pixels,
+            width,
+            &[Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            }],
+            self.flip_vertical,
+        );
+        // The canvas doesn't show the buffer of the surface anymore.
+        self.buffer_presented = false;
+
+        Ok(true)
+    }
+
+    /// Present the pending frame, once the browser is about to render the next one.
+    fn request_animation_frame(&self) -> Result<(), JsValue> {
+        let canvas = Rc::downgrade(&self.canvas);
+        let pending = Rc::downgrade(&self.pending);
+        let feedback = Rc::downgrade(&self.feedback);
+        // Don't keep the surface alive, it may be dropped before the callback runs.
+        let callback = Closure::once_into_js(move || {
+            if let (Some(canvas), Some(pending), Some(feedback)) =
+                (canvas.upgrade(), pending.upgrade(), feedback.upgrade())
+            {
+                if let Some(presented_at) = flush(&canvas, &pending) {
+                    feedback.borrow_mut().shown(presented_at.elapsed());
+                }
+            }
+        });
+        request_animation_frame(callback.unchecked_ref()).map(drop)
+    }
+
+    /// Present the pending frame right away.
+    fn flush(&self) {
+        flush(&self.canvas, &self.pending);
+    }
+
+    /// Take the latencies and dropped frames since the last call.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        self.feedback.take()
+    }
+
+    /// Fetch the buffer from the window.
+    pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        let (width, height) = self
+            .size
+            .expect("Must set size of surface before calling `fetch()`");
+
+        // The canvas only has the contents of a pending frame once it was presented.
+        self.flush();
+
+        #[cfg(feature = "webgl")]
+        if let Some(gl) = self.canvas.borrow_mut().webgl_mut() {
+            return Ok(gl.fetch(width.get(), height.get()));
+        }
+
+        let image_data = self
+            .canvas
+            .borrow()
+            .get_image_data(0., 0., width.get().into(), height.get().into())
+            .ok()
+            .swbuf_err("`Canvas` contains pixels from a different origin")?;
+
+        Ok(image_data
+            .data()
+            .0
+            .chunks_exact(4)
+            .map(|chunk| u32::from_be_bytes([0, chunk[0], chunk[1], chunk[2]]))
+            .collect())
+    }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(self.pending.borrow().is_none())
+    }
+
+    /// Free the pixels, the canvas keeps what was drawn onto it.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        self.buffer = Vec::new();
+        Ok(())
+    }
+
+    /// Wait for the next vertical blank.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Browsers don't expose the refresh rate of the display.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Get the size of the window in pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        let canvas = self.canvas.borrow();
+        // An `OffscreenCanvas` isn't laid out, so it only has the size of its backing store.
+        let element = canvas.element().ok_or(SoftBufferError::Unimplemented)?;
+        let scale = device_pixel_ratio().ok_or(SoftBufferError::Unimplemented)?;
+
+        // Unlike `clientWidth`, this isn't rounded to whole CSS pixels.
+        let rect = element.get_bounding_client_rect();
+        Ok((
+            (rect.width() * scale).round() as u32,
+            (rect.height() * scale).round() as u32,
+        ))
+    }
+
+    /// Get whether the page is hidden, or the canvas is scrolled out of the viewport.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        let canvas = self.canvas.borrow();
+        let element = canvas.element().ok_or(SoftBufferError::Unimplemented)?;
+        let window = web_sys::window().ok_or(SoftBufferError::Unimplemented)?;
+        let document = element
+            .owner_document()
+            .ok_or(SoftBufferError::Unimplemented)?;
+        if document.visibility_state() == VisibilityState::Hidden {
+            return Ok(Visibility::Hidden);
+        }
+
+        // In CSS pixels, relative to the viewport.
+        let rect = element.get_bounding_client_rect();
+        let size = |length: Result<JsValue, JsValue>| length.ok().and_then(|v| v.as_f64());
+        let (width, height) = match (size(window.inner_width()), size(window.inner_height())) {
+            (Some(width), Some(height)) => (width, height),
+            _ => return Ok(Visibility::Visible),
+        };
+        Ok(
+            if rect.right() <= 0.0
+                || rect.bottom() <= 0.0
+                || rect.left() >= width
+                || rect.top() >= height
+            {
+                Visibility::Hidden
+            } else if rect.left() < 0.0
+                || rect.top() < 0.0
+                || rect.right() > width
+                || rect.bottom() > height
+            {
+                Visibility::PartiallyOccluded
+            } else {
+                Visibility::Visible
+            },
+        )
+    }
+
+    /// Set how the buffer is scaled to the window.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        match scaling {
+            Scaling::TopLeft => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The canvas is only as large as the buffer, use CSS on it instead.
+    pub fn set_background_color(&mut self, _color: u32) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// The browser doesn't make presents wait.
+    pub fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// There is only one buffer already.
+    pub fn set_single_buffered(&mut self, _single: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Draw the rows of the buffer onto the canvas bottom-up.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        self.flip_vertical = flip;
+        Ok(())
+    }
+
+    /// Resize the canvas right before it is drawn to, so the browser doesn't render it cleared.
+    pub fn set_smooth_resize(&mut self, smooth: bool) -> Result<(), SoftBufferError> {
+        self.smooth_resize = smooth;
+        Ok(())
+    }
+
+    /// Set the CSS size of the canvas to its size divided by the scale factor.
+    pub fn set_scale_factor(&mut self, scale_factor: f64) -> Result<(), SoftBufferError> {
+        self.scale_factor = Some(scale_factor);
+        self.set_css_size()
+    }
+
+    /// Set the CSS size of the canvas element from its size and the scale factor, if both are set.
+    fn set_css_size(&self) -> Result<(), SoftBufferError> {
+        let ((width, height), scale_factor) = match (self.size, self.scale_factor) {
+            (Some(size), Some(scale_factor)) => (size, scale_factor),
+            _ => return Ok(()),
+        };
+        let canvas = self.canvas.borrow();
+        // An `OffscreenCanvas` has no CSS size, the canvas it was transferred from does.
+        let element = match canvas.element() {
+            Some(element) => element,
+            None => return Ok(()),
+        };
+        let style = element.style();
+        style
+            .set_property(
+                "width",
+                &format!("{}px", f64::from(width.get()) / scale_factor),
+            )
+            .ok()
+            .swbuf_err("Failed to set the CSS width of the canvas")?;
+        style
+            .set_property(
+                "height",
+                &format!("{}px", f64::from(height.get()) / scale_factor),
+            )
+            .ok()
+            .swbuf_err("Failed to set the CSS height of the canvas")?;
+        Ok(())
+    }
+
+    /// Use CSS on the canvas instead.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        let max_size = self.canvas.borrow().max_size();
+        Capabilities {
+            partial_present: true,
+            zero_copy: false,
+            max_size: (max_size, max_size),
+            buffer_count: 1,
+            readback: true,
+        }
+    }
+
+    /// Canvases are only drawn from the memory of the page, which is copied.
+    pub unsafe fn present_external(
+        &mut self,
+        _memory: ExternalMemory,
+        _width: NonZeroU32,
+        _height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+/// Extension methods for the Wasm target on [`Surface`](crate::Surface).
+pub trait SurfaceExtWeb: Sized {
+    /// Creates a new instance of this struct, using the provided [`HtmlCanvasElement`].
+    ///
+    /// # Errors
+    /// - If the canvas was already controlled by an `OffscreenCanvas`.
+    /// - If a another context then "2d" was already created for this canvas.
+    fn from_canvas(canvas: HtmlCanvasElement) -> Result<Self, SoftBufferError>;
+
+    /// Creates a new instance of this struct, using the provided [`OffscreenCanvas`].
+    ///
+    /// This also works in a web worker, e.g. with a canvas obtained through
+    /// `HtmlCanvasElement.transferControlToOffscreen()` and posted to the worker. The surface
+    /// must then be created and presented from the worker, as it can't be sent between threads.
+    ///
+    /// # Errors
+    /// If a another context then "2d" was already created for this canvas.
+    fn from_offscreen_canvas(offscreen_canvas: OffscreenCanvas) -> Result<Self, SoftBufferError>;
+
+    /// Defer presenting to the next `requestAnimationFrame()` callback, instead of drawing onto
+    /// the canvas right away.
+    ///
+    /// Presents before the browser renders the next frame are coalesced into one, showing the
+    /// last buffer with the damage of all of them. [`Surface::buffer_released`] returns `false`
+    /// while a frame is pending, so apps can skip rendering frames that would never be shown.
+    ///
+    /// [`Surface::buffer_released`]: crate::Surface::buffer_released
+    fn set_sync_to_animation_frame(&mut self, sync: bool);
+
+    /// The number of physical pixels per CSS pixel, as reported by `window.devicePixelRatio`.
+    ///
+    /// This changes with the zoom level of the page, and when the window moves to another
+    /// display. Returns `None` in a worker, which has no `window`.
+    fn device_pixel_ratio(&self) -> Option<f64>;
+
+    /// Present the latest frame of a [`frame_channel`], if a new one was published, resizing the
+    /// surface to its size.
+    ///
+    /// Returns whether a frame was presented. This never blocks, so it can be called on the main
+    /// thread, e.g. from a `requestAnimationFrame()` callback.
+    fn present_frame(&mut self, reader: &mut FrameReader) -> Result<bool, SoftBufferError>;
+}
+
+impl SurfaceExtWeb for crate::Surface {
+    fn from_canvas(canvas: HtmlCanvasElement) -> Result<Self, SoftBufferError> {
+        let imple = crate::SurfaceDispatch::Web(WebImpl::from_canvas(canvas)?);
+
+        Ok(Self::from_impl(imple, crate::NoWindowHandle(()), None))
+    }
+
+    fn from_offscreen_canvas(offscreen_canvas: OffscreenCanvas) -> Result<Self, SoftBufferError> {
+        let imple = crate::SurfaceDispatch::Web(WebImpl::from_offscreen_canvas(offscreen_canvas)?);
+
+        Ok(Self::from_impl(imple, crate::NoWindowHandle(()), None))
+    }
+
+    fn device_pixel_ratio(&self) -> Option<f64> {
+        device_pixel_ratio()
+    }
+
+    fn present_frame(&mut self, reader: &mut FrameReader) -> Result<bool, SoftBufferError> {
+        match &mut *self.surface_impl {
+            crate::SurfaceDispatch::Web(imp) => {
+                let presented = imp.present_frame(reader)?;
+                // The surface was resized to the frame.
+                self.config.size = imp.size;
+                Ok(presented)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    // Other backends only exist with some features.
+    #[allow(clippy::single_match)]
+    fn set_sync_to_animation_frame(&mut self, sync: bool) {
+        match &mut *self.surface_impl {
+            crate::SurfaceDispatch::Web(imp) => {
+                imp.sync_to_animation_frame = sync;
+                if !sync {
+                    imp.flush();
+                }
+            }
+            #[allow(unreachable_patterns)]
+            _ => {}
+        }
+    }
+}
+
+impl Canvas {
+    fn size(&self) -> (u32, u32) {
+        match self {
+            Self::Canvas { canvas, .. } => (canvas.width(), canvas.height()),
+            Self::OffscreenCanvas { canvas, .. } => (canvas.width(), canvas.height()),
+            #[cfg(feature = "webgl")]
+            Self::WebGl { canvas, .. } => (canvas.width(), canvas.height()),
+            #[cfg(feature = "webgl")]
+            Self::OffscreenWebGl { canvas, .. } => (canvas.width(), canvas.height()),
+        }
+    }
+
+    fn set_width(&self, width: u32) {
+        match self {
+            Self::Canvas { canvas, .. } => canvas.set_width(width),
+            Self::OffscreenCanvas { canvas, .. } => canvas.set_width(width),
+            #[cfg(feature = "webgl")]
+            Self::WebGl { canvas, .. } => canvas.set_width(width),
+            #[cfg(feature = "webgl")]
+            Self::OffscreenWebGl { canvas, .. } => canvas.set_width(width),
+        }
+    }
+
+    fn set_height(&self, height: u32) {
+        match self {
+            Self::Canvas { canvas, .. } => canvas.set_height(height),
+            Self::OffscreenCanvas { canvas, .. } => canvas.set_height(height),
+            #[cfg(feature = "webgl")]
+            Self::WebGl { canvas, .. } => canvas.set_height(height),
+            #[cfg(feature = "webgl")]
+            Self::OffscreenWebGl { canvas, .. } => canvas.set_height(height),
+        }
+    }
+
+    /// The canvas element in the document, if it isn't an [`OffscreenCanvas`].
+    fn element(&self) -> Option<&HtmlCanvasElement> {
+        match self {
+            Self::Canvas { canvas, .. } => Some(canvas),
+            #[cfg(feature = "webgl")]
+            Self::WebGl { canvas, .. } => Some(canvas),
+            _ => None,
+        }
+    }
+
+    /// Draw the damaged parts of the buffer onto the canvas, upside down if `flip_vertical`.
+    ///
+    /// If the canvas isn't the size of the buffer yet, it is resized, which clears it, and all of
+    /// the buffer is drawn.
+    fn present(&mut self, buffer: &[u32], width: NonZeroU32, damage: &[Rect], flip_vertical: bool) {
+        let full;
+        let height = buffer.len() as u32 / width.get();
+        let damage = if self.size() != (width.get(), height) {
+            self.set_width(width.get());
+            self.set_height(height);
+            full = [Rect {
+                x: 0,
+                y: 0,
+                width,
+                // The buffer isn't empty.
+                height: NonZeroU32::new(height).unwrap(),
+            }];
+            &full[..]
+        } else {
+            damage
+        };
+
+        #[cfg(feature = "webgl")]
+        if let Some(gl) = self.webgl_mut() {
+            // Uploading the texture is the copy.
+            stats::time_copy(|| gl.present(buffer, width, damage, flip_vertical));
+            return;
+        }
+
+        let width = width.get();
+        let height = buffer.len() as u32 / width;
+
+        for rect in damage {
+            // Only convert the damaged part of the buffer, `putImageData` clips anything outside
+            // of the canvas anyway.
+            let x = rect.x.min(width);
+            let y = rect.y.min(height);
+            let rect_width = rect.width.get().min(width - x);
+            let rect_height = rect.height.get().min(height - y);
+            if rect_width == 0 || rect_height == 0 {
+                continue;
+            }
+
+            // Create a bitmap from the damaged rows, in the order they are shown.
+            let mut bitmap = vec![0; rect_width as usize * rect_height as usize];
+            stats::time_copy(|| {
+                for (row, dst) in bitmap.chunks_exact_mut(rect_width as usize).enumerate() {
+                    let src_y = if flip_vertical {
+                        (y + rect_height) as usize - 1 - row
+                    } else {
+                        y as usize + row
+                    };
+                    let src = &buffer[src_y * width as usize..][..(x + rect_width) as usize];
+                    convert::xrgb_to_rgbx(&src[x as usize..], dst);
+                }
+            });
+            // SAFETY: Wasm is little endian, so these are the bytes in RGBA order.
+            let bitmap =
+                unsafe { slice::from_raw_parts(bitmap.as_ptr().cast::<u8>(), bitmap.len() * 4) };
+            let image_data = image_data(bitmap, rect_width);
+
+            // This can only throw an error if `data` is detached, which is impossible.
+            let y = if flip_vertical {
+                height - y - rect_height
+            } else {
+                y
+            };
+            self.put_image_data(&image_data, x.into(), y.into())
+                .unwrap();
+        }
+    }
+
+    /// The largest width and height of the canvas that can be drawn to.
+    fn max_size(&self) -> u32 {
+        match self {
+            #[cfg(feature = "webgl")]
+            Self::WebGl { gl, .. } | Self::OffscreenWebGl { gl, .. } => {
+                MAX_CANVAS_SIZE.min(gl.max_texture_size)
+            }
+            _ => MAX_CANVAS_SIZE,
+        }
+    }
+
+    /// The WebGL presenter, which is used instead of the 2D context methods below.
+    #[cfg(feature = "webgl")]
+    fn webgl_mut(&mut self) -> Option<&mut webgl::WebGl> {
+        match self {
+            Self::WebGl { gl, .. } | Self::OffscreenWebGl { gl, .. } => Some(gl),
+            _ => None,
+        }
+    }
+
+    fn get_image_data(&self, sx: f64, sy: f64, sw: f64, sh: f64) -> Result<ImageData, JsValue> {
+        match self {
+            Canvas::Canvas { ctx, .. } => ctx.get_image_data(sx, sy, sw, sh),
+            Canvas::OffscreenCanvas { ctx, .. } => ctx.get_image_data(sx, sy, sw, sh),
+            #[cfg(feature = "webgl")]
+            Canvas::WebGl { .. } | Canvas::OffscreenWebGl { .. } => unreachable!(),
+        }
+    }
+
+    fn put_image_data(&self, imagedata: &ImageData, dx: f64, dy: f64) -> Result<(), JsValue> {
+        trace_span!("put_image_data");
+        match self {
+            Self::Canvas { ctx, .. } => ctx.put_image_data(imagedata, dx, dy),
+            Self::OffscreenCanvas { ctx, .. } => ctx.put_image_data(imagedata, dx, dy),
+            #[cfg(feature = "webgl")]
+            Self::WebGl { .. } | Self::OffscreenWebGl { .. } => unreachable!(),
+        }
+    }
+}
+
+pub struct BufferImpl<'a> {
+    imp: &'a mut WebImpl,
+}
+
+impl<'a> BufferImpl<'a> {
+    pub fn pixels(&self) -> &[u32] {
+        &self.imp.buffer
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.imp.buffer
+    }
+
+    pub fn age(&self) -> u8 {
+        if self.imp.buffer_presented {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// The pixels are copied into the canvas, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    /// The pixels are copied into the canvas.
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        None
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Push the buffer to the canvas.
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let (width, height) = self
+            .imp
+            .size
+            .expect("Must set size of surface before calling `present()`");
+        self.imp.present_with_damage(&[Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }])
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        self.imp.present_with_damage(damage)
+    }
+
+    /// The canvas is the size of the buffer.
+    pub fn present_viewport(
+        self,
+        _damage: &[Rect],
+        _viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+/// The attributes to request 2D contexts with.
+fn context_options() -> Object {
+    let options = Object::new();
+    // Keeps the canvas in memory instead of on the GPU. `putImageData` writes it from memory
+    // anyway, and this way `getImageData` in `fetch()` doesn't have to read it back from the GPU.
+    // Setting a property on a plain object can't fail.
+    Reflect::set(&options, &"willReadFrequently".into(), &true.into()).unwrap();
+    options
+}
+
+/// Create an `ImageData` from RGBA pixels, `width` pixels per row.
+fn image_data(bitmap: &[u8], width: u32) -> ImageData {
+    #[cfg(target_feature = "atomics")]
+    let result = {
+        use js_sys::{Uint8Array, Uint8ClampedArray};
+
+        #[wasm_bindgen]
+        extern "C" {
+            #[wasm_bindgen(js_name = ImageData)]
+            type ImageDataExt;
+
+            #[wasm_bindgen(catch, constructor, js_class = ImageData)]
+            fn new(array: Uint8ClampedArray, sw: u32) -> Result<ImageDataExt, JsValue>;
+        }
+
+        let array = Uint8Array::new_with_length(bitmap.len() as u32);
+        array.copy_from(bitmap);
+        let array = Uint8ClampedArray::new(&array);
+        ImageDataExt::new(array, width)
+            .map(JsValue::from)
+            .map(ImageData::unchecked_from_js)
+    };
+    #[cfg(not(target_feature = "atomics"))]
+    let result = ImageData::new_with_u8_clamped_array(wasm_bindgen::Clamped(bitmap), width);
+    // This should only throw an error if the buffer we pass's size is incorrect.
+    result.unwrap()
+}
+
+fn device_pixel_ratio() -> Option<f64> {
+    web_sys::window().map(|window| window.device_pixel_ratio())
+}
+
+/// Present the pending frame, if there is one, and return when it was presented to the surface.
+fn flush(
+    canvas: &RefCell<Canvas>,
+    pending: &RefCell<Option<PendingFrame>>,
+) -> Option<stats::Timestamp> {
+    let frame = pending.borrow_mut().take()?;
+    canvas.borrow_mut().present(
+        &frame.buffer,
+        frame.width,
+        &frame.damage,
+        frame.flip_vertical,
+    );
+    Some(frame.presented_at)
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// Also available in workers, unlike `Window.requestAnimationFrame()`.
+    #[wasm_bindgen(catch, js_name = requestAnimationFrame)]
+    fn request_animation_frame(callback: &js_sys::Function) -> Result<i32, JsValue>;
+}
+
+#[inline(always)]
+fn total_len(width: u32, height: u32) -> usize {
+    // Convert width and height to `usize`, then multiply.
+    width
+        .try_into()
+        .ok()
+        .and_then(|w: usize| height.try_into().ok().and_then(|h| w.checked_mul(h)))
+        .unwrap_or_else(|| {
+            panic!(
+                "Overflow when calculating total length of buffer: {}x{}",
+                width, height
+            );
+        })
+}
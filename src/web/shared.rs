@@ -0,0 +1,120 @@
+//! Handing frames from a rendering thread to the thread that presents them.
+//!
+//! With the `atomics` target feature, the memory of the module is a `SharedArrayBuffer`, so other
+//! wasm threads can render into it, as long as the page is cross-origin isolated. A canvas can
+//! only be presented from the thread that owns it though, and the main thread may not block. This
+//! passes frames through three buffers without locking: the renderer owns one, the presenter owns
+//! another, and the third holds the latest finished frame.
+
+use crate::util;
+
+use std::cell::UnsafeCell;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Set in `Shared::latest` when the presenter hasn't taken the frame yet.
+const FRESH: usize = 0b100;
+const INDEX: usize = 0b011;
+
+struct Slot {
+    width: NonZeroU32,
+    pixels: Vec<u32>,
+}
+
+struct Shared {
+    slots: [UnsafeCell<Slot>; 3],
+    /// The index of the slot with the latest finished frame, and `FRESH`.
+    latest: AtomicUsize,
+}
+
+// SAFETY: Each slot is only accessed by the side whose index refers to it, and handed over with
+// acquire and release swaps of `latest`.
+unsafe impl Sync for Shared {}
+
+/// Create a pair of handles for passing frames of `0RGB` pixels from one thread to another.
+///
+/// The [`FrameWriter`] can be sent to the thread that renders, while the [`FrameReader`] stays
+/// with the surface, which presents the frames with
+/// [`SurfaceExtWeb::present_frame`](crate::SurfaceExtWeb::present_frame).
+pub fn frame_channel(width: NonZeroU32, height: NonZeroU32) -> (FrameWriter, FrameReader) {
+    let slot = || {
+        UnsafeCell::new(Slot {
+            width,
+            pixels: Vec::new(),
+        })
+    };
+    let shared = Arc::new(Shared {
+        slots: [slot(), slot(), slot()],
+        latest: AtomicUsize::new(1),
+    });
+
+    (
+        FrameWriter {
+            shared: shared.clone(),
+            back: 0,
+            width,
+            height,
+        },
+        FrameReader { shared, front: 2 },
+    )
+}
+
+/// The rendering side of a [`frame_channel`].
+pub struct FrameWriter {
+    shared: Arc<Shared>,
+    back: usize,
+    width: NonZeroU32,
+    height: NonZeroU32,
+}
+
+impl FrameWriter {
+    /// Set the size of the following frames.
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) {
+        self.width = width;
+        self.height = height;
+    }
+
+    /// The pixels of the frame being rendered.
+    ///
+    /// The contents are those of an older frame, or unspecified after a resize.
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        // SAFETY: The back slot is only accessed by the writer.
+        let slot = unsafe { &mut *self.shared.slots[self.back].get() };
+        slot.width = self.width;
+        let len = self.width.get() as usize * self.height.get() as usize;
+        util::resize_pixels(&mut slot.pixels, len);
+        &mut slot.pixels
+    }
+
+    /// Hand the frame to the reader, replacing the previous one if it wasn't presented yet.
+    pub fn publish(&mut self) {
+        // Make sure the frame has the current size, even if it wasn't drawn into.
+        self.pixels_mut();
+        let previous = self.shared.latest.swap(self.back | FRESH, Ordering::AcqRel);
+        self.back = previous & INDEX;
+    }
+}
+
+/// The presenting side of a [`frame_channel`].
+pub struct FrameReader {
+    shared: Arc<Shared>,
+    front: usize,
+}
+
+impl FrameReader {
+    /// Take the latest frame, if one was published since the last call. This never blocks.
+    pub(super) fn take(&mut self) -> Option<(&[u32], NonZeroU32)> {
+        if self.shared.latest.load(Ordering::Relaxed) & FRESH == 0 {
+            return None;
+        }
+
+        // Only the reader clears `FRESH`, so the frame can't be taken from under us.
+        let latest = self.shared.latest.swap(self.front, Ordering::AcqRel);
+        self.front = latest & INDEX;
+
+        // SAFETY: The front slot is only accessed by the reader.
+        let slot = unsafe { &*self.shared.slots[self.front].get() };
+        Some((&slot.pixels, slot.width))
+    }
+}
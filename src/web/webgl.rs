@@ -0,0 +1,279 @@
+//! Presenting through a WebGL2 texture, enabled by the `webgl` feature.
+//!
+//! `putImageData` converts and copies the whole image on the CPU, which is slow for large
+//! canvases. Instead, the damaged parts of the buffer are uploaded into a texture as they are, and
+//! drawn onto the canvas by a shader that also swaps the channels.
+
+use crate::error::{self, SwResultExt};
+use crate::{Backend, Rect, SoftBufferError};
+use js_sys::{Object, Reflect};
+use wasm_bindgen::JsCast;
+use web_sys::{
+    WebGl2RenderingContext as Gl, WebGlProgram, WebGlShader, WebGlTexture, WebGlUniformLocation,
+};
+
+use std::num::NonZeroU32;
+
+const VERTEX_SHADER: &str = "#version 300 es
+void main() {
+    // A triangle covering the whole viewport.
+    vec2 position = vec2(gl_VertexID == 1 ? 3.0 : -1.0, gl_VertexID == 2 ? 3.0 : -1.0);
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+";
+
+const FRAGMENT_SHADER: &str = "#version 300 es
+precision highp float;
+uniform highp sampler2D buffer;
+uniform bool flip;
+out vec4 color;
+void main() {
+    // Rows of the buffer start at the top, but fragment coordinates at the bottom, so a flipped
+    // buffer is drawn as it is.
+    ivec2 size = textureSize(buffer, 0);
+    float y = flip ? gl_FragCoord.y : float(size.y) - gl_FragCoord.y;
+    ivec2 position = ivec2(gl_FragCoord.x, y);
+    // The bytes of our `0RGB` pixels are uploaded as RGBA, so swap them back.
+    color = vec4(texelFetch(buffer, position, 0).bgr, 1.0);
+}
+";
+
+/// The attributes to request the context with.
+pub(super) fn context_options() -> Object {
+    let options = Object::new();
+    for (key, value) in [("alpha", false), ("antialias", false), ("depth", false)] {
+        // Setting a property on a plain object can't fail.
+        Reflect::set(&options, &key.into(), &value.into()).unwrap();
+    }
+    options
+}
+
+pub(super) struct WebGl {
+    gl: Gl,
+    texture: WebGlTexture,
+    /// The `flip` uniform of the fragment shader.
+    flip: Option<WebGlUniformLocation>,
+    /// The size of the texture, once it was allocated.
+    size: Option<(u32, u32)>,
+    /// The largest width and height of a texture.
+    pub max_texture_size: u32,
+    /// Whether the context was lost at the last present.
+    lost: bool,
+}
+
+impl WebGl {
+    /// Set up the program and texture on the result of `getContext("webgl2")`.
+    pub fn new(context: Option<Object>) -> Result<Self, SoftBufferError> {
+        let gl: Gl = context
+            .swbuf_err("WebGL2 is not available")?
+            .dyn_into()
+            .unwrap_or_else(|_| {
+                panic!("`getContext(\"webgl2\")` didn't return a `WebGL2RenderingContext`")
+            });
+
+        let vertex_shader = compile_shader(&gl, Gl::VERTEX_SHADER, VERTEX_SHADER)?;
+        let fragment_shader = compile_shader(&gl, Gl::FRAGMENT_SHADER, FRAGMENT_SHADER)?;
+        let program = link_program(&gl, &vertex_shader, &fragment_shader)?;
+        gl.use_program(Some(&program));
+        let flip = gl.get_uniform_location(&program, "flip");
+
+        let texture = gl
+            .create_texture()
+            .swbuf_err("Failed to create WebGL texture")?;
+        gl.bind_texture(Gl::TEXTURE_2D, Some(&texture));
+        // Without mipmaps, the texture is only complete with these filters.
+        gl.tex_parameteri(Gl::TEXTURE_2D, Gl::TEXTURE_MIN_FILTER, Gl::NEAREST as i32);
+        gl.tex_parameteri(Gl::TEXTURE_2D, Gl::TEXTURE_MAG_FILTER, Gl::NEAREST as i32);
+
+        // Every WebGL2 implementation supports textures of at least 2048 pixels.
+        let max_texture_size = gl
+            .get_parameter(Gl::MAX_TEXTURE_SIZE)
+            .ok()
+            .and_then(|size| size.as_f64())
+            .map_or(2048, |size| size as u32);
+
+        Ok(Self {
+            gl,
+            texture,
+            flip,
+            size: None,
+            max_texture_size,
+            lost: false,
+        })
+    }
+
+    /// Upload the damaged parts of the buffer, and draw the texture onto the canvas.
+    pub fn present(
+        &mut self,
+        buffer: &[u32],
+        width: NonZeroU32,
+        damage: &[Rect],
+        flip_vertical: bool,
+    ) {
+        let full = [Rect {
+            x: 0,
+            y: 0,
+            width,
+            height: NonZeroU32::new(buffer.len() as u32 / width).unwrap(),
+        }];
+        let (width, height) = (full[0].width.get(), full[0].height.get());
+
+        // Nothing is drawn into a lost context, and the program and texture are gone when the
+        // browser restores it, so the surface has to be created again.
+        let lost = self.gl.is_context_lost();
+        if lost && !self.lost {
+            error::report(
+                Backend::Web,
+                SoftBufferError::PlatformError(Some("The WebGL context was lost".into()), None),
+            );
+        }
+        self.lost = lost;
+
+        let gl = &self.gl;
+        gl.bind_texture(Gl::TEXTURE_2D, Some(&self.texture));
+
+        // SAFETY: Any `u32` is also valid as four bytes.
+        let bytes =
+            unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast::<u8>(), buffer.len() * 4) };
+
+        let damage = if self.size != Some((width, height)) {
+            // The new texture has no content yet, so upload all of it.
+            self.size = Some((width, height));
+            gl.viewport(0, 0, width as i32, height as i32);
+            gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                Gl::TEXTURE_2D,
+                0,
+                Gl::RGBA8 as i32,
+                width as i32,
+                height as i32,
+                0,
+                Gl::RGBA,
+                Gl::UNSIGNED_BYTE,
+                None,
+            )
+            // This only throws if the size is larger than WebGL supports, which is reported
+            // through `getError()` instead.
+            .unwrap();
+            &full[..]
+        } else {
+            damage
+        };
+
+        gl.pixel_storei(Gl::UNPACK_ROW_LENGTH, width as i32);
+        for rect in damage {
+            // Only upload the part of the damage inside the buffer, like `putImageData` does.
+            let x = rect.x.min(width);
+            let y = rect.y.min(height);
+            let rect_width = rect.width.get().min(width - x);
+            let rect_height = rect.height.get().min(height - y);
+            if rect_width == 0 || rect_height == 0 {
+                continue;
+            }
+
+            gl.pixel_storei(Gl::UNPACK_SKIP_PIXELS, x as i32);
+            gl.pixel_storei(Gl::UNPACK_SKIP_ROWS, y as i32);
+            gl.tex_sub_image_2d_with_i32_and_i32_and_u32_and_type_and_opt_u8_array(
+                Gl::TEXTURE_2D,
+                0,
+                x as i32,
+                y as i32,
+                rect_width as i32,
+                rect_height as i32,
+                Gl::RGBA,
+                Gl::UNSIGNED_BYTE,
+                Some(bytes),
+            )
+            // This can only throw if the data is too small, which is impossible.
+            .unwrap();
+        }
+
+        // The canvas is cleared after it was composited, so always draw all of it.
+        gl.uniform1i(self.flip.as_ref(), flip_vertical.into());
+        gl.draw_arrays(Gl::TRIANGLES, 0, 3);
+    }
+
+    /// Read the last presented buffer back from the canvas.
+    pub fn fetch(&self, width: u32, height: u32) -> Vec<u32> {
+        if self.size != Some((width, height)) {
+            // Nothing was presented at this size yet.
+            return vec![0; width as usize * height as usize];
+        }
+
+        // Draw the texture again, as the canvas may have been cleared since.
+        self.gl.draw_arrays(Gl::TRIANGLES, 0, 3);
+        let mut bytes = vec![0; width as usize * height as usize * 4];
+        self.gl
+            .read_pixels_with_opt_u8_array(
+                0,
+                0,
+                width as i32,
+                height as i32,
+                Gl::RGBA,
+                Gl::UNSIGNED_BYTE,
+                Some(&mut bytes),
+            )
+            // This can only throw if the data is too small, which is impossible.
+            .unwrap();
+
+        // Rows are read from the bottom up.
+        bytes
+            .chunks_exact(width as usize * 4)
+            .rev()
+            .flat_map(|row| row.chunks_exact(4))
+            .map(|chunk| u32::from_be_bytes([0, chunk[0], chunk[1], chunk[2]]))
+            .collect()
+    }
+}
+
+fn compile_shader(gl: &Gl, kind: u32, source: &str) -> Result<WebGlShader, SoftBufferError> {
+    let shader = gl
+        .create_shader(kind)
+        .swbuf_err("Failed to create WebGL shader")?;
+    gl.shader_source(&shader, source);
+    gl.compile_shader(&shader);
+
+    if gl
+        .get_shader_parameter(&shader, Gl::COMPILE_STATUS)
+        .as_bool()
+        .unwrap_or(false)
+    {
+        Ok(shader)
+    } else {
+        Err(SoftBufferError::PlatformError(
+            Some(format!(
+                "Failed to compile WebGL shader: {}",
+                gl.get_shader_info_log(&shader).unwrap_or_default()
+            )),
+            None,
+        ))
+    }
+}
+
+fn link_program(
+    gl: &Gl,
+    vertex_shader: &WebGlShader,
+    fragment_shader: &WebGlShader,
+) -> Result<WebGlProgram, SoftBufferError> {
+    let program = gl
+        .create_program()
+        .swbuf_err("Failed to create WebGL program")?;
+    gl.attach_shader(&program, vertex_shader);
+    gl.attach_shader(&program, fragment_shader);
+    gl.link_program(&program);
+
+    if gl
+        .get_program_parameter(&program, Gl::LINK_STATUS)
+        .as_bool()
+        .unwrap_or(false)
+    {
+        Ok(program)
+    } else {
+        Err(SoftBufferError::PlatformError(
+            Some(format!(
+                "Failed to link WebGL program: {}",
+                gl.get_program_info_log(&program).unwrap_or_default()
+            )),
+            None,
+        ))
+    }
+}
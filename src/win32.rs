@@ -2,17 +2,40 @@
 //!
 //! This module converts the input buffer into a bitmap and then stretches it to the window.
 
-use crate::{Rect, SoftBufferError};
+use crate::{
+    error, stats, util, Backend, Capabilities, ColorSpace, ExternalMemory, NativeBuffer,
+    PresentStatus, Rect, Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
 use raw_window_handle::Win32WindowHandle;
 
+use std::ffi::c_void;
 use std::io;
 use std::mem;
 use std::num::{NonZeroI32, NonZeroU32};
 use std::ptr::{self, NonNull};
 use std::slice;
+use std::time::Duration;
 
-use windows_sys::Win32::Foundation::HWND;
+use windows_sys::Win32::Foundation::{
+    CloseHandle, HANDLE, HWND, INVALID_HANDLE_VALUE, POINT, RECT,
+};
+use windows_sys::Win32::Graphics::Dwm::{DwmGetWindowAttribute, DWMWA_CLOAKED};
 use windows_sys::Win32::Graphics::Gdi;
+use windows_sys::Win32::System::Memory::{CreateFileMappingW, PAGE_READWRITE};
+use windows_sys::Win32::UI::WindowsAndMessaging::{
+    GetClientRect, GetWindowRect, IsIconic, IsWindow,
+};
+
+/// The largest width and height of a bitmap that can be drawn, as GDI coordinates are limited to
+/// 27 bits.
+const MAX_SIZE: u32 = (1 << 27) - 1;
+
+const ZERO_RECT: RECT = RECT {
+    left: 0,
+    top: 0,
+    right: 0,
+    bottom: 0,
+};
 
 const ZERO_QUAD: Gdi::RGBQUAD = Gdi::RGBQUAD {
     rgbBlue: 0,
@@ -27,9 +50,15 @@ struct Buffer {
     pixels: NonNull<u32>,
     width: NonZeroI32,
     height: NonZeroI32,
+    /// Whether the first row of `pixels` is the bottom one.
+    bottom_up: bool,
     presented: bool,
 }
 
+// SAFETY: GDI objects aren't tied to the thread that created them, and the bitmap is only used
+// by the surface that owns it.
+unsafe impl Send for Buffer {}
+
 impl Drop for Buffer {
     fn drop(&mut self) {
         unsafe {
@@ -40,7 +69,16 @@ impl Drop for Buffer {
 }
 
 impl Buffer {
-    fn new(window_dc: Gdi::HDC, width: NonZeroI32, height: NonZeroI32) -> Self {
+    /// Create a bitmap at `offset` into `section`, or in new memory if it is `0`.
+    fn new(
+        window_dc: Gdi::HDC,
+        width: NonZeroI32,
+        height: NonZeroI32,
+        bottom_up: bool,
+        section: HANDLE,
+        offset: u32,
+    ) -> Result<Self, SoftBufferError> {
+        trace_span!("create_dib_section");
         let dc = unsafe { Gdi::CreateCompatibleDC(window_dc) };
         assert!(dc != 0);
 
@@ -49,7 +87,12 @@ impl Buffer {
             bmi_header: Gdi::BITMAPINFOHEADER {
                 biSize: mem::size_of::<Gdi::BITMAPINFOHEADER>() as u32,
                 biWidth: width.get(),
-                biHeight: -height.get(),
+                // A positive height makes a bottom-up bitmap.
+                biHeight: if bottom_up {
+                    height.get()
+                } else {
+                    -height.get()
+                },
                 biPlanes: 1,
                 biBitCount: 32,
                 biCompression: Gdi::BI_BITFIELDS as u32,
@@ -76,8 +119,6 @@ impl Buffer {
         };
 
         // XXX alignment?
-        // XXX better to use CreateFileMapping, and pass hSection?
-        // XXX test return value?
         let mut pixels: *mut u32 = ptr::null_mut();
         let bitmap = unsafe {
             Gdi::CreateDIBSection(
@@ -85,25 +126,35 @@ impl Buffer {
                 &bitmap_info as *const BitmapInfo as *const _,
                 Gdi::DIB_RGB_COLORS,
                 &mut pixels as *mut *mut u32 as _,
-                0,
-                0,
+                section,
+                offset,
             )
         };
-        assert!(bitmap != 0);
-        let pixels = NonNull::new(pixels).unwrap();
+        let pixels = match NonNull::new(pixels) {
+            Some(pixels) if bitmap != 0 => pixels,
+            _ => {
+                let err = io::Error::last_os_error();
+                unsafe { Gdi::DeleteDC(dc) };
+                return Err(SoftBufferError::PlatformError(
+                    Some("Failed to create a DIB section".into()),
+                    Some(Box::new(err)),
+                ));
+            }
+        };
 
         unsafe {
             Gdi::SelectObject(dc, bitmap);
         }
 
-        Self {
+        Ok(Self {
             dc,
             bitmap,
             width,
             height,
+            bottom_up,
             pixels,
             presented: false,
-        }
+        })
     }
 
     #[inline]
@@ -127,6 +178,41 @@ impl Buffer {
     }
 }
 
+/// Memory that the bitmaps are created in, so that resizing doesn't allocate every time.
+struct Section {
+    handle: HANDLE,
+    size: usize,
+}
+
+impl Section {
+    fn new(size: usize) -> io::Result<Self> {
+        trace_span!("create_file_mapping", size);
+        let handle = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                ptr::null(),
+                PAGE_READWRITE,
+                (size as u64 >> 32) as u32,
+                size as u32,
+                ptr::null(),
+            )
+        };
+        if handle == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { handle, size })
+    }
+}
+
+impl Drop for Section {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.handle);
+        }
+    }
+}
+
 /// The handle to a window for software buffering.
 pub struct Win32Impl {
     /// The window handle.
@@ -136,7 +222,21 @@ pub struct Win32Impl {
     dc: Gdi::HDC,
 
     /// The buffer used to hold the image.
+    ///
+    /// This is declared before `section`, so its bitmap is dropped first.
     buffer: Option<Buffer>,
+
+    /// The memory of the buffer.
+    section: Option<Section>,
+
+    /// Whether the buffer is created bottom-up.
+    flip_vertical: bool,
+
+    /// Whether the buffer is drawn in the center of the window, instead of its upper-left corner.
+    centered: bool,
+
+    /// The color that the parts of the window outside of the buffer are filled with.
+    background: Option<u32>,
 }
 
 /// The Win32-compatible bitmap information.
@@ -175,10 +275,16 @@ impl Win32Impl {
             dc,
             window: hwnd,
             buffer: None,
+            section: None,
+            flip_vertical: false,
+            centered: false,
+            background: None,
         })
     }
 
     pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
+        self.check_window()?;
+
         let (width, height) = (|| {
             let width = NonZeroI32::new(i32::try_from(width.get()).ok()?)?;
             let height = NonZeroI32::new(i32::try_from(height.get()).ok()?)?;
@@ -187,38 +293,112 @@ impl Win32Impl {
         .ok_or(SoftBufferError::SizeOutOfRange { width, height })?;
 
         if let Some(buffer) = self.buffer.as_ref() {
-            if buffer.width == width && buffer.height == height {
+            if buffer.width == width
+                && buffer.height == height
+                && buffer.bottom_up == self.flip_vertical
+            {
                 return Ok(());
             }
         }
 
-        self.buffer = Some(Buffer::new(self.dc, width, height));
+        // Drop the old bitmap before the memory it is in.
+        self.buffer = None;
+
+        // Round the size up to the next power of two to prevent frequent reallocations.
+        let size = (width.get() as usize * height.get() as usize * 4).next_power_of_two();
+        let section = match self.section.take() {
+            Some(section) if section.size >= size => section,
+            _ => Section::new(size).map_err(|err| {
+                SoftBufferError::PlatformError(
+                    Some("Failed to create a file mapping".into()),
+                    Some(Box::new(err)),
+                )
+            })?,
+        };
+        self.buffer = Some(Buffer::new(
+            self.dc,
+            width,
+            height,
+            self.flip_vertical,
+            section.handle,
+            0,
+        )?);
+        self.section = Some(section);
 
         Ok(())
     }
 
-    pub fn buffer_mut(&mut self) -> Result<BufferImpl, SoftBufferError> {
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        self.check_window()?;
         if self.buffer.is_none() {
-            panic!("Must set size of surface before calling `buffer_mut()`");
+            return Err(SoftBufferError::SurfaceNotConfigured);
         }
 
         Ok(BufferImpl(self))
     }
 
-    fn present_with_damage(&mut self, damage: &[Rect]) -> Result<(), SoftBufferError> {
+    /// Draw the damaged parts of `viewport` into the window, so that its upper-left corner is in
+    /// the upper-left corner or the center of the window.
+    fn present_with_damage(
+        &mut self,
+        damage: &[Rect],
+        viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        self.check_window()?;
+
+        // Nothing is drawn to a minimized window.
+        if unsafe { IsIconic(self.window) } != 0 {
+            return Ok(PresentStatus::Skipped);
+        }
+
+        let (offset_x, offset_y) = self.place(viewport)?;
         let buffer = self.buffer.as_mut().unwrap();
+        let (bottom_up, buffer_height) = (buffer.bottom_up, buffer.height.get());
+        // Get the rect in the coordinates of the bitmap.
+        let to_gdi = |rect: Rect| -> Result<_, SoftBufferError> {
+            let (x, y, width, height) = (|| {
+                Some((
+                    i32::try_from(rect.x).ok()?,
+                    i32::try_from(rect.y).ok()?,
+                    i32::try_from(rect.width.get()).ok()?,
+                    i32::try_from(rect.height.get()).ok()?,
+                ))
+            })()
+            .ok_or(SoftBufferError::DamageOutOfRange { rect })?;
+            // GDI shows bottom-up bitmaps upside down, so the rows of the rect are there too.
+            let y = if bottom_up {
+                buffer_height - y - height
+            } else {
+                y
+            };
+            Ok((x, y, width, height))
+        };
+        let (origin_x, origin_y, _, _) = to_gdi(viewport)?;
         unsafe {
+            trace_span!("bit_blt", rects = damage.len());
             for rect in damage.iter().copied() {
-                let (x, y, width, height) = (|| {
-                    Some((
-                        i32::try_from(rect.x).ok()?,
-                        i32::try_from(rect.y).ok()?,
-                        i32::try_from(rect.width.get()).ok()?,
-                        i32::try_from(rect.height.get()).ok()?,
-                    ))
-                })()
-                .ok_or(SoftBufferError::DamageOutOfRange { rect })?;
-                Gdi::BitBlt(self.dc, x, y, width, height, buffer.dc, x, y, Gdi::SRCCOPY);
+                let (x, y, width, height) = to_gdi(rect)?;
+                let copied = Gdi::BitBlt(
+                    self.dc,
+                    x - origin_x + offset_x,
+                    y - origin_y + offset_y,
+                    width,
+                    height,
+                    buffer.dc,
+                    x,
+                    y,
+                    Gdi::SRCCOPY,
+                );
+                // The rest of the damage can still be shown.
+                if copied == 0 {
+                    error::report(
+                        Backend::Win32,
+                        SoftBufferError::PlatformError(
+                            Some("Failed to copy damage to the window".into()),
+                            Some(Box::new(io::Error::last_os_error())),
+                        ),
+                    );
+                }
             }
 
             // Validate the window.
@@ -226,13 +406,58 @@ impl Win32Impl {
         }
         buffer.presented = true;
 
+        Ok(PresentStatus::Presented)
+    }
+
+    /// Copy `src` of the window of `other` to `dst` of this window with GDI.
+    pub fn copy_from(
+        &mut self,
+        other: &Self,
+        src: Rect,
+        dst: (u32, u32),
+    ) -> Result<(), SoftBufferError> {
+        self.check_window()?;
+        other.check_window()?;
+        let (src_x, src_y, dst_x, dst_y, width, height) = (|| {
+            Some((
+                i32::try_from(src.x).ok()?,
+                i32::try_from(src.y).ok()?,
+                i32::try_from(dst.0).ok()?,
+                i32::try_from(dst.1).ok()?,
+                i32::try_from(src.width.get()).ok()?,
+                i32::try_from(src.height.get()).ok()?,
+            ))
+        })()
+        .ok_or(SoftBufferError::DamageOutOfRange { rect: src })?;
+
+        trace_span!("bit_blt");
+        let copied = unsafe {
+            Gdi::BitBlt(
+                self.dc,
+                dst_x,
+                dst_y,
+                width,
+                height,
+                other.dc,
+                src_x,
+                src_y,
+                Gdi::SRCCOPY,
+            )
+        };
+        if copied == 0 {
+            return Err(SoftBufferError::PlatformError(
+                Some("Failed to copy between windows".into()),
+                Some(Box::new(io::Error::last_os_error())),
+            ));
+        }
         Ok(())
     }
 
     /// Fetch the buffer from the window.
     pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        self.check_window()?;
         let buffer = self.buffer.as_ref().unwrap();
-        let temp_buffer = Buffer::new(self.dc, buffer.width, buffer.height);
+        let temp_buffer = Buffer::new(self.dc, buffer.width, buffer.height, false, 0, 0)?;
 
         // Just go the other way.
         unsafe {
@@ -256,6 +481,407 @@ impl Win32Impl {
 
         Ok(temp_buffer.pixels().to_vec())
     }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// Free the bitmap and its memory, the window keeps showing what was drawn into it.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        // Drop the bitmap before the memory it is in.
+        self.buffer = None;
+        self.section = None;
+        Ok(())
+    }
+
+    /// Wait for the next vertical blank.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Get the refresh interval of the current display mode of the monitor the window is on.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        self.check_window()?;
+        let monitor = unsafe { Gdi::MonitorFromWindow(self.window, Gdi::MONITOR_DEFAULTTONEAREST) };
+
+        let mut info: Gdi::MONITORINFOEXW = unsafe { mem::zeroed() };
+        info.monitorInfo.cbSize = mem::size_of::<Gdi::MONITORINFOEXW>() as u32;
+        if unsafe { Gdi::GetMonitorInfoW(monitor, &mut info.monitorInfo) } == 0 {
+            return Err(SoftBufferError::PlatformError(
+                Some("Failed to get monitor info".into()),
+                Some(Box::new(io::Error::last_os_error())),
+            ));
+        }
+
+        let mut mode: Gdi::DEVMODEW = unsafe { mem::zeroed() };
+        mode.dmSize = mem::size_of::<Gdi::DEVMODEW>() as u16;
+        if unsafe {
+            Gdi::EnumDisplaySettingsW(
+                info.szDevice.as_ptr(),
+                Gdi::ENUM_CURRENT_SETTINGS,
+                &mut mode,
+            )
+        } == 0
+        {
+            return Err(SoftBufferError::PlatformError(
+                Some("Failed to get display settings".into()),
+                None,
+            ));
+        }
+
+        // 0 and 1 stand for the default rate of the hardware, which isn't known.
+        match mode.dmDisplayFrequency {
+            0 | 1 => Err(SoftBufferError::Unimplemented),
+            frequency => Ok(Duration::from_secs(1) / frequency),
+        }
+    }
+
+    /// Get the size of the client area of the window in pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        self.check_window()?;
+        let mut rect = ZERO_RECT;
+        if unsafe { GetClientRect(self.window, &mut rect) } == 0 {
+            return Err(SoftBufferError::PlatformError(
+                Some("Failed to get client rect".into()),
+                Some(Box::new(io::Error::last_os_error())),
+            ));
+        }
+        Ok((
+            (rect.right - rect.left) as u32,
+            (rect.bottom - rect.top) as u32,
+        ))
+    }
+
+    /// Get whether the window is minimized or cloaked, or clipped by other windows.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        self.check_window()?;
+        if unsafe { IsIconic(self.window) } != 0 {
+            return Ok(Visibility::Hidden);
+        }
+
+        // Cloaked windows, e.g. on other virtual desktops, aren't drawn by DWM.
+        let mut cloaked = 0u32;
+        let result = unsafe {
+            DwmGetWindowAttribute(
+                self.window,
+                DWMWA_CLOAKED,
+                (&mut cloaked as *mut u32).cast(),
+                mem::size_of::<u32>() as u32,
+            )
+        };
+        if result >= 0 && cloaked != 0 {
+            return Ok(Visibility::Hidden);
+        }
+
+        // With desktop composition, windows are drawn offscreen and never clipped by others.
+        let mut clip = ZERO_RECT;
+        let mut client = ZERO_RECT;
+        let region = unsafe { Gdi::GetClipBox(self.dc, &mut clip) };
+        if unsafe { GetClientRect(self.window, &mut client) } == 0 {
+            return Err(SoftBufferError::PlatformError(
+                Some("Failed to get client rect".into()),
+                Some(Box::new(io::Error::last_os_error())),
+            ));
+        }
+        Ok(match region {
+            Gdi::NULLREGION => Visibility::Hidden,
+            Gdi::COMPLEXREGION => Visibility::PartiallyOccluded,
+            Gdi::SIMPLEREGION
+                if (clip.left, clip.top, clip.right, clip.bottom)
+                    != (client.left, client.top, client.right, client.bottom) =>
+            {
+                Visibility::PartiallyOccluded
+            }
+            _ => Visibility::Visible,
+        })
+    }
+
+    /// Check that the window wasn't destroyed, since its device context is invalid then.
+    fn check_window(&self) -> Result<(), SoftBufferError> {
+        if unsafe { IsWindow(self.window) } == 0 {
+            return Err(SoftBufferError::WindowDestroyed);
+        }
+        Ok(())
+    }
+
+    /// Set how the buffer is scaled to the window.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        self.centered = match scaling {
+            Scaling::TopLeft => false,
+            Scaling::Center => true,
+            _ => return Err(SoftBufferError::Unimplemented),
+        };
+        Ok(())
+    }
+
+    /// Fill the parts of the window outside of the buffer by every present.
+    pub fn set_background_color(&mut self, color: u32) -> Result<(), SoftBufferError> {
+        self.background = Some(color);
+        Ok(())
+    }
+
+    /// GDI doesn't wait for the compositor.
+    pub fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Where the upper-left corner of `viewport` is drawn in the window, after filling the parts
+    /// of the window around it with the background color.
+    fn place(&self, viewport: Rect) -> Result<(i32, i32), SoftBufferError> {
+        if !self.centered && self.background.is_none() {
+            return Ok((0, 0));
+        }
+        let window = self.window_size()?;
+        let image = (viewport.width.get(), viewport.height.get());
+        let position = util::image_position(self.centered, window, image);
+
+        if let Some(color) = self.background {
+            // GDI colors are `0x00BBGGRR`.
+            let color = (color & 0xff) << 16 | (color & 0xff00) | (color >> 16 & 0xff);
+            unsafe {
+                let brush = Gdi::CreateSolidBrush(color);
+                for rect in util::uncovered(window, position, image) {
+                    // The window is smaller than `i32::MAX`.
+                    let rect = RECT {
+                        left: rect.x as i32,
+                        top: rect.y as i32,
+                        right: (rect.x + rect.width.get()) as i32,
+                        bottom: (rect.y + rect.height.get()) as i32,
+                    };
+                    Gdi::FillRect(self.dc, &rect, brush);
+                }
+                Gdi::DeleteObject(brush);
+            }
+        }
+
+        // The window is smaller than `i32::MAX`, and so is the part of the image cut off.
+        Ok((position.0 as i32, position.1 as i32))
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// There is only one buffer already.
+    pub fn set_single_buffered(&mut self, _single: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Recreate the bitmap bottom-up, in the same memory, which makes GDI flip it.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        self.flip_vertical = flip;
+        match &self.buffer {
+            Some(buffer) => self.resize(
+                NonZeroU32::new(buffer.width.get() as u32).unwrap(),
+                NonZeroU32::new(buffer.height.get() as u32).unwrap(),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// The window procedure of the application decides how the exposed parts are erased.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// The window is sized in physical pixels, if the process is DPI aware.
+    pub fn set_scale_factor(&mut self, _scale_factor: f64) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the region of the window to the shape, moved past the border of the window.
+    pub fn set_shape(&mut self, shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        self.check_window()?;
+        let region = match shape {
+            Some(shape) => {
+                // The region is in the coordinates of the whole window, not of the client area.
+                let mut window_rect = ZERO_RECT;
+                let mut client_origin = POINT { x: 0, y: 0 };
+                unsafe {
+                    GetWindowRect(self.window, &mut window_rect);
+                    Gdi::ClientToScreen(self.window, &mut client_origin);
+                }
+                let (dx, dy) = (
+                    client_origin.x - window_rect.left,
+                    client_origin.y - window_rect.top,
+                );
+
+                // The rects of an `RGNDATA` follow its header. The rects fit inside the bitmap,
+                // so their coordinates fit in `i32`s.
+                let header_len = mem::size_of::<Gdi::RGNDATAHEADER>() / mem::size_of::<RECT>();
+                let mut data = vec![ZERO_RECT; header_len];
+                data.extend(shape.iter().map(|rect| RECT {
+                    left: rect.x as i32 + dx,
+                    top: rect.y as i32 + dy,
+                    right: (rect.x + rect.width.get()) as i32 + dx,
+                    bottom: (rect.y + rect.height.get()) as i32 + dy,
+                }));
+                let bound = data[header_len..]
+                    .iter()
+                    .fold(None, |bound: Option<RECT>, rect| {
+                        Some(match bound {
+                            None => *rect,
+                            Some(bound) => RECT {
+                                left: bound.left.min(rect.left),
+                                top: bound.top.min(rect.top),
+                                right: bound.right.max(rect.right),
+                                bottom: bound.bottom.max(rect.bottom),
+                            },
+                        })
+                    });
+                let header = Gdi::RGNDATAHEADER {
+                    dwSize: mem::size_of::<Gdi::RGNDATAHEADER>() as u32,
+                    iType: Gdi::RDH_RECTANGLES,
+                    nCount: shape.len() as u32,
+                    nRgnSize: 0,
+                    rcBound: bound.unwrap_or(ZERO_RECT),
+                };
+                unsafe { ptr::write(data.as_mut_ptr().cast(), header) };
+
+                let region = unsafe {
+                    Gdi::ExtCreateRegion(
+                        ptr::null(),
+                        mem::size_of_val(&data[..]) as u32,
+                        data.as_ptr().cast(),
+                    )
+                };
+                if region == 0 {
+                    return Err(SoftBufferError::PlatformError(
+                        Some("Failed to create a region".into()),
+                        Some(Box::new(io::Error::last_os_error())),
+                    ));
+                }
+                region
+            }
+            None => 0,
+        };
+
+        // The window owns the region once it is set.
+        if unsafe { Gdi::SetWindowRgn(self.window, region, 1) } == 0 {
+            let err = io::Error::last_os_error();
+            if region != 0 {
+                unsafe { Gdi::DeleteObject(region) };
+            }
+            return Err(SoftBufferError::PlatformError(
+                Some("Failed to set the region of the window".into()),
+                Some(Box::new(err)),
+            ));
+        }
+        Ok(())
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            partial_present: true,
+            zero_copy: true,
+            max_size: (MAX_SIZE, MAX_SIZE),
+            buffer_count: 1,
+            readback: true,
+        }
+    }
+
+    /// Draw a bitmap created in the section with GDI.
+    pub unsafe fn present_external(
+        &mut self,
+        memory: ExternalMemory,
+        width: NonZeroU32,
+        height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        #[allow(unreachable_patterns)]
+        let (section, offset) = match memory {
+            ExternalMemory::Win32Section { section, offset } => (section, offset),
+            _ => return Err(SoftBufferError::Unimplemented),
+        };
+        self.check_window()?;
+
+        // Nothing is drawn to a minimized window.
+        if unsafe { IsIconic(self.window) } != 0 {
+            return Ok(PresentStatus::Skipped);
+        }
+
+        let (bitmap_width, bitmap_height) = (|| {
+            let width = NonZeroI32::new(i32::try_from(width.get()).ok()?)?;
+            let height = NonZeroI32::new(i32::try_from(height.get()).ok()?)?;
+            Some((width, height))
+        })()
+        .ok_or(SoftBufferError::SizeOutOfRange { width, height })?;
+        let bitmap = Buffer::new(
+            self.dc,
+            bitmap_width,
+            bitmap_height,
+            false,
+            section.as_ptr() as HANDLE,
+            offset,
+        )?;
+        unsafe {
+            trace_span!("bit_blt");
+            Gdi::BitBlt(
+                self.dc,
+                0,
+                0,
+                bitmap_width.get(),
+                bitmap_height.get(),
+                bitmap.dc,
+                0,
+                0,
+                Gdi::SRCCOPY,
+            );
+            Gdi::ValidateRect(self.window, ptr::null_mut());
+
+            // The memory may be reused once this returns.
+            Gdi::GdiFlush();
+        }
+
+        Ok(PresentStatus::Presented)
+    }
+}
+
+/// Extension methods for Win32 on [`Surface`](crate::Surface).
+pub trait SurfaceExtWindows {
+    /// The `HDC` of the window, which the buffer is drawn into, e.g. for drawing on top of it
+    /// with GDI after presenting. Returns `None` for surfaces of other backends.
+    fn hdc(&self) -> Option<NonNull<c_void>>;
+
+    /// The `HDC` of the memory device context of the bitmap that is the buffer, e.g. for drawing
+    /// text into it with GDI before presenting. Call `GdiFlush` before touching the pixels
+    /// again. The device context is replaced when the bitmap is, e.g. by
+    /// [`Surface::resize`](crate::Surface::resize), and returns `None` before the first one.
+    fn buffer_hdc(&self) -> Option<NonNull<c_void>>;
+}
+
+impl<D, W> SurfaceExtWindows for crate::Surface<D, W> {
+    fn hdc(&self) -> Option<NonNull<c_void>> {
+        match &*self.surface_impl {
+            crate::SurfaceDispatch::Win32(imp) => NonNull::new(imp.dc as *mut c_void),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    fn buffer_hdc(&self) -> Option<NonNull<c_void>> {
+        match &*self.surface_impl {
+            crate::SurfaceDispatch::Win32(imp) => {
+                NonNull::new(imp.buffer.as_ref()?.dc as *mut c_void)
+            }
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
 }
 
 pub struct BufferImpl<'a>(&'a mut Win32Impl);
@@ -278,20 +904,49 @@ impl<'a> BufferImpl<'a> {
         }
     }
 
-    pub fn present(self) -> Result<(), SoftBufferError> {
-        let imp = self.0;
-        let buffer = imp.buffer.as_ref().unwrap();
-        imp.present_with_damage(&[Rect {
+    /// The pixels are copied into the window, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        let buffer = self.0.buffer.as_ref().unwrap();
+        Some(NativeBuffer::Win32 {
+            hbitmap: NonNull::new(buffer.bitmap as *mut c_void)?,
+            hdc: NonNull::new(buffer.dc as *mut c_void)?,
+        })
+    }
+
+    pub fn set_shape(&mut self, shape: &[Rect]) -> Result<(), SoftBufferError> {
+        self.0.set_shape(Some(shape))
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let full = self.full_rect();
+        self.0.present_with_damage(&[full], full)
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let full = self.full_rect();
+        self.0.present_with_damage(damage, full)
+    }
+
+    pub fn present_viewport(
+        self,
+        damage: &[Rect],
+        viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        self.0.present_with_damage(damage, viewport)
+    }
+
+    fn full_rect(&self) -> Rect {
+        let buffer = self.0.buffer.as_ref().unwrap();
+        Rect {
             x: 0,
             y: 0,
             // We know width/height will be non-negative
             width: buffer.width.try_into().unwrap(),
             height: buffer.height.try_into().unwrap(),
-        }])
-    }
-
-    pub fn present_with_damage(self, damage: &[Rect]) -> Result<(), SoftBufferError> {
-        let imp = self.0;
-        imp.present_with_damage(damage)
+        }
     }
 }
@@ -0,0 +1,85 @@
+//! Creating surfaces for `winit` windows, and keeping them the size of the window.
+
+use crate::{Context, NoDisplayHandle, SoftBufferError, Surface, SurfaceDispatch};
+use ::winit::dpi::PhysicalSize;
+use ::winit::event::{Event, WindowEvent};
+use ::winit::window::Window;
+use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
+use std::borrow::Borrow;
+use std::num::NonZeroU32;
+
+impl<W: Borrow<Window>> Surface<NoDisplayHandle, W> {
+    /// Creates a new surface for a `winit` window, sized to fill the window.
+    ///
+    /// The surface owns `window`, which can be a `&Window`, or an `Rc<Window>` for moving the
+    /// surface into the closure passed to `EventLoop::run`. Pass the events of the event loop to
+    /// [`Surface::handle_winit_event`] to keep the surface the size of the window.
+    ///
+    /// On Android, windows only exist between `Event::Resumed` and `Event::Suspended`, so the
+    /// surface must be created after the former, and dropped at the latter.
+    pub fn from_winit(window: W) -> Result<Self, SoftBufferError> {
+        let raw_display_handle = window.borrow().raw_display_handle();
+        let raw_window_handle = window.borrow().raw_window_handle();
+        // SAFETY: The surface owns the window, and the backends keep what they need of the context.
+        let imple = unsafe {
+            let context = Context::from_raw(raw_display_handle)?;
+            SurfaceDispatch::new(&context.context_impl, raw_window_handle, None)?
+        };
+
+        let mut surface = Self::from_impl(imple, window, None);
+        let size = surface.window.borrow().inner_size();
+        surface.resize_to_winit(size)?;
+        Ok(surface)
+    }
+
+    /// Resizes the surface when its window is resized, or moved to a display with another scale
+    /// factor, by handling `WindowEvent::Resized` and `WindowEvent::ScaleFactorChanged`. The
+    /// buffers are freed with [`Surface::release_buffers`] at `WindowEvent::Occluded(true)`,
+    /// where that is implemented. Other events, and events of other windows, are ignored.
+    ///
+    /// Returns whether the surface was resized, or the window is shown again after its buffers
+    /// were freed, in which case the window should be redrawn. For virtual surfaces, see
+    /// [`Surface::set_view_size`], the view is resized instead of the buffer.
+    pub fn handle_winit_event<T>(&mut self, event: &Event<'_, T>) -> Result<bool, SoftBufferError> {
+        let size = match event {
+            Event::WindowEvent { window_id, event } if *window_id == self.window.borrow().id() => {
+                match event {
+                    WindowEvent::Resized(size) => *size,
+                    WindowEvent::ScaleFactorChanged { new_inner_size, .. } => **new_inner_size,
+                    WindowEvent::Occluded(true) => {
+                        return match self.release_buffers() {
+                            Err(SoftBufferError::Unimplemented) => Ok(false),
+                            result => result.map(|()| false),
+                        };
+                    }
+                    WindowEvent::Occluded(false) => return Ok(self.buffers_released),
+                    _ => return Ok(false),
+                }
+            }
+            _ => return Ok(false),
+        };
+        self.resize_to_winit(size)
+    }
+
+    /// Resizes the surface, or its view, to `size`, unless it is zero, e.g. while the window is
+    /// minimized on Windows, or it is the current size already.
+    fn resize_to_winit(&mut self, size: PhysicalSize<u32>) -> Result<bool, SoftBufferError> {
+        let size = (NonZeroU32::new(size.width), NonZeroU32::new(size.height));
+        if let Some(view_size) = self.view.size {
+            return match size {
+                (Some(width), Some(height)) if view_size != (width, height) => {
+                    self.set_view_size(Some((width, height)));
+                    Ok(true)
+                }
+                _ => Ok(false),
+            };
+        }
+        match size {
+            (Some(width), Some(height)) if self.config.size != Some((width, height)) => {
+                self.resize(width, height)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
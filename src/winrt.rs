@@ -0,0 +1,520 @@
+//! Implementation of software buffering for WinRT `CoreWindow`s.
+//!
+//! UWP apps don't get an `HWND` to draw into with GDI, so instead this module uploads the buffer
+//! into the back buffer of a DXGI swap chain created for the `CoreWindow`, and presents that.
+//! Swap chains can also be created for an `HWND`, as a fallback for GDI.
+
+use crate::error::SwResultExt;
+use crate::{
+    stats, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus, Rect, Scaling,
+    ScalingFilter, SoftBufferError, Visibility,
+};
+use raw_window_handle::{Win32WindowHandle, WinRtWindowHandle};
+
+use std::mem::ManuallyDrop;
+use std::num::NonZeroU32;
+use std::ptr;
+use std::time::Duration;
+
+use windows::core::{ComInterface, IUnknown, Interface};
+use windows::Win32::Foundation::{HMODULE, HWND, RECT};
+use windows::Win32::Graphics::Direct3D::{
+    D3D_DRIVER_TYPE, D3D_DRIVER_TYPE_HARDWARE, D3D_DRIVER_TYPE_WARP, D3D_FEATURE_LEVEL_10_0,
+    D3D_FEATURE_LEVEL_11_0, D3D_FEATURE_LEVEL_9_3,
+};
+use windows::Win32::Graphics::Direct3D11::{
+    D3D11CreateDevice, ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_BOX,
+    D3D11_CREATE_DEVICE_BGRA_SUPPORT, D3D11_REQ_TEXTURE2D_U_OR_V_DIMENSION, D3D11_SDK_VERSION,
+};
+use windows::Win32::Graphics::Dxgi::Common::{
+    DXGI_ALPHA_MODE_IGNORE, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_UNKNOWN, DXGI_SAMPLE_DESC,
+};
+use windows::Win32::Graphics::Dxgi::{
+    IDXGIDevice, IDXGIFactory2, IDXGISwapChain1, DXGI_PRESENT_PARAMETERS, DXGI_SCALING_NONE,
+    DXGI_SWAP_CHAIN_DESC1, DXGI_SWAP_EFFECT_FLIP_SEQUENTIAL, DXGI_USAGE_RENDER_TARGET_OUTPUT,
+};
+
+/// The swap chain, and the buffer that is uploaded into it.
+struct Buffer {
+    swap_chain: IDXGISwapChain1,
+    pixels: Vec<u32>,
+    width: NonZeroU32,
+    height: NonZeroU32,
+    /// The number of presents since the buffers were (re)created, up to 2.
+    presents: u8,
+    /// The dirty rectangles of the last present, or `None` if all of it changed.
+    last_dirty_rects: Option<Vec<RECT>>,
+}
+
+/// The window a swap chain is created for.
+enum Window {
+    /// A `CoreWindow`, as an `IUnknown`.
+    Core(IUnknown),
+    Hwnd(HWND),
+}
+
+pub struct WinRtImpl {
+    window: Window,
+
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+
+    /// Created by the first `resize`, since a swap chain needs a size.
+    buffer: Option<Buffer>,
+}
+
+impl WinRtImpl {
+    /// Create a new `WinRtImpl` from a `WinRtWindowHandle`.
+    ///
+    /// # Safety
+    ///
+    /// The `WinRtWindowHandle` must be a valid `CoreWindow`.
+    pub unsafe fn new(handle: &WinRtWindowHandle) -> Result<Self, SoftBufferError> {
+        if handle.core_window.is_null() {
+            return Err(SoftBufferError::IncompleteWindowHandle);
+        }
+
+        // The handle doesn't own a reference, so take our own instead of releasing theirs.
+        let window = ManuallyDrop::new(unsafe { IUnknown::from_raw(handle.core_window) });
+        let window = Window::Core((*window).clone());
+        Self::with_window(window)
+    }
+
+    /// Create a new `WinRtImpl` from a `Win32WindowHandle`.
+    ///
+    /// # Safety
+    ///
+    /// The `Win32WindowHandle` must be a valid window.
+    pub unsafe fn from_hwnd(handle: &Win32WindowHandle) -> Result<Self, SoftBufferError> {
+        if handle.hwnd.is_null() {
+            return Err(SoftBufferError::IncompleteWindowHandle);
+        }
+
+        Self::with_window(Window::Hwnd(HWND(handle.hwnd as isize)))
+    }
+
+    fn with_window(window: Window) -> Result<Self, SoftBufferError> {
+        // Fall back to the software rasterizer if there is no hardware device, e.g. in a VM.
+        let (device, context) = create_device(D3D_DRIVER_TYPE_HARDWARE)
+            .or_else(|_| create_device(D3D_DRIVER_TYPE_WARP))
+            .swbuf_err("Failed to create a Direct3D 11 device")?;
+
+        Ok(Self {
+            window,
+            device,
+            context,
+            buffer: None,
+        })
+    }
+
+    pub fn resize(&mut self, width: NonZeroU32, height: NonZeroU32) -> Result<(), SoftBufferError> {
+        if let Some(buffer) = self.buffer.as_mut() {
+            if buffer.width == width && buffer.height == height {
+                return Ok(());
+            }
+
+            // Keep the number and format of the buffers.
+            trace_span!("resize_buffers");
+            unsafe {
+                buffer.swap_chain.ResizeBuffers(
+                    0,
+                    width.get(),
+                    height.get(),
+                    DXGI_FORMAT_UNKNOWN,
+                    0,
+                )
+            }
+            .swbuf_err("Failed to resize the swap chain")?;
+        } else {
+            let swap_chain = self.create_swap_chain(width, height)?;
+            self.buffer = Some(Buffer {
+                swap_chain,
+                pixels: Vec::new(),
+                width,
+                height,
+                presents: 0,
+                last_dirty_rects: None,
+            });
+        }
+
+        let buffer = self.buffer.as_mut().unwrap();
+        buffer.pixels = vec![0; width.get() as usize * height.get() as usize];
+        buffer.width = width;
+        buffer.height = height;
+        buffer.presents = 0;
+        buffer.last_dirty_rects = None;
+
+        Ok(())
+    }
+
+    fn create_swap_chain(
+        &self,
+        width: NonZeroU32,
+        height: NonZeroU32,
+    ) -> Result<IDXGISwapChain1, SoftBufferError> {
+        trace_span!("create_swap_chain");
+        let factory = (|| unsafe {
+            self.device
+                .cast::<IDXGIDevice>()?
+                .GetAdapter()?
+                .GetParent::<IDXGIFactory2>()
+        })()
+        .swbuf_err("Failed to get the DXGI factory")?;
+
+        let desc = DXGI_SWAP_CHAIN_DESC1 {
+            Width: width.get(),
+            Height: height.get(),
+            // Our pixels are `0RGB` in native endianness, which is BGRA in memory.
+            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            Stereo: false.into(),
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            BufferUsage: DXGI_USAGE_RENDER_TARGET_OUTPUT,
+            BufferCount: 2,
+            // Draw the buffer unscaled in the upper-left corner, like the other backends do.
+            Scaling: DXGI_SCALING_NONE,
+            SwapEffect: DXGI_SWAP_EFFECT_FLIP_SEQUENTIAL,
+            AlphaMode: DXGI_ALPHA_MODE_IGNORE,
+            Flags: 0,
+        };
+
+        match &self.window {
+            Window::Core(window) => {
+                unsafe { factory.CreateSwapChainForCoreWindow(&self.device, window, &desc, None) }
+                    .swbuf_err("Failed to create a swap chain for the CoreWindow")
+            }
+            Window::Hwnd(hwnd) => {
+                unsafe { factory.CreateSwapChainForHwnd(&self.device, *hwnd, &desc, None, None) }
+                    .swbuf_err("Failed to create a swap chain for the window")
+            }
+        }
+    }
+
+    pub fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
+        if self.buffer.is_none() {
+            return Err(SoftBufferError::SurfaceNotConfigured);
+        }
+
+        Ok(BufferImpl(self))
+    }
+
+    fn present_with_damage(&mut self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let buffer = self.buffer.as_mut().unwrap();
+
+        let mut dirty_rects = damage
+            .iter()
+            .filter_map(|rect| {
+                let rect = rect.clamp(buffer.width, buffer.height)?;
+                Some(RECT {
+                    left: rect.x as i32,
+                    top: rect.y as i32,
+                    right: (rect.x + rect.width.get()) as i32,
+                    bottom: (rect.y + rect.height.get()) as i32,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // The back buffers of a flip model swap chain are recycled, so the back buffer holds the
+        // frame from two presents ago. It only needs what changed since then: the damage of the
+        // last present and of this one. Anything else gets the whole buffer.
+        let full = [RECT {
+            left: 0,
+            top: 0,
+            right: buffer.width.get() as i32,
+            bottom: buffer.height.get() as i32,
+        }];
+        let uploads = match &buffer.last_dirty_rects {
+            Some(last) if buffer.presents >= 2 && !dirty_rects.is_empty() => {
+                last.iter().chain(&dirty_rects)
+            }
+            _ => full.iter().chain(&[]),
+        };
+        unsafe {
+            let texture = buffer
+                .swap_chain
+                .GetBuffer::<ID3D11Texture2D>(0)
+                .swbuf_err("Failed to get the back buffer")?;
+            stats::time_copy(|| {
+                for rect in uploads {
+                    let region = D3D11_BOX {
+                        left: rect.left as u32,
+                        top: rect.top as u32,
+                        front: 0,
+                        right: rect.right as u32,
+                        bottom: rect.bottom as u32,
+                        back: 1,
+                    };
+                    let offset =
+                        rect.top as usize * buffer.width.get() as usize + rect.left as usize;
+                    self.context.UpdateSubresource(
+                        &texture,
+                        0,
+                        Some(&region),
+                        buffer.pixels[offset..].as_ptr().cast(),
+                        buffer.width.get() * 4,
+                        0,
+                    );
+                }
+            });
+        }
+
+        // No dirty rectangles means the whole buffer changed.
+        let parameters = DXGI_PRESENT_PARAMETERS {
+            DirtyRectsCount: dirty_rects.len() as u32,
+            pDirtyRects: if dirty_rects.is_empty() {
+                ptr::null_mut()
+            } else {
+                dirty_rects.as_mut_ptr()
+            },
+            pScrollRect: ptr::null_mut(),
+            pScrollOffset: ptr::null_mut(),
+        };
+        trace_span!("present1");
+        unsafe { buffer.swap_chain.Present1(0, 0, &parameters) }
+            .ok()
+            .swbuf_err("Failed to present the swap chain")?;
+        buffer.presents = (buffer.presents + 1).min(2);
+        buffer.last_dirty_rects = if dirty_rects.is_empty() {
+            None
+        } else {
+            Some(dirty_rects)
+        };
+
+        Ok(PresentStatus::Presented)
+    }
+
+    /// Fetch the buffer from the window.
+    pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// The buffers of the swap chain are shown by the compositor.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Wait for the next vertical blank of the output the window is on.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        let buffer = self
+            .buffer
+            .as_ref()
+            .swbuf_err("The swap chain is created by the first resize")?;
+        unsafe {
+            buffer
+                .swap_chain
+                .GetContainingOutput()
+                .and_then(|output| output.WaitForVBlank())
+        }
+        .swbuf_err("Failed to wait for the vertical blank")
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// DXGI lists the modes an output supports, but not the one the desktop currently uses.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Get the size of the window in pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Not implemented yet, see `Surface::visibility()`.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// Set how the buffer is scaled to the window.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        match scaling {
+            Scaling::TopLeft => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The parts of the window outside of the swap chain aren't drawn to.
+    pub fn set_background_color(&mut self, _color: u32) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// DXGI presents block until a back buffer is available.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        if timeout.is_some() {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// The window server keeps reading the last buffer after a present.
+    pub fn set_single_buffered(&mut self, single: bool) -> Result<(), SoftBufferError> {
+        if single {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rows are always shown top-down.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        if flip {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// DXGI keeps showing the last frame of the swap chain until the next present.
+    pub fn set_smooth_resize(&mut self, _smooth: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// The swap chain is in physical pixels.
+    pub fn set_scale_factor(&mut self, _scale_factor: f64) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// `CoreWindow`s have no shapes.
+    pub fn set_shape(&mut self, _shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        // The back buffers are textures, so they are limited to the texture size of the feature
+        // level of the device.
+        let feature_level = unsafe { self.device.GetFeatureLevel() };
+        let max_size = if feature_level.0 >= D3D_FEATURE_LEVEL_11_0.0 {
+            D3D11_REQ_TEXTURE2D_U_OR_V_DIMENSION
+        } else if feature_level.0 >= D3D_FEATURE_LEVEL_10_0.0 {
+            8192
+        } else if feature_level.0 >= D3D_FEATURE_LEVEL_9_3.0 {
+            4096
+        } else {
+            2048
+        };
+        Capabilities {
+            partial_present: true,
+            zero_copy: false,
+            max_size: (max_size, max_size),
+            buffer_count: 1,
+            readback: false,
+        }
+    }
+
+    /// The swap chain only shows its own buffers.
+    pub unsafe fn present_external(
+        &mut self,
+        _memory: ExternalMemory,
+        _width: NonZeroU32,
+        _height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
+
+fn create_device(
+    driver_type: D3D_DRIVER_TYPE,
+) -> windows::core::Result<(ID3D11Device, ID3D11DeviceContext)> {
+    let mut device = None;
+    let mut context = None;
+    unsafe {
+        D3D11CreateDevice(
+            None,
+            driver_type,
+            HMODULE::default(),
+            // Required for `B8G8R8A8` swap chains.
+            D3D11_CREATE_DEVICE_BGRA_SUPPORT,
+            None,
+            D3D11_SDK_VERSION,
+            Some(&mut device),
+            None,
+            Some(&mut context),
+        )?;
+    }
+    Ok((device.unwrap(), context.unwrap()))
+}
+
+pub struct BufferImpl<'a>(&'a mut WinRtImpl);
+
+impl<'a> BufferImpl<'a> {
+    #[inline]
+    pub fn pixels(&self) -> &[u32] {
+        &self.0.buffer.as_ref().unwrap().pixels
+    }
+
+    #[inline]
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.0.buffer.as_mut().unwrap().pixels
+    }
+
+    pub fn age(&self) -> u8 {
+        match self.0.buffer.as_ref() {
+            Some(buffer) if buffer.presents > 0 => 1,
+            _ => 0,
+        }
+    }
+
+    /// The pixels are uploaded into the swap chain, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    /// The pixels are uploaded into the swap chain.
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        None
+    }
+
+    /// Shapes are never set, see `set_shape()` of the surface.
+    pub fn set_shape(&mut self, _shape: &[Rect]) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let imp = self.0;
+        let buffer = imp.buffer.as_ref().unwrap();
+        imp.present_with_damage(&[Rect {
+            x: 0,
+            y: 0,
+            width: buffer.width,
+            height: buffer.height,
+        }])
+    }
+
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let imp = self.0;
+        imp.present_with_damage(damage)
+    }
+
+    /// The swap chain shows all of its back buffer.
+    pub fn present_viewport(
+        self,
+        _damage: &[Rect],
+        _viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+}
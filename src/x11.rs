@@ -6,31 +6,49 @@
 #![allow(clippy::uninlined_format_args)]
 
 use crate::error::SwResultExt;
-use crate::{Rect, SoftBufferError};
+use crate::{
+    stats, util, Capabilities, ColorSpace, ExternalMemory, NativeBuffer, PresentStatus, Rect,
+    Scaling, ScalingFilter, SoftBufferError, Visibility,
+};
 use nix::libc::{shmat, shmctl, shmdt, shmget, IPC_PRIVATE, IPC_RMID};
 use raw_window_handle::{XcbDisplayHandle, XcbWindowHandle, XlibDisplayHandle, XlibWindowHandle};
 use std::ptr::{null_mut, NonNull};
 use std::{
     fmt, io, mem,
     num::{NonZeroU16, NonZeroU32},
-    rc::Rc,
     slice,
+    sync::Arc,
+    time::Duration,
 };
 
 use as_raw_xcb_connection::AsRawXcbConnection;
-use x11rb::connection::{Connection, SequenceNumber};
-use x11rb::cookie::Cookie;
+use x11rb::connection::{Connection, RequestConnection, SequenceNumber};
+use x11rb::cookie::{Cookie, VoidCookie};
 use x11rb::errors::{ConnectionError, ReplyError, ReplyOrIdError};
+use x11rb::protocol::randr::{self, ConnectionExt as _};
+use x11rb::protocol::shape::{self as xshape, ConnectionExt as _};
 use x11rb::protocol::shm::{self, ConnectionExt as _};
 use x11rb::protocol::xproto::{self, ConnectionExt as _};
+use x11rb::protocol::ErrorKind;
+use x11rb::utils::RawFdContainer;
 use x11rb::xcb_ffi::XCBConnection;
 
+/// The size of a `PutImage` request without its data.
+const PUT_IMAGE_HEADER_BYTES: usize = 24;
+
+/// The largest width and height of a window that can be drawn to, as coordinates are 16-bit signed
+/// integers.
+const MAX_SIZE: u32 = i16::MAX as u32;
+
 pub struct X11DisplayImpl {
     /// The handle to the XCB connection.
     connection: XCBConnection,
 
     /// SHM extension is available.
     is_shm_available: bool,
+
+    /// SHM segments can be attached from file descriptors, which needs MIT-SHM 1.2.
+    is_shm_fd_available: bool,
 }
 
 impl X11DisplayImpl {
@@ -85,9 +103,12 @@ impl X11DisplayImpl {
             log::warn!("SHM extension is not available. Performance may be poor.");
         }
 
+        let is_shm_fd_available = is_shm_available && is_shm_fd_available(&connection);
+
         Ok(Self {
             connection,
             is_shm_available,
+            is_shm_fd_available,
         })
     }
 }
@@ -95,7 +116,7 @@ impl X11DisplayImpl {
 /// The handle to an X11 drawing context.
 pub struct X11Impl {
     /// X display this window belongs to.
-    display: Rc<X11DisplayImpl>,
+    display: Arc<X11DisplayImpl>,
 
     /// The window to draw to.
     window: xproto::Window,
@@ -109,6 +130,9 @@ pub struct X11Impl {
     /// The visual ID of the drawing context.
     visual_id: u32,
 
+    /// How the X server lays out images of the depth of the window, if not like the buffer.
+    layout: Option<ImageLayout>,
+
     /// The buffer we draw to.
     buffer: Buffer,
 
@@ -117,6 +141,21 @@ pub struct X11Impl {
 
     /// The current buffer width/height.
     size: Option<(NonZeroU16, NonZeroU16)>,
+
+    /// The last request drawing to the window, whose errors are checked in `buffer_mut()`.
+    ///
+    /// Presenting doesn't wait for the X server, so this is how we find out that the window was
+    /// destroyed.
+    drawn: Option<SequenceNumber>,
+
+    /// Whether the rows of the buffer are put into the window bottom-up.
+    flip_vertical: bool,
+
+    /// Whether the buffer is put into the center of the window, instead of its upper-left corner.
+    centered: bool,
+
+    /// The color that the parts of the window outside of the buffer are filled with.
+    background: Option<u32>,
 }
 
 /// The buffer that is being drawn to.
@@ -155,7 +194,7 @@ impl X11Impl {
     /// The `XlibWindowHandle` and `XlibDisplayHandle` must be valid.
     pub unsafe fn from_xlib(
         window_handle: XlibWindowHandle,
-        display: Rc<X11DisplayImpl>,
+        display: Arc<X11DisplayImpl>,
     ) -> Result<Self, SoftBufferError> {
         let mut xcb_window_handle = XcbWindowHandle::empty();
         xcb_window_handle.window = window_handle.window as _;
@@ -172,7 +211,7 @@ impl X11Impl {
     /// The `XcbWindowHandle` and `XcbDisplayHandle` must be valid.
     pub(crate) unsafe fn from_xcb(
         window_handle: XcbWindowHandle,
-        display: Rc<X11DisplayImpl>,
+        display: Arc<X11DisplayImpl>,
     ) -> Result<Self, SoftBufferError> {
         log::trace!("new: window_handle={:X}", window_handle.window,);
 
@@ -239,8 +278,10 @@ impl X11Impl {
             (geometry_reply, visual_id)
         };
 
-        // See if SHM is available.
-        let buffer = if display.is_shm_available {
+        let layout = ImageLayout::new(display.connection.setup(), geometry_reply.depth)?;
+
+        // See if SHM is available. The X server reads images from a segment in its own layout.
+        let buffer = if display.is_shm_available && layout.is_none() {
             // SHM is available.
             Buffer::Shm(ShmBuffer {
                 seg: None,
@@ -257,9 +298,14 @@ impl X11Impl {
             gc,
             depth: geometry_reply.depth,
             visual_id,
+            layout,
             buffer,
             buffer_presented: false,
             size: None,
+            drawn: None,
+            flip_vertical: false,
+            centered: false,
+            background: None,
         })
     }
 
@@ -299,16 +345,72 @@ impl X11Impl {
     }
 
     /// Get a mutable reference to the buffer.
-    pub(crate) fn buffer_mut(&mut self) -> Result<BufferImpl, SoftBufferError> {
+    pub(crate) fn buffer_mut(&mut self) -> Result<BufferImpl<'_>, SoftBufferError> {
         log::trace!("buffer_mut: window={:X}", self.window);
 
         // Finish waiting on the previous `shm::PutImage` request, if any.
         self.buffer.finish_wait(&self.display.connection)?;
 
+        // With SHM, the server already replied to a later request, so this doesn't block.
+        if let Some(drawn) = self.drawn.take() {
+            window_err(
+                VoidCookie::new(&self.display.connection, drawn).check(),
+                "Failed to draw image to window",
+            )?;
+        }
+
         // We can now safely call `buffer_mut` on the buffer.
         Ok(BufferImpl(self))
     }
 
+    /// Copy `src` of the window of `other` to `dst` of this window in the X server.
+    pub fn copy_from(
+        &mut self,
+        other: &Self,
+        src: Rect,
+        dst: (u32, u32),
+    ) -> Result<(), SoftBufferError> {
+        log::trace!(
+            "copy_from: window={:X}, other={:X}",
+            self.window,
+            other.window
+        );
+
+        // `CopyArea` only works between drawables of the same depth.
+        if self.depth != other.depth {
+            return Err(SoftBufferError::Unimplemented);
+        }
+        let (src_x, src_y, dst_x, dst_y, width, height) = (|| {
+            Some((
+                i16::try_from(src.x).ok()?,
+                i16::try_from(src.y).ok()?,
+                i16::try_from(dst.0).ok()?,
+                i16::try_from(dst.1).ok()?,
+                u16::try_from(src.width.get()).ok()?,
+                u16::try_from(src.height.get()).ok()?,
+            ))
+        })()
+        .ok_or(SoftBufferError::DamageOutOfRange { rect: src })?;
+
+        trace_span!("copy_area");
+        self.display
+            .connection
+            .copy_area(
+                other.window,
+                self.window,
+                self.gc,
+                src_x,
+                src_y,
+                dst_x,
+                dst_y,
+                width,
+                height,
+            )
+            .map(|c| track_drawn(&self.display.connection, &mut self.drawn, c))
+            .push_err()
+            .swbuf_err("Failed to copy between windows")
+    }
+
     /// Fetch the buffer from the window.
     pub fn fetch(&mut self) -> Result<Vec<u32>, SoftBufferError> {
         log::trace!("fetch: window={:X}", self.window);
@@ -331,10 +433,15 @@ impl X11Impl {
                 u32::MAX,
             )
             .swbuf_err("Failed to send image fetching request")?
-            .reply()
-            .swbuf_err("Failed to fetch image from window")?;
+            .reply();
+        let reply = window_err(reply, "Failed to fetch image from window")?;
 
         if reply.depth == self.depth && reply.visual == self.visual_id {
+            if let Some(layout) = self.layout {
+                let mut out = Vec::with_capacity(total_len(width.get(), height.get()) / 4);
+                layout.unpack(&reply.data, width.get().into(), &mut out);
+                return Ok(out);
+            }
             let mut out = vec![0u32; reply.data.len() / 4];
             bytemuck::cast_slice_mut::<u32, u8>(&mut out).copy_from_slice(&reply.data);
             Ok(out)
@@ -345,6 +452,481 @@ impl X11Impl {
             ))
         }
     }
+
+    /// Check whether the next buffer is released by the display server.
+    pub fn buffer_released(&mut self) -> Result<bool, SoftBufferError> {
+        Ok(true)
+    }
+
+    /// Free the SHM segment or the pixels, the X server keeps the contents of the window.
+    pub fn release_buffers(&mut self) -> Result<(), SoftBufferError> {
+        match &mut self.buffer {
+            Buffer::Shm(shm) => {
+                // The X server may still be reading the segment.
+                shm.finish_wait(&self.display.connection)
+                    .swbuf_err("Failed to wait for X11 buffer")?;
+                if let Some((segment, seg_id)) = shm.seg.take() {
+                    if let Ok(token) = self.display.connection.shm_detach(seg_id) {
+                        token.ignore_error();
+                    }
+                    drop(segment);
+                }
+            }
+            Buffer::Wire(wire) => *wire = Vec::new(),
+        }
+        self.size = None;
+        Ok(())
+    }
+
+    /// Wait for the next vertical blank.
+    pub fn wait_for_vblank(&mut self) -> Result<(), SoftBufferError> {
+        Err(SoftBufferError::Unimplemented)
+    }
+
+    /// The display server doesn't report when frames are shown.
+    pub fn take_feedback(&mut self) -> stats::Feedback {
+        stats::Feedback::default()
+    }
+
+    /// Get the refresh interval of the mode of the CRTC that shows the center of the window, or
+    /// of the first active CRTC if none does, through RandR.
+    pub fn refresh_rate(&self) -> Result<Duration, SoftBufferError> {
+        let connection = &self.display.connection;
+        if connection
+            .extension_information(randr::X11_EXTENSION_NAME)
+            .ok()
+            .flatten()
+            .is_none()
+        {
+            return Err(SoftBufferError::Unimplemented);
+        }
+
+        let geometry = connection
+            .get_geometry(self.window)
+            .swbuf_err("Failed to send geometry request")?
+            .reply();
+        let geometry = window_err(geometry, "Failed to get geometry reply")?;
+        let position = connection
+            .translate_coordinates(self.window, geometry.root, 0, 0)
+            .swbuf_err("Failed to send translate coordinates request")?
+            .reply();
+        let position = window_err(position, "Failed to get translate coordinates reply")?;
+        let center_x = i32::from(position.dst_x) + i32::from(geometry.width) / 2;
+        let center_y = i32::from(position.dst_y) + i32::from(geometry.height) / 2;
+
+        let resources = connection
+            .randr_get_screen_resources_current(geometry.root)
+            .swbuf_err("Failed to send RandR screen resources request")?
+            .reply()
+            .swbuf_err("Failed to get RandR screen resources reply")?;
+        let crtcs = resources
+            .crtcs
+            .iter()
+            .map(|&crtc| connection.randr_get_crtc_info(crtc, resources.config_timestamp))
+            .collect::<Result<Vec<_>, _>>()
+            .swbuf_err("Failed to send RandR CRTC info request")?;
+        let mut first = None;
+        let mut containing = None;
+        for crtc in crtcs {
+            let crtc = crtc
+                .reply()
+                .swbuf_err("Failed to get RandR CRTC info reply")?;
+            if crtc.mode == 0 {
+                continue;
+            }
+            first = first.or(Some(crtc.mode));
+            let (x, y) = (i32::from(crtc.x), i32::from(crtc.y));
+            if (x..x + i32::from(crtc.width)).contains(&center_x)
+                && (y..y + i32::from(crtc.height)).contains(&center_y)
+            {
+                containing = Some(crtc.mode);
+            }
+        }
+
+        let mode = containing.or(first).swbuf_err("No RandR CRTC is active")?;
+        let mode = resources
+            .modes
+            .iter()
+            .find(|info| info.id == mode)
+            .swbuf_err("The mode of the RandR CRTC is unknown")?;
+
+        // Double scanned modes show each line twice, interlaced ones every other line per field.
+        let mut vtotal = u64::from(mode.vtotal);
+        if mode.mode_flags.contains(randr::ModeFlag::DOUBLE_SCAN) {
+            vtotal *= 2;
+        }
+        if mode.mode_flags.contains(randr::ModeFlag::INTERLACE) {
+            vtotal /= 2;
+        }
+        let pixels = u64::from(mode.htotal) * vtotal;
+        if mode.dot_clock == 0 || pixels == 0 {
+            return Err(SoftBufferError::PlatformError(
+                Some("The RandR mode has no timings".into()),
+                None,
+            ));
+        }
+        Ok(Duration::from_nanos(
+            pixels * 1_000_000_000 / u64::from(mode.dot_clock),
+        ))
+    }
+
+    /// Get the size of the window in pixels.
+    pub fn window_size(&self) -> Result<(u32, u32), SoftBufferError> {
+        let geometry = self
+            .display
+            .connection
+            .get_geometry(self.window)
+            .swbuf_err("Failed to send geometry request")?
+            .reply();
+        let geometry = window_err(geometry, "Failed to get geometry reply")?;
+        Ok((geometry.width.into(), geometry.height.into()))
+    }
+
+    /// Get whether the window is mapped, and whether the top-level windows above it, or the
+    /// edges of the screen, cut parts of it off.
+    pub fn visibility(&self) -> Result<Visibility, SoftBufferError> {
+        let connection = &self.display.connection;
+        let attrs = connection
+            .get_window_attributes(self.window)
+            .swbuf_err("Failed to send window attributes request")?
+            .reply();
+        let attrs = window_err(attrs, "Failed to get window attributes reply")?;
+        if attrs.map_state != xproto::MapState::VIEWABLE {
+            return Ok(Visibility::Hidden);
+        }
+
+        let geometry = connection
+            .get_geometry(self.window)
+            .swbuf_err("Failed to send geometry request")?
+            .reply();
+        let geometry = window_err(geometry, "Failed to get geometry reply")?;
+        let position = connection
+            .translate_coordinates(self.window, geometry.root, 0, 0)
+            .swbuf_err("Failed to send translate coordinates request")?
+            .reply();
+        let position = window_err(position, "Failed to get translate coordinates reply")?;
+        let window = Bounds::new(
+            position.dst_x,
+            position.dst_y,
+            geometry.width,
+            geometry.height,
+            0,
+        );
+
+        // The window manager reparents top-level windows into frames, so find the child of the
+        // root that the window is inside of. The children of the root are stacked bottom to top.
+        let mut top_level = self.window;
+        loop {
+            let tree = connection
+                .query_tree(top_level)
+                .swbuf_err("Failed to send query tree request")?
+                .reply();
+            let tree = window_err(tree, "Failed to get query tree reply")?;
+            if tree.parent == geometry.root || tree.parent == x11rb::NONE {
+                break;
+            }
+            top_level = tree.parent;
+        }
+        let root = connection
+            .get_geometry(geometry.root)
+            .swbuf_err("Failed to send geometry request")?
+            .reply()
+            .swbuf_err("Failed to get geometry reply")?;
+        let stacking = connection
+            .query_tree(geometry.root)
+            .swbuf_err("Failed to send query tree request")?
+            .reply()
+            .swbuf_err("Failed to get query tree reply")?
+            .children;
+
+        let screen = Bounds::new(0, 0, root.width, root.height, 0);
+        if !window.intersects(&screen) {
+            return Ok(Visibility::Hidden);
+        }
+        let mut visibility = if screen.contains(&window) {
+            Visibility::Visible
+        } else {
+            Visibility::PartiallyOccluded
+        };
+
+        let above = match stacking.iter().position(|&child| child == top_level) {
+            Some(index) => &stacking[index + 1..],
+            None => &[],
+        };
+        // Send all requests before waiting for any of the replies.
+        let cookies = above
+            .iter()
+            .map(|&sibling| {
+                Ok((
+                    connection.get_window_attributes(sibling)?,
+                    connection.get_geometry(sibling)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, ConnectionError>>()
+            .swbuf_err("Failed to send window attributes request")?;
+        for (attrs, geometry) in cookies {
+            // The windows above may have been destroyed in the meantime.
+            let (attrs, geometry) = match (attrs.reply(), geometry.reply()) {
+                (Ok(attrs), Ok(geometry)) => (attrs, geometry),
+                _ => continue,
+            };
+            if attrs.map_state != xproto::MapState::VIEWABLE
+                || attrs.class == xproto::WindowClass::INPUT_ONLY
+            {
+                continue;
+            }
+
+            let sibling = Bounds::new(
+                geometry.x,
+                geometry.y,
+                geometry.width,
+                geometry.height,
+                geometry.border_width,
+            );
+            if sibling.contains(&window) {
+                return Ok(Visibility::Hidden);
+            }
+            if sibling.intersects(&window) {
+                visibility = Visibility::PartiallyOccluded;
+            }
+        }
+
+        Ok(visibility)
+    }
+
+    /// Set how the buffer is scaled to the window.
+    pub fn set_scaling(
+        &mut self,
+        scaling: Scaling,
+        _filter: ScalingFilter,
+    ) -> Result<(), SoftBufferError> {
+        self.centered = match scaling {
+            Scaling::TopLeft => false,
+            Scaling::Center => true,
+            _ => return Err(SoftBufferError::Unimplemented),
+        };
+        Ok(())
+    }
+
+    /// Fill the parts of the window outside of the buffer by every present.
+    pub fn set_background_color(&mut self, color: u32) -> Result<(), SoftBufferError> {
+        self.background = Some(color);
+        Ok(())
+    }
+
+    /// XCB can't wait for the reply that the X server is done with a timeout.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SoftBufferError> {
+        if timeout.is_some() {
+            Err(SoftBufferError::Unimplemented)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Where the upper-left corner of `viewport` is put into the window, after filling the parts
+    /// of the window around it with the background color.
+    fn place(&mut self, viewport: Rect) -> Result<(i64, i64), SoftBufferError> {
+        if !self.centered && self.background.is_none() {
+            return Ok((0, 0));
+        }
+        let window = self.window_size()?;
+        let image = (viewport.width.get(), viewport.height.get());
+        let position = util::image_position(self.centered, window, image);
+
+        if let Some(color) = self.background {
+            let rects = util::uncovered(window, position, image)
+                .into_iter()
+                // The window is smaller than `u16::MAX`.
+                .map(|rect| xproto::Rectangle {
+                    x: rect.x as i16,
+                    y: rect.y as i16,
+                    width: rect.width.get() as u16,
+                    height: rect.height.get() as u16,
+                })
+                .collect::<Vec<_>>();
+            if !rects.is_empty() {
+                let connection = &self.display.connection;
+                // Images don't use the foreground of the graphics context.
+                connection
+                    .change_gc(self.gc, &xproto::ChangeGCAux::new().foreground(color))
+                    .push_err()
+                    .and_then(|_| {
+                        connection
+                            .poly_fill_rectangle(self.window, self.gc, &rects)
+                            .push_err()
+                    })
+                    .map(|c| track_drawn(connection, &mut self.drawn, c))
+                    .swbuf_err("Failed to fill the background of the window")?;
+            }
+        }
+
+        Ok(position)
+    }
+
+    /// Set the color space of the buffer.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) -> Result<(), SoftBufferError> {
+        match color_space {
+            ColorSpace::Srgb => Ok(()),
+            _ => Err(SoftBufferError::Unimplemented),
+        }
+    }
+
+    /// There is only one buffer already.
+    pub fn set_single_buffered(&mut self, _single: bool) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Put the rows of the buffer into the window bottom-up.
+    pub fn set_flip_vertical(&mut self, flip: bool) -> Result<(), SoftBufferError> {
+        self.flip_vertical = flip;
+        Ok(())
+    }
+
+    /// Set the bit gravity of the window, so the X server keeps its contents in the upper-left
+    /// corner when it is resized, instead of clearing all of it.
+    pub fn set_smooth_resize(&mut self, smooth: bool) -> Result<(), SoftBufferError> {
+        let gravity = if smooth {
+            xproto::Gravity::NORTH_WEST
+        } else {
+            xproto::Gravity::BIT_FORGET
+        };
+        self.display
+            .connection
+            .change_window_attributes(
+                self.window,
+                &xproto::ChangeWindowAttributesAux::new().bit_gravity(gravity),
+            )
+            .swbuf_err("Failed to set the bit gravity of the window")?
+            .ignore_error();
+        Ok(())
+    }
+
+    /// X11 windows are in physical pixels.
+    pub fn set_scale_factor(&mut self, _scale_factor: f64) -> Result<(), SoftBufferError> {
+        Ok(())
+    }
+
+    /// Set the bounding shape of the window with the Shape extension.
+    pub fn set_shape(&mut self, shape: Option<&[Rect]>) -> Result<(), SoftBufferError> {
+        let conn = &self.display.connection;
+        if !matches!(
+            conn.extension_information(xshape::X11_EXTENSION_NAME),
+            Ok(Some(_))
+        ) {
+            return Err(SoftBufferError::Unimplemented);
+        }
+
+        trace_span!("set_shape");
+        let cookie = match shape {
+            Some(shape) => {
+                // The rects fit inside the buffer, whose size fits in an `i16`.
+                let rects: Vec<_> = shape
+                    .iter()
+                    .map(|rect| xproto::Rectangle {
+                        x: rect.x as i16,
+                        y: rect.y as i16,
+                        width: rect.width.get() as u16,
+                        height: rect.height.get() as u16,
+                    })
+                    .collect();
+                conn.shape_rectangles(
+                    xshape::SO::SET,
+                    xshape::SK::BOUNDING,
+                    xproto::ClipOrdering::YX_BANDED,
+                    self.window,
+                    0,
+                    0,
+                    &rects,
+                )
+            }
+            None => conn.shape_mask(
+                xshape::SO::SET,
+                xshape::SK::BOUNDING,
+                self.window,
+                0,
+                0,
+                x11rb::NONE,
+            ),
+        };
+        cookie
+            .swbuf_err("Failed to set the shape of the window")?
+            .ignore_error();
+        Ok(())
+    }
+
+    /// What this backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            partial_present: true,
+            zero_copy: matches!(self.buffer, Buffer::Shm(_)),
+            max_size: (MAX_SIZE, MAX_SIZE),
+            buffer_count: 1,
+            readback: true,
+        }
+    }
+
+    /// Put shared memory into the window through an XShm segment attached from its fd.
+    pub unsafe fn present_external(
+        &mut self,
+        memory: ExternalMemory,
+        width: NonZeroU32,
+        height: NonZeroU32,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        let (fd, offset, stride) = match memory {
+            ExternalMemory::SharedMemory { fd, offset, stride } => (fd, offset, stride),
+            _ => return Err(SoftBufferError::Unimplemented),
+        };
+        if !self.display.is_shm_fd_available || self.layout.is_some() {
+            return Err(SoftBufferError::Unimplemented);
+        }
+        // The rows of the image are `total_width` pixels apart.
+        let (width, height, total_width) = (|| {
+            let total_width = u16::try_from(stride / 4).ok()?;
+            let image_width = u16::try_from(width.get()).ok()?;
+            if stride % 4 != 0 || total_width < image_width {
+                return None;
+            }
+            Some((image_width, u16::try_from(height.get()).ok()?, total_width))
+        })()
+        .ok_or(SoftBufferError::SizeOutOfRange { width, height })?;
+
+        trace_span!("shm_put_image_external");
+        let conn = &self.display.connection;
+        // The X server takes ownership of the file descriptor it is sent.
+        let fd = nix::unistd::dup(fd).swbuf_err("Failed to duplicate the file descriptor")?;
+        let seg = conn.generate_id().swbuf_err("Failed to allocate an XID")?;
+        conn.shm_attach_fd(seg, RawFdContainer::new(fd), true)
+            .swbuf_err("Failed to send the file descriptor")?
+            .check()
+            .swbuf_err("Failed to attach the shared memory")?;
+        let put = conn
+            .shm_put_image(
+                self.window,
+                self.gc,
+                total_width,
+                height,
+                0,
+                0,
+                width,
+                height,
+                0,
+                0,
+                self.depth,
+                xproto::ImageFormat::Z_PIXMAP.into(),
+                false,
+                seg,
+                offset,
+            )
+            .swbuf_err("Failed to draw image to window");
+        let detach = conn
+            .shm_detach(seg)
+            .swbuf_err("Failed to detach the shared memory");
+        let put = put?;
+        detach?.ignore_error();
+        window_err(put.check(), "Failed to draw image to window")?;
+
+        Ok(PresentStatus::Presented)
+    }
 }
 
 pub struct BufferImpl<'a>(&'a mut X11Impl);
@@ -370,13 +952,52 @@ impl<'a> BufferImpl<'a> {
         }
     }
 
+    /// The pixels are copied into the window, so there is one buffer.
+    pub fn index(&self) -> usize {
+        0
+    }
+
+    pub fn native_buffer(&self) -> Option<NativeBuffer> {
+        match &self.0.buffer {
+            Buffer::Shm(ShmBuffer {
+                seg: Some((_, shmseg)),
+                ..
+            }) => Some(NativeBuffer::X11Shm { shmseg: *shmseg }),
+            _ => None,
+        }
+    }
+
+    pub fn set_shape(&mut self, shape: &[Rect]) -> Result<(), SoftBufferError> {
+        self.0.set_shape(Some(shape))
+    }
+
     /// Push the buffer to the window.
-    pub fn present_with_damage(self, damage: &[Rect]) -> Result<(), SoftBufferError> {
+    pub fn present_with_damage(self, damage: &[Rect]) -> Result<PresentStatus, SoftBufferError> {
+        let full = self.full_rect();
+        self.put_damage(damage, full)
+    }
+
+    /// Push the damaged parts of the viewport of the buffer to the upper-left corner of the
+    /// window.
+    pub fn present_viewport(
+        self,
+        damage: &[Rect],
+        viewport: Rect,
+    ) -> Result<PresentStatus, SoftBufferError> {
+        self.put_damage(damage, viewport)
+    }
+
+    /// Push the damaged parts of `viewport`, which contains them, so that its upper-left corner
+    /// is in the upper-left corner or the center of the window.
+    fn put_damage(self, damage: &[Rect], viewport: Rect) -> Result<PresentStatus, SoftBufferError> {
         let imp = self.0;
+        let (offset_x, offset_y) = imp.place(viewport)?;
 
         let (surface_width, surface_height) = imp
             .size
             .expect("Must set size of surface before calling `present_with_damage()`");
+        // Flipped rows are counted from the bottom of the viewport.
+        let viewport_bottom = viewport.y + viewport.height.get();
 
         log::trace!("present: window={:X}", imp.window);
 
@@ -384,24 +1005,103 @@ impl<'a> BufferImpl<'a> {
             Buffer::Wire(ref wire) => {
                 // This is a suboptimal strategy, raise a stink in the debug logs.
                 log::debug!("Falling back to non-SHM method for window drawing.");
+                trace_span!("put_image", rects = damage.len());
+
+                let surface_width = surface_width.get();
+                let surface_height = surface_height.get();
+                let max_request_bytes = imp.display.connection.maximum_request_bytes();
+                let mut rows = Vec::new();
+                damage
+                    .iter()
+                    .try_for_each(|rect| -> Result<(), SoftBufferError> {
+                        // Only send the part of the damage inside the buffer.
+                        let right = rect
+                            .x
+                            .saturating_add(rect.width.get())
+                            .min(surface_width.into());
+                        let bottom = rect
+                            .y
+                            .saturating_add(rect.height.get())
+                            .min(surface_height.into());
+                        if rect.x >= right || rect.y >= bottom {
+                            return Ok(());
+                        }
+                        let (dst_x, width) = (|| {
+                            Some((
+                                i16::try_from(i64::from(rect.x - viewport.x) + offset_x).ok()?,
+                                u16::try_from(right - rect.x).ok()?,
+                            ))
+                        })()
+                        .ok_or(SoftBufferError::DamageOutOfRange { rect: *rect })?;
+
+                        // Requests can't be larger than the maximum request length, so large images
+                        // are sent in bands of rows. The rows of each band have to be contiguous,
+                        // which they already are for the full width of the buffer.
+                        let stride = surface_width as usize;
+                        let row_bytes = imp
+                            .layout
+                            .map_or(width as usize * 4, |layout| layout.stride(width.into()));
+                        let band_rows = (max_request_bytes.saturating_sub(PUT_IMAGE_HEADER_BYTES)
+                            / row_bytes)
+                            .max(1);
+                        let mut top = rect.y;
+                        while top < bottom {
+                            let band_bottom = bottom.min(top.saturating_add(band_rows as u32));
+                            let data = if rect.x == 0
+                                && right == surface_width.into()
+                                && !imp.flip_vertical
+                                && imp.layout.is_none()
+                            {
+                                bytemuck::cast_slice(
+                                    &wire[top as usize * stride..band_bottom as usize * stride],
+                                )
+                            } else {
+                                rows.clear();
+                                for y in top as usize..band_bottom as usize {
+                                    // Flipped bands are sent bottom row first.
+                                    let y = if imp.flip_vertical {
+                                        (top + band_bottom) as usize - 1 - y
+                                    } else {
+                                        y
+                                    };
+                                    let row = &wire
+                                        [y * stride + rect.x as usize..y * stride + right as usize];
+                                    match imp.layout {
+                                        Some(layout) => layout.pack(row, &mut rows),
+                                        None => rows.extend_from_slice(bytemuck::cast_slice(row)),
+                                    }
+                                }
+                                &rows[..]
+                            };
+                            let band_y = if imp.flip_vertical {
+                                viewport_bottom - band_bottom
+                            } else {
+                                top - viewport.y
+                            };
+                            let band_y = i16::try_from(i64::from(band_y) + offset_y)
+                                .or(Err(SoftBufferError::DamageOutOfRange { rect: *rect }))?;
 
-                imp.display
-                    .connection
-                    .put_image(
-                        xproto::ImageFormat::Z_PIXMAP,
-                        imp.window,
-                        imp.gc,
-                        surface_width.get(),
-                        surface_height.get(),
-                        0,
-                        0,
-                        0,
-                        imp.depth,
-                        bytemuck::cast_slice(wire),
-                    )
-                    .map(|c| c.ignore_error())
-                    .push_err()
-                    .swbuf_err("Failed to draw image to window")?;
+                            imp.display
+                                .connection
+                                .put_image(
+                                    xproto::ImageFormat::Z_PIXMAP,
+                                    imp.window,
+                                    imp.gc,
+                                    width,
+                                    (band_bottom - top) as u16,
+                                    dst_x,
+                                    band_y,
+                                    0,
+                                    imp.depth,
+                                    data,
+                                )
+                                .map(|c| track_drawn(&imp.display.connection, &mut imp.drawn, c))
+                                .push_err()
+                                .swbuf_err("Failed to draw image to window")?;
+                            top = band_bottom;
+                        }
+                        Ok(())
+                    })?;
             }
 
             Buffer::Shm(ref mut shm) => {
@@ -409,6 +1109,7 @@ impl<'a> BufferImpl<'a> {
                 // SAFETY: We know that we called finish_wait() before this.
                 // Put the image into the window.
                 if let Some((_, segment_id)) = shm.seg {
+                    trace_span!("shm_put_image", rects = damage.len());
                     damage
                         .iter()
                         .try_for_each(|rect| {
@@ -416,36 +1117,52 @@ impl<'a> BufferImpl<'a> {
                                 Some((
                                     u16::try_from(rect.x).ok()?,
                                     u16::try_from(rect.y).ok()?,
-                                    i16::try_from(rect.x).ok()?,
-                                    i16::try_from(rect.y).ok()?,
+                                    i16::try_from(i64::from(rect.x - viewport.x) + offset_x)
+                                        .ok()?,
+                                    i16::try_from(i64::from(rect.y - viewport.y) + offset_y)
+                                        .ok()?,
                                     u16::try_from(rect.width.get()).ok()?,
                                     u16::try_from(rect.height.get()).ok()?,
                                 ))
                             })(
                             )
                             .ok_or(SoftBufferError::DamageOutOfRange { rect: *rect })?;
-                            imp.display
-                                .connection
-                                .shm_put_image(
-                                    imp.window,
-                                    imp.gc,
-                                    surface_width.get(),
-                                    surface_height.get(),
-                                    src_x,
-                                    src_y,
-                                    width,
-                                    height,
-                                    dst_x,
-                                    dst_y,
-                                    imp.depth,
-                                    xproto::ImageFormat::Z_PIXMAP.into(),
-                                    false,
-                                    segment_id,
-                                    0,
-                                )
-                                .push_err()
-                                .map(|c| c.ignore_error())
-                                .swbuf_err("Failed to draw image to window")
+                            let mut put = |src_y: u16, dst_y: i16, height: u16| {
+                                imp.display
+                                    .connection
+                                    .shm_put_image(
+                                        imp.window,
+                                        imp.gc,
+                                        surface_width.get(),
+                                        surface_height.get(),
+                                        src_x,
+                                        src_y,
+                                        width,
+                                        height,
+                                        dst_x,
+                                        dst_y,
+                                        imp.depth,
+                                        xproto::ImageFormat::Z_PIXMAP.into(),
+                                        false,
+                                        segment_id,
+                                        0,
+                                    )
+                                    .push_err()
+                                    .map(|c| {
+                                        track_drawn(&imp.display.connection, &mut imp.drawn, c)
+                                    })
+                                    .swbuf_err("Failed to draw image to window")
+                            };
+                            if imp.flip_vertical {
+                                // The X server can't flip images, so every row is put on its own.
+                                // The damage fits inside the buffer, so this doesn't overflow.
+                                let bottom =
+                                    (i64::from(viewport_bottom - 1 - rect.y) + offset_y) as i16;
+                                (0..height)
+                                    .try_for_each(|row| put(src_y + row, bottom - row as i16, 1))
+                            } else {
+                                put(src_y, dst_y, height)
+                            }
                         })
                         .and_then(|()| {
                             // Send a short request to act as a notification for when the X server is done processing the image.
@@ -458,20 +1175,88 @@ impl<'a> BufferImpl<'a> {
 
         imp.buffer_presented = true;
 
-        Ok(())
+        Ok(PresentStatus::Presented)
+    }
+
+    pub fn present(self) -> Result<PresentStatus, SoftBufferError> {
+        let full = self.full_rect();
+        self.put_damage(&[full], full)
     }
 
-    pub fn present(self) -> Result<(), SoftBufferError> {
+    fn full_rect(&self) -> Rect {
         let (width, height) = self
             .0
             .size
             .expect("Must set size of surface before calling `present()`");
-        self.present_with_damage(&[Rect {
+        Rect {
             x: 0,
             y: 0,
             width: width.into(),
             height: height.into(),
-        }])
+        }
+    }
+}
+
+/// Remember a request drawing to the window, so `buffer_mut()` can check it for errors.
+fn track_drawn(
+    connection: &XCBConnection,
+    drawn: &mut Option<SequenceNumber>,
+    cookie: VoidCookie<'_, XCBConnection>,
+) {
+    let sequence = cookie.sequence_number();
+    // Dropping the cookie would turn the error into an event.
+    mem::forget(cookie);
+    // Only the errors of the last request need to be checked.
+    if let Some(previous) = drawn.replace(sequence) {
+        VoidCookie::new(connection, previous).ignore_error();
+    }
+}
+
+/// Convert an X11 error, reporting errors about the window as
+/// [`SoftBufferError::WindowDestroyed`].
+fn window_err<T>(result: Result<T, ReplyError>, msg: &str) -> Result<T, SoftBufferError> {
+    match result {
+        Err(ReplyError::X11Error(err))
+            if matches!(err.error_kind, ErrorKind::Window | ErrorKind::Drawable) =>
+        {
+            Err(SoftBufferError::WindowDestroyed)
+        }
+        result => result.swbuf_err(msg),
+    }
+}
+
+/// The edges of a window on its parent, including its border.
+struct Bounds {
+    left: i32,
+    top: i32,
+    right: i32,
+    bottom: i32,
+}
+
+impl Bounds {
+    fn new(x: i16, y: i16, width: u16, height: u16, border_width: u16) -> Self {
+        let (x, y) = (i32::from(x), i32::from(y));
+        let border = 2 * i32::from(border_width);
+        Self {
+            left: x,
+            top: y,
+            right: x + i32::from(width) + border,
+            bottom: y + i32::from(height) + border,
+        }
+    }
+
+    fn contains(&self, other: &Self) -> bool {
+        self.left <= other.left
+            && self.top <= other.top
+            && self.right >= other.right
+            && self.bottom >= other.bottom
+    }
+
+    fn intersects(&self, other: &Self) -> bool {
+        self.left < other.right
+            && other.left < self.right
+            && self.top < other.bottom
+            && other.top < self.bottom
     }
 }
 
@@ -486,7 +1271,8 @@ impl Buffer {
         match self {
             Buffer::Shm(ref mut shm) => shm.alloc_segment(conn, total_len(width, height)),
             Buffer::Wire(wire) => {
-                wire.resize(total_len(width, height), 0);
+                // `total_len` is in bytes.
+                util::resize_pixels(wire, total_len(width, height) / 4);
                 Ok(())
             }
         }
@@ -547,6 +1333,7 @@ impl ShmBuffer {
 
         // Reallocate if necessary.
         if needs_realloc {
+            trace_span!("shm_alloc", size);
             let new_seg = ShmSegment::new(size, buffer_size)?;
             self.associate(conn, new_seg)?;
         } else if let Some((ref mut seg, _)) = self.seg {
@@ -633,6 +1420,7 @@ impl ShmBuffer {
     /// Wait for the SHM processing to finish.
     fn finish_wait(&mut self, c: &impl Connection) -> Result<(), PushBufferError> {
         if let Some(done_processing) = self.done_processing.take() {
+            trace_span!("shm_wait");
             // Cast to a cookie and wait on it.
             let cookie = Cookie::<_, xproto::GetInputFocusReply>::new(c, done_processing);
             cookie.reply()?;
@@ -649,6 +1437,9 @@ struct ShmSegment {
     buffer_size: usize,
 }
 
+// SAFETY: The segment is owned by the surface, and can be attached or detached from any thread.
+unsafe impl Send for ShmSegment {}
+
 impl ShmSegment {
     /// Create a new `ShmSegment` with the given size.
     fn new(size: usize, buffer_size: usize) -> io::Result<Self> {
@@ -786,6 +1577,14 @@ fn is_shm_available(c: &impl Connection) -> bool {
     matches!((attach.check(), detach.check()), (Ok(()), Ok(())))
 }
 
+/// Test to see if SHM segments can be attached from file descriptors.
+fn is_shm_fd_available(c: &impl Connection) -> bool {
+    match c.shm_query_version().map(|cookie| cookie.reply()) {
+        Ok(Ok(version)) => (version.major_version, version.minor_version) >= (1, 2),
+        _ => false,
+    }
+}
+
 /// An error that can occur when pushing a buffer to the window.
 #[derive(Debug)]
 enum PushBufferError {
@@ -850,6 +1649,90 @@ impl<T, E: Into<PushBufferError>> PushResultExt<T, E> for Result<T, E> {
     }
 }
 
+/// How the X server lays out the pixels of images, when that isn't like the buffer, with 32 bits
+/// per pixel in the byte order of the client. Some drivers pack depth 24 into 24 bits per pixel,
+/// or pad rows to 64 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ImageLayout {
+    bytes_per_pixel: usize,
+    /// Rows are padded to a multiple of this many bytes.
+    scanline_pad: usize,
+    big_endian: bool,
+}
+
+impl ImageLayout {
+    /// The layout of images of `depth` on the X server, or `None` if it is that of the buffer.
+    ///
+    /// Returns [`SoftBufferError::Unimplemented`] unless the images have 24 or 32 bits per pixel.
+    fn new(setup: &xproto::Setup, depth: u8) -> Result<Option<Self>, SoftBufferError> {
+        let format = setup
+            .pixmap_formats
+            .iter()
+            .find(|format| format.depth == depth)
+            .swbuf_err("The X server has no pixmap format for the depth of the window")?;
+        let layout = Self {
+            bytes_per_pixel: usize::from(format.bits_per_pixel / 8),
+            scanline_pad: usize::from(format.scanline_pad / 8).max(1),
+            big_endian: setup.image_byte_order == xproto::ImageOrder::MSB_FIRST,
+        };
+        match format.bits_per_pixel {
+            32 if layout.big_endian == cfg!(target_endian = "big")
+                && 4 % layout.scanline_pad == 0 =>
+            {
+                Ok(None)
+            }
+            24 | 32 => Ok(Some(layout)),
+            bits_per_pixel => {
+                // The pixels would have to be quantized, which isn't implemented.
+                log::warn!(
+                    "Images of depth {} have {} bits per pixel, which buffers aren't converted to",
+                    depth,
+                    bits_per_pixel
+                );
+                Err(SoftBufferError::Unimplemented)
+            }
+        }
+    }
+
+    /// The length of a row of `width` pixels with its padding, in bytes.
+    fn stride(&self, width: usize) -> usize {
+        let bytes = width * self.bytes_per_pixel;
+        (bytes + self.scanline_pad - 1) / self.scanline_pad * self.scanline_pad
+    }
+
+    /// Append the row to `out` in this layout.
+    fn pack(&self, row: &[u32], out: &mut Vec<u8>) {
+        let start = out.len();
+        let bytes_per_pixel = self.bytes_per_pixel;
+        for &pixel in row {
+            // Packed pixels leave out the unused highest byte.
+            if self.big_endian {
+                out.extend_from_slice(&pixel.to_be_bytes()[4 - bytes_per_pixel..]);
+            } else {
+                out.extend_from_slice(&pixel.to_le_bytes()[..bytes_per_pixel]);
+            }
+        }
+        out.resize(start + self.stride(row.len()), 0);
+    }
+
+    /// Append the rows of `width` pixels of `data` in this layout to `out`.
+    fn unpack(&self, data: &[u8], width: usize, out: &mut Vec<u32>) {
+        let bytes_per_pixel = self.bytes_per_pixel;
+        for row in data.chunks_exact(self.stride(width)) {
+            out.extend(row.chunks_exact(bytes_per_pixel).take(width).map(|bytes| {
+                let mut pixel = [0; 4];
+                if self.big_endian {
+                    pixel[4 - bytes_per_pixel..].copy_from_slice(bytes);
+                    u32::from_be_bytes(pixel)
+                } else {
+                    pixel[..bytes_per_pixel].copy_from_slice(bytes);
+                    u32::from_le_bytes(pixel)
+                }
+            }));
+        }
+    }
+}
+
 /// Get the length that a slice needs to be to hold a buffer of the given dimensions.
 #[inline(always)]
 fn total_len(width: u16, height: u16) -> usize {
@@ -0,0 +1,77 @@
+#![cfg(all(feature = "compositor", feature = "testing"))]
+
+use softbuffer::compositor::Compositor;
+use softbuffer::testing::HeadlessSurface;
+use softbuffer::{PresentStatus, Rect};
+use std::num::NonZeroU32;
+
+#[test]
+fn composite_layers() {
+    let size = |n| NonZeroU32::new(n).unwrap();
+    let mut surface = HeadlessSurface::new(size(4), size(2));
+    let mut compositor = Compositor::new(size(4), size(2));
+    compositor.set_background(0xff00_0000);
+    let bottom = compositor.add_layer(size(2), size(2));
+    let top = compositor.add_layer(size(2), size(1));
+
+    compositor
+        .layer_mut(bottom)
+        .unwrap()
+        .pixels_mut()
+        .fill(0xff00_00ff);
+    let mut layer = compositor.layer_mut(top).unwrap();
+    // Half transparent red, premultiplied.
+    layer.pixels_mut().fill(0x8080_0000);
+    layer.set_position(1, 0);
+    assert_eq!(
+        compositor.present(&mut surface).unwrap(),
+        PresentStatus::Presented
+    );
+    assert_eq!(
+        surface.frame().pixels(),
+        [
+            0x0000_00ff,
+            0x0080_007f,
+            0x0080_0000,
+            0x0000_0000,
+            0x0000_00ff,
+            0x0000_00ff,
+            0x0000_0000,
+            0x0000_0000,
+        ]
+    );
+    assert_eq!(
+        compositor.present(&mut surface).unwrap(),
+        PresentStatus::Skipped
+    );
+
+    // Raising the bottom layer and moving it out of the frame only changes where it was.
+    let mut layer = compositor.layer_mut(bottom).unwrap();
+    layer.set_z(1);
+    layer.set_position(-1, 1);
+    layer
+        .update(&[Rect {
+            x: 0,
+            y: 0,
+            width: size(1),
+            height: size(1),
+        }])
+        .unwrap()[0] = 0xff00_ff00;
+    assert_eq!(
+        compositor.present(&mut surface).unwrap(),
+        PresentStatus::Presented
+    );
+    assert_eq!(
+        &surface.frame().pixels()[..5],
+        [
+            0x0000_0000,
+            0x0080_0000,
+            0x0080_0000,
+            0x0000_0000,
+            0x0000_00ff
+        ]
+    );
+
+    assert!(compositor.remove_layer(top));
+    assert!(compositor.layer_mut(top).is_none());
+}
@@ -0,0 +1,531 @@
+#![cfg(feature = "framebuffer")]
+
+use softbuffer::{
+    Backend, FramebufferFormat, PresentStatus, RawFramebuffer, Rect, RectCopy, Region, Scaling,
+    ScalingFilter, SoftBufferError, Surface,
+};
+use std::num::NonZeroU32;
+use std::ptr::NonNull;
+
+/// Returns a surface on zeroed memory, which is returned too. Bind both with
+/// `let (memory, surface)`, so the surface is dropped first.
+fn framebuffer(
+    width: u32,
+    height: u32,
+    stride: usize,
+    format: FramebufferFormat,
+) -> (Vec<u8>, Surface) {
+    let mut memory = vec![0u8; stride * height as usize];
+    let framebuffer = RawFramebuffer {
+        ptr: NonNull::new(memory.as_mut_ptr()).unwrap(),
+        width: NonZeroU32::new(width).unwrap(),
+        height: NonZeroU32::new(height).unwrap(),
+        stride,
+        format,
+    };
+    // Moving the `Vec` doesn't move the memory it points to.
+    let surface = unsafe { Surface::from_framebuffer(framebuffer) }.unwrap();
+    (memory, surface)
+}
+
+#[test]
+fn backend() {
+    let (_memory, surface) = framebuffer(4, 3, 16, FramebufferFormat::Bgrx8888);
+    assert_eq!(surface.backend(), Backend::Framebuffer);
+    assert!(Backend::compiled().contains(&Backend::Framebuffer));
+    assert_eq!(surface.window_size().unwrap(), (4, 3));
+}
+
+#[test]
+fn capabilities() {
+    let (_memory, surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    let capabilities = surface.capabilities();
+    assert!(capabilities.partial_present);
+    assert_eq!(capabilities.buffer_count, 1);
+    assert!(capabilities.readback);
+}
+
+#[test]
+fn single_buffered() {
+    let (_memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    surface.set_single_buffered(true).unwrap();
+    let size = NonZeroU32::new(2).unwrap();
+    surface.resize(size, size).unwrap();
+    let mut buffer = surface.buffer_mut().unwrap();
+    assert_eq!(buffer.index(), 0);
+    buffer.fill(0x0012_3456);
+    buffer.present().unwrap();
+    assert_eq!(surface.fetch().unwrap(), vec![0x0012_3456; 4]);
+}
+
+#[test]
+fn frame_stats() {
+    let (_memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    let size = NonZeroU32::new(2).unwrap();
+    surface.resize(size, size).unwrap();
+    assert_eq!(surface.frame_stats().frames, 0);
+    surface.buffer_mut().unwrap().present().unwrap();
+
+    let stats = surface.frame_stats();
+    assert_eq!(stats.frames, 1);
+    // Framebuffers don't find out when frames are shown.
+    assert_eq!(stats.latency, None);
+    assert_eq!(stats.dropped_frames, 0);
+}
+
+#[test]
+fn present_and_fetch_rgb565() {
+    // Padding at the end of each row, like most real framebuffers have.
+    let stride = 12;
+    let (memory, mut surface) = framebuffer(4, 3, stride, FramebufferFormat::Rgb565);
+    surface
+        .resize(NonZeroU32::new(4).unwrap(), NonZeroU32::new(3).unwrap())
+        .unwrap();
+
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer.fill(0x00ff_ffff);
+    buffer[5] = 0x00ff_0000;
+    buffer
+        .present_with_damage([Rect {
+            x: 1,
+            y: 1,
+            width: NonZeroU32::new(2).unwrap(),
+            height: NonZeroU32::new(1).unwrap(),
+        }])
+        .unwrap();
+
+    // Only the damaged pixels were copied.
+    let mut expected = vec![0; 12];
+    expected[5] = 0x00ff_0000;
+    expected[6] = 0x00ff_ffff;
+    assert_eq!(surface.fetch().unwrap(), expected);
+    drop(surface);
+    assert_eq!(&memory[stride + 2..stride + 6], &[0x00, 0xf8, 0xff, 0xff]);
+}
+
+#[test]
+fn skip_unchanged() {
+    let size = NonZeroU32::new(2).unwrap();
+    let (_memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    surface.resize(size, size).unwrap();
+    surface.set_skip_unchanged(true);
+
+    for (pixel, status) in [
+        (0x0012_3456, PresentStatus::Presented),
+        (0x0012_3456, PresentStatus::Skipped),
+        (0x0065_4321, PresentStatus::Presented),
+    ] {
+        let mut buffer = surface.buffer_mut().unwrap();
+        buffer.fill(pixel);
+        assert_eq!(buffer.present().unwrap(), status);
+    }
+    assert_eq!(surface.frame_stats().frames, 2);
+    assert_eq!(surface.fetch().unwrap(), vec![0x0065_4321; 4]);
+}
+
+#[test]
+fn damage_out_of_range() {
+    let size = NonZeroU32::new(2).unwrap();
+    let (_memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    surface.resize(size, size).unwrap();
+
+    let rect = Rect {
+        x: 1,
+        y: 0,
+        width: size,
+        height: size,
+    };
+    let buffer = surface.buffer_mut().unwrap();
+    assert!(matches!(
+        buffer.present_with_damage([rect]),
+        Err(SoftBufferError::DamageOutOfRange { .. })
+    ));
+    assert_eq!(surface.frame_stats().frames, 0);
+}
+
+#[test]
+fn buffer_mut_without_resize() {
+    let (_memory, mut surface) = framebuffer(3, 2, 12, FramebufferFormat::Bgrx8888);
+    assert!(matches!(
+        surface.fetch(),
+        Err(SoftBufferError::SurfaceNotConfigured)
+    ));
+    assert_eq!(surface.size(), None);
+    assert!(matches!(
+        surface.is_size_stale(),
+        Err(SoftBufferError::SurfaceNotConfigured)
+    ));
+
+    // The buffer is sized like the framebuffer.
+    assert_eq!(surface.buffer_mut().unwrap().len(), 6);
+    let (width, height) = surface.size().unwrap();
+    assert_eq!((width.get(), height.get()), (3, 2));
+    assert!(!surface.is_size_stale().unwrap());
+
+    surface.resize(width, width).unwrap();
+    assert!(surface.is_size_stale().unwrap());
+}
+
+#[test]
+fn release_buffers() {
+    let size = NonZeroU32::new(2).unwrap();
+    let (_memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    surface.resize(size, size).unwrap();
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer.fill(0x0012_3456);
+    buffer.present().unwrap();
+
+    // Nothing is fetched while the buffers are released, and they are allocated again.
+    surface.release_buffers().unwrap();
+    assert!(matches!(
+        surface.fetch(),
+        Err(SoftBufferError::SurfaceNotConfigured)
+    ));
+    let buffer = surface.buffer_mut().unwrap();
+    assert_eq!(buffer.len(), 4);
+    assert_eq!(buffer.age(), 0);
+    drop(buffer);
+    // The framebuffer still shows the last frame.
+    assert_eq!(surface.fetch().unwrap(), vec![0x0012_3456; 4]);
+}
+
+#[test]
+fn resize_scaled() {
+    let (_memory, mut surface) = framebuffer(4, 3, 16, FramebufferFormat::Bgrx8888);
+    let (width, height) = surface.resize_scaled((2.0, 1.5), 2.0).unwrap();
+    assert_eq!((width.get(), height.get()), (4, 3));
+    assert_eq!(surface.buffer_mut().unwrap().len(), 12);
+}
+
+#[test]
+fn present_centered() {
+    let (memory, mut surface) = framebuffer(4, 2, 16, FramebufferFormat::Bgrx8888);
+    surface
+        .set_scaling(Scaling::Center, ScalingFilter::Nearest)
+        .unwrap();
+    surface.set_background_color(0x0011_2233).unwrap();
+    surface
+        .resize(NonZeroU32::new(2).unwrap(), NonZeroU32::new(1).unwrap())
+        .unwrap();
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer.fill(0x00ff_ffff);
+    buffer.present().unwrap();
+    drop(surface);
+
+    // The buffer is drawn 1:1 into the upper middle, and the rest is the background.
+    const BG: u32 = 0x0011_2233;
+    let pixels: Vec<u32> = memory
+        .chunks_exact(4)
+        .map(|pixel| u32::from_le_bytes(pixel.try_into().unwrap()))
+        .collect();
+    assert_eq!(pixels, [BG, 0x00ff_ffff, 0x00ff_ffff, BG, BG, BG, BG, BG]);
+}
+
+#[test]
+fn strict() {
+    let (width, height) = (NonZeroU32::new(4).unwrap(), NonZeroU32::new(3).unwrap());
+    let (_memory, mut surface) = framebuffer(4, 3, 16, FramebufferFormat::Bgrx8888);
+    surface.set_strict(true);
+    assert!(matches!(
+        surface.buffer_mut(),
+        Err(SoftBufferError::SurfaceNotConfigured)
+    ));
+    surface.resize(height, height).unwrap();
+    assert!(matches!(
+        surface.buffer_mut(),
+        Err(SoftBufferError::SizeMismatch { .. })
+    ));
+
+    surface.resize(width, height).unwrap();
+    let half = Rect {
+        x: 0,
+        y: 0,
+        width: NonZeroU32::new(2).unwrap(),
+        height,
+    };
+    let buffer = surface.buffer_mut().unwrap();
+    assert!(matches!(
+        buffer.present_with_damage([half]),
+        Err(SoftBufferError::IncompleteDamage)
+    ));
+    let other_half = Rect { x: 2, ..half };
+    let mut region: Region = [half, other_half].into_iter().collect();
+    // Rects inside of the region don't add anything.
+    region.add(Rect {
+        x: 3,
+        width: NonZeroU32::new(1).unwrap(),
+        ..half
+    });
+    assert_eq!(region.rects(), [half, other_half]);
+    assert_eq!(
+        region.bounds(),
+        Some(Rect {
+            width: NonZeroU32::new(4).unwrap(),
+            ..half
+        })
+    );
+    let buffer = surface.buffer_mut().unwrap();
+    buffer.present_with_damage(&region).unwrap();
+
+    // The buffer has the last frame now, so part of it can be damaged.
+    let buffer = surface.buffer_mut().unwrap();
+    assert_eq!(buffer.age(), 1);
+    buffer.present_with_damage(std::iter::once(half)).unwrap();
+}
+
+#[test]
+fn flip_vertical() {
+    let size = NonZeroU32::new(2).unwrap();
+    let (memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    surface.resize(size, size).unwrap();
+    surface.set_flip_vertical(true).unwrap();
+
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer.copy_from_slice(&[0x0000_0001, 0x0000_0002, 0x0000_0003, 0x0000_0004]);
+    // The damaged top row of the buffer is the bottom row of the framebuffer.
+    buffer
+        .present_with_damage([Rect {
+            x: 0,
+            y: 0,
+            width: size,
+            height: NonZeroU32::new(1).unwrap(),
+        }])
+        .unwrap();
+    assert_eq!(surface.fetch().unwrap(), [0, 0, 1, 2]);
+
+    surface.buffer_mut().unwrap().present().unwrap();
+    assert_eq!(surface.fetch().unwrap(), [3, 4, 1, 2]);
+    drop(surface);
+    assert_eq!(&memory[..4], &[3, 0, 0, 0]);
+}
+
+#[test]
+fn copy_rects() {
+    let size = NonZeroU32::new(2).unwrap();
+    let row = NonZeroU32::new(1).unwrap();
+    let (_memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    surface.resize(size, size).unwrap();
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer.copy_from_slice(&[1, 2, 3, 4]);
+    buffer.present().unwrap();
+
+    // Scroll up by a row, and draw the row that scrolled in.
+    let mut buffer = surface.buffer_mut().unwrap();
+    let scroll = RectCopy {
+        src_x: 0,
+        src_y: 1,
+        dst: Rect {
+            x: 0,
+            y: 0,
+            width: size,
+            height: row,
+        },
+    };
+    buffer.copy_rects(&[scroll]).unwrap();
+    buffer[2..].copy_from_slice(&[5, 6]);
+    let new_row = Rect {
+        x: 0,
+        y: 1,
+        width: size,
+        height: row,
+    };
+    buffer.present_with_damage([new_row]).unwrap();
+    assert_eq!(surface.fetch().unwrap(), [3, 4, 5, 6]);
+
+    let mut buffer = surface.buffer_mut().unwrap();
+    let out_of_range = RectCopy { src_x: 1, ..scroll };
+    assert!(matches!(
+        buffer.copy_rects(&[out_of_range]),
+        Err(SoftBufferError::DamageOutOfRange { .. })
+    ));
+}
+
+#[test]
+fn present_viewport() {
+    let size = NonZeroU32::new(2).unwrap();
+    let (memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    // The buffer is larger than the framebuffer.
+    let buffer_size = NonZeroU32::new(3).unwrap();
+    surface.resize(buffer_size, buffer_size).unwrap();
+    surface.set_skip_unchanged(true);
+
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer.copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    let viewport = Rect {
+        x: 1,
+        y: 1,
+        width: size,
+        height: size,
+    };
+    assert_eq!(
+        buffer.present_viewport(viewport).unwrap(),
+        PresentStatus::Presented
+    );
+    drop(surface);
+    let pixels: Vec<u8> = memory.chunks_exact(4).map(|pixel| pixel[0]).collect();
+    assert_eq!(pixels, [5, 6, 8, 9]);
+}
+
+#[test]
+fn pan_view() {
+    let size = NonZeroU32::new(2).unwrap();
+    let (_memory, mut surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    let buffer_size = NonZeroU32::new(3).unwrap();
+    surface.resize(buffer_size, buffer_size).unwrap();
+    surface.set_view_size(Some((size, size)));
+
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer.copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    buffer.present().unwrap();
+    // Fetching returns what the framebuffer shows in the upper-left corner of the buffer.
+    assert_eq!(surface.fetch().unwrap(), [1, 2, 0, 4, 5, 0, 0, 0, 0]);
+
+    // The view stops at the edges of the buffer.
+    surface.set_pan(5, 1);
+    assert_eq!(surface.pan(), (1, 1));
+
+    // Panning damages all of the view.
+    let pixel = Rect {
+        x: 2,
+        y: 2,
+        width: NonZeroU32::new(1).unwrap(),
+        height: NonZeroU32::new(1).unwrap(),
+    };
+    surface
+        .buffer_mut()
+        .unwrap()
+        .present_with_damage([pixel])
+        .unwrap();
+    assert_eq!(surface.fetch().unwrap(), [5, 6, 0, 8, 9, 0, 0, 0, 0]);
+
+    // Otherwise only the damaged pixels are copied.
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer[4] = 50;
+    buffer[8] = 90;
+    buffer.present_with_damage([pixel]).unwrap();
+    assert_eq!(surface.fetch().unwrap(), [5, 6, 0, 8, 90, 0, 0, 0, 0]);
+}
+
+#[test]
+fn present_pixels_to_two_surfaces() {
+    let size = NonZeroU32::new(2).unwrap();
+    let (_first_memory, first) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    let (_second_memory, second) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    let mut surfaces = [first, second];
+
+    let full = Rect {
+        x: 0,
+        y: 0,
+        width: size,
+        height: size,
+    };
+    let mut pixels = [1, 2, 3, 4];
+    for surface in &mut surfaces {
+        surface
+            .present_pixels(&pixels, size, size, &[full])
+            .unwrap();
+    }
+
+    // The buffers of the surfaces have the last frame, so only the damage is copied.
+    pixels[0] = 10;
+    pixels[3] = 40;
+    let pixel = Rect {
+        x: 1,
+        y: 1,
+        width: NonZeroU32::new(1).unwrap(),
+        height: NonZeroU32::new(1).unwrap(),
+    };
+    for surface in &mut surfaces {
+        assert_eq!(
+            surface
+                .present_pixels(&pixels, size, size, &[pixel])
+                .unwrap(),
+            PresentStatus::Presented
+        );
+        assert_eq!(surface.fetch().unwrap(), [1, 2, 3, 40]);
+    }
+}
+
+#[test]
+fn copy_between_framebuffers() {
+    let size = NonZeroU32::new(2).unwrap();
+    let (_from_memory, mut from) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    from.resize(size, size).unwrap();
+    let mut buffer = from.buffer_mut().unwrap();
+    buffer.copy_from_slice(&[0x0000_00ff, 0x0000_ff00, 0x00ff_0000, 0x00ff_ffff]);
+    buffer.present().unwrap();
+
+    let (_to_memory, mut to) = framebuffer(2, 2, 4, FramebufferFormat::Rgb565);
+    to.resize(size, size).unwrap();
+
+    // The bottom row is copied to the right, and clipped to the framebuffer.
+    let row = Rect {
+        x: 0,
+        y: 1,
+        width: size,
+        height: NonZeroU32::new(1).unwrap(),
+    };
+    to.copy_from(&from, row, (1, 0)).unwrap();
+    assert_eq!(to.fetch().unwrap(), [0, 0x00ff_0000, 0, 0]);
+}
+
+#[cfg(feature = "present-thread")]
+#[test]
+fn present_thread() {
+    use softbuffer::present_thread::PresentThread;
+
+    let size = NonZeroU32::new(2).unwrap();
+    let (_memory, surface) = framebuffer(2, 2, 8, FramebufferFormat::Bgrx8888);
+    let mut present_thread = PresentThread::new(surface).unwrap();
+    let mut frame = [0x0012_3456; 4];
+    let all = Rect {
+        x: 0,
+        y: 0,
+        width: size,
+        height: size,
+    };
+    present_thread.submit(&frame, size, size, &[all]).unwrap();
+    frame[3] = 0x00ff_ffff;
+    let corner = Rect {
+        x: 1,
+        y: 1,
+        width: NonZeroU32::new(1).unwrap(),
+        height: NonZeroU32::new(1).unwrap(),
+    };
+    present_thread
+        .submit(&frame, size, size, &[corner])
+        .unwrap();
+    let outside = Rect { x: 2, ..corner };
+    assert!(matches!(
+        present_thread.submit(&frame, size, size, &[outside]),
+        Err(SoftBufferError::DamageOutOfRange { .. })
+    ));
+
+    // The frames were presented in order before the surface is returned.
+    let (mut surface, result) = present_thread.finish();
+    result.unwrap();
+    assert_eq!(surface.fetch().unwrap(), frame);
+
+    // Errors of the thread are returned with the surface. Strict surfaces reject frames of
+    // another size than the framebuffer.
+    surface.set_strict(true);
+    let mut present_thread = PresentThread::new(surface).unwrap();
+    let one = NonZeroU32::new(1).unwrap();
+    present_thread
+        .submit(
+            &frame[..2],
+            size,
+            one,
+            &[Rect {
+                x: 0,
+                y: 0,
+                width: one,
+                height: one,
+            }],
+        )
+        .unwrap();
+    let (mut surface, result) = present_thread.finish();
+    assert!(matches!(result, Err(SoftBufferError::SizeMismatch { .. })));
+    surface.resize(size, size).unwrap();
+    assert!(surface.buffer_mut().is_ok());
+}
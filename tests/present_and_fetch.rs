@@ -1,3 +1,4 @@
+use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 use softbuffer::{Context, Surface};
 use std::num::NonZeroU32;
 use winit::event_loop::EventLoopWindowTarget;
@@ -26,8 +27,8 @@ fn all_red(elwt: &EventLoopWindowTarget<()>) {
     #[cfg(not(target_arch = "wasm32"))]
     std::thread::sleep(std::time::Duration::from_millis(1));
 
-    let context = unsafe { Context::new(elwt) }.unwrap();
-    let mut surface = unsafe { Surface::new(&context, &window) }.unwrap();
+    let context = unsafe { Context::from_raw(elwt.raw_display_handle()) }.unwrap();
+    let mut surface = unsafe { Surface::from_raw(&context, window.raw_window_handle()) }.unwrap();
     let size = window.inner_size();
 
     // Set the size of the surface to the size of the window.
@@ -0,0 +1,125 @@
+#![cfg(feature = "testing")]
+
+use softbuffer::assert_frame_matches;
+use softbuffer::testing::{Frame, HeadlessSurface, Mismatch};
+use softbuffer::Rect;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::{env, fs, process};
+
+#[test]
+fn compare_frames() {
+    let (width, height) = (NonZeroU32::new(3).unwrap(), NonZeroU32::new(2).unwrap());
+    let mut surface = HeadlessSurface::new(width, height);
+    assert_eq!(surface.window_size().unwrap(), (3, 2));
+    surface.resize(width, height).unwrap();
+    let mut buffer = surface.buffer_mut().unwrap();
+    buffer.fill(0x0010_2030);
+    buffer[4] = 0x00ff_ffff;
+    buffer.present().unwrap();
+
+    let frame = surface.frame();
+    let mut pixels = vec![0x0010_2030; 6];
+    pixels[4] = 0x00ff_fffe;
+    let reference = Frame::new(width, height, pixels);
+    assert_eq!(frame.compare(&reference, 1), Ok(()));
+    assert_eq!(
+        frame.compare(&reference, 0),
+        Err(Mismatch::Pixels {
+            count: 1,
+            first: (1, 1),
+            max_difference: 1,
+        })
+    );
+    assert!(matches!(
+        frame.compare(&Frame::new(height, width, vec![0; 6]), 255),
+        Err(Mismatch::Size { .. })
+    ));
+
+    assert_eq!(Frame::from_ppm(&frame.to_ppm()).unwrap(), frame);
+    let path = env::temp_dir().join(format!("softbuffer-{}/frame.ppm", process::id()));
+    reference.save(&path).unwrap();
+    assert_frame_matches!(frame, &path, 1);
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn ppm_header_comments() {
+    let ppm = b"P6 # made by hand\n1\n1 255\n\x01\x02\x03";
+    let frame = Frame::from_ppm(ppm).unwrap();
+    assert_eq!(frame.pixels(), [0x0001_0203]);
+    assert!(Frame::from_ppm(b"P6 1 1 255\n\x01").is_err());
+}
+
+#[test]
+fn smooth_resize() {
+    let size = |n| NonZeroU32::new(n).unwrap();
+    let mut surface = HeadlessSurface::new(size(3), size(2));
+    surface.set_smooth_resize(true).unwrap();
+    surface.resize(size(2), size(2)).unwrap();
+    let mut buffer = surface.buffer_mut().unwrap();
+    assert_eq!(*buffer, [0; 4]);
+    buffer.copy_from_slice(&[1, 2, 3, 4]);
+    buffer.present().unwrap();
+
+    // The last frame is kept in the upper-left corner.
+    surface.resize(size(3), size(1)).unwrap();
+    surface.resize(size(3), size(2)).unwrap();
+    let buffer = surface.buffer_mut().unwrap();
+    assert_eq!(*buffer, [1, 2, 0, 3, 4, 0]);
+}
+
+#[test]
+fn resize_fill() {
+    let size = |n| NonZeroU32::new(n).unwrap();
+    let mut surface = HeadlessSurface::new(size(3), size(2));
+    surface.set_resize_fill(Some(0x0012_3456));
+    surface.resize(size(2), size(2)).unwrap();
+    let mut buffer = surface.buffer_mut().unwrap();
+    assert_eq!(*buffer, [0x0012_3456; 4]);
+    buffer.copy_from_slice(&[1, 2, 3, 4]);
+    buffer.present().unwrap();
+
+    // Only the parts around the last frame are filled when resizing smoothly.
+    surface.set_smooth_resize(true).unwrap();
+    surface.resize(size(3), size(2)).unwrap();
+    let buffer = surface.buffer_mut().unwrap();
+    assert_eq!(*buffer, [1, 2, 0x0012_3456, 3, 4, 0x0012_3456]);
+}
+
+#[test]
+fn present_hook() {
+    let size = |n| NonZeroU32::new(n).unwrap();
+    let mut surface = HeadlessSurface::new(size(3), size(2));
+    let presents = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&presents);
+    surface.set_present_hook(move |info| {
+        recorded
+            .lock()
+            .unwrap()
+            .push((info.frame, info.interval.is_some(), info.damage.to_vec()));
+    });
+    let rect = Rect {
+        x: 1,
+        y: 0,
+        width: size(1),
+        height: size(2),
+    };
+    surface.buffer_mut().unwrap().present().unwrap();
+    surface
+        .buffer_mut()
+        .unwrap()
+        .present_with_damage([rect])
+        .unwrap();
+
+    let all = Rect {
+        x: 0,
+        y: 0,
+        width: size(3),
+        height: size(2),
+    };
+    assert_eq!(
+        *presents.lock().unwrap(),
+        [(0, false, vec![all]), (1, true, vec![rect])]
+    );
+}